@@ -11,6 +11,8 @@ if command -v cargo > /dev/null; then
     read -p "Path to shell configuration file: " shell
     DATA_PATH=$path cargo build -q --bin server --release
     cargo build -q --bin client --release
+    cargo build -q --bin benchmark --release
+    cargo build -q --bin sentinel --release
     if [ -d "./mini_bin" ]; then
         echo "bin setup"
     else
@@ -18,6 +20,8 @@ if command -v cargo > /dev/null; then
     fi
     mv ./target/release/server mini_bin
     mv ./target/release/client mini_bin
+    mv ./target/release/benchmark mini_bin
+    mv ./target/release/sentinel mini_bin
     cur_dir=$(pwd)
     path_exist="$(grep '.*mini_bin.*' $shell)"
     if [ -n "$path_exist" ]; then
@@ -0,0 +1,381 @@
+//! Users, passwords and what each user may run, checked by the server before every command.
+//!
+//! Users are described with rules like `ACL SETUSER`: `on`/`off`, `>password`, `nopass`,
+//! `+@read`/`-@write`, `~pattern`... The ACL file holds one user per line, in the form
+//! `ACL LIST` replies with: `user <name> <rule>...`, with passwords saved as SHA-256 hashes.
+
+use std::{collections::BTreeMap, fs, path::PathBuf, sync::{Arc, Mutex}};
+
+use sha2::{Digest, Sha256};
+
+use crate::{commands::{self, CommandSpec}, models::{self, CacheError, ErrorCode, MainError}, CacheResult};
+
+/// The user connections start as when it has no password.
+pub const DEFAULT_USER: &str = "default";
+
+/// Groups of commands a user can be allowed to run, from the command flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Read,
+    Write,
+    Admin,
+    PubSub,
+    Scripting
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [Category::Read, Category::Write, Category::Admin, Category::PubSub, Category::Scripting];
+
+    pub fn new(name: &str) -> Option<Category> {
+        Category::ALL.into_iter().find(|c| c.name() == name)
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Admin => "admin",
+            Self::PubSub => "pubsub",
+            Self::Scripting => "scripting"
+        }
+    }
+    /// The category a command belongs to, None for commands every user may run (ping, auth...).
+    pub fn of(spec: &CommandSpec) -> Option<Category> {
+        if spec.flags.contains(&commands::ADMIN) {
+            Some(Self::Admin)
+        } else if spec.flags.contains(&commands::WRITE) {
+            Some(Self::Write)
+        } else if spec.flags.contains(&commands::READONLY) {
+            Some(Self::Read)
+        } else if spec.flags.contains(&commands::PUBSUB) {
+            Some(Self::PubSub)
+        } else if spec.flags.contains(&commands::SCRIPTING) {
+            Some(Self::Scripting)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub enabled: bool,
+    /// Any password is accepted.
+    nopass: bool,
+    /// SHA-256 hashes of the passwords, in hex.
+    passwords: Vec<String>,
+    categories: Vec<Category>,
+    key_patterns: Vec<String>
+}
+
+impl User {
+    /// A new user is disabled and allowed nothing until rules say otherwise.
+    pub fn new(name: &str) -> User {
+        User { name: name.to_string(), enabled: false, nopass: false, passwords: Vec::new(), categories: Vec::new(), key_patterns: Vec::new() }
+    }
+    pub fn apply(&mut self, rule: &str) -> Result<(), CacheError> {
+        let unknown = || CacheError::new(ErrorCode::Syntax, format!("Unknown ACL rule {}", rule));
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            },
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            },
+            "allkeys" => self.key_patterns = vec![String::from("*")],
+            "resetkeys" => self.key_patterns.clear(),
+            "allcommands" => self.categories = Category::ALL.to_vec(),
+            "nocommands" => self.categories.clear(),
+            "reset" => *self = User::new(&self.name),
+            _ => match rule.split_at(rule.chars().next().map_or(0, char::len_utf8)) {
+                (">", password) => {
+                    let hash = hash_password(password);
+                    if !self.passwords.contains(&hash) {
+                        self.passwords.push(hash);
+                    }
+                    self.nopass = false;
+                },
+                ("<", password) => {
+                    let hash = hash_password(password);
+                    self.passwords.retain(|p| *p != hash);
+                },
+                ("#", hash) if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    let hash = hash.to_lowercase();
+                    if !self.passwords.contains(&hash) {
+                        self.passwords.push(hash);
+                    }
+                    self.nopass = false;
+                },
+                ("~", pattern) if !pattern.is_empty() => {
+                    if !self.key_patterns.iter().any(|p| p == pattern) {
+                        self.key_patterns.push(pattern.to_string());
+                    }
+                },
+                ("+", category) => {
+                    for category in User::categories(category).ok_or_else(unknown)? {
+                        if !self.categories.contains(&category) {
+                            self.categories.push(category);
+                        }
+                    }
+                    self.categories.sort();
+                },
+                ("-", category) => {
+                    let removed = User::categories(category).ok_or_else(unknown)?;
+                    self.categories.retain(|c| !removed.contains(c));
+                },
+                _ => return Err(unknown())
+            }
+        }
+        Ok(())
+    }
+    /// `@read`, `@write`, `@admin`, `@pubsub` or `@all`.
+    fn categories(name: &str) -> Option<Vec<Category>> {
+        match name.strip_prefix('@')? {
+            "all" => Some(Category::ALL.to_vec()),
+            name => Category::new(name).map(|c| vec![c])
+        }
+    }
+    pub fn check_password(&self, password: &str) -> bool {
+        self.nopass || self.passwords.contains(&hash_password(password))
+    }
+    /// True if the user is allowed to run the command, whatever keys it is given.
+    pub fn can_run(&self, spec: &CommandSpec) -> bool {
+        match Category::of(spec) {
+            Some(category) => self.categories.contains(&category),
+            None => true
+        }
+    }
+    pub fn can_access(&self, key: &str) -> bool {
+        self.key_patterns.iter().any(|pattern| models::glob_match(pattern, key))
+    }
+    /// The rules that rebuild this user, as listed by `ACL LIST` and kept in the ACL file.
+    pub fn rules(&self) -> String {
+        let mut rules = format!("user {} {}", self.name, if self.enabled { "on" } else { "off" });
+        if self.nopass {
+            rules += " nopass";
+        }
+        for hash in &self.passwords {
+            rules += &format!(" #{}", hash);
+        }
+        if self.key_patterns.is_empty() {
+            rules += " resetkeys";
+        }
+        for pattern in &self.key_patterns {
+            rules += &format!(" ~{}", pattern);
+        }
+        if self.categories.len() == Category::ALL.len() {
+            rules += " +@all";
+        } else if self.categories.is_empty() {
+            rules += " -@all";
+        } else {
+            for category in &self.categories {
+                rules += &format!(" +@{}", category.name());
+            }
+        }
+        rules
+    }
+}
+
+fn hash_password(password: &str) -> String {
+    Sha256::digest(password.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Every user of the server, shared by all connections.
+pub struct Acl {
+    users: Mutex<BTreeMap<String, User>>,
+    /// Where users are saved after every change, if anywhere.
+    path: Option<PathBuf>
+}
+
+impl Acl {
+    /// Loads the users of the ACL file if there is one. Without a `default` user in the file,
+    /// `default` may run everything, with requirepass as its password or without any.
+    pub fn new(requirepass: Option<&str>, path: Option<PathBuf>) -> Result<Acl, MainError> {
+        let mut users = BTreeMap::new();
+        if let Some(path) = &path {
+            match fs::read_to_string(path) {
+                Ok(text) => {
+                    for (i, line) in text.lines().enumerate() {
+                        let user = Acl::parse_line(line).map_err(|e| MainError::FileReadError(format!("{} line {}: {}", path.display(), i + 1, e.message)))?;
+                        if let Some(user) = user {
+                            users.insert(user.name.clone(), user);
+                        }
+                    }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(MainError::FileReadError(format!("ACL file {} could not be read: {}", path.display(), e)))
+            }
+        }
+        if !users.contains_key(DEFAULT_USER) {
+            let mut user = User::new(DEFAULT_USER);
+            let password = match requirepass {
+                Some(password) => format!(">{}", password),
+                None => String::from("nopass")
+            };
+            for rule in ["on", password.as_str(), "allkeys", "allcommands"] {
+                user.apply(rule).map_err(|e| MainError::BadCommandFormat(e.message))?;
+            }
+            users.insert(user.name.clone(), user);
+        }
+        Ok(Acl { users: Mutex::new(users), path })
+    }
+    /// `user <name> <rule>...`, None for blank lines and # comments.
+    fn parse_line(line: &str) -> Result<Option<User>, CacheError> {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None => return Ok(None),
+            Some(word) if word.starts_with('#') => return Ok(None),
+            Some("user") => {},
+            Some(_) => return Err(CacheError::new(ErrorCode::Syntax, "Lines start with user <name>"))
+        }
+        let name = words.next().ok_or_else(|| CacheError::new(ErrorCode::Syntax, "Missing user name"))?;
+        let mut user = User::new(name);
+        for rule in words {
+            user.apply(rule)?;
+        }
+        Ok(Some(user))
+    }
+    /// The user a new connection is logged in as, None if it has to AUTH first.
+    pub fn initial_user(&self) -> Option<String> {
+        let users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        match users.get(DEFAULT_USER) {
+            Some(user) if user.enabled && user.nopass => Some(user.name.clone()),
+            _ => None
+        }
+    }
+    /// `auth <password>` for the default user or `auth <user> <password>`, the name of the user on success.
+    pub fn authenticate(&self, args: &[String]) -> Result<String, CacheError> {
+        let (name, password) = match args {
+            [_, password] => (DEFAULT_USER, password),
+            [_, name, password] => (name.as_str(), password),
+            _ => return Err(CacheError::new(ErrorCode::Syntax, "Use auth [user] password"))
+        };
+        let users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        match users.get(name) {
+            Some(user) if user.enabled && user.check_password(password) => Ok(user.name.clone()),
+            _ => Err(CacheError::new(ErrorCode::WrongPass, "Invalid user name or password, or the user is disabled"))
+        }
+    }
+    /// Fails if the user may not run the command given as its arguments, or touch one of its keys.
+    pub fn check(&self, name: &str, spec: &CommandSpec, args: &[String]) -> Result<(), CacheError> {
+        let users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let user = match users.get(name) {
+            Some(user) if user.enabled => user,
+            _ => return Err(CacheError::new(ErrorCode::NoAuth, format!("User {} was deleted or disabled, authenticate again", name)))
+        };
+        // Any user may ask who it is
+        let whoami = spec.handler == crate::Cache::Acl && args.get(1).is_some_and(|s| s.eq_ignore_ascii_case("whoami"));
+        if !user.can_run(spec) && !whoami {
+            return Err(CacheError::new(ErrorCode::NoPerm, format!("User {} has no permission to run {}", name, spec.name)));
+        }
+        match spec.keys(args).into_iter().find(|key| !user.can_access(key)) {
+            Some(key) => Err(CacheError::new(ErrorCode::NoPerm, format!("User {} has no permission to access key {}", name, key))),
+            None => Ok(())
+        }
+    }
+    /// Runs `acl setuser|deluser|list|whoami|cat` for the given user.
+    pub fn execute(&self, name: &str, args: &[String]) -> CacheResult {
+        let subcommand = args.get(1).map(|s| s.to_lowercase()).unwrap_or_default();
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        match (subcommand.as_str(), &args[args.len().min(2)..]) {
+            ("whoami", []) => CacheResult::Success(name.to_string()),
+            ("list", []) => CacheResult::Array(users.values().map(User::rules).collect()),
+            ("cat", []) => CacheResult::Array(Category::ALL.iter().map(|c| c.name().to_string()).collect()),
+            ("setuser", [user_name, rules @ ..]) => {
+                let mut user = users.get(user_name).cloned().unwrap_or_else(|| User::new(user_name));
+                for rule in rules {
+                    if let Err(e) = user.apply(rule) {
+                        return CacheResult::Failure(e);
+                    }
+                }
+                let previous = users.insert(user.name.clone(), user.clone());
+                self.save(&users).map_or_else(|e| {
+                    // Keep memory and the file the same
+                    match previous {
+                        Some(previous) => users.insert(user.name, previous),
+                        None => users.remove(&user.name)
+                    };
+                    e
+                }, |_| CacheResult::Success(String::from("OK")))
+            },
+            ("deluser", names) if !names.is_empty() => {
+                if names.iter().any(|n| n == DEFAULT_USER) {
+                    return CacheResult::error(ErrorCode::Err, "The default user cannot be deleted");
+                }
+                let removed: Vec<User> = names.iter().filter_map(|n| users.remove(n)).collect();
+                match self.save(&users) {
+                    Ok(_) => CacheResult::Success(removed.len().to_string()),
+                    Err(e) => {
+                        for user in removed {
+                            users.insert(user.name.clone(), user);
+                        }
+                        e
+                    }
+                }
+            },
+            _ => CacheResult::error(ErrorCode::Syntax, "Use acl setuser <name> [rule...] | deluser <name>... | list | whoami | cat")
+        }
+    }
+    /// Rewrites the ACL file, through a temporary file so a failed write leaves the old one.
+    fn save(&self, users: &BTreeMap<String, User>) -> Result<(), CacheResult> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let text: String = users.values().map(|user| user.rules() + "\n").collect();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, text).and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| CacheResult::error(ErrorCode::Err, format!("ACL file {} could not be written: {}", path.display(), e)))
+    }
+}
+
+/// The user a script runs for, so the commands it sends with redis.call get the same checks.
+#[derive(Clone)]
+pub struct Caller {
+    pub acl: Arc<Acl>,
+    pub user: String
+}
+
+impl Caller {
+    /// Fails if the user may not run the command given as its arguments. Unknown commands pass,
+    /// they fail once they run.
+    pub fn check(&self, args: &[String]) -> Result<(), CacheError> {
+        match args.first().and_then(|name| commands::lookup(name)) {
+            Some(spec) => self.acl.check(&self.user, spec, args),
+            None => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acl_rules_round_trip() {
+        let mut user = User::new("app");
+        for rule in ["on", ">pw", "+@all", "-@admin", "~app:*", "~shared"] {
+            user.apply(rule).unwrap();
+        }
+        assert!(user.check_password("pw"));
+        assert!(!user.check_password("other"));
+        assert!(user.can_access("app:1") && user.can_access("shared") && !user.can_access("sharedx"));
+        assert!(user.can_run(commands::lookup("set").unwrap()));
+        assert!(!user.can_run(commands::lookup("shutdown").unwrap()));
+        assert!(user.can_run(commands::lookup("ping").unwrap()));
+        assert!(user.apply("+@everything").is_err());
+        assert!(user.apply("sometimes").is_err());
+        // What ACL LIST shows rebuilds the same user
+        let rules = user.rules();
+        let mut copy = User::new("app");
+        for rule in rules.split(' ').skip(2) {
+            copy.apply(rule).unwrap();
+        }
+        assert_eq!(copy, user);
+        user.apply("reset").unwrap();
+        assert_eq!(user.rules(), "user app off resetkeys -@all");
+    }
+}
@@ -0,0 +1,279 @@
+use std::{env, process::ExitCode, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::TcpStream};
+use mini_mcache::{protocol, CacheResult};
+
+pub const HELP_TEXT: &str = r#"
+usage: benchmark [options]
+
+  -h <host>        server host (default 127.0.0.1)
+  -p <port>        server port (default 8080)
+  -c <clients>     number of parallel connections (default 50)
+  -n <requests>    total number of requests per test (default 100000)
+  -d <size>        size of the values in bytes (default 3)
+  -r <keyspace>    number of distinct random keys (default 10000)
+  -t <tests>       comma separated tests to run, one after the other
+                   (default set,get,hset,hget,sadd,smembers,del)
+  --mix            run the tests as one interleaved workload instead.
+                   weights can be given with -t get:8,set:2
+  --help           show this text
+
+strings use key:<n>, hashes hash:<n> and sets set:<n>, so tests do not clash on types.
+set and sadd fail for keys that already exist, failures are counted separately.
+"#;
+
+#[derive(Debug, Clone, Copy)]
+enum Test {
+    Set,
+    Get,
+    HSet,
+    HGet,
+    SAdd,
+    SMembers,
+    Del
+}
+
+impl Test {
+    fn new(name: &str) -> Option<Test> {
+        match name.to_lowercase().as_str() {
+            "set" => Some(Self::Set),
+            "get" => Some(Self::Get),
+            "hset" => Some(Self::HSet),
+            "hget" => Some(Self::HGet),
+            "sadd" => Some(Self::SAdd),
+            "smembers" => Some(Self::SMembers),
+            "del" => Some(Self::Del),
+            _ => None
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Set => "set",
+            Self::Get => "get",
+            Self::HSet => "hset",
+            Self::HGet => "hget",
+            Self::SAdd => "sadd",
+            Self::SMembers => "smembers",
+            Self::Del => "del"
+        }
+    }
+    fn args(&self, key: u64, value: &str) -> Vec<String> {
+        let (cmd, prefix) = match self {
+            Self::Set | Self::Get | Self::Del => (self.name(), "key"),
+            Self::HSet | Self::HGet => (self.name(), "hash"),
+            Self::SAdd | Self::SMembers => (self.name(), "set")
+        };
+        let mut args = vec![cmd.to_string(), format!("{}:{:012}", prefix, key)];
+        match self {
+            Self::Set | Self::SAdd => args.push(value.to_string()),
+            Self::HSet => {
+                args.push(String::from("field"));
+                args.push(value.to_string());
+            },
+            _ => {}
+        }
+        args
+    }
+}
+
+struct Options {
+    addr: String,
+    clients: usize,
+    requests: usize,
+    size: usize,
+    keyspace: u64,
+    tests: Vec<(Test, u32)>,
+    mix: bool
+}
+
+impl Options {
+    fn new(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+        let (mut host, mut port) = (String::from("127.0.0.1"), 8080u16);
+        let mut options = Options {
+            addr: String::new(),
+            clients: 50,
+            requests: 100000,
+            size: 3,
+            keyspace: 10000,
+            tests: ["set", "get", "hset", "hget", "sadd", "smembers", "del"].iter().filter_map(|t| Test::new(t)).map(|t| (t, 1)).collect(),
+            mix: false
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "-h" => host = value()?,
+                "-p" => port = number(&value()?)?,
+                "-c" => options.clients = number(&value()?)?,
+                "-n" => options.requests = number(&value()?)?,
+                "-d" => options.size = number(&value()?)?,
+                "-r" => options.keyspace = number(&value()?)?,
+                "-t" => options.tests = tests(&value()?)?,
+                "--mix" => options.mix = true,
+                "--help" => return Ok(None),
+                _ => return Err(format!("Unknown option {}", arg))
+            }
+        }
+        if options.clients == 0 || options.keyspace == 0 {
+            return Err(String::from("-c and -r must be greater than 0"));
+        }
+        options.addr = format!("{}:{}", host, port);
+        Ok(Some(options))
+    }
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} is not a valid number", value))
+}
+
+fn tests(value: &str) -> Result<Vec<(Test, u32)>, String> {
+    let mut list = Vec::new();
+    for item in value.split(',').filter(|i| !i.trim().is_empty()) {
+        let (name, weight) = match item.split_once(':') {
+            Some((name, weight)) => (name, number(weight)?),
+            None => (item, 1)
+        };
+        let test = Test::new(name.trim()).ok_or_else(|| format!("Unknown test {}", name))?;
+        list.push((test, weight));
+    }
+    if list.is_empty() || list.iter().all(|(_, w)| *w == 0) {
+        return Err(String::from("No test selected"));
+    }
+    Ok(list)
+}
+
+/// Small xorshift generator, good enough to spread keys and pick tests.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    latencies: Vec<Duration>,
+    succeeded: usize,
+    failed: usize
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.latencies.extend(other.latencies);
+        self.succeeded += other.succeeded;
+        self.failed += other.failed;
+    }
+    fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let index = ((self.latencies.len() as f64 * p).ceil() as usize).clamp(1, self.latencies.len());
+        self.latencies[index - 1]
+    }
+    fn show(&mut self, title: &str, options: &Options, elapsed: Duration) {
+        self.latencies.sort();
+        let total = self.succeeded + self.failed;
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        println!("====== {} ======", title);
+        println!("  {} requests completed in {:.2} seconds", total, elapsed.as_secs_f64());
+        println!("  {} parallel clients, {} bytes payload, {} keys", options.clients, options.size, options.keyspace);
+        println!("  {} succeeded, {} failed", self.succeeded, self.failed);
+        println!("  throughput: {:.2} requests per second", total as f64 / elapsed.as_secs_f64().max(f64::EPSILON));
+        println!("  latency (ms): p50={:.3} p99={:.3} p999={:.3} max={:.3}\n",
+            ms(self.percentile(0.5)), ms(self.percentile(0.99)), ms(self.percentile(0.999)),
+            ms(self.latencies.last().copied().unwrap_or_default()));
+    }
+}
+
+async fn client(addr: Arc<String>, workload: Arc<Vec<(Test, u32)>>, remaining: Arc<AtomicUsize>, options: (u64, usize), seed: u64) -> std::io::Result<Report> {
+    let (keyspace, size) = options;
+    let stream = TcpStream::connect(addr.as_str()).await?;
+    stream.set_nodelay(true)?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut rng = Rng(seed | 1);
+    let total_weight: u64 = workload.iter().map(|(_, w)| *w as u64).sum();
+    let value = "x".repeat(size);
+    let mut report = Report::default();
+    let mut line = Vec::new();
+    // Every client takes requests from the shared counter until none are left
+    while remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+        let mut pick = rng.next() % total_weight;
+        let mut test = workload[0].0;
+        for (candidate, weight) in workload.iter() {
+            if pick < *weight as u64 {
+                test = *candidate;
+                break;
+            }
+            pick -= *weight as u64;
+        }
+        let request = protocol::encode_request("benchmark", &test.args(rng.next() % keyspace, &value));
+        let start = Instant::now();
+        writer.write_all(&request).await?;
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "server closed connection"));
+        }
+        report.latencies.push(start.elapsed());
+        match protocol::decode_reply(&line) {
+            CacheResult::Success(_) | CacheResult::Array(_) | CacheResult::Nil => report.succeeded += 1,
+            CacheResult::Failure(_) => report.failed += 1
+        }
+    }
+    Ok(report)
+}
+
+async fn run(options: &Options, workload: Vec<(Test, u32)>) -> std::io::Result<(Report, Duration)> {
+    let addr = Arc::new(options.addr.clone());
+    let workload = Arc::new(workload);
+    let remaining = Arc::new(AtomicUsize::new(options.requests));
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(1);
+    let start = Instant::now();
+    let mut tasks = Vec::with_capacity(options.clients);
+    for i in 0..options.clients {
+        let settings = (options.keyspace, options.size);
+        tasks.push(tokio::spawn(client(addr.clone(), workload.clone(), remaining.clone(), settings, seed.wrapping_add(i as u64 * 7919))));
+    }
+    let mut report = Report::default();
+    for task in tasks {
+        match task.await {
+            Ok(result) => report.merge(result?),
+            Err(e) => return Err(std::io::Error::other(e))
+        }
+    }
+    Ok((report, start.elapsed()))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let options = match Options::new(env::args().skip(1)) {
+        Ok(Some(o)) => o,
+        Ok(None) => {
+            println!("{}", HELP_TEXT);
+            return ExitCode::SUCCESS
+        },
+        Err(e) => {
+            eprintln!("{}\n{}", e, HELP_TEXT);
+            return ExitCode::FAILURE
+        }
+    };
+    let runs: Vec<(String, Vec<(Test, u32)>)> = if options.mix {
+        let title = options.tests.iter().map(|(t, w)| format!("{}:{}", t.name(), w)).collect::<Vec<_>>().join(",");
+        vec![(format!("MIX {}", title), options.tests.clone())]
+    } else {
+        options.tests.iter().map(|(t, _)| (t.name().to_uppercase(), vec![(*t, 1)])).collect()
+    };
+    for (title, workload) in runs {
+        match run(&options, workload).await {
+            Ok((mut report, elapsed)) => report.show(&title, &options, elapsed),
+            Err(e) => {
+                eprintln!("{} failed: {}", title, e);
+                return ExitCode::FAILURE
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
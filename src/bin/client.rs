@@ -1,95 +1,532 @@
-use core::str;
-use std::{io::{self, Write}, net::{IpAddr, Ipv4Addr, SocketAddr}};
-
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
-pub mod utils;
-
-
-pub const HELP_TEXT: &str = r#"
-available commands:
-
-fetch commands
-  get <key>
-      retrieve the value of a key.
-  hget <key>
-      retrieve all fields and values stored in a hash (like redis hgetall).
-  smembers <key>
-      retrieve all members of a set.
-
-change commands
-  set <key> <value>
-      set the value of a key.
-  hset <key> <field> <value>
-      set the value of a field in a hash.
-  sadd <key> <value>
-      add a value to a set.
-
-delete commands
-  del <key>
-      delete a key and its value.
-  hdel <key> <field>
-      delete a specific field from a hash.
-  sremove <key> <value>
-      remove a value from a set.
+use std::{env, path::PathBuf, process::ExitCode};
 
+use mini_mcache::{client::Connection, command_names, commands, protocol, tls, CacheResult};
+use tokio::{io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader}, sync::mpsc};
+use rustyline::{completion::{Completer, Pair}, error::ReadlineError, highlight::Highlighter, hint::Hinter, history::DefaultHistory, validate::Validator, Context, Editor, Helper};
+
+pub const USAGE: &str = r#"
+usage: client [options] [command [arg...]]
+
+  -h, --host <host>   server host (default 127.0.0.1)
+  -p, --port <port>   server port (default 8080)
+  -s, --socket <path> unix socket of the server, instead of host and port
+  --user <name>       user to log in as (default: the default user)
+  -a, --pass <pass>   password sent with AUTH once connected, MINI_MCACHE_PASSWORD is read if unset
+  --tls               connect over TLS
+  --cacert <path>     PEM CA certificate the server certificate is checked against (default: public roots)
+  --cert <path>       PEM client certificate, for servers that require one
+  --key <path>        PEM private key of --cert
+  --pipe              run the commands read from stdin, one per line
+  --raw               print values as they are, one per line
+  --json              print every reply as a json value, null for missing keys
+  --help              show this text
+
+without a command or --pipe the client starts an interactive prompt.
+the exit status is 1 if any command failed and 2 if the server could not be reached.
+"#;
+
+/// Shown after the command list of `help`.
+pub const NOTES: &str = r#"
 notes:
   - keys are strings.
   - hash fields are stored as key–value pairs.
+  - quote arguments that hold spaces: set greeting "hello world".
+    "double quotes" read \" \n \t escapes, 'single quotes' are taken as is.
+  - tab completes command names, arrow keys browse the history.
+  - help <command> shows a single command.
 "#;
 
+/// Name of the history file kept in the home directory.
+const HISTORY_FILE: &str = ".mini_mcache_history";
 
-#[tokio::main]
-async fn main() {
-    // utils::file_control::select_folder();
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+/// Completes the command name, the first word of the line.
+struct ClientHelper;
+
+impl Completer for ClientHelper {
+    type Candidate = Pair;
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before.len() - before.trim_start().len();
+        let word = before[start..].to_lowercase();
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = command_names().chain(["help", "exit"])
+            .filter(|name| name.starts_with(&word))
+            .map(|name| Pair { display: name.to_string(), replacement: format!("{} ", name) })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ClientHelper {
+    type Hint = String;
+    /// The arguments of the command once its name is typed, e.g. `<key> <value>` after `set `.
+    fn hint(&self, line: &str, pos: usize, _: &Context<'_>) -> Option<String> {
+        let name = line.trim_start().strip_suffix(' ')?;
+        if pos < line.len() || name.contains(char::is_whitespace) {
+            return None;
+        }
+        commands::lookup(name).map(|spec| spec.arguments.to_string()).filter(|arguments| !arguments.is_empty())
+    }
+}
+
+impl Highlighter for ClientHelper {}
+
+impl Validator for ClientHelper {}
+
+impl Helper for ClientHelper {}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// Numbered array items, as in the interactive prompt.
+    Plain,
+    Raw,
+    Json
+}
+
+struct Options {
+    addr: String,
+    user: Option<String>,
+    password: Option<String>,
+    tls: bool,
+    ca_cert: Option<PathBuf>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    format: Format,
+    pipe: bool,
+    command: Vec<String>
+}
+
+impl Options {
+    fn new(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+        let (mut host, mut port, mut socket) = (String::from("127.0.0.1"), 8080u16, None);
+        let mut options = Options {
+            addr: String::new(), user: None, password: None, tls: false, ca_cert: None, cert: None, key: None,
+            format: Format::Plain, pipe: false, command: Vec::new()
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "-h" | "--host" => host = value()?,
+                "-p" | "--port" => port = value()?.parse().map_err(|_| format!("{} is not a valid port", arg))?,
+                "-s" | "--socket" => socket = Some(value()?),
+                "--user" => options.user = Some(value()?),
+                "-a" | "--pass" => options.password = Some(value()?),
+                "--tls" => options.tls = true,
+                "--cacert" => options.ca_cert = Some(PathBuf::from(value()?)),
+                "--cert" => options.cert = Some(PathBuf::from(value()?)),
+                "--key" => options.key = Some(PathBuf::from(value()?)),
+                "--pipe" => options.pipe = true,
+                "--raw" => options.format = Format::Raw,
+                "--json" => options.format = Format::Json,
+                "--help" => return Ok(None),
+                _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+                // Everything from the command name on belongs to the command, even if it starts with -
+                _ => {
+                    options.command.push(arg);
+                    options.command.extend(args);
+                    break;
+                }
+            }
+        }
+        if options.cert.is_some() != options.key.is_some() {
+            return Err(String::from("--cert and --key go together"));
+        }
+        if !options.tls && (options.ca_cert.is_some() || options.cert.is_some()) {
+            return Err(String::from("--cacert, --cert and --key need --tls"));
+        }
+        if options.pipe && !options.command.is_empty() {
+            return Err(String::from("--pipe reads the commands from stdin, do not give one as arguments"));
+        }
+        if socket.is_some() && options.tls {
+            return Err(String::from("--tls does not apply to a unix socket"));
+        }
+        options.addr = match socket {
+            Some(path) => format!("unix:{}", path),
+            None => format!("{}:{}", host, port)
+        };
+        Ok(Some(options))
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c)
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Prints a reply, failures go to stderr except in json. Returns false for a failure.
+fn show(result: CacheResult, format: Format) -> bool {
+    match (result, format) {
+        (CacheResult::Failure(f), Format::Json) => {
+            println!("{{\"error\":{},\"code\":\"{}\"}}", json_string(&f.message), f.code.name());
+            false
+        },
+        (CacheResult::Failure(f), Format::Plain) => {
+            eprintln!("(error) {}", f);
+            false
+        },
+        (CacheResult::Failure(f), Format::Raw) => {
+            eprintln!("{}", f);
+            false
+        },
+        (CacheResult::Nil, Format::Json) => {
+            println!("null");
+            true
+        },
+        (CacheResult::Nil, Format::Raw) => {
+            println!();
+            true
+        },
+        (CacheResult::Nil, Format::Plain) => {
+            println!("(nil)");
+            true
+        },
+        (CacheResult::Success(s), Format::Json) => {
+            println!("{}", json_string(&s));
+            true
+        },
+        (CacheResult::Success(s), _) => {
+            println!("{}", s);
+            true
+        },
+        (CacheResult::Array(items), Format::Json) => {
+            let items: Vec<String> = items.iter().map(|i| json_string(i)).collect();
+            println!("[{}]", items.join(","));
+            true
+        },
+        (CacheResult::Array(items), Format::Raw) => {
+            for item in items {
+                println!("{}", item);
+            }
+            true
+        },
+        (CacheResult::Array(items), Format::Plain) => {
+            if items.is_empty() {
+                println!("(empty array)");
+            }
+            for (i, item) in items.iter().enumerate() {
+                println!("{}) {}", i + 1, item);
+            }
+            true
+        }
+    }
+}
+
+/// Prints the reply of a command, opening up the replies EXEC holds.
+fn show_reply<S: AsRef<str>>(args: &[S], result: CacheResult, format: Format) -> bool {
+    let exec = args.first().is_some_and(|a| a.as_ref().eq_ignore_ascii_case("exec"));
+    match result {
+        CacheResult::Array(items) if exec => {
+            let mut ok = true;
+            for (i, item) in items.iter().enumerate() {
+                if format == Format::Plain {
+                    print!("{}) ", i + 1);
+                }
+                ok &= show(protocol::decode_reply(item.as_bytes()), format);
+            }
+            ok
+        },
+        other => show(other, format)
+    }
+}
+
+/// Exit status when a command got a failure reply.
+const FAILED: u8 = 1;
+/// Exit status when the server could not be reached.
+const UNREACHABLE: u8 = 2;
+
+async fn interactive(mut connection: Connection, format: Format) -> ExitCode {
+    let mut editor: Editor<ClientHelper, DefaultHistory> = match Editor::new() {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("Line editor failed {}", e);
+            return ExitCode::FAILURE
+        }
+    };
+    editor.set_helper(Some(ClientHelper));
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history yet on the first run
+        let _ = editor.load_history(path);
+    }
+    let mut status = ExitCode::SUCCESS;
     loop {
-        let mut client = match TcpStream::connect(addr).await {
-            Ok(s) => s,
+        let input = match editor.readline("client=# ") {
+            Ok(line) => line,
+            // CTRL + C clears the line, CTRL + D quits
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
             Err(e) => {
-                eprintln!("Connection failed {}", e);
-                return
+                eprintln!("{}", e);
+                break
             }
         };
-        print!("client=# ");
-        io::stdout().flush().unwrap(); 
-        let mut input = String::new();
-        let _ = io::stdin().read_line(&mut input);
-        if input.trim() == "exit" || input.trim() == "q" {
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(input);
+        if input == "exit" || input == "q" {
             // We quit the program
             break;
-        } else if input.trim() == "help" {
+        } else if input == "help" {
             // We show how to use it
-            println!("\n{}", HELP_TEXT);
-        } else {
-            let mut args: String = String::from("target/fill/client\t");
-            let list: Vec<&str> = input.trim().split(' ').collect();
-            for data in list {
-                if data.trim().is_empty() {
-                    continue;
-                }
-                args.push_str(data);
-                args.push('\t');
+            println!("\n{}{}", commands::help_text(), NOTES);
+            continue;
+        } else if let Some(name) = input.strip_prefix("help ") {
+            match commands::lookup(name.trim()) {
+                Some(spec) => println!("  {}\n      {}", spec.syntax(), spec.summary),
+                None => eprintln!("Unknown command {}", name.trim())
             }
-            match client.write_all(args.as_bytes()).await {
-                Ok(_) => {
+            continue;
+        }
+        let args = match protocol::split_args(input) {
+            Ok(a) => a,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue
+            }
+        };
+        match connection.execute(&args).await {
+            Ok(result) if listening(&args, &result) => {
+                show(result, format);
+                // Like redis-cli, a subscribed or monitoring client only listens until CTRL + C
+                status = listen(&mut connection, format).await;
+                break
+            },
+            Ok(result) => {
+                show_reply(&args, result, format);
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                status = ExitCode::from(UNREACHABLE);
+                break
+            }
+        }
+    }
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            eprintln!("Could not save history to {}: {}", path.display(), e);
+        }
+    }
+    status
+}
+
+/// True if the reply confirms a subscribe, psubscribe or monitor, the server then pushes to the connection.
+fn listening<S: AsRef<str>>(args: &[S], result: &CacheResult) -> bool {
+    let Some(name) = args.first().map(|a| a.as_ref().to_lowercase()) else {
+        return false;
+    };
+    match name.as_str() {
+        "subscribe" | "psubscribe" => matches!(result, CacheResult::Array(_)),
+        "monitor" => matches!(result, CacheResult::Success(_)),
+        _ => false
+    }
+}
+
+/// Prints what is pushed to a subscribed or monitoring connection until CTRL + C or the server closes it.
+async fn listen(connection: &mut Connection, format: Format) -> ExitCode {
+    eprintln!("Reading messages... (press Ctrl-C to quit)");
+    loop {
+        tokio::select! {
+            reply = connection.read_reply() => match reply {
+                Ok(result) => {
+                    show(result, format);
                 },
                 Err(e) => {
                     eprintln!("{}", e);
+                    return ExitCode::from(UNREACHABLE)
                 }
-            }
-            let mut buffer = [0; 1024];
-            let n = match client.read(&mut buffer).await {
-                Ok(s) => s,
+            },
+            _ = tokio::signal::ctrl_c() => return ExitCode::SUCCESS
+        }
+    }
+}
+
+/// A stdin line, either sent to the server or rejected before that.
+enum Line {
+    /// The arguments of the request, to show its reply
+    Sent(Vec<String>),
+    Invalid(usize, String)
+}
+
+/// Streams the commands read from stdin. Requests are written by their own task so replies
+/// are read while stdin is still being sent, instead of one round trip per command.
+async fn pipe(connection: Connection, format: Format) -> ExitCode {
+    let (mut reader, mut writer) = connection.into_split();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let sender = tokio::spawn(async move {
+        let mut lines = BufReader::new(io::stdin()).lines();
+        let mut number = 0;
+        while let Some(line) = lines.next_line().await? {
+            number += 1;
+            let args = match protocol::split_args(&line) {
+                Ok(a) if a.is_empty() => continue,
+                Ok(a) => a,
                 Err(e) => {
-                    eprintln!("{}", e);
-                    0
+                    let _ = tx.send(Line::Invalid(number, e));
+                    continue
                 }
             };
-            if n == 0 {
-                eprintln!("server closed connection");
-            } else {
-                println!("{}", str::from_utf8(&buffer[..n]).unwrap());
+            writer.write_all(&protocol::encode_request("client", &args)).await?;
+            let _ = tx.send(Line::Sent(args));
+        }
+        writer.flush().await
+    });
+    let mut failed = false;
+    let mut reply = Vec::new();
+    while let Some(line) = rx.recv().await {
+        match line {
+            Line::Invalid(number, e) => {
+                eprintln!("line {}: {}", number, e);
+                failed = true;
+            },
+            Line::Sent(args) => {
+                reply.clear();
+                match reader.read_until(b'\n', &mut reply).await {
+                    Ok(n) if n > 0 => failed |= !show_reply(&args, protocol::decode_reply(&reply), format),
+                    _ => {
+                        eprintln!("Server closed the connection");
+                        return ExitCode::from(UNREACHABLE)
+                    }
+                }
             }
         }
     }
-}
\ No newline at end of file
+    match sender.await {
+        Ok(Ok(_)) => {},
+        Ok(Err(e)) => {
+            eprintln!("{}", e);
+            return ExitCode::from(UNREACHABLE)
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE
+        }
+    }
+    if failed { ExitCode::from(FAILED) } else { ExitCode::SUCCESS }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    // utils::file_control::select_folder();
+    let options = match Options::new(env::args().skip(1)) {
+        Ok(Some(o)) => o,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS
+        },
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(UNREACHABLE)
+        }
+    };
+    let tls = if options.tls {
+        let identity = options.cert.as_deref().zip(options.key.as_deref());
+        match tls::client_config(options.ca_cert.as_deref(), identity) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::from(UNREACHABLE)
+            }
+        }
+    } else {
+        None
+    };
+    let mut connection = match Connection::connect_with(&options.addr, tls.as_ref()).await {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(UNREACHABLE)
+        }
+    };
+    if let Some(password) = options.password.or_else(|| env::var("MINI_MCACHE_PASSWORD").ok()) {
+        if let Err(e) = connection.auth(options.user.as_deref(), &password).await {
+            eprintln!("Authentication failed: {}", e);
+            return ExitCode::from(UNREACHABLE)
+        }
+    }
+    if options.pipe {
+        return pipe(connection, options.format).await;
+    }
+    if options.command.is_empty() {
+        return interactive(connection, options.format).await;
+    }
+    match connection.execute(&options.command).await {
+        Ok(result) if listening(&options.command, &result) => {
+            show(result, options.format);
+            listen(&mut connection, options.format).await
+        },
+        Ok(result) => if show_reply(&options.command, result, options.format) { ExitCode::SUCCESS } else { ExitCode::from(FAILED) },
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(UNREACHABLE)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(line: &str) -> Vec<String> {
+        let history = DefaultHistory::new();
+        let (_, candidates) = ClientHelper.complete(line, line.len(), &Context::new(&history)).unwrap();
+        candidates.into_iter().map(|c| c.replacement).collect()
+    }
+
+    fn options(args: &[&str]) -> Result<Option<Options>, String> {
+        Options::new(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn reads_options_before_the_command() {
+        let o = options(&["-h", "cache.local", "--port", "6400", "--json", "set", "n", "--raw"]).unwrap().unwrap();
+        assert_eq!(o.addr, "cache.local:6400");
+        assert_eq!(o.format, Format::Json);
+        assert_eq!(o.command, ["set", "n", "--raw"]);
+        assert!(options(&["--pipe"]).unwrap().unwrap().pipe);
+        assert!(options(&["--help"]).unwrap().is_none());
+        assert!(options(&["--pipe", "get", "n"]).is_err());
+        assert!(options(&["-p", "http"]).is_err());
+        assert!(options(&["--port"]).is_err());
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("say \"hi\"\n\\\u{1}"), "\"say \\\"hi\\\"\\n\\\\\\u0001\"");
+    }
+
+    #[test]
+    fn completes_command_names() {
+        assert_eq!(complete("sm"), ["smembers "]);
+        assert_eq!(complete("H"), ["hget ", "hset ", "hdel ", "help "]);
+        assert!(complete("get na").is_empty());
+    }
+    #[test]
+    fn hints_the_arguments_of_a_command() {
+        let history = DefaultHistory::new();
+        let hint = |line: &str| ClientHelper.hint(line, line.len(), &Context::new(&history));
+        assert_eq!(hint("setex ").as_deref(), Some("<key> <seconds> <value>"));
+        assert_eq!(hint("ping "), None);
+        assert_eq!(hint("set"), None);
+        assert_eq!(hint("set name "), None);
+    }
+}
@@ -0,0 +1,90 @@
+use std::{env, net::SocketAddr, process::ExitCode, sync::Arc};
+
+use mini_mcache::{logging, protocol, sentinel::{Sentinel, SentinelConfig}, CacheResult, Command};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, signal::{self, unix::{signal as unix_signal, SignalKind}}};
+use tracing::{error, info};
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let config = match SentinelConfig::new(env::args().skip(1)) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE
+        }
+    };
+    // Flushes what is left of the log file on exit
+    let _log = match logging::init(&config.log) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE
+        }
+    };
+    // Like the server, only local clients and sentinels can connect unless --bind says otherwise
+    let addr = SocketAddr::new(config.bind, config.port);
+    let listener = match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!(%addr, error = %e, "socket failed");
+            return ExitCode::FAILURE
+        }
+    };
+    let mut sigterm = match unix_signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!(error = %e, "signal handler failed");
+            return ExitCode::FAILURE
+        }
+    };
+    for monitored in &config.monitors {
+        info!(monitor = %monitored.name, primary = %monitored.addr, quorum = monitored.quorum, "watching");
+    }
+    let sentinel = Arc::new(Sentinel::new(config.clone()));
+    info!(runid = %sentinel.runid(), %addr, announced = %config.announced_addr(), "listening");
+    sentinel.start();
+    tokio::select! {
+        _ = accept_loop(&listener, &sentinel) => {},
+        _ = signal::ctrl_c() => {},
+        _ = sigterm.recv() => {}
+    };
+    info!("sentinel stopped");
+    ExitCode::SUCCESS
+}
+
+async fn accept_loop(listener: &TcpListener, sentinel: &Arc<Sentinel>) {
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                error!(error = %e, "accepting connections failed");
+                return
+            }
+        };
+        tokio::spawn(process_stream(socket, sentinel.clone()));
+    }
+}
+
+/// Answers the requests of a client or of another sentinel, one line each.
+async fn process_stream(socket: TcpStream, sentinel: Arc<Sentinel>) {
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut buffer = Vec::with_capacity(256);
+    loop {
+        buffer.clear();
+        match reader.read_until(b'\n', &mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        if buffer.last() == Some(&b'\n') {
+            buffer.pop();
+        }
+        let result = match Command::new(buffer.len(), std::mem::take(&mut buffer)) {
+            Ok(cmd) => sentinel.execute(&cmd.args()),
+            Err(e) => CacheResult::Failure(e.into())
+        };
+        if writer.write_all(&protocol::encode_reply(&result)).await.is_err() {
+            return;
+        }
+    }
+}
@@ -1,202 +1,725 @@
-use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, path::PathBuf, sync::Arc};
+use std::{env, fs, net::SocketAddr, os::unix::fs::{FileTypeExt, PermissionsExt}, path::{Path, PathBuf}, process::ExitCode, sync::Arc, time::{Duration, Instant}};
 
-use bytes::{Bytes};
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::{mpsc::{self, Receiver, Sender}, Mutex}};
-use utils::{models::Memory, Cache, CacheResult, Command};
-
-use crate::utils::models::Pipe;
-
-
-pub mod utils;
+use mini_mcache::{acl::{Acl, Caller}, cluster, commands, config::Config, logging, metrics, models::{self, CacheError, ErrorCode}, monitor::Feed, protocol, pubsub::{PubSub, Subscription}, replication, tls, transaction::Watch, Cache, CacheResult, Command, ShutdownMode, Store};
+use tokio::{io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream, UnixListener, UnixStream}, signal::{self, unix::{signal as unix_signal, SignalKind}}, sync::{broadcast, mpsc::{self, Sender}}, time};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, debug_span, error, field, info, info_span, warn, Instrument, Span};
 
 const DATA_PATH: Option<&str> = option_env!("DATA_PATH");
 
 #[tokio::main]
-async fn main() {
-    // DATA_PATH IS DEFINED AT COMPILE TIME
-    let build_path = match DATA_PATH {
-        Some(value) => value,
-        None => {
-            eprintln!("⚠️  DATA_PATH environment variable not set, using default: ./data");
-            eprintln!("   To set a custom path, use: export DATA_PATH=/your/custom/path");
-            return
-        }
-    };
-    let full_path = format!("{}/_data.bin", build_path);
-    let path = PathBuf::from(full_path);
-    
-    let memory = match Memory::new(path) {
-        Ok(m) => m,
+async fn main() -> ExitCode {
+    let config = match Config::new(env::args().skip(1)) {
+        Ok(c) => c,
         Err(e) => {
             eprintln!("{}", e);
-            return
+            return ExitCode::FAILURE
+        }
+    };
+    // Flushes what is left of the log file on exit
+    let _log = match logging::init(&config.log) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE
+        }
+    };
+    // DATA_PATH IS DEFINED AT COMPILE TIME, --data overrides it
+    let path = match (&config.data_path, DATA_PATH) {
+        (Some(path), _) => path.clone(),
+        (None, Some(build_path)) => PathBuf::from(format!("{}/_data.bin", build_path)),
+        (None, None) => {
+            error!("DATA_PATH was not set at build time, run with --data <path> or build with export DATA_PATH=/your/custom/path");
+            return ExitCode::FAILURE
         }
     };
     
-    let resource = Arc::new(Mutex::new(memory));
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-    let listener = match TcpListener::bind(addr).await {
-        Ok(l) => {
-            println!("Listing at {}", addr);
-            l
+    let store = match Store::with_config(path.clone(), &config) {
+        Ok(s) => Arc::new(s),
+        Err(e) => {
+            error!(path = %path.display(), error = %e, "store failed to open");
+            return ExitCode::FAILURE
+        }
+    };
+    let acl = match Acl::new(config.requirepass.as_deref(), config.acl_file.clone()) {
+        Ok(a) => Arc::new(a),
+        Err(e) => {
+            error!(error = %e, "users failed to load");
+            return ExitCode::FAILURE
+        }
+    };
+    // Without a certificate connections stay plain TCP
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => match tls::server_config(cert, key, config.tls_ca_cert.as_deref()) {
+            Ok(c) => Some(TlsAcceptor::from(c)),
+            Err(e) => {
+                error!(error = %e, "TLS failed to start");
+                return ExitCode::FAILURE
+            }
+        },
+        _ => None
+    };
+    // Port 0 turns the TCP listener off, to only serve the Unix socket
+    let tcp = match config.port {
+        0 => None,
+        port => {
+            let addr = SocketAddr::new(config.bind, port);
+            match TcpListener::bind(addr).await {
+                Ok(l) => {
+                    info!(%addr, tls = tls.is_some(), "listening");
+                    Some(Listener::Tcp(l))
+                },
+                Err(e) => {
+                    error!(%addr, error = %e, "socket failed");
+                    return ExitCode::FAILURE
+                }
+            }
+        }
+    };
+    let unix = match &config.unix_socket {
+        Some(socket_path) => match bind_unix(socket_path, config.unix_socket_perm) {
+            Ok(l) => {
+                info!(addr = %socket_path.display(), mode = %format_args!("{:o}", config.unix_socket_perm), "listening");
+                Some(Listener::Unix(l, socket_path.display().to_string()))
+            },
+            Err(e) => {
+                error!(addr = %socket_path.display(), error = %e, "unix socket failed");
+                return ExitCode::FAILURE
+            }
+        },
+        None => None
+    };
+    let metrics = match config.metrics_port {
+        Some(port) => {
+            let addr = SocketAddr::new(config.bind, port);
+            match TcpListener::bind(addr).await {
+                Ok(l) => {
+                    info!(url = %format_args!("http://{}/metrics", addr), "serving metrics");
+                    Some(l)
+                },
+                Err(e) => {
+                    error!(%addr, error = %e, "metrics socket failed");
+                    return ExitCode::FAILURE
+                }
+            }
+        },
+        None => None
+    };
+    // The other nodes of the cluster gossip on the bus, away from the clients
+    let bus = match store.cluster() {
+        Some(cluster) => {
+            let addr = SocketAddr::new(config.bind, cluster.bus_port());
+            match TcpListener::bind(addr).await {
+                Ok(l) => {
+                    info!(%addr, "cluster bus listening");
+                    Some(l)
+                },
+                Err(e) => {
+                    error!(%addr, error = %e, "cluster bus socket failed");
+                    return ExitCode::FAILURE
+                }
+            }
+        },
+        None => None
+    };
+    let mut sigterm = match unix_signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!(error = %e, "signal handler failed");
+            return ExitCode::FAILURE
+        }
+    };
+    // Every connection holds a clone of done_tx, so done_rx only returns once they all finished
+    let (notify, _) = broadcast::channel::<()>(1);
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<ShutdownMode>(1);
+    let shared = Shared { store: store.clone(), acl, pubsub: store.pubsub().clone(), tls, notify, done_tx, shutdown_tx };
+    let mode = tokio::select! {
+        _ = accept_loop(tcp.as_ref(), &shared) => ShutdownMode::Save,
+        _ = accept_loop(unix.as_ref(), &shared) => ShutdownMode::Save,
+        _ = serve_metrics(metrics.as_ref(), &store) => ShutdownMode::Save,
+        _ = serve_bus(bus.as_ref(), &shared) => ShutdownMode::Save,
+        _ = signal::ctrl_c() => ShutdownMode::Save,
+        _ = sigterm.recv() => ShutdownMode::Save,
+        Some(mode) = shutdown_rx.recv() => mode,
+        // Only happens under fail-fast, nothing can be saved at that point
+        error = store.failed() => {
+            error!(%error, "persistence failed, stopping");
+            return ExitCode::FAILURE
+        }
+    };
+    info!(?mode, "shutting down, no longer accepting connections");
+    drop(tcp);
+    drop(unix);
+    drop(metrics);
+    drop(bus);
+    if let Some(socket_path) = &config.unix_socket {
+        let _ = fs::remove_file(socket_path);
+    }
+    // Idle connections stop waiting for a request, in-flight commands run to completion
+    let _ = shared.notify.send(());
+    drop(shared);
+    let _ = done_rx.recv().await;
+    // Connections are gone, so this is the last reference
+    let store = match Arc::try_unwrap(store) {
+        Ok(s) => s,
+        Err(_) => {
+            error!("store is still in use, pending writes could not be saved");
+            return ExitCode::FAILURE
+        }
+    };
+    match store.close(mode).await {
+        Ok(_) if mode == ShutdownMode::NoSave => {
+            info!("pending writes discarded");
+            ExitCode::SUCCESS
+        },
+        Ok(_) => {
+            info!(path = %path.display(), "data saved");
+            ExitCode::SUCCESS
         },
         Err(e) => {
-            eprintln!("Socket failded {}", e);
-            return
+            error!(error = %e, "pending writes could not be saved");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    /// The listener and the path of its socket file
+    Unix(UnixListener, String)
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream)
+}
+
+impl Listener {
+    /// The next connection and the address of its client, ip:port or the path of the socket.
+    async fn accept(&self) -> std::io::Result<(Socket, String)> {
+        match self {
+            Self::Tcp(l) => l.accept().await.map(|(s, addr)| (Socket::Tcp(s), addr.to_string())),
+            Self::Unix(l, path) => l.accept().await.map(|(s, _)| (Socket::Unix(s), path.clone()))
         }
+    }
+}
+
+/// What every connection task gets a clone of.
+struct Shared {
+    store: Arc<Store>,
+    acl: Arc<Acl>,
+    pubsub: Arc<PubSub>,
+    /// Applies to TCP connections, the Unix socket is local
+    tls: Option<TlsAcceptor>,
+    notify: broadcast::Sender<()>,
+    done_tx: mpsc::Sender<()>,
+    shutdown_tx: Sender<ShutdownMode>
+}
+
+/// Binds the socket file, replacing the one a previous run left, and sets its permissions.
+fn bind_unix(path: &Path, mode: u32) -> std::io::Result<UnixListener> {
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Answers metrics scrapes, never returns without a listener.
+async fn serve_metrics(listener: Option<&TcpListener>, store: &Store) {
+    match listener {
+        Some(listener) => metrics::serve(listener, store).await,
+        None => std::future::pending().await
+    }
+}
+
+/// Answers the other nodes of the cluster, never returns without a listener.
+async fn serve_bus(listener: Option<&TcpListener>, shared: &Shared) {
+    match (listener, shared.store.cluster()) {
+        (Some(listener), Some(cluster)) => cluster::serve_bus(listener, cluster, &shared.acl).await,
+        _ => std::future::pending().await
+    }
+}
+
+/// Serves the connections of listener, never returns without one.
+async fn accept_loop(listener: Option<&Listener>, shared: &Shared) {
+    let Some(listener) = listener else {
+        return std::future::pending().await;
     };
-    let (tx, rx) = mpsc::channel(100);
-    let m_job = resource.clone();
-    tokio::spawn(async move {
-        update_data_to_file(m_job, rx).await;
-    });
     loop {
-        let (socket, _) = match listener.accept().await {
+        let (socket, addr) = match listener.accept().await {
             Ok(l) => l,
             Err(e) => {
-                eprintln!("Stream failed {}", e);
+                error!(error = %e, "accepting connections failed");
                 return
             }
         };
-        let tx_new = tx.clone();
-        let m = resource.clone();
+        let store = shared.store.clone();
+        let acl = shared.acl.clone();
+        let pubsub = shared.pubsub.clone();
+        let shutdown = shared.notify.subscribe();
+        let done = shared.done_tx.clone();
+        let shutdown_tx = shared.shutdown_tx.clone();
+        let tls = shared.tls.clone();
+        let span = info_span!("connection", client = %addr);
         tokio::spawn(async move {
-            process_stream(socket, m, tx_new).await;
-        });
+            debug!("connected");
+            match (socket, tls) {
+                // A client that never finishes the handshake does not hold the connection forever
+                (Socket::Tcp(socket), Some(acceptor)) => match time::timeout(Duration::from_secs(10), acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) => process_stream(stream, addr, store, acl, pubsub, shutdown, shutdown_tx).await,
+                    Ok(Err(e)) => warn!(error = %e, "TLS handshake failed"),
+                    Err(_) => warn!("TLS handshake timed out")
+                },
+                (Socket::Tcp(socket), None) => process_stream(socket, addr, store, acl, pubsub, shutdown, shutdown_tx).await,
+                (Socket::Unix(socket), _) => process_stream(socket, addr, store, acl, pubsub, shutdown, shutdown_tx).await
+            }
+            debug!("disconnected");
+            drop(done);
+        }.instrument(span));
     }
 }
 
-async fn process_stream(mut socket: TcpStream, memory: Arc<Mutex<Memory>>, tx: Sender<Pipe>) {
-    let mut buffer: Vec<u8> = vec![0;1024];
-    let size  = match socket.read(&mut buffer).await {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Reading failed {}", e);
-            return;
+/// State of one connection.
+struct Session {
+    /// Address of the client, ip:port or the path of the unix socket
+    addr: String,
+    /// None until the connection authenticated, unless the default user has no password
+    user: Option<String>,
+    /// Channels and patterns subscribed to, None before the first subscribe
+    subscription: Option<Subscription>,
+    /// Commands queued since MULTI, None outside a transaction
+    queued: Option<Vec<(Cache, Command)>>,
+    /// Set when a command was refused since MULTI, EXEC then runs nothing
+    aborted: bool,
+    /// Keys watched for the next EXEC
+    watch: Option<Watch>,
+    /// Port a replica listens on, from replconf listening-port
+    replica_port: u16,
+    /// Set by psync, the connection then carries the replication stream
+    psync: Option<Vec<String>>,
+    /// Set by asking, lets the next command use a slot this node is importing
+    asking: bool,
+    /// Set by monitor, every command the server receives is then pushed to the connection
+    monitor: Option<Feed>
+}
+
+impl Session {
+    fn subscribed(&self) -> bool {
+        self.subscription.as_ref().is_some_and(|s| s.count() > 0)
+    }
+    /// A failure reply, which also aborts the transaction being queued.
+    fn refuse(&mut self, error: CacheError) -> CacheResult {
+        if self.queued.is_some() {
+            self.aborted = true;
         }
+        CacheResult::Failure(error)
+    }
+    /// Leaves the transaction and unwatches every key.
+    fn reset_transaction(&mut self) -> Option<Vec<(Cache, Command)>> {
+        self.aborted = false;
+        self.watch = None;
+        self.queued.take()
+    }
+}
+
+/// Next command for a monitoring connection, Err once it fell too far behind.
+async fn next_monitored(monitor: &mut Option<Feed>) -> Result<Arc<str>, u64> {
+    match monitor {
+        Some(feed) => feed.recv().await,
+        None => std::future::pending().await
+    }
+}
+
+/// Next message for a subscribed connection, Some(None) once it fell too far behind.
+async fn next_message(subscription: &mut Option<Subscription>) -> Option<Option<CacheResult>> {
+    match subscription {
+        Some(subscription) => Some(subscription.recv().await.map(|message| message.reply())),
+        None => std::future::pending().await
+    }
+}
+
+async fn process_stream<S: AsyncRead + AsyncWrite>(
+    socket: S,
+    addr: String,
+    store: Arc<Store>,
+    acl: Arc<Acl>,
+    pubsub: Arc<PubSub>,
+    mut shutdown: broadcast::Receiver<()>,
+    shutdown_tx: Sender<ShutdownMode>
+) {
+    let _client = store.stats().connected();
+    let mut session = Session {
+        addr, user: acl.initial_user(), subscription: None, queued: None, aborted: false, watch: None, replica_port: 0, psync: None, asking: false, monitor: None
     };
-    let cmd = match Command::new(size, buffer) {
-        Ok(c) => c,
-        Err(e) => {
-            let _ = socket.write_all(e.show_err()).await;
-            let _ = socket.flush().await;
+    let (reader, mut writer) = io::split(socket);
+    let mut reader = BufReader::new(reader);
+    let mut buffer: Vec<u8> = Vec::with_capacity(1024);
+    loop {
+        // Bytes of a request cut short by a pushed message stay in buffer, it is emptied once a request is taken
+        let size = tokio::select! {
+            read = protocol::read_request(&mut reader, &mut buffer) => match read {
+                Ok(s) => s,
+                // Too long, the rest of the request cannot be told from the next one
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    warn!(error = %e, "request too long");
+                    let _ = writer.write_all(&protocol::encode_reply(&CacheResult::error(ErrorCode::Err, e.to_string()))).await;
+                    let _ = writer.flush().await;
+                    return;
+                },
+                Err(e) => {
+                    warn!(error = %e, "reading failed");
+                    return;
+                }
+            },
+            // Published messages are pushed between replies
+            Some(message) = next_message(&mut session.subscription) => {
+                let message = message.unwrap_or_else(|| {
+                    CacheResult::error(ErrorCode::Err, format!("Disconnected after {} unread messages", mini_mcache::pubsub::OUTBOX_SIZE))
+                });
+                let overflowed = matches!(message, CacheResult::Failure(_));
+                if writer.write_all(&protocol::encode_reply(&message)).await.is_err() || overflowed {
+                    let _ = writer.flush().await;
+                    return;
+                }
+                let _ = writer.flush().await;
+                continue;
+            },
+            line = next_monitored(&mut session.monitor) => {
+                let (line, lagged) = match line {
+                    Ok(line) => (CacheResult::Success(line.to_string()), false),
+                    Err(lost) => (CacheResult::error(ErrorCode::Err, format!("Disconnected after missing {} commands", lost)), true)
+                };
+                if writer.write_all(&protocol::encode_reply(&line)).await.is_err() || lagged {
+                    let _ = writer.flush().await;
+                    return;
+                }
+                let _ = writer.flush().await;
+                continue;
+            },
+            // Between two requests there is nothing in flight to finish
+            _ = shutdown.recv() => return
+        };
+        if size == 0 {
             return;
         }
-    };
-    let cache = match Cache::new(&cmd) {
-        Ok(c) => c,
-        Err(e) => {
-            let _ = socket.write_all(e.show_err()).await;
-            let _ = socket.flush().await;
+        if buffer.last() == Some(&b'\n') {
+            buffer.pop();
+        }
+        let request = std::mem::take(&mut buffer);
+        // The command is named once parsed, see handle_request
+        let span = debug_span!("command", command = field::Empty, duration_us = field::Empty);
+        let started = Instant::now();
+        let result = handle_request(request.len(), request, &store, &acl, &pubsub, &mut session, &shutdown_tx).instrument(span.clone()).await;
+        span.record("duration_us", started.elapsed().as_micros() as u64);
+        span.in_scope(|| match &result {
+            CacheResult::Failure(e) => debug!(code = ?e.code, "command failed"),
+            _ => debug!("command done")
+        });
+        if let Some(args) = session.psync.take() {
+            // psync replies on its own, then the connection only carries the stream
+            let ip = session.addr.rsplit_once(':').map_or(session.addr.as_str(), |(ip, _)| ip).to_string();
+            replication::serve(store.memory().clone(), &args, (ip, session.replica_port), reader, writer, shutdown).await;
             return;
         }
+        if writer.write_all(&protocol::encode_reply(&result)).await.is_err() {
+            return;
+        }
+        let _ = writer.flush().await;
+    }
+}
+
+async fn handle_request(
+    size: usize,
+    buffer: Vec<u8>,
+    store: &Store,
+    acl: &Arc<Acl>,
+    pubsub: &Arc<PubSub>,
+    session: &mut Session,
+    shutdown_tx: &Sender<ShutdownMode>
+) -> CacheResult {
+    store.stats().command();
+    let cmd = match Command::new(size, buffer) {
+        Ok(c) => c,
+        Err(e) => return session.refuse(e.into())
     };
-    let result = match cache.handle_cmd(cmd, memory, tx).await {
-        CacheResult::Success(s) => s,
-        CacheResult::Failure(f) => f
-    };
-    let _ = socket.write_all(result.as_bytes()).await;
-    let _ = socket.flush().await;
+    let args = cmd.args();
+    Span::current().record("command", args[0].as_str());
+    let started = Instant::now();
+    let result = run_request(cmd, &args, store, acl, pubsub, session, shutdown_tx).await;
+    store.slowlog().record(&args, &session.addr, started.elapsed());
+    result
 }
 
-async fn update_data_to_file(memory: Arc<Mutex<Memory>>, mut rx: Receiver<Pipe>) {
-    loop {
-        if let Some(data) = rx.recv().await {
-            let mut memory = memory.lock().await;
-            match data {
-                Pipe::Delete(value) => {
-                    println!("delete: {:?}", value);
-                    let result = memory.modify_file(value).await;
-                    if result.is_err() {
-                        panic!("error")
-                    }
-                }, 
-                Pipe::Recent(value) => {
-                    let mut name: Option<Bytes> = Option::None;
-                    if let Some(found) = memory.recent.get(&value) {
-                        name = Some(found.clone());
-                    }
-                    if let Some(name) = name.take() {
-                        memory.recent_to_file_schedular(
-                            value,
-                            &name
-                        ).await;
-                    }
-                }
+/// Runs a parsed request for the connection of session.
+async fn run_request(
+    cmd: Command,
+    args: &[String],
+    store: &Store,
+    acl: &Arc<Acl>,
+    pubsub: &Arc<PubSub>,
+    session: &mut Session,
+    shutdown_tx: &Sender<ShutdownMode>
+) -> CacheResult {
+    let spec = commands::lookup(&args[0]);
+    let _timer = spec.map(|spec| store.stats().time(spec));
+    let is_auth = spec.is_some_and(|spec| spec.handler == Cache::Auth);
+    if session.user.is_none() && !is_auth {
+        return CacheResult::error(ErrorCode::NoAuth, "Authentication required");
+    }
+    let cache = match Cache::new(&cmd) {
+        Ok(c) => c,
+        Err(e) => return session.refuse(e.into())
+    };
+    if let (Some(name), Some(spec)) = (session.user.as_deref(), spec) {
+        if let Err(e) = acl.check(name, spec, args) {
+            if e.code == ErrorCode::NoAuth {
+                session.user = None;
             }
+            return session.refuse(e);
         }
     }
+    if session.monitor.is_some() && cache != Cache::Ping {
+        return CacheResult::error(ErrorCode::Err, "Only ping is allowed while monitoring, close the connection to stop");
+    }
+    if session.subscribed() && !cache.allowed_when_subscribed() {
+        return CacheResult::error(ErrorCode::Err, "Only (p)subscribe, (p)unsubscribe and ping are allowed once subscribed");
+    }
+    if let (Some(cluster), Some(spec)) = (store.cluster(), spec) {
+        let asking = std::mem::take(&mut session.asking);
+        if let Err(e) = cluster.route(store.memory(), &cluster::command_keys(spec, args), asking).await {
+            return session.refuse(e);
+        }
+    }
+    // Monitors see the commands allowed to run, the queued ones of a transaction once EXEC runs them
+    let queuing = session.queued.is_some() && !cache.is_transaction();
+    if !queuing {
+        store.monitors().feed(args, &session.addr);
+    }
+    if let Some(result) = transaction(cache, args, session, store).await {
+        return result;
+    }
+    if let Some(queued) = session.queued.as_mut() {
+        if cache.needs_server() || cache.runs_alone() {
+            return session.refuse(CacheError::new(ErrorCode::Err, format!("{} cannot be used in a transaction", args[0])));
+        }
+        queued.push((cache, cmd));
+        return CacheResult::Success(String::from("QUEUED"));
+    }
+    match cache {
+        Cache::Publish => CacheResult::Success(pubsub.publish(&args[1], &args[2]).to_string()),
+        Cache::Subscribe | Cache::Unsubscribe | Cache::PSubscribe | Cache::PUnsubscribe => {
+            subscriptions(cache, &args[1..], &mut session.subscription, pubsub)
+        },
+        Cache::PubSub => pubsub_info(&args[1..], pubsub),
+        Cache::PSync => {
+            session.psync = Some(args.to_vec());
+            CacheResult::Success(String::new())
+        },
+        Cache::ReplConf => match (args[1].to_lowercase().as_str(), &args[2..]) {
+            ("listening-port", [port]) => match port.parse() {
+                Ok(port) => {
+                    session.replica_port = port;
+                    CacheResult::Success(String::from("OK"))
+                },
+                Err(_) => CacheResult::error(ErrorCode::Syntax, format!("{} is not a port", port))
+            },
+            // Acks are read by the stream once psync ran
+            ("ack", [_]) => CacheResult::Success(String::from("OK")),
+            _ => CacheResult::error(ErrorCode::Syntax, "Use replconf listening-port <port> | ack <offset>")
+        },
+        Cache::Auth => match acl.authenticate(args) {
+            Ok(name) => {
+                session.user = Some(name);
+                CacheResult::Success(String::from("OK"))
+            },
+            Err(e) => CacheResult::Failure(e)
+        },
+        Cache::Acl => match session.user.as_deref() {
+            Some(name) => acl.execute(name, args),
+            None => CacheResult::Failure(CacheError::new(ErrorCode::NoAuth, "Authentication required"))
+        },
+        Cache::Asking => match store.cluster() {
+            Some(_) => {
+                session.asking = true;
+                CacheResult::Success(String::from("OK"))
+            },
+            None => CacheResult::error(ErrorCode::Err, "This server is not in cluster mode, start it with --cluster-enabled yes")
+        },
+        Cache::Monitor => {
+            if session.monitor.is_none() {
+                session.monitor = Some(store.monitors().attach());
+            }
+            CacheResult::Success(String::from("OK"))
+        },
+        Cache::Shutdown => match ShutdownMode::new(&cmd) {
+            Ok(mode) => {
+                let _ = shutdown_tx.send(mode).await;
+                CacheResult::Success(String::from("OK"))
+            },
+            Err(e) => CacheResult::Failure(e.into())
+        },
+        Cache::Eval | Cache::EvalSha => {
+            let caller = session.user.clone().map(|user| Caller { acl: acl.clone(), user });
+            store.eval(cache, cmd.args(), caller).await
+        },
+        _ => store.run(cache, cmd).await
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    // use std::{env};
-
-
-    use super::*;
-
-    async fn handler(data: String) -> CacheResult {
-        let path = PathBuf::from("src/files/_data.bin");
-        let memory = Memory::new(path).unwrap();
-        let memory = Arc::new(Mutex::new(memory));
-        let cmd = match Command::new(data.len(), data.into_bytes()) {
-            Ok(c) => c,
-            Err(e) => {
-                println!("{}", e.show_err_str());
-                return CacheResult::Failure(e.to_string());
+/// Runs multi, exec, discard, watch and unwatch. None for any other command.
+async fn transaction(cache: Cache, args: &[String], session: &mut Session, store: &Store) -> Option<CacheResult> {
+    let in_multi = session.queued.is_some();
+    let result = match cache {
+        Cache::Multi if in_multi => CacheResult::error(ErrorCode::Err, "MULTI calls can not be nested"),
+        Cache::Multi => {
+            session.queued = Some(Vec::new());
+            CacheResult::Success(String::from("OK"))
+        },
+        Cache::Exec if !in_multi => CacheResult::error(ErrorCode::Err, "EXEC without MULTI"),
+        Cache::Exec if session.aborted => {
+            session.reset_transaction();
+            CacheResult::error(ErrorCode::ExecAbort, "Transaction discarded because of previous errors")
+        },
+        Cache::Exec => {
+            let watch = session.watch.take();
+            let queued = session.reset_transaction().unwrap_or_default();
+            for (_, cmd) in &queued {
+                store.monitors().feed(&cmd.args(), &session.addr);
             }
-        };
-        let cache = match Cache::new(&cmd) {
-            Ok(c) => c,
-            Err(e) => {
-                println!("{}", e.show_err_str());
-                return CacheResult::Failure(e.to_string());
+            store.exec(queued, watch.as_ref()).await
+        },
+        Cache::Discard if !in_multi => CacheResult::error(ErrorCode::Err, "DISCARD without MULTI"),
+        Cache::Discard => {
+            session.reset_transaction();
+            CacheResult::Success(String::from("OK"))
+        },
+        Cache::Watch if in_multi => CacheResult::error(ErrorCode::Err, "WATCH inside MULTI is not allowed"),
+        Cache::Watch => {
+            let watch = session.watch.get_or_insert_with(|| store.watch());
+            for key in &args[1..] {
+                watch.add(&models::escape_stored(key));
             }
+            CacheResult::Success(String::from("OK"))
+        },
+        Cache::Unwatch => {
+            session.watch = None;
+            CacheResult::Success(String::from("OK"))
+        },
+        _ => return None
+    };
+    Some(result)
+}
+
+/// Runs (p)subscribe and (p)unsubscribe, replying a kind, channel, count triple per channel.
+fn subscriptions(cache: Cache, names: &[String], subscription: &mut Option<Subscription>, pubsub: &Arc<PubSub>) -> CacheResult {
+    let current = subscription.get_or_insert_with(|| pubsub.subscription());
+    // Without names, unsubscribe from everything
+    let names = match (cache, names.is_empty()) {
+        (Cache::Unsubscribe, true) => current.channels(),
+        (Cache::PUnsubscribe, true) => current.patterns(),
+        _ => names.to_vec()
+    };
+    let mut reply = Vec::new();
+    for name in &names {
+        let (kind, count) = match cache {
+            Cache::Subscribe => ("subscribe", current.subscribe(name)),
+            Cache::Unsubscribe => ("unsubscribe", current.unsubscribe(name)),
+            Cache::PSubscribe => ("psubscribe", current.psubscribe(name)),
+            _ => ("punsubscribe", current.punsubscribe(name))
         };
-        let (tx, _) = mpsc::channel(100);
-        return cache.handle_cmd(cmd, memory, tx).await;
+        reply.extend([kind.to_string(), name.clone(), count.to_string()]);
     }
-    #[tokio::test]
-    async fn process_stream() {
-        let data: String = String::from("target/debug/client\tsadd\tjames\tname\tmakuo\tage\t25\t");
-        let result = handler(data).await;
-        assert!(matches!(result, CacheResult::Success(_)));
-        assert!(!matches!(result, CacheResult::Failure(_)));
-        let data: String = String::from("target/debug/client\tsmembers\tjames\t");
-        let result = handler(data).await;
-        assert!(matches!(result, CacheResult::Success(_)));
-        assert!(!matches!(result, CacheResult::Failure(_)));
+    if names.is_empty() {
+        let kind = if cache == Cache::Unsubscribe { "unsubscribe" } else { "punsubscribe" };
+        reply.extend([kind.to_string(), String::new(), current.count().to_string()]);
     }
-    #[tokio::test]
-    async fn process_cmd() {
-        let data: String = String::from("target/debug/client\tset\tdo\tmakuo\t");
-        let cmd = Command::new(data.len(), data.into_bytes());
-        assert!(cmd.is_ok());
+    if current.count() == 0 {
+        *subscription = None;
     }
-    #[tokio::test]
-    async fn process_wrong_stream() {
-        let data: String = String::from("target/debug/client\tmet\tname\tmakuo\t");
-        let cmd = Command::new(data.len(), data.into_bytes()).unwrap();
-        let cache = Cache::new(&cmd);
-        assert!(cache.is_err());
+    CacheResult::Array(reply)
+}
+
+/// Runs `pubsub channels [pattern] | numsub [channel...] | numpat`.
+fn pubsub_info(args: &[String], pubsub: &PubSub) -> CacheResult {
+    match (args[0].to_lowercase().as_str(), &args[1..]) {
+        ("channels", []) => CacheResult::Array(pubsub.channels(None)),
+        ("channels", [pattern]) => CacheResult::Array(pubsub.channels(Some(pattern))),
+        ("numsub", channels) => CacheResult::Array(channels.iter().flat_map(|c| [c.clone(), pubsub.numsub(c).to_string()]).collect()),
+        ("numpat", []) => CacheResult::Success(pubsub.numpat().to_string()),
+        _ => CacheResult::error(ErrorCode::Syntax, "Use pubsub channels [pattern] | numsub [channel...] | numpat")
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncBufReadExt;
+
     #[tokio::test]
-    async fn process_good_stream() {
-        let data: String = String::from("target/debug/client\tset\tname\tmakuo\t");
-        let cmd = Command::new(data.len(), data.into_bytes()).unwrap();
-        let cache = Cache::new(&cmd);
-        assert!(cache.is_ok());
+    async fn connection_serves_many_requests() {
+        let path = std::env::temp_dir().join("mini-cache-connection_serves_many_requests.bin");
+        let _ = std::fs::remove_file(&path);
+        let store = Arc::new(Store::open(path).unwrap());
+        let acl = Arc::new(Acl::new(None, None).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (notify, _) = broadcast::channel(1);
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+        let shutdown = notify.subscribe();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            process_stream(socket, String::from("test"), store, acl, Arc::new(PubSub::default()), shutdown, shutdown_tx).await;
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        writer.write_all(&protocol::encode_request("test", &["set", "name", "makuo"])).await.unwrap();
+        writer.write_all(&protocol::encode_request("test", &["get", "name"])).await.unwrap();
+        writer.write_all(&protocol::encode_request("test", &["met", "name"])).await.unwrap();
+        writer.write_all(&protocol::encode_request("test", &["shutdown", "nosave"])).await.unwrap();
+        let mut lines = Vec::new();
+        for _ in 0..4 {
+            let mut line = Vec::new();
+            reader.read_until(b'\n', &mut line).await.unwrap();
+            lines.push(String::from_utf8(line).unwrap());
+        }
+        assert_eq!(lines[0], "+1\n");
+        assert_eq!(lines[1], "+makuo\n");
+        assert!(lines[2].starts_with('-'));
+        assert_eq!(lines[3], "+OK\n");
+        assert_eq!(shutdown_rx.recv().await, Some(ShutdownMode::NoSave));
+        // Idle connections are closed on shutdown
+        notify.send(()).unwrap();
+        let mut line = Vec::new();
+        assert_eq!(reader.read_until(b'\n', &mut line).await.unwrap(), 0);
     }
+
     #[tokio::test]
-    async fn process_good_stream_hset() {
-        let data: String = String::from("target/debug/client\thset\tperson2\tname\tmakuo\tage\t25\t");
-        let result = handler(data).await;
-        let data: String = String::from("target/debug/client\thget\tperson2\t");
-        let result_two = handler(data).await;
-        assert!(matches!(result, CacheResult::Success(_)));
-        assert!(matches!(result_two, CacheResult::Success(_)));
-        assert!(!matches!(result, CacheResult::Failure(_)));
-        assert!(!matches!(result_two, CacheResult::Failure(_)));
-        
+    async fn requests_past_the_limit_close_the_connection() {
+        let path = std::env::temp_dir().join("mini-cache-requests_past_the_limit_close_the_connection.bin");
+        let _ = std::fs::remove_file(&path);
+        let store = Arc::new(Store::open(path).unwrap());
+        let acl = Arc::new(Acl::new(None, None).unwrap());
+        let (client, server) = io::duplex(64 * 1024);
+        let (notify, _) = broadcast::channel(1);
+        let (shutdown_tx, _shutdown_rx) = mpsc::channel(1);
+        let shutdown = notify.subscribe();
+        tokio::spawn(process_stream(server, String::from("test"), store, acl, Arc::new(PubSub::default()), shutdown, shutdown_tx));
+        let (reader, mut writer) = io::split(client);
+        let mut reader = BufReader::new(reader);
+        let chunk = vec![b'x'; 64 * 1024];
+        for _ in 0..=protocol::MAX_REQUEST / chunk.len() {
+            if writer.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line).await.unwrap();
+        assert!(String::from_utf8_lossy(&line).contains("Requests are limited"), "{:?}", String::from_utf8_lossy(&line));
+        line.clear();
+        assert_eq!(reader.read_until(b'\n', &mut line).await.unwrap(), 0);
     }
 }
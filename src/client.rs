@@ -0,0 +1,519 @@
+//! Async client for a running server, with typed commands over a pool of connections.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), mini_mcache::client::ClientError> {
+//! use std::time::Duration;
+//!
+//! let client = mini_mcache::client::Client::connect("127.0.0.1:8080").await?;
+//! client.set_ex("session", "makuo", Duration::from_secs(60)).await?;
+//! client.hset("person", &[("name", "makuo"), ("age", "25")]).await?;
+//! let person = client.hgetall("person").await?;
+//! # Ok(()) }
+//! ```
+
+use std::{collections::{HashMap, VecDeque}, fmt::{self, Display}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use tokio::{io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf}, net::{TcpStream, UnixStream}, sync::{OwnedSemaphorePermit, Semaphore}, time};
+use tokio_rustls::TlsConnector;
+
+use super::{cluster, commands, models::{CacheError, ErrorCode}, protocol, pubsub::Message, tls, CacheResult};
+
+/// Name the server sees for requests sent by this client.
+const CLIENT_NAME: &str = "mini_mcache::client";
+/// Most MOVED and ASK redirects followed for one command, past that the nodes disagree.
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The server could not be reached or the connection broke.
+    ConnectionError(String),
+    /// The server refused the command, e.g. a set on a key that already exists.
+    ServerError(CacheError),
+    /// The reply did not have the shape the command returns.
+    ReplyError(String)
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ConnectionError(data) => write!(f, "{}", data),
+            Self::ServerError(error) => write!(f, "{}", error),
+            Self::ReplyError(data) => write!(f, "{}", data)
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+}
+
+/// Settings of the connection pool.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Most connections open at the same time, commands wait for a free one past that.
+    pub pool_size: usize,
+    pub connect_timeout: Duration,
+    /// Connections idle for longer are pinged before being used again.
+    pub health_check_after: Duration,
+    /// User every connection logs in as, the default user if only a password is set.
+    pub user: Option<String>,
+    /// Sent with AUTH on every new connection when set.
+    pub password: Option<String>,
+    /// Connect over TLS with these settings, see [`tls::client_config`].
+    pub tls: Option<Arc<rustls::ClientConfig>>
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            pool_size: 8,
+            connect_timeout: Duration::from_secs(5),
+            health_check_after: Duration::from_secs(30),
+            user: None,
+            password: None,
+            tls: None
+        }
+    }
+}
+
+/// A plain TCP or a TLS stream.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> Stream for S {}
+
+pub type Reader = BufReader<ReadHalf<Box<dyn Stream>>>;
+pub type Writer = WriteHalf<Box<dyn Stream>>;
+
+/// A single connection, requests are answered in order.
+pub struct Connection {
+    reader: Reader,
+    writer: Writer
+}
+
+impl Connection {
+    pub async fn connect(addr: &str) -> Result<Connection, ClientError> {
+        Connection::connect_with(addr, None).await
+    }
+    /// Connects over TLS when tls is given, checking the server certificate against the host of addr.
+    /// An addr of the form `unix:/path` is a Unix socket, which TLS does not apply to.
+    pub async fn connect_with(addr: &str, tls: Option<&Arc<rustls::ClientConfig>>) -> Result<Connection, ClientError> {
+        let failed = |e: io::Error| ClientError::ConnectionError(format!("Connection to {} failed: {}", addr, e));
+        if let Some(path) = addr.strip_prefix("unix:") {
+            let stream: Box<dyn Stream> = Box::new(UnixStream::connect(path).await.map_err(failed)?);
+            let (reader, writer) = io::split(stream);
+            return Ok(Connection { reader: BufReader::new(reader), writer });
+        }
+        let stream = TcpStream::connect(addr).await.map_err(failed)?;
+        stream.set_nodelay(true).map_err(|e| ClientError::ConnectionError(e.to_string()))?;
+        let stream: Box<dyn Stream> = match tls {
+            Some(config) => {
+                let name = tls::server_name(addr).map_err(|e| ClientError::ConnectionError(e.to_string()))?;
+                Box::new(TlsConnector::from(config.clone()).connect(name, stream).await.map_err(failed)?)
+            },
+            None => Box::new(stream)
+        };
+        let (reader, writer) = io::split(stream);
+        Ok(Connection { reader: BufReader::new(reader), writer })
+    }
+    /// Sends a command given as its arguments and waits for the reply.
+    pub async fn execute<S: AsRef<str>>(&mut self, args: &[S]) -> Result<CacheResult, ClientError> {
+        let broken = |e: std::io::Error| ClientError::ConnectionError(e.to_string());
+        self.writer.write_all(&protocol::encode_request(CLIENT_NAME, args)).await.map_err(broken)?;
+        self.writer.flush().await.map_err(broken)?;
+        self.read_reply().await
+    }
+    /// Waits for the next reply line, e.g. a message pushed to a subscribed connection.
+    pub async fn read_reply(&mut self) -> Result<CacheResult, ClientError> {
+        let mut line = Vec::new();
+        let read = self.reader.read_until(b'\n', &mut line).await.map_err(|e| ClientError::ConnectionError(e.to_string()))?;
+        // A reply cut before its end, e.g. a TLS alert sent to a plain client, is not a reply
+        if read == 0 || line.last() != Some(&b'\n') {
+            return Err(ClientError::ConnectionError(String::from("Server closed the connection")));
+        }
+        Ok(protocol::decode_reply(&line))
+    }
+    /// The reading and writing halves, to stream requests without waiting for each reply.
+    pub fn into_split(self) -> (Reader, Writer) {
+        (self.reader, self.writer)
+    }
+    /// Logs the connection in, as the default user if user is None.
+    pub async fn auth(&mut self, user: Option<&str>, password: &str) -> Result<(), ClientError> {
+        let result = match user {
+            Some(user) => self.execute(&["auth", user, password]).await?,
+            None => self.execute(&["auth", password]).await?
+        };
+        match result {
+            CacheResult::Success(_) => Ok(()),
+            CacheResult::Failure(f) => Err(ClientError::ServerError(f)),
+            other => Err(ClientError::ReplyError(format!("Unexpected auth reply {:?}", other)))
+        }
+    }
+    pub async fn ping(&mut self) -> Result<(), ClientError> {
+        match self.execute(&["ping"]).await? {
+            CacheResult::Success(s) if s == "PONG" => Ok(()),
+            other => Err(ClientError::ReplyError(format!("Unexpected ping reply {:?}", other)))
+        }
+    }
+}
+
+struct Idle {
+    connection: Connection,
+    since: Instant
+}
+
+struct Pool {
+    addr: String,
+    config: ClientConfig,
+    idle: Mutex<Vec<Idle>>,
+    slots: Arc<Semaphore>
+}
+
+impl Pool {
+    fn new(addr: &str, config: ClientConfig) -> Pool {
+        let slots = Arc::new(Semaphore::new(config.pool_size.max(1)));
+        Pool { addr: addr.to_string(), config, idle: Mutex::new(Vec::new()), slots }
+    }
+    async fn connect(&self) -> Result<Connection, ClientError> {
+        let mut connection = match time::timeout(self.config.connect_timeout, Connection::connect_with(&self.addr, self.config.tls.as_ref())).await {
+            Ok(result) => result?,
+            Err(_) => return Err(ClientError::ConnectionError(format!("Connection to {} timed out", self.addr)))
+        };
+        if let Some(password) = &self.config.password {
+            connection.auth(self.config.user.as_deref(), password).await?;
+        }
+        Ok(connection)
+    }
+    /// An idle connection if one is still healthy, or a new one. The bool is true for a reused connection.
+    async fn checkout(&self) -> Result<(Connection, bool, OwnedSemaphorePermit), ClientError> {
+        let permit = match self.slots.clone().acquire_owned().await {
+            Ok(p) => p,
+            Err(_) => return Err(ClientError::ConnectionError(String::from("Connection pool is closed")))
+        };
+        loop {
+            let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+            let Some(mut idle) = idle else {
+                break;
+            };
+            if idle.since.elapsed() < self.config.health_check_after || idle.connection.ping().await.is_ok() {
+                return Ok((idle.connection, true, permit));
+            }
+        }
+        Ok((self.connect().await?, false, permit))
+    }
+    fn checkin(&self, connection: Connection) {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        idle.push(Idle { connection, since: Instant::now() });
+    }
+    fn clear(&self) {
+        self.idle.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+/// The other nodes of a cluster, learned from redirects.
+#[derive(Default)]
+struct Redirects {
+    /// A pool per node, by host:port
+    pools: Mutex<HashMap<String, Arc<Pool>>>,
+    /// The node of the slots that were answered with MOVED
+    slots: Mutex<HashMap<u16, String>>
+}
+
+/// Handle to a server, cheap to clone and share between tasks.
+///
+/// A connection taken from the pool that turns out to be broken (e.g. after a server restart)
+/// is replaced and the command sent again on a new one. Since the first attempt may have
+/// reached the server, a command can in rare cases run twice.
+///
+/// Connected to a node of a cluster, [`Client::execute`] and the typed commands follow MOVED and
+/// ASK redirects to the node serving the key, and send the next commands on a slot straight to
+/// the node it moved to. Transactions and subscribers stay on the node given to connect.
+#[derive(Clone)]
+pub struct Client {
+    pool: Arc<Pool>,
+    redirects: Arc<Redirects>
+}
+
+impl Client {
+    /// Connects to addr (host:port or unix:/path) with the default pool settings.
+    pub async fn connect(addr: &str) -> Result<Client, ClientError> {
+        Client::with_config(addr, ClientConfig::default()).await
+    }
+    /// Opens a first connection so an unreachable server is reported right away.
+    pub async fn with_config(addr: &str, config: ClientConfig) -> Result<Client, ClientError> {
+        let pool = Pool::new(addr, config);
+        let connection = pool.connect().await?;
+        pool.checkin(connection);
+        Ok(Client { pool: Arc::new(pool), redirects: Arc::default() })
+    }
+    /// Runs a raw command, e.g. `["hset", "person", "name", "makuo"]`. Failure replies are returned, not turned into errors.
+    pub async fn execute<S: AsRef<str>>(&self, args: &[S]) -> Result<CacheResult, ClientError> {
+        let mut pool = self.routed(args);
+        let mut asking = false;
+        for _ in 0..=MAX_REDIRECTS {
+            let result = Client::send(&pool, args, asking).await?;
+            let redirect = match &result {
+                CacheResult::Failure(e) if matches!(e.code, ErrorCode::Moved | ErrorCode::Ask) => Client::redirect(e),
+                _ => None
+            };
+            let Some((slot, addr)) = redirect else {
+                return Ok(result);
+            };
+            // An ASK redirect is for this command only, the slot is still served where it was
+            asking = matches!(&result, CacheResult::Failure(e) if e.code == ErrorCode::Ask);
+            if !asking {
+                self.redirects.slots.lock().unwrap_or_else(|e| e.into_inner()).insert(slot, addr.clone());
+            }
+            pool = self.pool_of(&addr);
+        }
+        Err(ClientError::ReplyError(format!("More than {} redirects for {}", MAX_REDIRECTS, args[0].as_ref())))
+    }
+    /// Runs args on a connection of pool, after asking when set.
+    async fn send<S: AsRef<str>>(pool: &Pool, args: &[S], asking: bool) -> Result<CacheResult, ClientError> {
+        let (mut connection, reused, permit) = pool.checkout().await?;
+        let result = match Client::request(&mut connection, args, asking).await {
+            Ok(result) => result,
+            Err(e) if !reused => return Err(e),
+            Err(_) => {
+                // The other idle connections were opened before the same failure, drop them too
+                pool.clear();
+                connection = pool.connect().await?;
+                Client::request(&mut connection, args, asking).await?
+            }
+        };
+        pool.checkin(connection);
+        drop(permit);
+        Ok(result)
+    }
+    async fn request<S: AsRef<str>>(connection: &mut Connection, args: &[S], asking: bool) -> Result<CacheResult, ClientError> {
+        if asking {
+            connection.execute(&["asking"]).await?;
+        }
+        connection.execute(args).await
+    }
+    /// The slot and host:port of a MOVED or ASK message.
+    fn redirect(error: &CacheError) -> Option<(u16, String)> {
+        let (slot, addr) = error.message.split_once(' ')?;
+        Some((slot.parse().ok()?, addr.to_string()))
+    }
+    /// The pool of the node a redirect sent the slot of the command's keys to, the first one otherwise.
+    fn routed<S: AsRef<str>>(&self, args: &[S]) -> Arc<Pool> {
+        let slots = self.redirects.slots.lock().unwrap_or_else(|e| e.into_inner());
+        if slots.is_empty() {
+            return self.pool.clone();
+        }
+        let args: Vec<String> = args.iter().map(|arg| arg.as_ref().to_string()).collect();
+        let addr = args.first().and_then(|name| commands::lookup(name))
+            .and_then(|spec| cluster::command_keys(spec, &args).first().map(|key| cluster::key_slot(key)))
+            .and_then(|slot| slots.get(&slot).cloned());
+        drop(slots);
+        match addr {
+            Some(addr) => self.pool_of(&addr),
+            None => self.pool.clone()
+        }
+    }
+    fn pool_of(&self, addr: &str) -> Arc<Pool> {
+        if addr == self.pool.addr {
+            return self.pool.clone();
+        }
+        let mut pools = self.redirects.pools.lock().unwrap_or_else(|e| e.into_inner());
+        pools.entry(addr.to_string()).or_insert_with(|| Arc::new(Pool::new(addr, self.pool.config.clone()))).clone()
+    }
+    pub async fn ping(&self) -> Result<(), ClientError> {
+        let (mut connection, _, permit) = self.pool.checkout().await?;
+        connection.ping().await?;
+        self.pool.checkin(connection);
+        drop(permit);
+        Ok(())
+    }
+    /// Runs the commands as one MULTI/EXEC transaction on a single connection and returns the
+    /// reply of each one. The transaction is not sent again if the connection breaks.
+    pub async fn transaction<S: AsRef<str>>(&self, commands: &[&[S]]) -> Result<Vec<CacheResult>, ClientError> {
+        let (mut connection, _, permit) = self.pool.checkout().await?;
+        Client::done(connection.execute(&["multi"]).await?)?;
+        for args in commands {
+            // A refused command makes exec fail with EXECABORT
+            connection.execute(args).await?;
+        }
+        let replies = connection.execute(&["exec"]).await?;
+        self.pool.checkin(connection);
+        drop(permit);
+        let items = match replies {
+            CacheResult::Array(items) => items,
+            other => return Client::unexpected(other)
+        };
+        Ok(items.iter().map(|item| protocol::decode_reply(item.as_bytes())).collect())
+    }
+    /// Sends a message to a channel, returns how many subscribers received it.
+    pub async fn publish(&self, channel: &str, message: &str) -> Result<usize, ClientError> {
+        match self.execute(&["publish", channel, message]).await? {
+            CacheResult::Success(count) => count.parse()
+                .map_err(|_| ClientError::ReplyError(format!("Unexpected publish reply {}", count))),
+            other => Client::unexpected(other)
+        }
+    }
+    /// A connection of its own, outside the pool, to subscribe to channels with.
+    pub async fn subscriber(&self) -> Result<Subscriber, ClientError> {
+        Ok(Subscriber { connection: self.pool.connect().await?, pending: VecDeque::new() })
+    }
+    /// The value of a string, None if the key does not exist.
+    pub async fn get(&self, key: &str) -> Result<Option<String>, ClientError> {
+        match self.execute(&["get", key]).await? {
+            CacheResult::Success(value) => Ok(Some(value)),
+            CacheResult::Nil => Ok(None),
+            other => Client::unexpected(other)
+        }
+    }
+    /// Stores a string, fails if the key already exists.
+    pub async fn set(&self, key: &str, value: &str) -> Result<(), ClientError> {
+        Client::done(self.execute(&["set", key, value]).await?)
+    }
+    /// Stores a string that is deleted once ttl passed, rounded down to whole seconds.
+    pub async fn set_ex(&self, key: &str, value: &str, ttl: Duration) -> Result<(), ClientError> {
+        Client::done(self.execute(&["setex", key, &ttl.as_secs().to_string(), value]).await?)
+    }
+    /// Time left before the key expires, None if it never does or does not exist.
+    pub async fn ttl(&self, key: &str) -> Result<Option<Duration>, ClientError> {
+        match self.execute(&["ttl", key]).await? {
+            CacheResult::Success(value) => match value.parse::<i64>() {
+                Ok(seconds) if seconds >= 0 => Ok(Some(Duration::from_secs(seconds as u64))),
+                Ok(_) => Ok(None),
+                Err(_) => Err(ClientError::ReplyError(format!("Unexpected ttl reply {}", value)))
+            },
+            other => Client::unexpected(other)
+        }
+    }
+    /// Stores a hash made of field and value pairs.
+    pub async fn hset(&self, key: &str, fields: &[(&str, &str)]) -> Result<(), ClientError> {
+        let mut args = vec!["hset", key];
+        for (field, value) in fields {
+            args.push(field);
+            args.push(value);
+        }
+        Client::done(self.execute(&args).await?)
+    }
+    /// All fields and values of a hash, empty if the key does not exist.
+    pub async fn hgetall(&self, key: &str) -> Result<HashMap<String, String>, ClientError> {
+        let items = Client::items(self.execute(&["hget", key]).await?)?;
+        if items.len() % 2 != 0 {
+            return Err(ClientError::ReplyError(String::from("A hash reply holds pairs of fields and values")));
+        }
+        let mut hash = HashMap::with_capacity(items.len() / 2);
+        let mut items = items.into_iter();
+        while let (Some(field), Some(value)) = (items.next(), items.next()) {
+            hash.insert(field, value);
+        }
+        Ok(hash)
+    }
+    /// Stores a set of unique members.
+    pub async fn sadd(&self, key: &str, members: &[&str]) -> Result<(), ClientError> {
+        let mut args = vec!["sadd", key];
+        args.extend_from_slice(members);
+        Client::done(self.execute(&args).await?)
+    }
+    /// Members of a set, empty if the key does not exist.
+    pub async fn smembers(&self, key: &str) -> Result<Vec<String>, ClientError> {
+        Client::items(self.execute(&["smembers", key]).await?)
+    }
+    /// Deletes a key of any type, false if it did not exist.
+    pub async fn del(&self, key: &str) -> Result<bool, ClientError> {
+        Client::deleted(self.execute(&["del", key]).await?)
+    }
+    pub async fn hdel(&self, key: &str, field: &str) -> Result<bool, ClientError> {
+        Client::deleted(self.execute(&["hdel", key, field]).await?)
+    }
+    pub async fn srem(&self, key: &str, member: &str) -> Result<bool, ClientError> {
+        Client::deleted(self.execute(&["sremove", key, member]).await?)
+    }
+    pub async fn info(&self) -> Result<String, ClientError> {
+        match self.execute(&["info"]).await? {
+            CacheResult::Success(info) => Ok(info),
+            other => Client::unexpected(other)
+        }
+    }
+    fn done(result: CacheResult) -> Result<(), ClientError> {
+        match result {
+            CacheResult::Success(_) => Ok(()),
+            other => Client::unexpected(other)
+        }
+    }
+    fn items(result: CacheResult) -> Result<Vec<String>, ClientError> {
+        match result {
+            CacheResult::Array(items) => Ok(items),
+            CacheResult::Nil => Ok(Vec::new()),
+            other => Client::unexpected(other)
+        }
+    }
+    fn deleted(result: CacheResult) -> Result<bool, ClientError> {
+        match result {
+            CacheResult::Success(count) => Ok(count != "0"),
+            CacheResult::Nil => Ok(false),
+            other => Client::unexpected(other)
+        }
+    }
+    fn unexpected<T>(result: CacheResult) -> Result<T, ClientError> {
+        match result {
+            CacheResult::Failure(f) => Err(ClientError::ServerError(f)),
+            other => Err(ClientError::ReplyError(format!("Unexpected reply {:?}", other)))
+        }
+    }
+}
+
+/// A connection subscribed to channels, see [`Client::subscriber`].
+///
+/// ```no_run
+/// # async fn run(client: mini_mcache::client::Client) -> Result<(), mini_mcache::client::ClientError> {
+/// let mut subscriber = client.subscriber().await?;
+/// subscriber.subscribe(&["news"]).await?;
+/// let message = subscriber.next_message().await?;
+/// println!("{}: {}", message.channel, message.payload);
+/// # Ok(()) }
+/// ```
+pub struct Subscriber {
+    connection: Connection,
+    /// Messages that arrived while waiting for the reply of a command
+    pending: VecDeque<Message>
+}
+
+impl Subscriber {
+    /// Returns the number of channels and patterns subscribed to afterwards.
+    pub async fn subscribe(&mut self, channels: &[&str]) -> Result<usize, ClientError> {
+        self.change("subscribe", channels).await
+    }
+    /// Unsubscribes from every channel if channels is empty.
+    pub async fn unsubscribe(&mut self, channels: &[&str]) -> Result<usize, ClientError> {
+        self.change("unsubscribe", channels).await
+    }
+    pub async fn psubscribe(&mut self, patterns: &[&str]) -> Result<usize, ClientError> {
+        self.change("psubscribe", patterns).await
+    }
+    /// Unsubscribes from every pattern if patterns is empty.
+    pub async fn punsubscribe(&mut self, patterns: &[&str]) -> Result<usize, ClientError> {
+        self.change("punsubscribe", patterns).await
+    }
+    /// Waits for the next message published to a channel subscribed to.
+    pub async fn next_message(&mut self) -> Result<Message, ClientError> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+        let reply = self.connection.read_reply().await?;
+        match Message::from_reply(&reply) {
+            Some(message) => Ok(message),
+            None => Client::unexpected(reply)
+        }
+    }
+    async fn change(&mut self, action: &str, names: &[&str]) -> Result<usize, ClientError> {
+        let mut args = vec![action];
+        args.extend_from_slice(names);
+        let mut reply = self.connection.execute(&args).await?;
+        // Messages may be pushed before the reply
+        while let Some(message) = Message::from_reply(&reply) {
+            self.pending.push_back(message);
+            reply = self.connection.read_reply().await?;
+        }
+        let items = Client::items(reply)?;
+        // One kind, name, count triple per name, the last count is the current one
+        match items.last().map(|count| count.parse()) {
+            Some(Ok(count)) if items.len() % 3 == 0 => Ok(count),
+            _ => Err(ClientError::ReplyError(format!("Unexpected {} reply {:?}", action, items)))
+        }
+    }
+}
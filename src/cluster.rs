@@ -0,0 +1,768 @@
+//! Cluster mode: the keys are split into [`SLOTS`] hash slots, each served by one node.
+//!
+//! The slot of a key is the CRC16 of the key, or of the part between its first `{` and the next
+//! `}` when that part is not empty, so `{user42}.name` and `{user42}.age` are always served
+//! together. Nodes are introduced with `cluster meet` and then gossip: a few times a second every
+//! node sends what it knows (the text of `cluster nodes`) to the others, which reply with theirs.
+//! Gossip goes over the cluster bus, a port of its own (the client port + 10000 by default) that
+//! clients never use, and needs a user allowed to run `cluster`.
+//! Each node claims slots with its config epoch and the highest epoch wins, so a node that took
+//! over a slot bumps its epoch for the others to follow. A request for a key of another node is
+//! answered `-MOVED <slot> <host:port>`. While a slot migrates, the source still serves the keys
+//! it holds and answers `-ASK <slot> <host:port>` for the others, which the target serves to the
+//! connections that sent `asking` first. `migrate` moves keys while the slot is in that state.
+
+use std::{collections::{BTreeMap, HashMap}, fs, path::PathBuf, sync::{Arc, Mutex, MutexGuard, Weak}, time::Duration};
+
+use tokio::{io::{AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, time};
+
+use crate::{acl::Acl, client::{ClientError, Connection}, commands::{self, CommandSpec}, config::Config, models::{self, escape_stored, CacheError, ErrorCode, MainError, Memory, Pipe}, protocol, replication, Cache, CacheResult, Command};
+use tokio::sync::mpsc::Sender;
+
+/// Number of hash slots the keys are split into.
+pub const SLOTS: usize = 16384;
+/// The cluster bus listens on the client port plus this, unless `--cluster-port` says otherwise.
+pub const BUS_PORT_OFFSET: u16 = 10000;
+/// How often a node sends what it knows to the others.
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(250);
+/// Longest wait for another node to answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The slot a key is served from.
+pub fn key_slot(key: &str) -> u16 {
+    let bytes = key.as_bytes();
+    let tagged = bytes.iter().position(|b| *b == b'{').and_then(|open| {
+        let close = bytes[open + 1..].iter().position(|b| *b == b'}')?;
+        // {} hashes the whole key
+        (close > 0).then(|| &bytes[open + 1..open + 1 + close])
+    });
+    crc16(tagged.unwrap_or(bytes)) % SLOTS as u16
+}
+
+/// The keys a command is routed by: those of its spec, or the numkeys ones of eval and evalsha.
+pub fn command_keys<'a>(spec: &CommandSpec, args: &'a [String]) -> Vec<&'a str> {
+    match spec.handler {
+        Cache::Eval | Cache::EvalSha => {
+            let numkeys = args.get(2).and_then(|n| n.parse::<usize>().ok()).unwrap_or(0);
+            args.iter().skip(3).take(numkeys).map(String::as_str).collect()
+        },
+        // Moves its keys from wherever they are, the slot is migrating
+        Cache::Migrate => Vec::new(),
+        _ => spec.keys(args)
+    }
+}
+
+/// CRC16-XMODEM, the checksum Redis uses for slots.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// A node of the cluster as known here.
+#[derive(Debug, Clone)]
+struct Node {
+    /// ip:port clients are redirected to
+    addr: String,
+    /// Port of the cluster bus, on the same ip
+    bus_port: u16,
+    /// Epoch of the node's slot claims, the highest wins a slot claimed by two nodes.
+    epoch: u64
+}
+
+#[derive(Debug)]
+struct State {
+    /// By node id, this node included.
+    nodes: HashMap<String, Node>,
+    /// The id of the node serving each slot.
+    owners: Vec<Option<String>>,
+    /// Slots of this node moving to the node of the id.
+    migrating: BTreeMap<u16, String>,
+    /// Slots moving from the node of the id to this one.
+    importing: BTreeMap<u16, String>,
+    /// Highest epoch seen in the cluster.
+    current_epoch: u64
+}
+
+impl State {
+    /// Gives the slot to the node of id if it has none yet or its owner claimed it with a lower epoch.
+    fn claim(&mut self, myself: &str, id: &str, slot: u16) -> bool {
+        let epoch = self.nodes[id].epoch;
+        let taken = match &self.owners[slot as usize] {
+            Some(owner) if owner == id => false,
+            Some(owner) => self.nodes.get(owner).is_none_or(|node| node.epoch < epoch),
+            None => true
+        };
+        if taken {
+            self.owners[slot as usize] = Some(id.to_string());
+            if id != myself {
+                self.migrating.remove(&slot);
+            }
+        }
+        taken
+    }
+    /// Takes a new epoch for the claims of this node, so they win over the older ones.
+    fn bump(&mut self, myself: &str) {
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        if let Some(node) = self.nodes.get_mut(myself) {
+            node.epoch = epoch;
+        }
+    }
+    fn slots_of(&self, id: &str) -> Vec<u16> {
+        (0..SLOTS as u16).filter(|slot| self.owners[*slot as usize].as_deref() == Some(id)).collect()
+    }
+}
+
+impl Node {
+    /// ip:port of the cluster bus of the node.
+    fn bus_addr(&self) -> String {
+        let ip = self.addr.rsplit_once(':').map_or(self.addr.as_str(), |(ip, _)| ip);
+        format!("{}:{}", ip, self.bus_port)
+    }
+}
+
+/// A line of `cluster nodes`.
+#[derive(Debug)]
+struct Line {
+    id: String,
+    addr: String,
+    bus_port: u16,
+    myself: bool,
+    epoch: u64,
+    slots: Vec<u16>,
+    migrating: Vec<(u16, String)>,
+    importing: Vec<(u16, String)>
+}
+
+impl Line {
+    /// `<id> <ip:port@bus-port> <flags> - 0 0 <epoch> connected <slots>...`, None if it is not one.
+    /// Without a bus port, the node has the default one.
+    fn parse(line: &str) -> Option<Line> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            return None;
+        }
+        let (addr, bus_port) = match fields[1].split_once('@') {
+            Some((addr, bus_port)) => (addr, bus_port.parse().ok()?),
+            None => (fields[1], fields[1].rsplit_once(':')?.1.parse::<u16>().ok()?.checked_add(BUS_PORT_OFFSET)?)
+        };
+        let mut parsed = Line {
+            id: fields[0].to_string(),
+            addr: addr.to_string(),
+            bus_port,
+            myself: fields[2].split(',').any(|flag| flag == "myself"),
+            epoch: fields[6].parse().ok()?,
+            slots: Vec::new(),
+            migrating: Vec::new(),
+            importing: Vec::new()
+        };
+        for range in &fields[8..] {
+            if let Some(moving) = range.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+                if let Some((slot, id)) = moving.split_once("->-") {
+                    parsed.migrating.push((slot.parse().ok()?, id.to_string()));
+                } else if let Some((slot, id)) = moving.split_once("-<-") {
+                    parsed.importing.push((slot.parse().ok()?, id.to_string()));
+                }
+                continue;
+            }
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let (start, end): (u16, u16) = (start.parse().ok()?, end.parse().ok()?);
+            if end as usize >= SLOTS {
+                return None;
+            }
+            parsed.slots.extend(start..=end);
+        }
+        Some(parsed)
+    }
+}
+
+/// The cluster as seen by this node.
+pub struct Cluster {
+    myself: String,
+    state: Mutex<State>,
+    /// Where the state is saved after every change.
+    path: PathBuf,
+    /// User and password to log in to the other nodes with.
+    user: Option<String>,
+    password: Option<String>
+}
+
+impl Cluster {
+    /// Loads the state saved at path, or starts a cluster of this node alone with a new id.
+    pub fn open(path: PathBuf, config: &Config) -> Result<Cluster, MainError> {
+        let addr = config.announced_addr();
+        let bus_port = config.cluster_bus_port()
+            .ok_or_else(|| MainError::BadCommandFormat(String::from("The cluster bus needs a port, set one with --cluster-port")))?;
+        let mut state = State { nodes: HashMap::new(), owners: vec![None; SLOTS], migrating: BTreeMap::new(), importing: BTreeMap::new(), current_epoch: 0 };
+        let mut myself = None;
+        if path.exists() {
+            let text = fs::read_to_string(&path).map_err(|e| MainError::FileReadError(e.to_string()))?;
+            for text in text.lines() {
+                if let Some(epoch) = text.strip_prefix("vars currentEpoch ") {
+                    state.current_epoch = epoch.trim().parse().unwrap_or(0);
+                    continue;
+                }
+                let Some(line) = Line::parse(text) else {
+                    continue;
+                };
+                // The addresses of this node are the ones it listens on now
+                let node = match line.myself {
+                    true => Node { addr: addr.clone(), bus_port, epoch: line.epoch },
+                    false => Node { addr: line.addr.clone(), bus_port: line.bus_port, epoch: line.epoch }
+                };
+                state.nodes.insert(line.id.clone(), node);
+                for slot in &line.slots {
+                    state.owners[*slot as usize] = Some(line.id.clone());
+                }
+                if line.myself {
+                    state.migrating.extend(line.migrating);
+                    state.importing.extend(line.importing);
+                    myself = Some(line.id);
+                }
+            }
+        }
+        let myself = match myself {
+            Some(id) => id,
+            None => {
+                let id = replication::new_replid();
+                state.nodes.insert(id.clone(), Node { addr, bus_port, epoch: 0 });
+                id
+            }
+        };
+        let cluster = Cluster { myself, state: Mutex::new(state), path, user: config.primary_user.clone(), password: config.primary_auth.clone() };
+        cluster.save(&cluster.state()).map_err(MainError::FileWriteError)?;
+        Ok(cluster)
+    }
+    pub fn myself(&self) -> &str {
+        &self.myself
+    }
+    /// The port the cluster bus of this node listens on.
+    pub fn bus_port(&self) -> u16 {
+        self.state().nodes.get(&self.myself).map_or(0, |node| node.bus_port)
+    }
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// Gossips with the other nodes in the background, until the cluster is dropped.
+    pub fn start(self: &Arc<Self>) {
+        tokio::spawn(gossip(Arc::downgrade(self)));
+    }
+    /// Checks that the keys of a command are served here: Err with the MOVED, ASK, CROSSSLOT or
+    /// CLUSTERDOWN error if not. asking is true when the connection sent `asking` just before.
+    pub async fn route(&self, memory: &Memory, keys: &[&str], asking: bool) -> Result<(), CacheError> {
+        let Some(slot) = keys.first().map(|key| key_slot(key)) else {
+            return Ok(());
+        };
+        if keys.iter().any(|key| key_slot(key) != slot) {
+            return Err(CacheError::new(ErrorCode::CrossSlot, "Keys of the command are in different slots"));
+        }
+        let (owner, migrating, importing) = {
+            let state = self.state();
+            let addr_of = |id: &String| state.nodes.get(id).map(|node| node.addr.clone());
+            (state.owners[slot as usize].clone(), state.migrating.get(&slot).and_then(addr_of), state.importing.contains_key(&slot))
+        };
+        match owner {
+            Some(owner) if owner == self.myself => {
+                let Some(target) = migrating else {
+                    return Ok(());
+                };
+                // Keys already moved, or not created yet, are served by the target
+                for key in keys {
+                    if !memory.exists(&escape_stored(key)).await {
+                        return Err(CacheError::new(ErrorCode::Ask, format!("{} {}", slot, target)));
+                    }
+                }
+                Ok(())
+            },
+            _ if importing && asking => Ok(()),
+            Some(owner) => {
+                let addr = self.state().nodes.get(&owner).map(|node| node.addr.clone()).unwrap_or_default();
+                Err(CacheError::new(ErrorCode::Moved, format!("{} {}", slot, addr)))
+            },
+            None => Err(CacheError::new(ErrorCode::ClusterDown, format!("Hash slot {} is not served by any node", slot)))
+        }
+    }
+    /// Runs `cluster <subcommand>`.
+    pub async fn execute(&self, args: &[String], memory: &Memory) -> CacheResult {
+        let usage = "Use cluster myid | nodes | slots | info | keyslot <key> | addslots <slot>... | addslotsrange <start> <end>... | meet <ip> <port> [<bus-port>] \
+            | setslot <slot> importing|migrating|node <id> | setslot <slot> stable | countkeysinslot <slot> | getkeysinslot <slot> <count>";
+        let subcommand = args[1].to_lowercase();
+        match (subcommand.as_str(), args.len()) {
+            ("myid", 2) => CacheResult::Success(self.myself.clone()),
+            ("nodes", 2) => CacheResult::Success(self.nodes()),
+            ("slots", 2) => self.slots(),
+            ("info", 2) => CacheResult::Success(self.info()),
+            ("keyslot", 3) => CacheResult::Success(key_slot(&args[2]).to_string()),
+            ("addslots", 3..) => match args[2..].iter().map(|s| slot(s)).collect::<Result<Vec<u16>, _>>() {
+                Ok(slots) => self.add_slots(&slots),
+                Err(e) => e
+            },
+            ("addslotsrange", n) if n >= 4 && n % 2 == 0 => {
+                let mut slots = Vec::new();
+                for pair in args[2..].chunks(2) {
+                    match (slot(&pair[0]), slot(&pair[1])) {
+                        (Ok(start), Ok(end)) if start <= end => slots.extend(start..=end),
+                        (Err(e), _) | (_, Err(e)) => return e,
+                        _ => return CacheResult::error(ErrorCode::Syntax, "A range cannot end before it starts")
+                    }
+                }
+                self.add_slots(&slots)
+            },
+            ("meet", 4 | 5) => {
+                let bus_port = match args.get(4) {
+                    Some(port) => port.parse::<u16>().ok(),
+                    None => args[3].parse::<u16>().ok().and_then(|port| port.checked_add(BUS_PORT_OFFSET))
+                };
+                match (args[3].parse::<u16>(), bus_port) {
+                    (Ok(_), Some(bus_port)) => self.meet(&format!("{}:{}", args[2], bus_port)).await,
+                    _ => CacheResult::error(ErrorCode::Syntax, format!("{} is not a port with a bus port", args[3..].join(" ")))
+                }
+            },
+            ("setslot", 4 | 5) => match slot(&args[2]) {
+                Ok(slot) => self.set_slot(slot, &args[3].to_lowercase(), args.get(4).map(String::as_str), memory).await,
+                Err(e) => e
+            },
+            ("countkeysinslot", 3) => match slot(&args[2]) {
+                Ok(slot) => CacheResult::Success(keys_in_slot(memory, slot).await.len().to_string()),
+                Err(e) => e
+            },
+            ("getkeysinslot", 4) => match (slot(&args[2]), args[3].parse::<usize>()) {
+                (Ok(slot), Ok(count)) => CacheResult::Array(keys_in_slot(memory, slot).await.into_iter().take(count).collect()),
+                (Err(e), _) => e,
+                (_, Err(_)) => CacheResult::error(ErrorCode::Syntax, format!("{} is not a count", args[3]))
+            },
+            _ => CacheResult::error(ErrorCode::Syntax, usage)
+        }
+    }
+    /// One line per node, this one first, as `<id> <ip:port@bus-port> <flags> - 0 0 <epoch> connected <slots>...`.
+    /// The line of this node also lists its slots migrating (`[slot->-id]`) and importing (`[slot-<-id]`).
+    pub fn nodes(&self) -> String {
+        self.nodes_of(&self.state())
+    }
+    fn nodes_of(&self, state: &State) -> String {
+        let mut ids: Vec<&String> = state.nodes.keys().filter(|id| **id != self.myself).collect();
+        ids.sort();
+        ids.insert(0, &self.myself);
+        let mut text = String::new();
+        for id in ids {
+            let node = &state.nodes[id];
+            let flags = if *id == self.myself { "myself,primary" } else { "primary" };
+            text.push_str(&format!("{} {}@{} {} - 0 0 {} connected", id, node.addr, node.bus_port, flags, node.epoch));
+            for (start, end) in ranges(&state.slots_of(id)) {
+                match start == end {
+                    true => text.push_str(&format!(" {}", start)),
+                    false => text.push_str(&format!(" {}-{}", start, end))
+                }
+            }
+            if *id == self.myself {
+                for (slot, target) in &state.migrating {
+                    text.push_str(&format!(" [{}->-{}]", slot, target));
+                }
+                for (slot, source) in &state.importing {
+                    text.push_str(&format!(" [{}-<-{}]", slot, source));
+                }
+            }
+            text.push('\n');
+        }
+        text
+    }
+    /// `cluster slots`: a `[start, end, ip, port, id]` array per range of slots served by the same node.
+    fn slots(&self) -> CacheResult {
+        let state = self.state();
+        let mut ids: Vec<&String> = state.nodes.keys().collect();
+        ids.sort();
+        let mut ranges_of: Vec<(u16, u16, &String)> = ids.into_iter()
+            .flat_map(|id| ranges(&state.slots_of(id)).into_iter().map(move |(start, end)| (start, end, id)))
+            .collect();
+        ranges_of.sort();
+        let items = ranges_of.into_iter().map(|(start, end, id)| {
+            let addr = &state.nodes[id].addr;
+            let (ip, port) = addr.rsplit_once(':').unwrap_or((addr, ""));
+            let range = [start.to_string(), end.to_string(), ip.to_string(), port.to_string(), id.clone()];
+            protocol::encode_nested(&CacheResult::Array(range.to_vec()))
+        }).collect();
+        CacheResult::Array(items)
+    }
+    /// `cluster info`: ok once every slot is served.
+    pub fn info(&self) -> String {
+        let state = self.state();
+        let assigned = state.owners.iter().filter(|owner| owner.is_some()).count();
+        let my_epoch = state.nodes.get(&self.myself).map_or(0, |node| node.epoch);
+        format!("cluster_state:{}\ncluster_slots_assigned:{}\ncluster_known_nodes:{}\ncluster_current_epoch:{}\ncluster_my_epoch:{}\n",
+            if assigned == SLOTS { "ok" } else { "fail" }, assigned, state.nodes.len(), state.current_epoch, my_epoch)
+    }
+    fn add_slots(&self, slots: &[u16]) -> CacheResult {
+        let mut state = self.state();
+        if let Some(slot) = slots.iter().find(|slot| state.owners[**slot as usize].is_some()) {
+            return CacheResult::error(ErrorCode::Err, format!("Slot {} is already served", slot));
+        }
+        state.bump(&self.myself);
+        for slot in slots {
+            state.owners[*slot as usize] = Some(self.myself.clone());
+        }
+        self.saved(&state)
+    }
+    /// `cluster setslot <slot> importing|migrating|node <id>` or `stable`.
+    async fn set_slot(&self, slot: u16, action: &str, id: Option<&str>, memory: &Memory) -> CacheResult {
+        // Checked before taking the state, counting keys waits on memory
+        let has_keys = action == "node" && !keys_in_slot(memory, slot).await.is_empty();
+        let mut state = self.state();
+        let owner = state.owners[slot as usize].clone();
+        let mine = owner.as_deref() == Some(self.myself.as_str());
+        match (action, id) {
+            ("stable", None) => {
+                state.migrating.remove(&slot);
+                state.importing.remove(&slot);
+            },
+            (_, Some(id)) if !state.nodes.contains_key(id) => return CacheResult::error(ErrorCode::Err, format!("Unknown node {}", id)),
+            ("importing", Some(id)) if mine => return CacheResult::error(ErrorCode::Err, format!("Slot {} is already served by this node, it cannot import it from {}", slot, id)),
+            ("importing", Some(id)) => {
+                state.importing.insert(slot, id.to_string());
+            },
+            ("migrating", Some(id)) if !mine => return CacheResult::error(ErrorCode::Err, format!("Slot {} is not served by this node, it cannot migrate it to {}", slot, id)),
+            ("migrating", Some(id)) => {
+                state.migrating.insert(slot, id.to_string());
+            },
+            ("node", Some(id)) if id == self.myself => {
+                // The new owner needs a new epoch, or the others keep the claim of the old one
+                state.importing.remove(&slot);
+                state.bump(&self.myself);
+                state.owners[slot as usize] = Some(self.myself.clone());
+            },
+            ("node", Some(id)) if mine && has_keys => {
+                return CacheResult::error(ErrorCode::Err, format!("Slot {} still holds keys, migrate them to {} first", slot, id));
+            },
+            ("node", Some(id)) => {
+                state.migrating.remove(&slot);
+                state.importing.remove(&slot);
+                state.owners[slot as usize] = Some(id.to_string());
+            },
+            _ => return CacheResult::error(ErrorCode::Syntax, "Use cluster setslot <slot> importing|migrating|node <id> | stable")
+        }
+        self.saved(&state)
+    }
+    /// Exchanges what this node and the node with its bus at addr know, which then gossip with each other.
+    async fn meet(&self, addr: &str) -> CacheResult {
+        let mut connection = match self.connect(addr).await {
+            Ok(c) => c,
+            Err(e) => return CacheResult::error(ErrorCode::Err, format!("Could not meet {}: {}", addr, e))
+        };
+        match self.exchange(&mut connection).await {
+            Ok(()) => CacheResult::Success(String::from("OK")),
+            Err(e) => CacheResult::error(ErrorCode::Err, format!("Could not meet {}: {}", addr, e))
+        }
+    }
+    async fn connect(&self, addr: &str) -> Result<Connection, ClientError> {
+        let mut connection = match time::timeout(REQUEST_TIMEOUT, Connection::connect(addr)).await {
+            Ok(connection) => connection?,
+            Err(_) => return Err(ClientError::ConnectionError(format!("Connection to {} timed out", addr)))
+        };
+        if let Some(password) = &self.password {
+            connection.auth(self.user.as_deref(), password).await?;
+        }
+        Ok(connection)
+    }
+    /// Sends what this node knows over connection and merges the reply.
+    async fn exchange(&self, connection: &mut Connection) -> Result<(), ClientError> {
+        let nodes = self.nodes();
+        match request(connection, &["gossip", &nodes]).await? {
+            CacheResult::Success(text) => {
+                self.merge(&text);
+                Ok(())
+            },
+            CacheResult::Failure(e) => Err(ClientError::ServerError(e)),
+            other => Err(ClientError::ReplyError(format!("Unexpected gossip reply {:?}", other)))
+        }
+    }
+    /// Learns the nodes and slot claims of the text of `cluster nodes` sent by another node.
+    fn merge(&self, text: &str) {
+        let mut state = self.state();
+        let mut changed = false;
+        for line in text.lines().filter_map(Line::parse) {
+            if line.id == self.myself {
+                continue;
+            }
+            state.current_epoch = state.current_epoch.max(line.epoch);
+            let node = state.nodes.entry(line.id.clone()).or_insert_with(|| {
+                changed = true;
+                Node { addr: line.addr.clone(), bus_port: line.bus_port, epoch: line.epoch }
+            });
+            // The sender knows its own addresses best, the others may have old ones
+            if line.myself && (node.addr != line.addr || node.bus_port != line.bus_port) {
+                node.addr = line.addr.clone();
+                node.bus_port = line.bus_port;
+                changed = true;
+            }
+            if line.epoch > node.epoch {
+                node.epoch = line.epoch;
+                changed = true;
+            }
+            for slot in line.slots {
+                changed |= state.claim(&self.myself, &line.id, slot);
+            }
+        }
+        if changed {
+            let _ = self.save(&state);
+        }
+    }
+    /// Replies to a request of the cluster bus: `auth`, then `gossip <nodes>` with what this node knows.
+    fn bus_request(&self, args: &[String], acl: &Acl, user: &mut Option<String>) -> CacheResult {
+        match args[0].to_lowercase().as_str() {
+            "auth" => match acl.authenticate(args) {
+                Ok(name) => {
+                    *user = Some(name);
+                    CacheResult::Success(String::from("OK"))
+                },
+                Err(e) => CacheResult::Failure(e)
+            },
+            "gossip" if args.len() == 2 => {
+                // Gossip hands out slots like cluster setslot, so it needs the same rights
+                let allowed = match (user.as_deref(), commands::lookup("cluster")) {
+                    (Some(name), Some(spec)) => acl.check(name, spec, args),
+                    _ => Err(CacheError::new(ErrorCode::NoAuth, "Authentication required"))
+                };
+                match allowed {
+                    Ok(()) => {
+                        self.merge(&args[1]);
+                        CacheResult::Success(self.nodes())
+                    },
+                    Err(e) => CacheResult::Failure(e)
+                }
+            },
+            _ => CacheResult::error(ErrorCode::Err, "The cluster bus only takes auth and gossip <nodes>")
+        }
+    }
+    /// Saves the state, OK or the failure to write the file.
+    fn saved(&self, state: &State) -> CacheResult {
+        match self.save(state) {
+            Ok(()) => CacheResult::Success(String::from("OK")),
+            Err(e) => CacheResult::error(ErrorCode::Err, e)
+        }
+    }
+    /// Writes the text of `cluster nodes` and the current epoch to the config file.
+    fn save(&self, state: &State) -> Result<(), String> {
+        let text = format!("{}vars currentEpoch {}\n", self.nodes_of(state), state.current_epoch);
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, text).and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(|e| format!("Cluster config file {} could not be written: {}", self.path.display(), e))
+    }
+}
+
+/// Parses a slot number, or the failure to reply.
+fn slot(text: &str) -> Result<u16, CacheResult> {
+    match text.parse::<u16>() {
+        Ok(slot) if (slot as usize) < SLOTS => Ok(slot),
+        _ => Err(CacheResult::error(ErrorCode::Syntax, format!("{} is not a slot, slots go from 0 to {}", text, SLOTS - 1)))
+    }
+}
+
+/// Sorted slots as (start, end) ranges of consecutive ones.
+fn ranges(slots: &[u16]) -> Vec<(u16, u16)> {
+    let mut ranges: Vec<(u16, u16)> = Vec::new();
+    for slot in slots {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == *slot => *end = *slot,
+            _ => ranges.push((*slot, *slot))
+        }
+    }
+    ranges
+}
+
+/// The keys stored here that belong to slot.
+async fn keys_in_slot(memory: &Memory, slot: u16) -> Vec<String> {
+    memory.keys().await.into_iter().filter(|key| key_slot(key) == slot).collect()
+}
+
+/// Sends a command and waits for its reply, for at most [`REQUEST_TIMEOUT`].
+async fn request(connection: &mut Connection, args: &[&str]) -> Result<CacheResult, ClientError> {
+    match time::timeout(REQUEST_TIMEOUT, connection.execute(args)).await {
+        Ok(reply) => reply,
+        Err(_) => Err(ClientError::ConnectionError(format!("No reply to {} in time", args[0])))
+    }
+}
+
+/// Exchanges what this node knows with every other node, every [`GOSSIP_INTERVAL`]. Connections
+/// are kept between two rounds, by node id. Only a weak reference is held so a dropped cluster stops it.
+async fn gossip(cluster: Weak<Cluster>) {
+    let mut connections: HashMap<String, Connection> = HashMap::new();
+    let mut interval = time::interval(GOSSIP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(cluster) = cluster.upgrade() else {
+            return
+        };
+        let peers: Vec<(String, String)> = cluster.state().nodes.iter()
+            .filter(|(id, _)| **id != cluster.myself)
+            .map(|(id, node)| (id.clone(), node.bus_addr()))
+            .collect();
+        for (id, addr) in peers {
+            let mut connection = match connections.remove(&id) {
+                Some(connection) => connection,
+                None => match cluster.connect(&addr).await {
+                    Ok(connection) => connection,
+                    // Down for now, tried again next round
+                    Err(_) => continue
+                }
+            };
+            if cluster.exchange(&mut connection).await.is_ok() {
+                connections.insert(id, connection);
+            }
+        }
+    }
+}
+
+/// Answers the other nodes on the cluster bus, each connection in a task of its own.
+pub async fn serve_bus(listener: &TcpListener, cluster: &Arc<Cluster>, acl: &Arc<Acl>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            // Errors like too many open files come back at once, waiting lets connections close meanwhile
+            Err(e) => {
+                tracing::warn!(error = %e, "accepting cluster bus connections failed");
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        tokio::spawn(bus_connection(stream, cluster.clone(), acl.clone()));
+    }
+}
+
+/// Replies to the requests of a node until it disconnects.
+async fn bus_connection(stream: TcpStream, cluster: Arc<Cluster>, acl: Arc<Acl>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut user = acl.initial_user();
+    loop {
+        let mut line = Vec::new();
+        match protocol::read_request(&mut reader, &mut line).await {
+            Ok(0) => return,
+            Err(e) => {
+                let _ = writer.write_all(&protocol::encode_reply(&CacheResult::error(ErrorCode::Err, e.to_string()))).await;
+                return;
+            },
+            Ok(_) => {}
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        let reply = match Command::new(line.len(), line) {
+            Ok(cmd) => cluster.bus_request(&cmd.args(), &acl, &mut user),
+            Err(e) => CacheResult::Failure(e.into())
+        };
+        if writer.write_all(&protocol::encode_reply(&reply)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs `migrate <host> <port> [replace] <key>...`: copies the keys to the server at host:port, in
+/// cluster mode each preceded by `asking` since the slot is still served here, then deletes them.
+/// A key is only deleted once the target stored it, and the target refuses a key it already holds
+/// unless replace is given. Other commands run between two keys, not while one is moved, and the
+/// keys moved before a failure stay moved. Replies NOKEY if none of the keys exists.
+pub async fn migrate(memory: &Arc<Memory>, tx: &Sender<Pipe>, args: &[String], cluster: Option<&Cluster>) -> CacheResult {
+    let addr = match args[2].parse::<u16>() {
+        Ok(port) => format!("{}:{}", args[1], port),
+        Err(_) => return CacheResult::error(ErrorCode::Syntax, format!("{} is not a port", args[2]))
+    };
+    // A single key named replace is a key, not the option
+    let (replace, keys) = match args[3].eq_ignore_ascii_case("replace") && args.len() > 4 {
+        true => (true, &args[4..]),
+        false => (false, &args[3..])
+    };
+    let mut found = false;
+    for key in keys {
+        found |= !memory.dump(&escape_stored(key)).await.is_empty();
+    }
+    if !found {
+        return CacheResult::Success(String::from("NOKEY"));
+    }
+    let connection = match cluster {
+        Some(cluster) => cluster.connect(&addr).await,
+        None => time::timeout(REQUEST_TIMEOUT, Connection::connect(&addr)).await
+            .unwrap_or_else(|_| Err(ClientError::ConnectionError(format!("Connection to {} timed out", addr))))
+    };
+    let mut connection = match connection {
+        Ok(c) => c,
+        Err(e) => return CacheResult::error(ErrorCode::Err, format!("Could not migrate to {}: {}", addr, e))
+    };
+    for key in keys {
+        // Held for one key at a time, so the key cannot change between its copy and its delete
+        let _exclusive = memory.exclusive.write().await;
+        let mut records = memory.dump(&escape_stored(key)).await;
+        if records.is_empty() {
+            continue;
+        }
+        // Drops the copy left on the target by a migration that failed before deleting here
+        if replace {
+            records.insert(0, vec![String::from("del"), key.clone()]);
+        }
+        for mut record in records {
+            // Keys with an expiry are sent with the seconds they have left
+            if record[0] == replication::SETEXAT {
+                let expires_at: u64 = record[2].parse().unwrap_or(0);
+                let seconds = expires_at.saturating_sub(models::now_millis()).div_ceil(1000).max(1);
+                record[0] = String::from("setex");
+                record[2] = seconds.to_string();
+            }
+            let record: Vec<&str> = record.iter().map(String::as_str).collect();
+            // A server outside cluster mode takes the keys as they come
+            let asked = match cluster {
+                Some(_) => request(&mut connection, &["asking"]).await,
+                None => Ok(CacheResult::Nil)
+            };
+            let reply = match asked {
+                Ok(CacheResult::Failure(e)) => Ok(CacheResult::Failure(e)),
+                Ok(_) => request(&mut connection, &record).await,
+                Err(e) => Err(e)
+            };
+            match reply {
+                Ok(CacheResult::Failure(e)) => return CacheResult::error(ErrorCode::Err, format!("{} refused {}: {}", addr, key, e)),
+                Ok(_) => {},
+                Err(e) => return CacheResult::error(ErrorCode::Err, format!("Could not migrate {} to {}: {}", key, addr, e))
+            }
+        }
+        let delete = match Command::from_args(&["del", key.as_str()]) {
+            Ok(cmd) => cmd,
+            Err(e) => return CacheResult::Failure(e.into())
+        };
+        if let CacheResult::Failure(e) = Cache::Del.handle_cmd(delete, memory.clone(), tx.clone()).await {
+            return CacheResult::Failure(e);
+        }
+    }
+    CacheResult::Success(String::from("OK"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_slots_follow_hash_tags() {
+        assert_eq!(key_slot("123456789"), 0x31C3);
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(key_slot("{user42}.name"), key_slot("{user42}.age"));
+        assert_eq!(key_slot("{user42}.name"), key_slot("user42"));
+        // An empty tag hashes the whole key, only the first { counts
+        assert_ne!(key_slot("{}a"), key_slot("{}b"));
+        assert_eq!(key_slot("foo{{bar}}"), key_slot("{bar"));
+        assert!((0..1000).all(|n| (key_slot(&n.to_string()) as usize) < SLOTS));
+    }
+
+    #[test]
+    fn node_lines_carry_the_bus_port() {
+        let line = Line::parse("abc 10.0.0.5:7000@17100 myself,primary - 0 0 3 connected 0-10").unwrap();
+        assert_eq!((line.addr.as_str(), line.bus_port, line.myself, line.epoch), ("10.0.0.5:7000", 17100, true, 3));
+        // Without one, the node has the default bus port
+        assert_eq!(Line::parse("abc 10.0.0.5:7000 primary - 0 0 3 connected").unwrap().bus_port, 17000);
+        assert!(Line::parse("abc 10.0.0.5:60000 primary - 0 0 3 connected").is_none());
+        let node = Node { addr: String::from("[::1]:7000"), bus_port: 17100, epoch: 0 };
+        assert_eq!(node.bus_addr(), "[::1]:17100");
+    }
+}
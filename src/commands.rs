@@ -0,0 +1,375 @@
+//! The command table: every command the server knows, with what it needs to be checked,
+//! dispatched and described. `COMMAND`, `COMMAND INFO`, `COMMAND DOCS` and the client's help
+//! are all read from here.
+
+use crate::{models::{self, ErrorCode, MainError}, Cache, CacheResult, Command};
+
+/// Changes memory and the backup file.
+pub const WRITE: &str = "write";
+/// Only reads memory.
+pub const READONLY: &str = "readonly";
+/// Acts on the server process rather than on keys.
+pub const ADMIN: &str = "admin";
+/// Runs in constant time.
+pub const FAST: &str = "fast";
+/// Publishes or subscribes to channels.
+pub const PUBSUB: &str = "pubsub";
+/// Starts, runs or cancels a transaction.
+pub const TRANSACTION: &str = "transaction";
+/// Runs or manages Lua scripts.
+pub const SCRIPTING: &str = "scripting";
+
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// Number of arguments, the name included. Negative means at least that many.
+    pub arity: i32,
+    pub flags: &'static [&'static str],
+    /// Position of the first key argument, 0 if the command takes no key.
+    pub first_key: i32,
+    /// Position of the last key argument, -1 for the last argument.
+    pub last_key: i32,
+    /// Distance between two key arguments.
+    pub key_step: i32,
+    /// Heading the command is listed under in the help.
+    pub group: &'static str,
+    /// Arguments after the name, e.g. `<key> <field> <value>`.
+    pub arguments: &'static str,
+    pub summary: &'static str,
+    pub handler: Cache
+}
+
+impl CommandSpec {
+    /// True if a request of argc arguments, the name included, has the number this command takes.
+    pub fn accepts(&self, argc: usize) -> bool {
+        let arity = self.arity.unsigned_abs() as usize;
+        if self.arity < 0 {
+            argc >= arity
+        } else {
+            argc == arity
+        }
+    }
+    /// The failure of a request with a number of arguments this command does not take.
+    pub fn wrong_arity(&self) -> MainError {
+        MainError::BadCommandFormat(format!("Wrong number of arguments for {}\n{}", self.name, self.syntax()))
+    }
+    pub fn is_write(&self) -> bool {
+        self.flags.contains(&WRITE)
+    }
+    /// How the command is typed, e.g. `hset <key> <field> <value>`.
+    pub fn syntax(&self) -> String {
+        if self.arguments.is_empty() {
+            return self.name.to_string();
+        }
+        format!("{} {}", self.name, self.arguments)
+    }
+    /// The key arguments among args, the name being the first argument.
+    pub fn keys<'a>(&self, args: &'a [String]) -> Vec<&'a str> {
+        if self.first_key <= 0 || self.key_step <= 0 {
+            return Vec::new();
+        }
+        let last = if self.last_key < 0 { args.len() as i32 + self.last_key } else { self.last_key };
+        (self.first_key..=last).step_by(self.key_step as usize)
+            .filter_map(|i| args.get(i as usize).map(String::as_str))
+            .collect()
+    }
+    /// Name, arity, flags and key positions, the reply of `COMMAND INFO` for this command.
+    pub fn info(&self) -> [String; 6] {
+        [
+            self.name.to_string(),
+            self.arity.to_string(),
+            self.flags.join(" "),
+            self.first_key.to_string(),
+            self.last_key.to_string(),
+            self.key_step.to_string()
+        ]
+    }
+    /// Name, group, syntax and summary, the reply of `COMMAND DOCS` for this command.
+    pub fn docs(&self) -> [String; 4] {
+        [self.name.to_string(), self.group.to_string(), self.syntax(), self.summary.to_string()]
+    }
+}
+
+pub static COMMANDS: [CommandSpec; 39] = [
+    CommandSpec {
+        name: "get", arity: 2, flags: &[READONLY, FAST], first_key: 1, last_key: 1, key_step: 1,
+        group: "fetch", arguments: "<key>",
+        summary: "Retrieve the value of a key.",
+        handler: Cache::Get
+    },
+    CommandSpec {
+        name: "hget", arity: 2, flags: &[READONLY], first_key: 1, last_key: 1, key_step: 1,
+        group: "fetch", arguments: "<key>",
+        summary: "Retrieve all fields and values stored in a hash (like redis hgetall).",
+        handler: Cache::HGet
+    },
+    CommandSpec {
+        name: "smembers", arity: 2, flags: &[READONLY], first_key: 1, last_key: 1, key_step: 1,
+        group: "fetch", arguments: "<key>",
+        summary: "Retrieve all members of a set.",
+        handler: Cache::SMembers
+    },
+    CommandSpec {
+        name: "set", arity: 3, flags: &[WRITE], first_key: 1, last_key: 1, key_step: 1,
+        group: "change", arguments: "<key> <value>",
+        summary: "Set the value of a key.",
+        handler: Cache::Set
+    },
+    CommandSpec {
+        name: "hset", arity: -4, flags: &[WRITE], first_key: 1, last_key: 1, key_step: 1,
+        group: "change", arguments: "<key> <field> <value> [<field> <value>...]",
+        summary: "Set the value of one or more fields in a hash.",
+        handler: Cache::HSet
+    },
+    CommandSpec {
+        name: "sadd", arity: -3, flags: &[WRITE], first_key: 1, last_key: 1, key_step: 1,
+        group: "change", arguments: "<key> <value> [<value>...]",
+        summary: "Add one or more unique values to a set.",
+        handler: Cache::SAdd
+    },
+    CommandSpec {
+        name: "del", arity: 2, flags: &[WRITE], first_key: 1, last_key: 1, key_step: 1,
+        group: "delete", arguments: "<key>",
+        summary: "Delete a key and its value.",
+        handler: Cache::Del
+    },
+    CommandSpec {
+        name: "hdel", arity: 3, flags: &[WRITE], first_key: 1, last_key: 1, key_step: 1,
+        group: "delete", arguments: "<key> <field>",
+        summary: "Delete a specific field from a hash.",
+        handler: Cache::HDel
+    },
+    CommandSpec {
+        name: "sremove", arity: 3, flags: &[WRITE], first_key: 1, last_key: 1, key_step: 1,
+        group: "delete", arguments: "<key> <value>",
+        summary: "Remove a value from a set.",
+        handler: Cache::SRemove
+    },
+    CommandSpec {
+        name: "setex", arity: 4, flags: &[WRITE], first_key: 1, last_key: 1, key_step: 1,
+        group: "expire", arguments: "<key> <seconds> <value>",
+        summary: "Set the value of a key that is deleted after the given seconds.",
+        handler: Cache::SetEx
+    },
+    CommandSpec {
+        name: "ttl", arity: 2, flags: &[READONLY, FAST], first_key: 1, last_key: 1, key_step: 1,
+        group: "expire", arguments: "<key>",
+        summary: "Seconds left before a key expires, -1 if it never does, -2 if it does not exist.",
+        handler: Cache::Ttl
+    },
+    CommandSpec {
+        name: "shutdown", arity: -1, flags: &[ADMIN], first_key: 0, last_key: 0, key_step: 0,
+        group: "admin", arguments: "[save|nosave]",
+        summary: "Stop the server. save (default) flushes pending writes, nosave drops them. Takes at most one of them.",
+        handler: Cache::Shutdown
+    },
+    CommandSpec {
+        name: "info", arity: -1, flags: &[ADMIN], first_key: 0, last_key: 0, key_step: 0,
+        group: "admin", arguments: "[server|clients|memory|persistence|stats|replication|keyspace]",
+        summary: "Show the state of the server: uptime, clients, commands, memory, backup writes, replication and keys.",
+        handler: Cache::Info
+    },
+    CommandSpec {
+        name: "ping", arity: 1, flags: &[FAST], first_key: 0, last_key: 0, key_step: 0,
+        group: "admin", arguments: "",
+        summary: "Check that the server answers, replies PONG.",
+        handler: Cache::Ping
+    },
+    CommandSpec {
+        name: "command", arity: -1, flags: &[], first_key: 0, last_key: 0, key_step: 0,
+        group: "admin", arguments: "[count|list|info|docs] [<name>...]",
+        summary: "Describe the commands: their arity, flags and key positions (info) or syntax and summary (docs).",
+        handler: Cache::Command
+    },
+    CommandSpec {
+        name: "auth", arity: -2, flags: &[FAST], first_key: 0, last_key: 0, key_step: 0,
+        group: "admin", arguments: "[<user>] <password>",
+        summary: "Log the connection in, as the default user when no user is given.",
+        handler: Cache::Auth
+    },
+    CommandSpec {
+        name: "acl", arity: -2, flags: &[ADMIN], first_key: 0, last_key: 0, key_step: 0,
+        group: "admin", arguments: "setuser <name> [<rule>...] | deluser <name>... | list | whoami | cat",
+        summary: "Manage users: rules are on, off, >password, nopass, +@category, -@category, ~pattern, allkeys, reset.",
+        handler: Cache::Acl
+    },
+    CommandSpec {
+        name: "slowlog", arity: -2, flags: &[ADMIN], first_key: 0, last_key: 0, key_step: 0,
+        group: "admin", arguments: "get [<count>] | len | reset",
+        summary: "Read or empty the log of the commands that ran longer than --slowlog-log-slower-than.",
+        handler: Cache::SlowLog
+    },
+    CommandSpec {
+        name: "monitor", arity: 1, flags: &[ADMIN], first_key: 0, last_key: 0, key_step: 0,
+        group: "admin", arguments: "",
+        summary: "Stream every command the server receives, with its time, database and client, until the connection closes.",
+        handler: Cache::Monitor
+    },
+    CommandSpec {
+        name: "publish", arity: 3, flags: &[PUBSUB, FAST], first_key: 0, last_key: 0, key_step: 0,
+        group: "pubsub", arguments: "<channel> <message>",
+        summary: "Send a message to the subscribers of a channel, replies how many received it.",
+        handler: Cache::Publish
+    },
+    CommandSpec {
+        name: "subscribe", arity: -2, flags: &[PUBSUB], first_key: 0, last_key: 0, key_step: 0,
+        group: "pubsub", arguments: "<channel> [<channel>...]",
+        summary: "Receive the messages published to the channels, until unsubscribed.",
+        handler: Cache::Subscribe
+    },
+    CommandSpec {
+        name: "unsubscribe", arity: -1, flags: &[PUBSUB], first_key: 0, last_key: 0, key_step: 0,
+        group: "pubsub", arguments: "[<channel>...]",
+        summary: "Stop receiving the messages of the channels, of every channel without one.",
+        handler: Cache::Unsubscribe
+    },
+    CommandSpec {
+        name: "psubscribe", arity: -2, flags: &[PUBSUB], first_key: 0, last_key: 0, key_step: 0,
+        group: "pubsub", arguments: "<pattern> [<pattern>...]",
+        summary: "Receive the messages of every channel matching the glob patterns.",
+        handler: Cache::PSubscribe
+    },
+    CommandSpec {
+        name: "punsubscribe", arity: -1, flags: &[PUBSUB], first_key: 0, last_key: 0, key_step: 0,
+        group: "pubsub", arguments: "[<pattern>...]",
+        summary: "Stop receiving the messages of the patterns, of every pattern without one.",
+        handler: Cache::PUnsubscribe
+    },
+    CommandSpec {
+        name: "pubsub", arity: -2, flags: &[PUBSUB], first_key: 0, last_key: 0, key_step: 0,
+        group: "pubsub", arguments: "channels [<pattern>] | numsub [<channel>...] | numpat",
+        summary: "Channels with subscribers, subscribers per channel, or the number of pattern subscriptions.",
+        handler: Cache::PubSub
+    },
+    CommandSpec {
+        name: "multi", arity: 1, flags: &[TRANSACTION, FAST], first_key: 0, last_key: 0, key_step: 0,
+        group: "transaction", arguments: "",
+        summary: "Start a transaction: the next commands are queued until exec or discard.",
+        handler: Cache::Multi
+    },
+    CommandSpec {
+        name: "exec", arity: 1, flags: &[TRANSACTION], first_key: 0, last_key: 0, key_step: 0,
+        group: "transaction", arguments: "",
+        summary: "Run the queued commands with nothing in between, nil if a watched key changed.",
+        handler: Cache::Exec
+    },
+    CommandSpec {
+        name: "discard", arity: 1, flags: &[TRANSACTION, FAST], first_key: 0, last_key: 0, key_step: 0,
+        group: "transaction", arguments: "",
+        summary: "Drop the queued commands and unwatch every key.",
+        handler: Cache::Discard
+    },
+    CommandSpec {
+        name: "watch", arity: -2, flags: &[TRANSACTION, FAST], first_key: 1, last_key: -1, key_step: 1,
+        group: "transaction", arguments: "<key> [<key>...]",
+        summary: "Make the next exec fail if one of the keys changes before it.",
+        handler: Cache::Watch
+    },
+    CommandSpec {
+        name: "unwatch", arity: 1, flags: &[TRANSACTION, FAST], first_key: 0, last_key: 0, key_step: 0,
+        group: "transaction", arguments: "",
+        summary: "Forget the watched keys.",
+        handler: Cache::Unwatch
+    },
+    CommandSpec {
+        name: "eval", arity: -3, flags: &[SCRIPTING], first_key: 0, last_key: 0, key_step: 0,
+        group: "scripting", arguments: "<script> <numkeys> [<key>...] [<arg>...]",
+        summary: "Run a Lua script with nothing in between, its keys in KEYS and other arguments in ARGV.",
+        handler: Cache::Eval
+    },
+    CommandSpec {
+        name: "evalsha", arity: -3, flags: &[SCRIPTING], first_key: 0, last_key: 0, key_step: 0,
+        group: "scripting", arguments: "<sha1> <numkeys> [<key>...] [<arg>...]",
+        summary: "Run a script loaded before by eval or script load, by its SHA-1.",
+        handler: Cache::EvalSha
+    },
+    CommandSpec {
+        name: "script", arity: -2, flags: &[SCRIPTING], first_key: 0, last_key: 0, key_step: 0,
+        group: "scripting", arguments: "load <script> | exists <sha1>... | flush | kill",
+        summary: "Load a script without running it, check which are loaded, forget them all, or stop the running one.",
+        handler: Cache::Script
+    },
+    CommandSpec {
+        name: "replicaof", arity: 3, flags: &[ADMIN], first_key: 0, last_key: 0, key_step: 0,
+        group: "replication", arguments: "<host> <port> | no one",
+        summary: "Follow a primary as a read-only replica, or stop following one and accept writes.",
+        handler: Cache::ReplicaOf
+    },
+    CommandSpec {
+        name: "psync", arity: 3, flags: &[ADMIN], first_key: 0, last_key: 0, key_step: 0,
+        group: "replication", arguments: "<replid> <offset>",
+        summary: "Used by replicas: turn the connection into the stream of changes, from offset or from a full copy.",
+        handler: Cache::PSync
+    },
+    CommandSpec {
+        name: "replconf", arity: -3, flags: &[ADMIN], first_key: 0, last_key: 0, key_step: 0,
+        group: "replication", arguments: "listening-port <port> | ack <offset>",
+        summary: "Used by replicas: announce the port they listen on.",
+        handler: Cache::ReplConf
+    },
+    CommandSpec {
+        name: "cluster", arity: -2, flags: &[ADMIN], first_key: 0, last_key: 0, key_step: 0,
+        group: "cluster", arguments: "myid | nodes | slots | info | keyslot <key> | addslots <slot>... | addslotsrange <start> <end>... | meet <ip> <port> | setslot <slot> importing|migrating|node <id> | setslot <slot> stable | countkeysinslot <slot> | getkeysinslot <slot> <count>",
+        summary: "Show the nodes and the slots they serve, introduce nodes, assign slots or move one to another node.",
+        handler: Cache::Cluster
+    },
+    CommandSpec {
+        name: "asking", arity: 1, flags: &[FAST], first_key: 0, last_key: 0, key_step: 0,
+        group: "cluster", arguments: "",
+        summary: "Let the next command use a slot this node is importing, after an ASK redirect.",
+        handler: Cache::Asking
+    },
+    CommandSpec {
+        name: "migrate", arity: -4, flags: &[WRITE, ADMIN], first_key: 3, last_key: -1, key_step: 1,
+        group: "cluster", arguments: "<host> <port> [replace] <key> [<key>...]",
+        summary: "Move keys to another server: copy them there, then delete them here. replace overwrites the keys the target holds.",
+        handler: Cache::Migrate
+    }
+];
+
+/// The command of the given name, whatever case it is typed in.
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+/// The help of every command, listed by group.
+pub fn help_text() -> String {
+    let mut text = String::from("available commands:\n");
+    let mut group = "";
+    for spec in COMMANDS.iter() {
+        if spec.group != group {
+            group = spec.group;
+            text += &format!("\n{} commands\n", group);
+        }
+        text += &format!("  {}\n      {}\n", spec.syntax(), spec.summary);
+    }
+    text
+}
+
+/// Runs `command [count|list|info|docs] [name...]`.
+pub fn describe(cmd: &Command) -> CacheResult {
+    let subcommand = models::unescape_stored(&cmd.key).to_lowercase();
+    let names: Vec<String> = models::values(&cmd.data).iter().map(|name| models::unescape_stored(name)).collect();
+    let specs = match selected(&names) {
+        Ok(specs) => specs,
+        Err(result) => return result
+    };
+    match subcommand.as_str() {
+        "count" => CacheResult::Success(COMMANDS.len().to_string()),
+        "list" => CacheResult::Array(COMMANDS.iter().map(|spec| spec.name.to_string()).collect()),
+        "" | "info" => CacheResult::Array(specs.iter().flat_map(|spec| spec.info()).collect()),
+        "docs" => CacheResult::Array(specs.iter().flat_map(|spec| spec.docs()).collect()),
+        _ => CacheResult::error(ErrorCode::Syntax, "Use command [count|list|info|docs] [name...]")
+    }
+}
+
+/// The commands named, or all of them if there is no name.
+fn selected(names: &[String]) -> Result<Vec<&'static CommandSpec>, CacheResult> {
+    if names.is_empty() {
+        return Ok(COMMANDS.iter().collect());
+    }
+    names.iter().map(|name| match lookup(name) {
+        Some(spec) => Ok(spec),
+        None => Err(CacheResult::error(ErrorCode::Err, format!("Unknown command {}", name)))
+    }).collect()
+}
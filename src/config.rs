@@ -0,0 +1,332 @@
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, path::PathBuf, time::Duration};
+
+use super::{logging::LogConfig, models::MainError, notify::Events, persistence::WriteErrorPolicy};
+
+pub const USAGE: &str = r#"usage: server [options]
+
+options:
+  --port <port>
+      port to listen on (default: 8080), 0 to only listen on the unix socket.
+  --bind <ip>
+      address the TCP and metrics listeners bind to, 0.0.0.0 for every interface (default: 127.0.0.1).
+  --unixsocket <path>
+      also listen on a unix socket at path.
+  --unixsocketperm <mode>
+      octal permissions of the unix socket file (default: 700).
+  --data <path>
+      backup file, instead of _data.bin in the DATA_PATH set at build time.
+  --on-write-error <stop-writes|fail-fast>
+      what to do once a backup write keeps failing (default: stop-writes).
+  --write-retries <n>
+      attempts before a failing backup write is reported (default: 5).
+  --write-backoff-ms <ms>
+      delay before the first retry, doubled on every attempt (default: 100).
+  --requirepass <password>
+      password of the default user, connections have to AUTH before anything else.
+  --aclfile <path>
+      file the users are loaded from and saved to after every ACL SETUSER or DELUSER.
+  --tls-cert <path> --tls-key <path>
+      PEM certificate and private key, every connection then has to use TLS.
+  --tls-ca-cert <path>
+      PEM CA certificate, clients then need a certificate signed by it (mutual TLS).
+  --lua-time-limit <ms>
+      longest a script may run before it is stopped (default: 5000).
+  --notify-keyspace-events <flags>
+      publish key changes over pub/sub: K keyspace, E keyevent, g$hsxe or A for the events (default: none).
+  --replicaof <host> <port>
+      follow the primary at host and port as a read-only replica.
+  --primaryuser <name> --primaryauth <password>
+      user and password the replica logs in to its primary with, and a cluster node to the others.
+  --repl-backlog-size <bytes>
+      how much of the replication stream is kept for replicas that reconnect (default: 1048576).
+  --cluster-enabled <yes|no>
+      serve only the hash slots assigned to this node, redirecting the others (default: no).
+  --cluster-config-file <path>
+      where the node saves what it knows of the cluster (default: the backup file with a .nodes extension).
+  --cluster-port <port>
+      port of the cluster bus the nodes gossip over (default: --port + 10000).
+  --cluster-announce-ip <ip>
+      address the other nodes and redirected clients reach this node at (default: the --bind address).
+  --metrics-port <port>
+      serve Prometheus metrics over HTTP at /metrics on this port (default: off).
+  --slowlog-log-slower-than <us>
+      log commands running at least this many microseconds to the slow log, 0 logs every command, negative none (default: 10000).
+  --slowlog-max-len <n>
+      entries the slow log keeps, the oldest are dropped (default: 128).
+  --loglevel <filter>
+      what to log: error, warn, info, debug or trace, or per module like warn,mini_mcache::replication=debug (default: info).
+  --log-format <text|pretty|json>
+      one line per event, several indented lines, or one JSON object per line (default: text).
+  --logfile <path>
+      log to this file instead of stderr.
+  --log-rotation <never|hourly|daily>
+      start a new log file, named after the date, every hour or day (default: never).
+"#;
+
+/// Runtime settings of the server, read from the command line.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// 0 when the server only listens on the unix socket.
+    pub port: u16,
+    /// Address of the TCP listeners.
+    pub bind: IpAddr,
+    pub unix_socket: Option<PathBuf>,
+    pub unix_socket_perm: u32,
+    pub data_path: Option<PathBuf>,
+    pub write_error_policy: WriteErrorPolicy,
+    pub write_retries: u32,
+    pub write_backoff: Duration,
+    pub requirepass: Option<String>,
+    pub acl_file: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_ca_cert: Option<PathBuf>,
+    pub notify_keyspace_events: Events,
+    pub lua_time_limit: Duration,
+    /// host:port of the primary followed by a replica.
+    pub replicaof: Option<String>,
+    pub primary_user: Option<String>,
+    pub primary_auth: Option<String>,
+    pub repl_backlog_size: usize,
+    pub cluster_enabled: bool,
+    pub cluster_config_file: Option<PathBuf>,
+    /// Port of the cluster bus, --port + 10000 when none.
+    pub cluster_port: Option<u16>,
+    /// Address given to the other nodes, the bind address when none.
+    pub cluster_announce_ip: Option<IpAddr>,
+    /// Port of the HTTP listener serving /metrics, none when off.
+    pub metrics_port: Option<u16>,
+    /// Microseconds a command runs before it is slow logged, negative for never.
+    pub slowlog_log_slower_than: i64,
+    pub slowlog_max_len: usize,
+    pub log: LogConfig
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 8080,
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            unix_socket: None,
+            unix_socket_perm: 0o700,
+            data_path: None,
+            write_error_policy: WriteErrorPolicy::StopWrites,
+            write_retries: 5,
+            write_backoff: Duration::from_millis(100),
+            requirepass: None,
+            acl_file: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_ca_cert: None,
+            notify_keyspace_events: Events::default(),
+            lua_time_limit: Duration::from_secs(5),
+            replicaof: None,
+            primary_user: None,
+            primary_auth: None,
+            repl_backlog_size: 1024 * 1024,
+            cluster_enabled: false,
+            cluster_config_file: None,
+            cluster_port: None,
+            cluster_announce_ip: None,
+            metrics_port: None,
+            slowlog_log_slower_than: 10_000,
+            slowlog_max_len: 128,
+            log: LogConfig::default()
+        }
+    }
+}
+
+impl Config {
+    /// Builds the config from the arguments after the program name.
+    pub fn new(mut args: impl Iterator<Item = String>) -> Result<Config, MainError> {
+        let mut config = Config::default();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| MainError::BadCommandFormat(format!("{} needs a value", arg)));
+            match arg.as_str() {
+                "--port" => config.port = Config::number(&value()?)?,
+                "--bind" => config.bind = Config::ip(&value()?)?,
+                "--unixsocket" => config.unix_socket = Some(PathBuf::from(value()?)),
+                "--unixsocketperm" => {
+                    let mode = value()?;
+                    config.unix_socket_perm = u32::from_str_radix(&mode, 8).ok().filter(|m| *m <= 0o777)
+                        .ok_or_else(|| MainError::BadCommandFormat(format!("{} is not an octal file mode", mode)))?;
+                },
+                "--data" => config.data_path = Some(PathBuf::from(value()?)),
+                "--on-write-error" => config.write_error_policy = WriteErrorPolicy::new(&value()?)?,
+                "--write-retries" => config.write_retries = Config::number(&value()?)?,
+                "--write-backoff-ms" => config.write_backoff = Duration::from_millis(Config::number(&value()?)?),
+                "--requirepass" => config.requirepass = Some(value()?),
+                "--aclfile" => config.acl_file = Some(PathBuf::from(value()?)),
+                "--tls-cert" => config.tls_cert = Some(PathBuf::from(value()?)),
+                "--tls-key" => config.tls_key = Some(PathBuf::from(value()?)),
+                "--tls-ca-cert" => config.tls_ca_cert = Some(PathBuf::from(value()?)),
+                "--lua-time-limit" => config.lua_time_limit = Duration::from_millis(Config::number(&value()?)?),
+                "--notify-keyspace-events" => config.notify_keyspace_events = Events::new(&value()?)?,
+                "--replicaof" => {
+                    let host = value()?;
+                    let port: u16 = Config::number(&value()?)?;
+                    config.replicaof = Some(format!("{}:{}", host, port));
+                },
+                "--primaryuser" => config.primary_user = Some(value()?),
+                "--primaryauth" => config.primary_auth = Some(value()?),
+                "--repl-backlog-size" => config.repl_backlog_size = Config::number(&value()?)?,
+                "--cluster-enabled" => config.cluster_enabled = match value()?.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    other => return Err(MainError::BadCommandFormat(format!("--cluster-enabled takes yes or no, not {}", other)))
+                },
+                "--cluster-config-file" => config.cluster_config_file = Some(PathBuf::from(value()?)),
+                "--cluster-port" => config.cluster_port = Some(Config::number(&value()?)?),
+                "--cluster-announce-ip" => config.cluster_announce_ip = Some(Config::ip(&value()?)?),
+                "--metrics-port" => config.metrics_port = Some(Config::number(&value()?)?),
+                "--slowlog-log-slower-than" => config.slowlog_log_slower_than = Config::number(&value()?)?,
+                "--slowlog-max-len" => config.slowlog_max_len = Config::number(&value()?)?,
+                _ => if !config.log.option(&arg, value)? {
+                    return Err(MainError::BadCommandFormat(format!("Unknown option {}\n{}", arg, USAGE)))
+                }
+            }
+        }
+        if config.port == 0 && config.unix_socket.is_none() {
+            return Err(MainError::BadCommandFormat(String::from("--port 0 needs --unixsocket, or there is nothing to listen on")));
+        }
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err(MainError::BadCommandFormat(String::from("--tls-cert and --tls-key go together")));
+        }
+        if config.tls_ca_cert.is_some() && config.tls_cert.is_none() {
+            return Err(MainError::BadCommandFormat(String::from("--tls-ca-cert needs --tls-cert and --tls-key")));
+        }
+        if config.cluster_enabled && config.port == 0 {
+            return Err(MainError::BadCommandFormat(String::from("--cluster-enabled needs a --port the other nodes can reach")));
+        }
+        if config.cluster_enabled && config.cluster_bus_port().is_none() {
+            return Err(MainError::BadCommandFormat(format!("--port {} leaves no port for the cluster bus, set one with --cluster-port", config.port)));
+        }
+        if config.cluster_enabled && config.cluster_announce_ip.unwrap_or(config.bind).is_unspecified() {
+            return Err(MainError::BadCommandFormat(format!("--bind {} needs --cluster-announce-ip, the other nodes cannot reach that address", config.bind)));
+        }
+        Ok(config)
+    }
+    /// Where the other nodes and redirected clients reach this server, as ip:port.
+    pub fn announced_addr(&self) -> String {
+        SocketAddr::new(self.cluster_announce_ip.unwrap_or(self.bind), self.port).to_string()
+    }
+    /// The port of the cluster bus, None when --port + 10000 is not a port.
+    pub fn cluster_bus_port(&self) -> Option<u16> {
+        self.cluster_port.or_else(|| self.port.checked_add(crate::cluster::BUS_PORT_OFFSET))
+    }
+    fn ip(value: &str) -> Result<IpAddr, MainError> {
+        value.parse().map_err(|_| MainError::BadCommandFormat(format!("{} is not an IP address", value)))
+    }
+    fn number<T: std::str::FromStr>(value: &str) -> Result<T, MainError> {
+        value.parse().map_err(|_| MainError::BadCommandFormat(format!("{} is not a valid number", value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::{LogFormat, LogRotation};
+
+    fn config(args: &[&str]) -> Result<Config, MainError> {
+        Config::new(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn port_and_data_path() {
+        let parsed = config(&["--port", "6400", "--data", "/tmp/cache.bin"]).unwrap();
+        assert_eq!(parsed.port, 6400);
+        assert_eq!(parsed.data_path, Some(PathBuf::from("/tmp/cache.bin")));
+        assert!(config(&["--port", "http"]).is_err());
+    }
+
+    #[test]
+    fn write_error_policy_and_retries() {
+        let parsed = config(&["--on-write-error", "fail-fast", "--write-retries", "3"]).unwrap();
+        assert_eq!(parsed.write_error_policy, WriteErrorPolicy::FailFast);
+        assert_eq!(parsed.write_retries, 3);
+        assert!(config(&["--write-retries"]).is_err());
+        assert!(config(&["--on-write-error", "ignore"]).is_err());
+    }
+
+    #[test]
+    fn tls_needs_a_key_with_the_certificate() {
+        assert!(config(&["--tls-cert", "server.pem"]).is_err());
+    }
+
+    #[test]
+    fn unix_socket() {
+        let parsed = config(&["--port", "0", "--unixsocket", "/tmp/cache.sock", "--unixsocketperm", "770"]).unwrap();
+        assert_eq!(parsed.unix_socket, Some(PathBuf::from("/tmp/cache.sock")));
+        assert_eq!(parsed.unix_socket_perm, 0o770);
+        // Something has to listen
+        assert!(config(&["--port", "0"]).is_err());
+        assert!(config(&["--unixsocket", "/tmp/cache.sock", "--unixsocketperm", "800"]).is_err());
+    }
+
+    #[test]
+    fn notify_keyspace_events() {
+        assert_eq!(config(&["--notify-keyspace-events", "KEA"]).unwrap().notify_keyspace_events.to_string(), "KEA");
+        assert!(config(&["--notify-keyspace-events", "Kq"]).is_err());
+    }
+
+    #[test]
+    fn replicaof_and_backlog_size() {
+        let parsed = config(&["--replicaof", "10.0.0.2", "6400", "--repl-backlog-size", "4096"]).unwrap();
+        assert_eq!(parsed.replicaof.as_deref(), Some("10.0.0.2:6400"));
+        assert_eq!(parsed.repl_backlog_size, 4096);
+        assert!(config(&["--replicaof", "10.0.0.2", "primary"]).is_err());
+    }
+
+    #[test]
+    fn cluster_enabled_and_config_file() {
+        let parsed = config(&["--cluster-enabled", "yes", "--cluster-config-file", "/tmp/cache.nodes"]).unwrap();
+        assert!(parsed.cluster_enabled);
+        assert_eq!(parsed.cluster_config_file, Some(PathBuf::from("/tmp/cache.nodes")));
+        assert!(config(&["--cluster-enabled", "maybe"]).is_err());
+    }
+
+    #[test]
+    fn metrics_port() {
+        assert_eq!(config(&["--metrics-port", "9121"]).unwrap().metrics_port, Some(9121));
+        assert_eq!(Config::default().metrics_port, None);
+    }
+
+    #[test]
+    fn logging() {
+        let parsed = config(&["--loglevel", "warn,mini_mcache::replication=debug", "--log-format", "json", "--logfile", "/tmp/cache.log", "--log-rotation", "daily"]).unwrap();
+        assert_eq!(parsed.log.level, "warn,mini_mcache::replication=debug");
+        assert_eq!(parsed.log.format, LogFormat::Json);
+        assert_eq!(parsed.log.file, Some(PathBuf::from("/tmp/cache.log")));
+        assert_eq!(parsed.log.rotation, LogRotation::Daily);
+        assert_eq!(Config::default().log, LogConfig::default());
+        assert!(config(&["--log-format", "xml"]).is_err());
+        assert!(config(&["--loglevel", "[["]).is_err());
+    }
+
+    #[test]
+    fn slowlog() {
+        let parsed = config(&["--slowlog-log-slower-than", "-1", "--slowlog-max-len", "16"]).unwrap();
+        assert_eq!((parsed.slowlog_log_slower_than, parsed.slowlog_max_len), (-1, 16));
+    }
+
+    #[test]
+    fn bind_and_cluster_announce_ip() {
+        assert_eq!(config(&[]).unwrap().announced_addr(), "127.0.0.1:8080");
+        let bound = config(&["--bind", "0.0.0.0", "--port", "7000", "--cluster-announce-ip", "10.0.0.5", "--cluster-enabled", "yes"]).unwrap();
+        assert_eq!(bound.bind, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(bound.announced_addr(), "10.0.0.5:7000");
+        assert_eq!(config(&["--bind", "::1", "--port", "7000"]).unwrap().announced_addr(), "[::1]:7000");
+        // The other nodes cannot reach every interface
+        assert!(config(&["--bind", "0.0.0.0", "--cluster-enabled", "yes"]).is_err());
+        assert!(config(&["--bind", "localhost"]).is_err());
+    }
+
+    #[test]
+    fn cluster_port() {
+        assert_eq!(config(&["--port", "7000"]).unwrap().cluster_bus_port(), Some(17000));
+        assert_eq!(config(&["--port", "7000", "--cluster-port", "7100"]).unwrap().cluster_bus_port(), Some(7100));
+        // Past the last port the bus needs a port of its own
+        assert!(config(&["--port", "60000", "--cluster-enabled", "yes"]).is_err());
+        assert!(config(&["--port", "60000", "--cluster-enabled", "yes", "--cluster-port", "7100"]).is_ok());
+    }
+}
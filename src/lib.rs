@@ -0,0 +1,708 @@
+//! mini-cache as a library: a Redis-inspired key–value store for strings, hashes and sets,
+//! kept in memory with a persistent backup on disk.
+//!
+//! [`Store`] runs everything in-process, with typed helpers ([`Store::set`], [`Store::hset`],
+//! [`Store::get`]...) and [`Store::execute`] for raw commands. The `server` binary exposes the
+//! same store over TCP, and [`client::Client`] talks to it from async code.
+
+use core::str;
+use std::sync::Arc;
+
+use tokio::sync::mpsc::Sender;
+
+pub mod models;
+pub mod file_control;
+pub mod config;
+pub mod persistence;
+pub mod protocol;
+pub mod store;
+pub mod client;
+pub mod commands;
+pub mod acl;
+pub mod tls;
+pub mod pubsub;
+pub mod notify;
+pub mod transaction;
+pub mod scripting;
+pub mod replication;
+pub mod sentinel;
+pub mod cluster;
+pub mod stats;
+pub mod metrics;
+pub mod logging;
+pub mod slowlog;
+pub mod monitor;
+
+pub use store::Store;
+
+use models::{CacheError, ErrorCode, MainError, Memory};
+
+use crate::models::{Delete, Pipe};
+
+// Names the values are stored under, a fetch or delete reads the key of the matching change command
+pub const FETCH_CMD: [&str; 3] = ["get", "hget", "smembers"];
+pub const CHANGE_CMD: [&str; 3] = ["set", "hset", "sadd"];
+pub const DEL_CMD: [&str; 3] = ["del", "hdel", "sremove"];
+
+/// Every command name the server knows, e.g. for completion in the client.
+pub fn command_names() -> impl Iterator<Item = &'static str> {
+    commands::COMMANDS.iter().map(|spec| spec.name)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cache {
+    Set,
+    HSet,
+    SAdd,
+    // FETCH_CMD
+    Get,
+    HGet,
+    SMembers,
+
+    // Delete CMD
+    Del,
+    HDel,
+    SRemove,
+
+    // Expire commands
+    SetEx,
+    Ttl,
+
+    // Admin commands
+    Shutdown,
+    Info,
+    Ping,
+    Command,
+    Auth,
+    Acl,
+    SlowLog,
+    Monitor,
+
+    // Pub/sub commands
+    Publish,
+    Subscribe,
+    Unsubscribe,
+    PSubscribe,
+    PUnsubscribe,
+    PubSub,
+
+    // Transactions
+    Multi,
+    Exec,
+    Discard,
+    Watch,
+    Unwatch,
+
+    // Scripting
+    Eval,
+    EvalSha,
+    Script,
+
+    // Replication
+    ReplicaOf,
+    PSync,
+    ReplConf,
+
+    // Cluster
+    Cluster,
+    Asking,
+    Migrate
+}
+
+impl Cache {
+    /// Looks the command up in [`commands::COMMANDS`] and checks its number of arguments.
+    pub fn new(cmd: &Command) -> Result<Cache, MainError> {
+        let spec = match commands::lookup(&cmd.action) {
+            Some(spec) => spec,
+            None => return Err(MainError::FindCacheTypeError(format!("Unknown command {}", cmd.action)))
+        };
+        if !spec.accepts(cmd.argc) {
+            return Err(spec.wrong_arity());
+        }
+        Ok(spec.handler)
+    }
+    pub async fn handle_cmd(&self, cmd: Command, memory: Arc<Memory>, tx: Sender<Pipe>) -> CacheResult {
+        match self {
+            Self::HSet if cmd.argc % 2 == 1 => {
+                CacheResult::error(ErrorCode::Syntax, "Use hset to store one or more field and value pairs.\nhset key field value")
+            }
+            Self::Set | Self::HSet | Self::SAdd => {
+                self.set(cmd, memory, tx).await
+            }
+            // FETCH_CMD 
+            Self::Get | Self::HGet | Self::SMembers => {
+                self.get(cmd, memory).await
+            },
+            Self::Del => {
+                self.del(cmd, Cache::Del, memory, tx).await
+            },
+            Self::HDel => {
+                self.del(cmd, Cache::HDel, memory, tx).await
+            },
+            Self::SRemove => {
+                self.del(cmd, Cache::SRemove, memory, tx).await
+            },
+            Self::SetEx => {
+                self.set_ex(cmd, memory, tx).await
+            },
+            Self::Ttl => {
+                CacheResult::Success(memory.ttl(&cmd.key).await.to_string())
+            },
+            Self::Ping => CacheResult::Success(String::from("PONG")),
+            Self::Command => commands::describe(&cmd),
+            // Handled by the server since they act on the process or the connection, not on memory
+            _ => CacheResult::error(ErrorCode::Err, "Admin commands are handled by the server")
+        }
+    }
+    /// True for the commands only a server can run, since they act on the process or the connection.
+    pub fn needs_server(&self) -> bool {
+        matches!(self, Self::Shutdown | Self::Auth | Self::Acl | Self::Publish | Self::Subscribe
+            | Self::Unsubscribe | Self::PSubscribe | Self::PUnsubscribe | Self::PubSub
+            | Self::Multi | Self::Exec | Self::Discard | Self::Watch | Self::Unwatch | Self::PSync | Self::ReplConf | Self::Asking | Self::Monitor)
+    }
+    /// True for eval, evalsha and script, which cannot run inside a transaction or a script.
+    pub fn is_script(&self) -> bool {
+        matches!(self, Self::Eval | Self::EvalSha | Self::Script)
+    }
+    /// True for the commands that cannot run inside a transaction or a script: scripts, and the
+    /// ones that change the role of the server or talk to other servers.
+    pub fn runs_alone(&self) -> bool {
+        self.is_script() || matches!(self, Self::ReplicaOf | Self::Cluster | Self::Migrate)
+    }
+    /// True for multi, exec, discard, watch and unwatch, which run at once inside a transaction.
+    pub fn is_transaction(&self) -> bool {
+        matches!(self, Self::Multi | Self::Exec | Self::Discard | Self::Watch | Self::Unwatch)
+    }
+    /// True for the commands a connection may still send once subscribed to a channel.
+    pub fn allowed_when_subscribed(&self) -> bool {
+        matches!(self, Self::Subscribe | Self::Unsubscribe | Self::PSubscribe | Self::PUnsubscribe | Self::Ping)
+    }
+    /// True for the commands that change memory and the backup file.
+    pub fn is_write(&self) -> bool {
+        commands::COMMANDS.iter().any(|spec| spec.handler == *self && spec.is_write())
+    }
+    async fn get(&self, mut cmd: Command, memory: Arc<Memory>) -> CacheResult {
+        // key -> command\tkey
+        cmd.reverse_action();
+        let key_value = cmd.reverse+"\t"+&cmd.key; // We use naming key_value because this is where we would store the value
+        memory.get(key_value).await
+    }
+    async fn del(&self, mut cmd: Command, cache: Cache, memory: Arc<Memory>, tx: Sender<Pipe>) -> CacheResult {
+        cmd.reverse_action();
+        let key_value = cmd.reverse+"\t"+&cmd.key; // We use naming key_value because this is where we would store the value
+        let delete = Delete{cmd: cache, key_value, key: cmd.del_action};
+        memory.del(delete, tx).await
+    }
+    async fn set(&self, cmd: Command, memory: Arc<Memory>, tx: Sender<Pipe>) -> CacheResult {
+        // key -> command\tkey
+        // value -> value\"value\"value\n
+        memory.set(cmd.key, cmd.data, cmd.action, tx).await
+    }
+    async fn set_ex(&self, cmd: Command, memory: Arc<Memory>, tx: Sender<Pipe>) -> CacheResult {
+        // setex\tkey'seconds"value" is stored as set\tkey'value" with its expiry on the side
+        let values = models::values(&cmd.data);
+        let seconds = match values.first().map(|s| s.parse::<u64>()) {
+            Some(Ok(s)) if s > 0 && values.len() == 2 => s,
+            _ => return CacheResult::error(ErrorCode::Syntax, "Use setex to store a key that expires after the given seconds.\nsetex key seconds value")
+        };
+        let data = format!("{}\t{}'{}\"", CHANGE_CMD[0], cmd.key, values[1]);
+        let expires_at = models::now_millis().saturating_add(seconds.saturating_mul(1000));
+        memory.set_ex(cmd.key, data, expires_at, tx).await
+    }
+
+}
+
+pub struct Command{
+    /// Number of arguments, the command name included.
+    argc: usize,
+    data: String,
+    key: String,
+    action: String, 
+    del_action: String,
+    reverse: String
+}
+
+impl Command {
+    pub fn new(size: usize, data: Vec<u8>) -> Result<Command, MainError> {
+        if data.len() < 4 || size == 0  || size > data.len() {
+            return Err(MainError::BadCommandFormat(String::from("Not enough commands")));
+        } 
+        // name\tcommand\tkey\tvalue\t, the name of the client is not part of the command
+        let mut args = Vec::new();
+        for val in data[..size-1].split(|b| *b == b'\t').skip(1) {
+            match str::from_utf8(val) {
+                Ok(v) => args.push(protocol::unescape(v)),
+                Err(e) => {
+                    return Err(MainError::BadCommandFormat(e.to_string()))
+                }
+            };
+        }
+        if args.is_empty() {
+            args.push(String::new());
+        }
+        Command::from_args(&args)
+    }
+    /// Builds a command straight from its arguments (command, key, values...), without the wire encoding.
+    /// Keys and values are kept in their stored, escaped form.
+    pub fn from_args<S: AsRef<str>>(args: &[S]) -> Result<Command, MainError> {
+        let mut key = String::new();
+        let mut action = String::new();
+        let mut values = String::new();
+        for (i, val) in args.iter().enumerate() {
+            let escaped = models::escape_stored(val.as_ref());
+            let val = if i == 0 { val.as_ref() } else { escaped.as_str() };
+            if i == 0 {
+                // Commands are stored lowercase, whatever case they were typed in
+                let val = val.to_lowercase();
+                action+=&val;
+                values+=&val;
+            } else if i == 1 {
+                key+= val;
+                values+="\t";
+                values+=val;
+                values+="\'";
+            } else {
+                values+=val;
+                values+="\"";
+            }
+        }
+        let last = match args.last() {
+            Some(l) if args.len() > 1 => models::escape_stored(l.as_ref()),
+            Some(l) => l.as_ref().to_string(),
+            None => return Err(MainError::BadCommandFormat(String::from("Not enough commands")))
+        };
+        Ok(Command { argc: args.len(), data: values, key, action, del_action: last, reverse: String::new() })
+    }
+    /// The arguments the command was built from, unescaped.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![self.action.clone()];
+        if self.argc > 1 {
+            args.push(models::unescape_stored(&self.key));
+            args.extend(models::values(&self.data).into_iter().map(models::unescape_stored));
+        }
+        args
+    }
+    pub fn reverse_action(&mut self) {
+        // pub const FETCH_CMD: [&'static str; 3] = ["get", "hget", "smembers"];
+        // pub const CHANGE_CMD: [&'static str; 3] = ["set", "hset", "sadd"];
+        self.reverse.clear();
+        match self.action.to_lowercase() {
+            key if key == FETCH_CMD[0] => self.reverse+=CHANGE_CMD[0],
+            key if key == FETCH_CMD[1] => self.reverse+=CHANGE_CMD[1],
+            key if key == FETCH_CMD[2] => self.reverse+=CHANGE_CMD[2],
+            key if key == CHANGE_CMD[0] => self.reverse+=FETCH_CMD[0],
+            key if key == CHANGE_CMD[1] => self.reverse+=FETCH_CMD[1],
+            key if key == CHANGE_CMD[2] => self.reverse+=FETCH_CMD[2],
+            key if key == DEL_CMD[1] => self.reverse+=CHANGE_CMD[1],
+            key if key == DEL_CMD[2] => self.reverse+=CHANGE_CMD[2],
+            _ => {
+                self.reverse.clear()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheResult {
+    Success(String),
+    Failure(CacheError),
+    /// Hash fields and values in turn, or set members.
+    Array(Vec<String>),
+    /// The key does not exist.
+    Nil,
+}
+
+impl CacheResult {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> CacheResult {
+        CacheResult::Failure(CacheError::new(code, message))
+    }
+}
+
+/// What the server does with writes that are still queued for the disk when it stops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownMode {
+    /// Drain the writer channel and flush everything to disk (the default).
+    Save,
+    /// Exit without persisting anything that is still pending.
+    NoSave
+}
+
+impl ShutdownMode {
+    pub fn new(cmd: &Command) -> Result<ShutdownMode, MainError> {
+        // The arity in the command table is only a minimum, anything after the mode is refused
+        if cmd.argc > 2 {
+            return Err(commands::lookup("shutdown").map_or_else(|| MainError::BadCommandFormat(String::from("Use shutdown [save|nosave]")), |spec| spec.wrong_arity()));
+        }
+        // shutdown -> key is empty, shutdown save|nosave -> key holds the mode
+        match cmd.key.to_lowercase().as_str() {
+            "" | "save" => Ok(Self::Save),
+            "nosave" => Ok(Self::NoSave),
+            _ => Err(MainError::BadCommandFormat(String::from("Use shutdown [save|nosave]")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // use std::{env};
+
+
+    use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+    use tokio::{sync::mpsc, time};
+
+    use super::*;
+    use crate::{config::Config, models::Memory, persistence::{update_data_to_file, Persistence}};
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mini-cache-{}.bin", name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn command(data: &str) -> Command {
+        let data = String::from(data);
+        Command::new(data.len(), data.into_bytes()).unwrap()
+    }
+
+    async fn handler(path: &Path, data: String) -> CacheResult {
+        let memory = Memory::new(path.to_path_buf()).unwrap();
+        let memory = Arc::new(memory);
+        let cmd = match Command::new(data.len(), data.into_bytes()) {
+            Ok(c) => c,
+            Err(e) => {
+                println!("{}", e.show_err_str());
+                return CacheResult::Failure(e.into());
+            }
+        };
+        let cache = match Cache::new(&cmd) {
+            Ok(c) => c,
+            Err(e) => {
+                println!("{}", e.show_err_str());
+                return CacheResult::Failure(e.into());
+            }
+        };
+        let (tx, _rx) = mpsc::channel(100);
+        cache.handle_cmd(cmd, memory, tx).await
+    }
+    #[tokio::test]
+    async fn process_stream() {
+        let path = test_path("process_stream");
+        let data: String = String::from("target/debug/client\tsadd\tjames\tname\tmakuo\tage\t25\t");
+        let result = handler(&path, data).await;
+        assert!(matches!(result, CacheResult::Success(_)));
+        assert!(!matches!(result, CacheResult::Failure(_)));
+        let data: String = String::from("target/debug/client\tsmembers\tjames\t");
+        let result = handler(&path, data).await;
+        assert!(matches!(result, CacheResult::Array(_)));
+        assert!(!matches!(result, CacheResult::Failure(_)));
+    }
+    #[tokio::test]
+    async fn process_cmd() {
+        let data: String = String::from("target/debug/client\tset\tdo\tmakuo\t");
+        let cmd = Command::new(data.len(), data.into_bytes());
+        assert!(cmd.is_ok());
+    }
+    #[tokio::test]
+    async fn process_wrong_stream() {
+        let data: String = String::from("target/debug/client\tmet\tname\tmakuo\t");
+        let cmd = Command::new(data.len(), data.into_bytes()).unwrap();
+        let cache = Cache::new(&cmd);
+        assert!(cache.is_err());
+    }
+    #[tokio::test]
+    async fn process_good_stream() {
+        let data: String = String::from("target/debug/client\tset\tname\tmakuo\t");
+        let cmd = Command::new(data.len(), data.into_bytes()).unwrap();
+        let cache = Cache::new(&cmd);
+        assert!(cache.is_ok());
+    }
+    #[tokio::test]
+    async fn process_good_stream_hset() {
+        let path = test_path("process_good_stream_hset");
+        let data: String = String::from("target/debug/client\thset\tperson2\tname\tmakuo\tage\t25\t");
+        let result = handler(&path, data).await;
+        let data: String = String::from("target/debug/client\thget\tperson2\t");
+        let result_two = handler(&path, data).await;
+        assert!(matches!(result, CacheResult::Success(_)));
+        assert!(matches!(result_two, CacheResult::Array(_)));
+        assert!(!matches!(result, CacheResult::Failure(_)));
+        assert!(!matches!(result_two, CacheResult::Failure(_)));
+        
+    }
+    #[test]
+    fn shutdown_mode() {
+        let mode = ShutdownMode::new(&command("target/debug/client\tshutdown\t"));
+        assert_eq!(mode.unwrap(), ShutdownMode::Save);
+        let mode = ShutdownMode::new(&command("target/debug/client\tshutdown\tNOSAVE\t"));
+        assert_eq!(mode.unwrap(), ShutdownMode::NoSave);
+        let mode = ShutdownMode::new(&command("target/debug/client\tshutdown\tlater\t"));
+        assert!(mode.is_err());
+        let mode = ShutdownMode::new(&command("target/debug/client\tshutdown\tnosave\tnow\t"));
+        assert!(matches!(mode, Err(MainError::BadCommandFormat(e)) if e.starts_with("Wrong number of arguments for shutdown")));
+    }
+    #[tokio::test]
+    async fn writer_drains_queue_on_close() {
+        let path = test_path("writer_drains_queue_on_close");
+        let memory = Arc::new(Memory::new(path.clone()).unwrap());
+        let (tx, rx) = mpsc::channel(100);
+        let cmd = command("target/debug/client\tset\tname\tmakuo\t");
+        let result = Cache::new(&cmd).unwrap().handle_cmd(cmd, memory.clone(), tx.clone()).await;
+        assert!(matches!(result, CacheResult::Success(_)));
+        drop(tx);
+        let persistence = Arc::new(Persistence::new(&Config::default()));
+        let result = update_data_to_file(memory.clone(), rx, persistence).await;
+        assert!(result.is_ok());
+        assert_eq!(memory.pending().await, 0);
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(data, "set\tname\'makuo\"\n");
+    }
+    #[tokio::test]
+    async fn writer_discards_queue_on_nosave() {
+        let path = test_path("writer_discards_queue_on_nosave");
+        let memory = Arc::new(Memory::new(path.clone()).unwrap());
+        let (tx, rx) = mpsc::channel(100);
+        let cmd = command("target/debug/client\tset\tname\tmakuo\t");
+        let _ = Cache::new(&cmd).unwrap().handle_cmd(cmd, memory.clone(), tx.clone()).await;
+        drop(tx);
+        let persistence = Arc::new(Persistence::new(&Config::default()));
+        persistence.discard_pending();
+        let _ = update_data_to_file(memory.clone(), rx, persistence).await;
+        memory.clear_pending().await;
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(data.is_empty());
+    }
+    #[tokio::test]
+    async fn sharded_memory_reloads_every_key() {
+        let path = test_path("sharded_memory_reloads_every_key");
+        let memory = Arc::new(Memory::new(path.clone()).unwrap());
+        let (tx, rx) = mpsc::channel(1000);
+        let persistence = Arc::new(Persistence::new(&Config::default()));
+        let writer = tokio::spawn(update_data_to_file(memory.clone(), rx, persistence));
+        let mut tasks = Vec::new();
+        for i in 0..200 {
+            let (memory, tx) = (memory.clone(), tx.clone());
+            tasks.push(tokio::spawn(async move {
+                let cmd = command(&format!("target/debug/client\tset\tkey{}\tvalue{}\t", i, i));
+                Cache::new(&cmd).unwrap().handle_cmd(cmd, memory, tx).await
+            }));
+        }
+        for task in tasks {
+            assert!(matches!(task.await.unwrap(), CacheResult::Success(_)));
+        }
+        drop(tx);
+        writer.await.unwrap().unwrap();
+        drop(memory);
+        let memory = Memory::new(path).unwrap();
+        assert!(memory.shards.iter().filter(|s| !s.try_read().unwrap().item.is_empty()).count() > 1);
+        for i in [0, 57, 199] {
+            let result = memory.get(format!("set\tkey{}", i)).await;
+            assert!(matches!(result, CacheResult::Success(value) if value.trim() == format!("value{}", i)));
+        }
+    }
+    #[tokio::test]
+    async fn hdel_and_sremove_keep_the_other_items() {
+        let path = test_path("hdel_and_sremove_keep_the_other_items");
+        let store = Store::open(path.clone()).unwrap();
+        store.hset("person", &[("name", "makuo"), ("age", "25"), ("city", "name")]).await;
+        store.sadd("humans", &["anita", "james", "ada"]).await;
+        store.close(ShutdownMode::Save).await.unwrap();
+        let store = Store::open(path.clone()).unwrap();
+        assert_eq!(store.hdel("person", "name").await, CacheResult::Success(String::from("1")));
+        assert_eq!(store.sremove("humans", "james").await, CacheResult::Success(String::from("1")));
+        let person = CacheResult::Array(["age", "25", "city", "name"].map(String::from).to_vec());
+        let humans = CacheResult::Array(["anita", "ada"].map(String::from).to_vec());
+        assert_eq!(store.hget("person").await, person);
+        assert_eq!(store.smembers("humans").await, humans);
+        store.close(ShutdownMode::Save).await.unwrap();
+        let store = Store::open(path).unwrap();
+        assert_eq!(store.hget("person").await, person);
+        assert_eq!(store.smembers("humans").await, humans);
+        store.close(ShutdownMode::Save).await.unwrap();
+    }
+    #[tokio::test]
+    async fn setex_keys_expire() {
+        let path = test_path("setex_keys_expire");
+        let store = Store::open(path.clone()).unwrap();
+        assert_eq!(store.set_ex("session", "makuo", 1).await, CacheResult::Success(String::from("1")));
+        assert_eq!(store.set_ex("later", "makuo", 100).await, CacheResult::Success(String::from("1")));
+        store.set("name", "makuo").await;
+        assert!(matches!(store.set_ex("session", "again", 1).await, CacheResult::Failure(_)));
+        assert!(matches!(store.set_ex("bad", "makuo", 0).await, CacheResult::Failure(_)));
+        assert_eq!(store.ttl("session").await, CacheResult::Success(String::from("1")));
+        assert_eq!(store.ttl("name").await, CacheResult::Success(String::from("-1")));
+        assert_eq!(store.ttl("nobody").await, CacheResult::Success(String::from("-2")));
+        assert_eq!(store.get("session").await, CacheResult::Success(String::from("makuo")));
+        time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(store.get("session").await, CacheResult::Nil);
+        assert_eq!(store.ttl("session").await, CacheResult::Success(String::from("-2")));
+        // The expired key no longer blocks a new value
+        assert_eq!(store.set("session", "again").await, CacheResult::Success(String::from("1")));
+        store.close(ShutdownMode::Save).await.unwrap();
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(!data.contains("expire\tsession"));
+        assert!(data.contains("expire\tlater"));
+        // The expiry is read back with the value
+        let store = Store::open(path).unwrap();
+        assert_eq!(store.get("session").await, CacheResult::Success(String::from("again")));
+        assert!(matches!(store.ttl("later").await, CacheResult::Success(s) if s == "100" || s == "99"));
+        assert_eq!(store.del("later").await, CacheResult::Success(String::from("1")));
+        assert_eq!(store.ttl("later").await, CacheResult::Success(String::from("-2")));
+        store.close(ShutdownMode::Save).await.unwrap();
+    }
+    #[tokio::test]
+    async fn failures_carry_an_error_code() {
+        let path = test_path("failures_carry_an_error_code");
+        let store = Store::open(path).unwrap();
+        store.set("name", "makuo").await;
+        store.hset("person", &[("name", "makuo")]).await;
+        let code = |result: CacheResult| match result {
+            CacheResult::Failure(f) => Some(f.code),
+            _ => None
+        };
+        assert_eq!(store.get("nobody").await, CacheResult::Nil);
+        assert_eq!(store.del("nobody").await, CacheResult::Nil);
+        assert_eq!(code(store.get("person").await), Some(ErrorCode::WrongType));
+        assert_eq!(code(store.hset("name", &[("age", "25")]).await), Some(ErrorCode::WrongType));
+        assert_eq!(code(store.set("name", "again").await), Some(ErrorCode::Err));
+        assert_eq!(code(store.execute(&["set", "name"]).await), Some(ErrorCode::Syntax));
+        assert_eq!(code(store.execute(&["nothing"]).await), Some(ErrorCode::Err));
+        store.close(ShutdownMode::Save).await.unwrap();
+    }
+    #[tokio::test]
+    async fn command_table_checks_arity_and_describes_commands() {
+        let path = test_path("command_table_checks_arity_and_describes_commands");
+        let store = Store::open(path).unwrap();
+        let syntax = |result: CacheResult| matches!(result, CacheResult::Failure(f) if f.code == ErrorCode::Syntax);
+        assert!(syntax(store.execute(&["get"]).await));
+        assert!(syntax(store.execute(&["get", "name", "again"]).await));
+        assert!(syntax(store.execute(&["sadd", "humans"]).await));
+        assert!(syntax(store.execute(&["hset", "person", "name", "makuo", "age"]).await));
+        assert!(syntax(store.execute(&["ping", "loud"]).await));
+        assert_eq!(store.execute(&["hset", "person", "name", "makuo", "age", "25"]).await, CacheResult::Success(String::from("1")));
+        assert_eq!(store.execute(&["COMMAND", "count"]).await, CacheResult::Success(commands::COMMANDS.len().to_string()));
+        assert_eq!(store.execute(&["command", "info", "get", "hset"]).await, CacheResult::Array([
+            "get", "2", "readonly fast", "1", "1", "1",
+            "hset", "-4", "write", "1", "1", "1"
+        ].map(String::from).to_vec()));
+        assert_eq!(store.execute(&["command", "docs", "TTL"]).await, CacheResult::Array([
+            "ttl", "expire", "ttl <key>", "Seconds left before a key expires, -1 if it never does, -2 if it does not exist."
+        ].map(String::from).to_vec()));
+        assert!(matches!(store.execute(&["command"]).await, CacheResult::Array(items) if items.len() == commands::COMMANDS.len() * 6));
+        assert!(matches!(store.execute(&["command", "info", "nothing"]).await, CacheResult::Failure(f) if f.code == ErrorCode::Err));
+        assert!(syntax(store.execute(&["command", "nothing"]).await));
+        // Every command name is listed once and write flags match what refuses writes
+        for spec in commands::COMMANDS.iter() {
+            assert_eq!(command_names().filter(|name| *name == spec.name).count(), 1);
+            assert_eq!(spec.handler.is_write(), spec.flags.contains(&commands::WRITE));
+        }
+        store.close(ShutdownMode::Save).await.unwrap();
+    }
+    #[tokio::test]
+    async fn store_publishes_keyspace_events() {
+        let path = test_path("store_publishes_keyspace_events");
+        let config = Config { notify_keyspace_events: notify::Events::new("KEg$hx").unwrap(), ..Config::default() };
+        let store = Store::with_config(path, &config).unwrap();
+        let mut keyspace = store.pubsub().subscription();
+        keyspace.psubscribe("__keyspace@0__:*");
+        let mut keyevent = store.pubsub().subscription();
+        keyevent.psubscribe("__keyevent@0__:*");
+        store.set("name", "makuo").await;
+        store.hset("person", &[("name", "makuo"), ("age", "25")]).await;
+        store.hdel("person", "age").await;
+        // Not an enabled class, and nothing removed
+        store.sadd("humans", &["anita"]).await;
+        store.hdel("person", "nothing").await;
+        store.del("name").await;
+        store.set_ex("session", "a b", 1).await;
+        let expected = [("name", "set"), ("person", "hset"), ("person", "hdel"), ("name", "del"), ("session", "set"), ("session", "expire")];
+        for (key, event) in expected {
+            let message = keyspace.recv().await.unwrap();
+            assert_eq!((message.channel, message.payload), (format!("__keyspace@0__:{}", key), event.to_string()));
+            let message = keyevent.recv().await.unwrap();
+            assert_eq!((message.channel, message.payload), (format!("__keyevent@0__:{}", event), key.to_string()));
+        }
+        let message = time::timeout(Duration::from_secs(3), keyevent.recv()).await.unwrap().unwrap();
+        assert_eq!((message.channel.as_str(), message.payload.as_str()), ("__keyevent@0__:expired", "session"));
+        store.close(ShutdownMode::Save).await.unwrap();
+    }
+    #[tokio::test]
+    async fn store_runs_transactions() {
+        let path = test_path("store_runs_transactions");
+        let store = Store::open(path.clone()).unwrap();
+        store.sadd("pending", &["anita", "james"]).await;
+        let queue = |args: &[&str]| {
+            let cmd = Command::from_args(args).unwrap();
+            (Cache::new(&cmd).unwrap(), cmd)
+        };
+        let mut watch = store.watch();
+        watch.add("pending");
+        store.sremove("pending", "james").await;
+        assert!(watch.is_dirty());
+        assert_eq!(store.exec(vec![queue(&["sremove", "pending", "anita"])], Some(&watch)).await, CacheResult::Nil);
+        let queued = vec![queue(&["sremove", "pending", "anita"]), queue(&["hset", "person", "name", "a\tb"]), queue(&["smembers", "pending"])];
+        let replies = store.exec(queued, None).await;
+        assert_eq!(replies, CacheResult::Array(vec![String::from("+1"), String::from("+1"), String::from("*0")]));
+        let CacheResult::Array(items) = replies else { unreachable!() };
+        assert_eq!(protocol::decode_reply(items[2].as_bytes()), CacheResult::Array(Vec::new()));
+        store.close(ShutdownMode::Save).await.unwrap();
+        let store = Store::open(path).unwrap();
+        assert_eq!(store.hget("person").await, CacheResult::Array(vec![String::from("name"), String::from("a\tb")]));
+        assert_eq!(store.smembers("pending").await, CacheResult::Array(Vec::new()));
+    }
+    #[tokio::test]
+    async fn store_runs_scripts() {
+        let path = test_path("store_runs_scripts");
+        let store = Store::open(path.clone()).unwrap();
+        let script = "redis.call('sadd', KEYS[1], ARGV[1], ARGV[2]) redis.call('set', KEYS[2], 'a\\tb') return redis.call('smembers', KEYS[1])";
+        let result = store.execute(&["eval", script, "2", "humans", "name", "anita", "james"]).await;
+        assert_eq!(result, CacheResult::Array(vec![String::from("anita"), String::from("james")]));
+        let sha = scripting::Scripts::sha1(script);
+        assert_eq!(store.execute(&["script", "exists", &sha]).await, CacheResult::Array(vec![String::from("1")]));
+        // Writes made before a failure are kept
+        let result = store.execute(&["eval", "redis.call('del', 'name') return redis.call('get', 'humans')", "0"]).await;
+        assert!(matches!(result, CacheResult::Failure(e) if e.code == ErrorCode::WrongType));
+        store.close(ShutdownMode::Save).await.unwrap();
+        let store = Store::open(path).unwrap();
+        assert_eq!(store.smembers("humans").await, CacheResult::Array(vec![String::from("anita"), String::from("james")]));
+        assert_eq!(store.get("name").await, CacheResult::Nil);
+    }
+    #[tokio::test]
+    async fn store_runs_typed_and_raw_commands() {
+        let path = test_path("store_runs_typed_and_raw_commands");
+        let store = Store::open(path.clone()).unwrap();
+        assert_eq!(store.set("name", "makuo").await, CacheResult::Success(String::from("1")));
+        assert!(matches!(store.set("name", "again").await, CacheResult::Failure(_)));
+        assert_eq!(store.hset("person", &[("name", "makuo"), ("age", "25")]).await, CacheResult::Success(String::from("1")));
+        assert_eq!(store.sadd("humans", &["anita", "james"]).await, CacheResult::Success(String::from("1")));
+        assert_eq!(store.get("name").await, CacheResult::Success(String::from("makuo")));
+        assert_eq!(store.execute(&["hget", "person"]).await, CacheResult::Array(["name", "makuo", "age", "25"].map(String::from).to_vec()));
+        assert_eq!(store.sremove("humans", "anita").await, CacheResult::Success(String::from("1")));
+        assert!(matches!(store.execute(&["shutdown"]).await, CacheResult::Failure(_)));
+        assert!(matches!(store.execute::<&str>(&[]).await, CacheResult::Failure(_)));
+        store.close(ShutdownMode::Save).await.unwrap();
+        let store = Store::open(path).unwrap();
+        assert_eq!(store.smembers("humans").await, CacheResult::Array(vec![String::from("james")]));
+        assert_eq!(store.get("name").await, CacheResult::Success(String::from("makuo")));
+        store.close(ShutdownMode::Save).await.unwrap();
+    }
+    #[tokio::test]
+    async fn values_with_delimiters_are_stored_escaped() {
+        let path = test_path("values_with_delimiters_are_stored_escaped");
+        let store = Store::open(path.clone()).unwrap();
+        let value = "it's \"quoted\"\nback\\slash\ttab";
+        assert_eq!(store.set("odd key's", value).await, CacheResult::Success(String::from("1")));
+        store.hset("person", &[("full \"name\"", "makuo 'm'")]).await;
+        store.sadd("humans", &["a\"b", "c'd"]).await;
+        assert_eq!(store.sremove("humans", "a\"b").await, CacheResult::Success(String::from("1")));
+        store.close(ShutdownMode::Save).await.unwrap();
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(data.starts_with("set\todd key\\ss'it\\ss \\dquoted\\d\\nback"));
+        let store = Store::open(path).unwrap();
+        assert_eq!(store.get("odd key's").await, CacheResult::Success(String::from(value)));
+        assert_eq!(store.hget("person").await, CacheResult::Array(["full \"name\"", "makuo 'm'"].map(String::from).to_vec()));
+        assert_eq!(store.smembers("humans").await, CacheResult::Array(vec![String::from("c'd")]));
+        assert_eq!(store.del("odd key's").await, CacheResult::Success(String::from("1")));
+        store.close(ShutdownMode::Save).await.unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! Logs of the server and the sentinel, written with `tracing` to stderr or to a file rotated by time.
+
+use std::{io::IsTerminal, path::{Path, PathBuf}};
+
+use tracing_appender::{non_blocking::WorkerGuard, rolling::{RollingFileAppender, Rotation}};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
+
+use crate::models::MainError;
+
+/// How each event is written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// One line per event, the spans before the message
+    Text,
+    /// Several indented lines per event, for reading by hand
+    Pretty,
+    /// One JSON object per line, with the fields of the spans
+    Json
+}
+
+/// When a log file is closed for a new one, named after the date.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily
+}
+
+/// Log settings shared by the server and the sentinel options.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    /// Directives of a tracing env filter, `info` or `warn,mini_mcache::replication=debug`
+    pub level: String,
+    pub format: LogFormat,
+    /// Stderr when none
+    pub file: Option<PathBuf>,
+    pub rotation: LogRotation
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig { level: String::from("info"), format: LogFormat::Text, file: None, rotation: LogRotation::Never }
+    }
+}
+
+impl LogConfig {
+    /// Reads the value of a log option into the config. False when arg is not one.
+    pub fn option(&mut self, arg: &str, value: impl FnOnce() -> Result<String, MainError>) -> Result<bool, MainError> {
+        match arg {
+            "--loglevel" => {
+                let level = value()?;
+                EnvFilter::try_new(&level).map_err(|e| MainError::BadCommandFormat(format!("Bad --loglevel {}: {}", level, e)))?;
+                self.level = level;
+            },
+            "--log-format" => self.format = match value()?.to_lowercase().as_str() {
+                "text" => LogFormat::Text,
+                "pretty" => LogFormat::Pretty,
+                "json" => LogFormat::Json,
+                other => return Err(MainError::BadCommandFormat(format!("--log-format takes text, pretty or json, not {}", other)))
+            },
+            "--logfile" => self.file = Some(PathBuf::from(value()?)),
+            "--log-rotation" => self.rotation = match value()?.to_lowercase().as_str() {
+                "never" => LogRotation::Never,
+                "hourly" => LogRotation::Hourly,
+                "daily" => LogRotation::Daily,
+                other => return Err(MainError::BadCommandFormat(format!("--log-rotation takes never, hourly or daily, not {}", other)))
+            },
+            _ => return Ok(false)
+        }
+        Ok(true)
+    }
+}
+
+/// Installs the global subscriber. Events written to a file go through a background thread,
+/// the returned guard flushes them when dropped so it has to live until the process exits.
+pub fn init(config: &LogConfig) -> Result<Option<WorkerGuard>, MainError> {
+    let filter = EnvFilter::try_new(&config.level).map_err(|e| MainError::BadCommandFormat(e.to_string()))?;
+    let (writer, guard) = match &config.file {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(appender(path, config.rotation)?);
+            (BoxMakeWriter::new(writer), Some(guard))
+        },
+        None => (BoxMakeWriter::new(std::io::stderr), None)
+    };
+    // Colors only for a terminal
+    let ansi = config.file.is_none() && std::io::stderr().is_terminal();
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer).with_ansi(ansi);
+    let installed = match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Pretty => builder.pretty().try_init(),
+        LogFormat::Json => builder.json().try_init()
+    };
+    installed.map_err(|e| MainError::BadCommandFormat(format!("Logging failed to start: {}", e)))?;
+    Ok(guard)
+}
+
+/// Appends to path, or to path with the date as extension once rotated.
+fn appender(path: &Path, rotation: LogRotation) -> Result<RollingFileAppender, MainError> {
+    let name = path.file_name()
+        .ok_or_else(|| MainError::BadCommandFormat(format!("--logfile {} is not a file", path.display())))?;
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let rotation = match rotation {
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY
+    };
+    RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(name.to_string_lossy())
+        .build(dir)
+        .map_err(|e| MainError::FileWriteError(format!("Log file {} failed: {}", path.display(), e)))
+}
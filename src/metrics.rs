@@ -0,0 +1,135 @@
+//! Prometheus metrics, served over HTTP at `/metrics` when the server runs with `--metrics-port`.
+
+use std::{fmt::Display, time::Duration};
+
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, time};
+
+use crate::{stats::{self, Histogram, BUCKETS}, Store};
+
+/// Prefix of every metric name.
+const PREFIX: &str = "mini_cache";
+/// The longest a scrape may take, from reading the request to writing the reply.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Requests with a longer head are refused.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// Metrics in the Prometheus text format.
+#[derive(Default)]
+struct Exposition {
+    text: String
+}
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        self.text.push_str(&format!("# HELP {}_{} {}\n# TYPE {}_{} {}\n", PREFIX, name, help, PREFIX, name, kind));
+    }
+    fn sample(&mut self, name: &str, labels: &str, value: impl Display) {
+        match labels {
+            "" => self.text.push_str(&format!("{}_{} {}\n", PREFIX, name, value)),
+            labels => self.text.push_str(&format!("{}_{}{{{}}} {}\n", PREFIX, name, labels, value))
+        }
+    }
+    /// A metric with a single sample.
+    fn single(&mut self, name: &str, kind: &str, help: &str, value: impl Display) {
+        self.header(name, kind, help);
+        self.sample(name, "", value);
+    }
+    /// The buckets, sum and count of histogram, labels being empty or ending with a comma.
+    fn histogram(&mut self, name: &str, labels: &str, histogram: &Histogram) {
+        let counts = histogram.cumulative();
+        for (bound, count) in BUCKETS.iter().zip(&counts) {
+            self.sample(&format!("{}_bucket", name), &format!("{}le=\"{}\"", labels, bound), count);
+        }
+        self.sample(&format!("{}_bucket", name), &format!("{}le=\"+Inf\"", labels), counts.last().copied().unwrap_or(0));
+        let labels = labels.trim_end_matches(',');
+        self.sample(&format!("{}_sum", name), labels, histogram.sum());
+        self.sample(&format!("{}_count", name), labels, histogram.count());
+    }
+}
+
+/// Every metric of the store, in the Prometheus text format.
+pub async fn render(store: &Store) -> String {
+    let stats = store.stats();
+    let persistence = store.persistence();
+    let mut metrics = Exposition::default();
+    metrics.single("uptime_seconds", "gauge", "Seconds since the server started.", stats.uptime().as_secs());
+    metrics.single("connected_clients", "gauge", "Client connections currently open.", stats.connected_clients());
+    metrics.single("connections_received_total", "counter", "Client connections accepted.", stats.connections_received());
+    metrics.single("commands_processed_total", "counter", "Commands received, whether they ran or were refused.", stats.commands_processed());
+
+    metrics.header("commands_total", "counter", "Calls of each command.");
+    for (name, histogram) in stats.command_latency() {
+        metrics.sample("commands_total", &format!("command=\"{}\"", name), histogram.count());
+    }
+    metrics.header("command_duration_seconds", "histogram", "Time taken by each command, queueing for locks included.");
+    for (name, histogram) in stats.command_latency() {
+        metrics.histogram("command_duration_seconds", &format!("command=\"{}\",", name), histogram);
+    }
+
+    let [strings, hashes, sets, expires] = stats::key_counts(store.memory()).await;
+    metrics.header("keys", "gauge", "Keys by type.");
+    metrics.sample("keys", "type=\"string\"", strings);
+    metrics.sample("keys", "type=\"hash\"", hashes);
+    metrics.sample("keys", "type=\"set\"", sets);
+    metrics.single("keys_expiring", "gauge", "Keys with an expiry time.", expires);
+    let (hits, misses) = (stats.keyspace_hits(), stats.keyspace_misses());
+    metrics.single("keyspace_hits_total", "counter", "Reads that found their key.", hits);
+    metrics.single("keyspace_misses_total", "counter", "Reads of a key that does not exist.", misses);
+    let ratio = if hits + misses == 0 { 0.0 } else { hits as f64 / (hits + misses) as f64 };
+    metrics.single("keyspace_hit_ratio", "gauge", "Hits out of every read since the start.", ratio);
+    metrics.single("expired_keys_total", "counter", "Keys deleted once past their expiry time.", stats.expired_keys());
+    metrics.single("evicted_keys_total", "counter", "Keys evicted for memory, always 0 as the server has no memory limit.", 0);
+
+    let usage = stats::usage(store.memory()).await;
+    metrics.header("memory_used_bytes", "gauge", "Estimated bytes held by the shards, by area.");
+    metrics.sample("memory_used_bytes", "area=\"buffer\"", usage.buffer_allocated);
+    metrics.sample("memory_used_bytes", "area=\"recent\"", usage.recent);
+    metrics.sample("memory_used_bytes", "area=\"maps\"", usage.maps);
+    metrics.single("memory_used_bytes_total", "gauge", "Estimated bytes held by the shards.", usage.total());
+
+    metrics.single("persistence_queue_depth", "gauge", "Writes waiting for the backup writer.", persistence.queued());
+    metrics.single("persistence_failed_writes_total", "counter", "Backup writes that failed, retries included.", persistence.failed_writes());
+    metrics.single("persistence_writes_refused", "gauge", "1 while writes are refused after backup failures.", persistence.refuses_writes() as u8);
+    metrics.header("fsync_duration_seconds", "histogram", "Time taken to flush the backup file to the disk.");
+    metrics.histogram("fsync_duration_seconds", "", &stats.fsync);
+    metrics.text
+}
+
+/// Answers scrapes one at a time, never returns.
+pub async fn serve(listener: &TcpListener, store: &Store) {
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        // A scraper that stalls is dropped rather than holding up the next one
+        let _ = time::timeout(SCRAPE_TIMEOUT, scrape(&mut stream, store)).await;
+    }
+}
+
+/// Reads one request and replies to it, then the connection is closed.
+async fn scrape(stream: &mut TcpStream, store: &Store) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut chunk = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 || head.len() + read > MAX_REQUEST {
+            return Ok(());
+        }
+        head.extend_from_slice(&chunk[..read]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let reply = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(path)) if path.split('?').next() == Some("/metrics") => {
+            response("200 OK", "text/plain; version=0.0.4; charset=utf-8", &render(store).await)
+        },
+        (Some("GET"), Some(_)) => response("404 Not Found", "text/plain", "Metrics are served at /metrics\n"),
+        _ => response("405 Method Not Allowed", "text/plain", "Only GET is supported\n")
+    };
+    stream.write_all(reply.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, content_type, body.len(), body)
+}
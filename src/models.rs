@@ -0,0 +1,996 @@
+use std::{collections::HashMap, hash::{DefaultHasher, Hash, Hasher}, io::{BufRead, BufReader, SeekFrom, Write}, path::PathBuf, sync::Arc, time::{Instant, SystemTime, UNIX_EPOCH}};
+use std::fs::{File, OpenOptions};
+
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::{fs::OpenOptions as OpenOptionsTokio, io::{AsyncBufReadExt, AsyncSeekExt, AsyncWriteExt, BufReader as TokioBufReader}, sync::{mpsc::{Permit, Sender}, RwLock}};
+
+use std::fmt::{self, Display, Debug};
+
+use crate::{notify::{Class, Notifier}, replication::{self, Replication}, slowlog::SlowLog, stats::Stats, transaction::Watches, Cache, CHANGE_CMD, DEL_CMD};
+
+use super::CacheResult;
+
+// command\tkey\'value\"value\"value\n
+// expire\tkey\'unix_ms\"\n for keys stored with setex
+// Keys and values are stored escaped (see escape_stored), so they never hold the delimiters above.
+
+/// Command part of the lines that hold the expiry time of a key.
+pub const EXPIRE: &str = "expire";
+
+pub enum MainError {
+    FileReadError(String),
+    FileWriteError(String),
+    BadCommandFormat(String),
+    FindCacheTypeError(String)
+}
+
+impl Display for MainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileReadError(data) => write!(f, "{}", data),
+            Self::FileWriteError(data) => write!(f, "{}", data),
+            Self::BadCommandFormat(data) => write!(f, "{}", data),
+            Self::FindCacheTypeError(data) => write!(f, "{}", data)
+        }
+    }
+}
+
+impl MainError {
+    pub fn show_err(&self) -> &[u8] {
+        match self {
+            Self::FileReadError(data) => data.as_bytes(),
+            Self::FileWriteError(data) => data.as_bytes(),
+            Self::BadCommandFormat(data) => data.as_bytes(),
+            Self::FindCacheTypeError(data) => data.as_bytes()
+        }
+    }
+    pub fn show_err_str(&self) -> &String {
+        match self {
+            Self::FileReadError(data) => data,
+            Self::FileWriteError(data) => data,
+            Self::BadCommandFormat(data) => data,
+            Self::FindCacheTypeError(data) => data
+        }
+    }
+}
+
+impl Debug for MainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileReadError(arg0) => f.debug_tuple("FileReadError").field(arg0).finish(),
+            Self::FileWriteError(arg0) => f.debug_tuple("FileWriteError").field(arg0).finish(),
+            Self::BadCommandFormat(arg0) => f.debug_tuple("BadCommandFormat").field(arg0).finish(),
+            Self::FindCacheTypeError(arg0) => f.debug_tuple("FindCacheTypeError").field(arg0).finish()
+        }
+    }
+}
+
+impl std::error::Error for MainError {
+}
+
+/// Machine readable kind of a failure reply, sent first on the wire: -CODE message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Anything without a more precise code, e.g. an unknown command or a key that already exists.
+    Err,
+    /// The key holds another type than the command works on.
+    WrongType,
+    /// The command needs a key that does not exist.
+    NoKey,
+    /// Wrong number or format of arguments.
+    Syntax,
+    /// The memory limit is reached.
+    Oom,
+    /// The connection has to authenticate first.
+    NoAuth,
+    /// The user is not allowed to run the command or to touch the key.
+    NoPerm,
+    /// AUTH was given an unknown user, a wrong password or a disabled user.
+    WrongPass,
+    /// Writes are not accepted right now.
+    ReadOnly,
+    /// EXEC did not run the transaction, a command was refused while it was queued.
+    ExecAbort,
+    /// EVALSHA was given the SHA-1 of a script that is not loaded.
+    NoScript,
+    /// The slot of the key is served by another node, the message is the slot and its address.
+    Moved,
+    /// The slot of the key is migrating and the key is not here, ask the node in the message once.
+    Ask,
+    /// The keys of the command are in different slots.
+    CrossSlot,
+    /// No node serves the slot of the key.
+    ClusterDown
+}
+
+impl ErrorCode {
+    pub fn new(code: &str) -> Option<ErrorCode> {
+        match code {
+            "ERR" => Some(Self::Err),
+            "WRONGTYPE" => Some(Self::WrongType),
+            "NOKEY" => Some(Self::NoKey),
+            "SYNTAX" => Some(Self::Syntax),
+            "OOM" => Some(Self::Oom),
+            "NOAUTH" => Some(Self::NoAuth),
+            "NOPERM" => Some(Self::NoPerm),
+            "WRONGPASS" => Some(Self::WrongPass),
+            "READONLY" => Some(Self::ReadOnly),
+            "EXECABORT" => Some(Self::ExecAbort),
+            "NOSCRIPT" => Some(Self::NoScript),
+            "MOVED" => Some(Self::Moved),
+            "ASK" => Some(Self::Ask),
+            "CROSSSLOT" => Some(Self::CrossSlot),
+            "CLUSTERDOWN" => Some(Self::ClusterDown),
+            _ => None
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Err => "ERR",
+            Self::WrongType => "WRONGTYPE",
+            Self::NoKey => "NOKEY",
+            Self::Syntax => "SYNTAX",
+            Self::Oom => "OOM",
+            Self::NoAuth => "NOAUTH",
+            Self::NoPerm => "NOPERM",
+            Self::WrongPass => "WRONGPASS",
+            Self::ReadOnly => "READONLY",
+            Self::ExecAbort => "EXECABORT",
+            Self::NoScript => "NOSCRIPT",
+            Self::Moved => "MOVED",
+            Self::Ask => "ASK",
+            Self::CrossSlot => "CROSSSLOT",
+            Self::ClusterDown => "CLUSTERDOWN"
+        }
+    }
+}
+
+/// What a failed command replies with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheError {
+    pub code: ErrorCode,
+    pub message: String
+}
+
+impl CacheError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> CacheError {
+        CacheError { code, message: message.into() }
+    }
+}
+
+impl Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code.name(), self.message)
+    }
+}
+
+impl std::error::Error for CacheError {
+}
+
+impl From<MainError> for CacheError {
+    fn from(e: MainError) -> Self {
+        let code = match e {
+            MainError::BadCommandFormat(_) => ErrorCode::Syntax,
+            _ => ErrorCode::Err
+        };
+        CacheError::new(code, e.to_string())
+    }
+}
+
+const WRONG_TYPE: &str = "Operation against a key holding the wrong kind of value";
+#[derive(Debug)]
+pub struct Position {
+    start: usize,
+    end: usize
+}
+
+/// Number of independently locked parts the keyspace is split into.
+pub const SHARDS: usize = 64;
+
+/// The keyspace, split by key hash so commands on different keys do not wait on each other.
+#[derive(Debug)]
+pub struct Memory {
+    pub path: PathBuf,
+    pub shards: Vec<RwLock<Shard>>,
+    /// Publishes keyspace notifications, none unless enabled.
+    pub notifier: Notifier,
+    /// Keys watched by connections before a transaction.
+    pub watches: Arc<Watches>,
+    /// Commands hold it shared and EXEC exclusively, so nothing runs in the middle of a transaction.
+    pub exclusive: RwLock<()>,
+    /// Stream of changes sent to replicas, and the primary followed when this is a replica.
+    pub replication: Arc<Replication>,
+    /// Counters reported by info.
+    pub stats: Arc<Stats>,
+    /// Commands that ran longer than the threshold.
+    pub slowlog: Arc<SlowLog>
+}
+
+#[derive(Debug, Default)]
+pub struct Shard {
+    pub buffer: BytesMut,
+    pub item: HashMap<String, Position>,
+    pub recent: HashMap<String, Bytes>,
+    /// Expiry time of keys, in unix milliseconds.
+    pub expires: HashMap<String, u64>
+}
+#[derive(Debug)]
+pub struct Delete {
+    pub cmd: Cache,
+    pub key_value: String,
+    pub key: String
+}
+
+
+impl Delete {
+    pub fn update_key_value(&mut self, key_value: String) {
+        self.key_value = key_value;
+    }
+}
+
+
+/// Work for the writer task. Recent carries the value so the file can be written without any lock.
+/// Batch holds the writes of a transaction, which reach the file together. Clear empties the
+/// file before a replica loads the data of its primary.
+pub enum Pipe {
+    Recent(String, Bytes), Delete(Delete), Expire(String, u64), Batch(Vec<Pipe>), Clear
+}
+
+impl Pipe {
+    /// The writes held, with batches opened up.
+    fn flatten(&self) -> Vec<&Pipe> {
+        match self {
+            Pipe::Batch(pipes) => pipes.iter().flat_map(Pipe::flatten).collect(),
+            pipe => vec![pipe]
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DeleteType {
+    Item(String), Recent(String), None
+}
+impl Memory {
+    pub fn new(path: PathBuf) -> Result<Memory, MainError> {
+        let mut shards: Vec<Shard> = (0..SHARDS).map(|_| Shard::default()).collect();
+        if path.exists() {
+            let file = match File::open(&path) {
+                Ok(f) => f,
+                Err(e) => {
+                    // panic!("Error at exists {}", e);
+                    return Err(MainError::FileReadError(e.to_string()))
+                }
+            };
+            let reader = BufReader::new(&file);
+            for line in reader.lines() {
+                let line = match line {
+                    Ok(l) => l,
+                    Err(e) => {
+                        // panic!("Cannot read line {}", e);
+                        return Err(MainError::FileReadError(e.to_string()))
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let mut line_data = line.split("\'");
+                let command_key = match line_data.next() {
+                    Some(l) => l.to_owned(),
+                    None => {
+                        // panic!("Could not split line");
+                        return Err(MainError::FileReadError(String::from("Could not split line")))
+                    }
+                };
+                let shard = &mut shards[Memory::shard_index(Memory::key_of(&command_key))];
+                if let Some(key) = command_key.strip_prefix(EXPIRE).and_then(|k| k.strip_prefix('\t')) {
+                    if let Some(time) = values(&line).first().and_then(|t| t.parse().ok()) {
+                        shard.expires.insert(key.to_string(), time);
+                    }
+                    continue;
+                }
+                let start = shard.buffer.len();
+                shard.buffer.put(line.as_bytes());
+                shard.item.insert(command_key, Position{start, end: shard.buffer.len()});
+            }
+        } else {
+            let _ = match File::create(&path) {
+                Ok(f) => f,
+                Err(e) => {
+                    // panic!("Error at not exists {}", e);
+                    return Err(MainError::FileReadError(e.to_string()))
+                }
+            };
+        }
+        let shards = shards.into_iter().map(RwLock::new).collect();
+        Ok(Memory {path, shards, notifier: Notifier::default(), watches: Arc::default(), exclusive: RwLock::new(()), replication: Arc::default(), stats: Arc::default(), slowlog: Arc::default() })
+    }
+    /// The key part of command\tkey, which decides the shard.
+    pub fn key_of(key_value: &str) -> &str {
+        match key_value.split_once('\t') {
+            Some((_, key)) => key,
+            None => key_value
+        }
+    }
+    fn shard_index(key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % SHARDS as u64) as usize
+    }
+    pub fn shard(&self, key: &str) -> &RwLock<Shard> {
+        &self.shards[Memory::shard_index(key)]
+    }
+    pub async fn get(&self, key_value: String) -> CacheResult {
+        let shard = self.shard(Memory::key_of(&key_value)).read().await;
+        let result = shard.get(key_value).await;
+        match result {
+            CacheResult::Nil => self.stats.miss(),
+            CacheResult::Success(_) | CacheResult::Array(_) => self.stats.hit(),
+            CacheResult::Failure(_) => {}
+        }
+        result
+    }
+    // The channel slot is reserved before the shard lock: the writer takes shard locks too, so waiting
+    // on a full channel while holding one would deadlock. Sending under the lock keeps the file in order.
+    pub async fn del(&self, delete: Delete, tx: Sender<Pipe>) -> CacheResult {
+        let permit = match tx.reserve().await {
+            Ok(p) => p,
+            Err(_) => return CacheResult::error(ErrorCode::Err, "Backup writer is not running")
+        };
+        let key = Memory::key_of(&delete.key_value).to_string();
+        let (class, event) = match delete.cmd {
+            Cache::HDel => (Class::Hash, "hdel"),
+            Cache::SRemove => (Class::Set, "srem"),
+            _ => (Class::Generic, "del")
+        };
+        let record = self.replication.feeding().then(|| match delete.cmd {
+            Cache::HDel => vec![DEL_CMD[1].to_string(), unescape_stored(&key), unescape_stored(&delete.key)],
+            Cache::SRemove => vec![DEL_CMD[2].to_string(), unescape_stored(&key), unescape_stored(&delete.key)],
+            _ => vec![DEL_CMD[0].to_string(), unescape_stored(&key)]
+        });
+        let mut shard = self.shard(&key).write().await;
+        let result = shard.del(delete, permit);
+        if result == CacheResult::Success(String::from("1")) {
+            self.changed(class, event, &key);
+            if let Some(record) = record {
+                self.replication.feed(&record);
+            }
+        }
+        result
+    }
+    pub async fn set(&self, key: String, value: String, action: String, tx: Sender<Pipe>) -> CacheResult {
+        // An expired key the sweeper did not reach yet must not block the new value
+        self.expire(&key, &tx).await;
+        let permit = match tx.reserve().await {
+            Ok(p) => p,
+            Err(_) => return CacheResult::error(ErrorCode::Err, "Backup writer is not running")
+        };
+        let class = match action.as_str() {
+            "hset" => Class::Hash,
+            "sadd" => Class::Set,
+            _ => Class::String
+        };
+        let record = self.replication.feeding().then(|| stored_args(&value));
+        let mut shard = self.shard(&key).write().await;
+        let result = shard.set(key.clone(), value, action.clone(), permit);
+        if matches!(result, CacheResult::Success(_)) {
+            self.changed(class, &action, &key);
+            if let Some(record) = record {
+                self.replication.feed(&record);
+            }
+        }
+        result
+    }
+    /// Stores a string that expires at the given unix time in milliseconds.
+    pub async fn set_ex(&self, key: String, value: String, expires_at: u64, tx: Sender<Pipe>) -> CacheResult {
+        self.expire(&key, &tx).await;
+        // The value and its expiry are queued together so the file never holds one without the other
+        let mut permits = match tx.reserve_many(2).await {
+            Ok(p) => p,
+            Err(_) => return CacheResult::error(ErrorCode::Err, "Backup writer is not running")
+        };
+        let (Some(set), Some(expire)) = (permits.next(), permits.next()) else {
+            return CacheResult::error(ErrorCode::Err, "Backup writer is not running")
+        };
+        // Replicas get the expiry time itself, so they expire the key at the same time
+        let record = self.replication.feeding().then(|| {
+            let mut args = stored_args(&value);
+            args[0] = String::from(replication::SETEXAT);
+            args.insert(2, expires_at.to_string());
+            args
+        });
+        let mut shard = self.shard(&key).write().await;
+        let result = shard.set(key.clone(), value, String::from(CHANGE_CMD[0]), set);
+        if matches!(result, CacheResult::Success(_)) {
+            shard.expires.insert(key.clone(), expires_at);
+            self.changed(Class::String, CHANGE_CMD[0], &key);
+            self.changed(Class::Generic, "expire", &key);
+            if let Some(record) = record {
+                self.replication.feed(&record);
+            }
+            expire.send(Pipe::Expire(key, expires_at));
+        }
+        result
+    }
+    /// Seconds before the key expires, -1 if it never does and -2 if it does not exist.
+    pub async fn ttl(&self, key: &str) -> i64 {
+        let shard = self.shard(key).read().await;
+        if !shard.exists(key) || shard.expired(key) {
+            return -2;
+        }
+        match shard.expires.get(key) {
+            Some(time) => time.saturating_sub(now_millis()).div_ceil(1000) as i64,
+            None => -1
+        }
+    }
+    /// Keys of every shard whose expiry time has passed.
+    pub async fn expired_keys(&self) -> Vec<String> {
+        let now = now_millis();
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.read().await;
+            keys.extend(shard.expires.iter().filter(|(_, time)| **time <= now).map(|(key, _)| key.clone()));
+        }
+        keys
+    }
+    /// Deletes the key if its expiry time has passed, returns true when it did.
+    pub async fn expire(&self, key: &str, tx: &Sender<Pipe>) -> bool {
+        if !self.shard(key).read().await.expired(key) {
+            return false;
+        }
+        let permit = match tx.reserve().await {
+            Ok(p) => p,
+            Err(_) => return false
+        };
+        let mut shard = self.shard(key).write().await;
+        // Checked again, the key could have been deleted while waiting for the lock
+        if !shard.expired(key) {
+            return false;
+        }
+        shard.expires.remove(key);
+        let delete = Delete { cmd: Cache::Del, key_value: format!("{}\t{}", CHANGE_CMD[0], key), key: key.to_string() };
+        if shard.exists(key) {
+            shard.handle_del(delete, permit);
+        } else {
+            // Only the expiry line is left in the file
+            permit.send(Pipe::Delete(delete));
+        }
+        self.changed(Class::Expired, "expired", key);
+        self.stats.expired();
+        // Replicas delete it when told, so they never drop a key the primary still has
+        if self.replication.feeding() {
+            self.replication.feed(&[String::from(DEL_CMD[0]), unescape_stored(key)]);
+        }
+        true
+    }
+    /// Marks the connections watching key and publishes the keyspace notification of event.
+    fn changed(&self, class: Class, event: &str, key: &str) {
+        self.watches.touch(key);
+        self.notifier.notify(class, event, key);
+    }
+    /// Every key as the records that rebuild it on a replica, skipping keys past their expiry time.
+    pub async fn snapshot(&self) -> Vec<Vec<String>> {
+        let mut records = Vec::new();
+        for shard in &self.shards {
+            records.extend(shard.read().await.records(|_| true));
+        }
+        records
+    }
+    /// The records of a single stored key, as in snapshot, empty if it does not exist.
+    pub async fn dump(&self, key: &str) -> Vec<Vec<String>> {
+        self.shard(key).read().await.records(|k| k == key)
+    }
+    /// True if the stored key exists with any command and is not past its expiry time.
+    pub async fn exists(&self, key: &str) -> bool {
+        let shard = self.shard(key).read().await;
+        shard.exists(key) && !shard.expired(key)
+    }
+    /// Every key not past its expiry time, unescaped.
+    pub async fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.read().await;
+            let stored = shard.item.keys().chain(shard.recent.keys()).map(|command_key| Memory::key_of(command_key));
+            keys.extend(stored.filter(|key| !shard.expired(key)).map(unescape_stored));
+        }
+        keys.sort();
+        keys
+    }
+    /// Forgets every key and empties the backup file, before a replica loads its primary's data.
+    pub async fn clear(&self, tx: &Sender<Pipe>) -> Result<(), std::io::Error> {
+        for shard in &self.shards {
+            let mut shard = shard.write().await;
+            let keys: Vec<String> = shard.item.keys().chain(shard.recent.keys()).map(|key| Memory::key_of(key).to_string()).collect();
+            *shard = Shard::default();
+            for key in keys {
+                self.watches.touch(&key);
+            }
+        }
+        // Queued after the writes already waiting, so none of them lands in the emptied file
+        tx.send(Pipe::Clear).await.map_err(|_| std::io::Error::other("Backup writer is not running"))
+    }
+    /// Empties the backup file.
+    pub async fn clear_file(&self) -> Result<(), std::io::Error> {
+        let file = OpenOptionsTokio::new().write(true).truncate(true).open(&self.path).await?;
+        self.sync_all(&file).await
+    }
+    /// Number of values that are in memory but not yet in the backup file.
+    pub async fn pending(&self) -> usize {
+        let mut count = 0;
+        for shard in &self.shards {
+            count += shard.read().await.recent.len();
+        }
+        count
+    }
+    /// Forgets every value that is not yet in the backup file (shutdown nosave).
+    pub async fn clear_pending(&self) {
+        for shard in &self.shards {
+            shard.write().await.recent.clear();
+        }
+    }
+
+    pub async fn recent_to_file_schedular(&self, key: String, value: &Bytes) -> Result<(), std::io::Error> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        // The file is written before taking the shard lock so commands never wait on the disk
+        let mut file = OpenOptionsTokio::new().append(true).open(&self.path).await?;
+        let mut data = Vec::with_capacity(value.len() + 1);
+        data.extend_from_slice(value);
+        data.push(b'\n');
+        file.write_all(&data).await?;
+        file.flush().await?;
+        let mut shard = self.shard(Memory::key_of(&key)).write().await;
+        // The value can change or go away while the file is written, only move it if it is the one on disk
+        if shard.recent.get(&key) != Some(value) {
+            return Ok(());
+        }
+        // The value now lives in buffer and on disk, so it is no longer pending
+        let start = shard.buffer.len();
+        shard.buffer.put(&value[..]);
+        let end = shard.buffer.len();
+        shard.item.insert(key.clone(), Position { start, end });
+        shard.recent.remove(&key);
+        Ok(())
+    }
+    /// Appends the expiry line of a key to the backup file.
+    pub async fn expire_to_file(&self, key: &str, expires_at: u64) -> Result<(), std::io::Error> {
+        let mut file = OpenOptionsTokio::new().append(true).open(&self.path).await?;
+        file.write_all(format!("{}\t{}'{}\"\n", EXPIRE, key, expires_at).as_bytes()).await?;
+        file.flush().await
+    }
+    /// Flushes the backup file to the disk so nothing written so far is lost on exit.
+    pub async fn sync(&self) -> Result<(), std::io::Error> {
+        let file = OpenOptionsTokio::new().append(true).open(&self.path).await?;
+        self.sync_all(&file).await
+    }
+    /// Flushes file to the disk, timing it for the fsync latency of the stats.
+    async fn sync_all(&self, file: &tokio::fs::File) -> Result<(), std::io::Error> {
+        let started = Instant::now();
+        let synced = file.sync_all().await;
+        self.stats.fsync.observe(started.elapsed());
+        synced
+    }
+    pub fn recent_to_file(&mut self) -> Result<(), std::io::Error> {
+        let mut data = Vec::new();
+        for shard in self.shards.iter_mut() {
+            for value in shard.get_mut().recent.values() {
+                if value.is_empty() {
+                    continue;
+                }
+                data.extend_from_slice(value);
+                data.push(b'\n');
+            }
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(&data)?;
+        // Only forget the values once they are safely in the file
+        for shard in self.shards.iter_mut() {
+            shard.get_mut().recent.clear();
+        }
+        Ok(())
+    }
+    /// Rewrites the backup file without the deleted data. Only the file is read, so no shard is locked.
+    pub async fn modify_file(&self, del: &Delete) -> Result<(), std::io::Error> {
+        let file = OpenOptionsTokio::new().read(true).write(true).open(&self.path).await?;
+        let reader_file = file.try_clone().await?;
+        let mut writer_file = file;
+        let mut new_file: String = String::new();
+        let reader = TokioBufReader::new(reader_file);
+        let mut lines = reader.lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if let Some(kept) = apply_delete(del, &line) {
+                new_file.push_str(&kept);
+                new_file.push('\n');
+            }
+        }
+        writer_file.set_len(0).await?;
+        writer_file.seek(SeekFrom::Start(0)).await?;
+        writer_file.write_all(new_file.as_bytes()).await?;
+        writer_file.flush().await?;
+        Ok(())
+    }
+    /// Writes the changes of a transaction to the backup file as one unit: the new file is built
+    /// aside and renamed over the old one, so it holds either all of them or none.
+    pub async fn batch_to_file(&self, batch: &Pipe) -> Result<(), std::io::Error> {
+        let content = tokio::fs::read_to_string(&self.path).await?;
+        let mut lines: Vec<String> = content.lines().filter(|line| !line.trim().is_empty()).map(String::from).collect();
+        let pipes = batch.flatten();
+        for pipe in &pipes {
+            match pipe {
+                Pipe::Delete(del) => lines = lines.iter().filter_map(|line| apply_delete(del, line)).collect(),
+                Pipe::Recent(_, value) if !value.is_empty() => lines.push(String::from_utf8_lossy(value).into_owned()),
+                Pipe::Expire(key, expires_at) => lines.push(format!("{}\t{}'{}\"", EXPIRE, key, expires_at)),
+                _ => {}
+            }
+        }
+        let mut text = lines.join("\n");
+        text.push('\n');
+        let tmp = self.path.with_extension("tmp");
+        let mut file = OpenOptionsTokio::new().write(true).create(true).truncate(true).open(&tmp).await?;
+        file.write_all(text.as_bytes()).await?;
+        self.sync_all(&file).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        // Same as after a single write, values on disk are no longer pending
+        for pipe in pipes {
+            let Pipe::Recent(key, value) = pipe else {
+                continue;
+            };
+            let mut shard = self.shard(Memory::key_of(key)).write().await;
+            if shard.recent.get(key) != Some(value) {
+                continue;
+            }
+            let start = shard.buffer.len();
+            shard.buffer.put(&value[..]);
+            let end = shard.buffer.len();
+            shard.item.insert(key.clone(), Position { start, end });
+            shard.recent.remove(key);
+        }
+        Ok(())
+    }
+}
+
+/// The stored line once del is applied to it, None if it goes away.
+fn apply_delete(del: &Delete, line: &str) -> Option<String> {
+    let command_key = line.split('\'').next().unwrap_or("");
+    if command_key == del.key_value {
+        return match del.cmd {
+            Cache::HDel | Cache::SRemove => Some(remove_items(&del.cmd, &del.key, line)),
+            _ => None
+        };
+    }
+    // The expiry of a deleted key goes with it
+    if matches!(del.cmd, Cache::Del) && command_key == format!("{}\t{}", EXPIRE, del.key) {
+        return None;
+    }
+    Some(line.to_string())
+}
+
+impl Shard {
+    pub async fn get(&self, key_value: String) -> CacheResult {
+        let key = Memory::key_of(&key_value);
+        if key.trim().is_empty() {
+            return CacheResult::error(ErrorCode::Syntax, "Key cannot be empty");
+        } else if self.expired(key) {
+            return CacheResult::Nil;
+        } else if let Some(value) = self.recent.get(&key_value) {
+            return Shard::reply(&key_value, value);
+        }
+        else if let Some(value) = self.item.get(&key_value) {
+            let items = &self.buffer[value.start..value.end];
+            return Shard::reply(&key_value, items);
+        }
+        // Stored, but not under this command
+        if self.exists(key) {
+            return CacheResult::error(ErrorCode::WrongType, WRONG_TYPE);
+        }
+        CacheResult::Nil
+    }
+    pub fn del(&mut self, delete: Delete, tx: Permit<'_, Pipe>) -> CacheResult {
+        if Memory::key_of(&delete.key_value).trim().is_empty() {
+            CacheResult::error(ErrorCode::Syntax, "Key cannot be empty")
+        } else {
+            self.handle_del(delete, tx)
+        }
+    }
+    /// Strings are a single value, hashes and sets every stored item.
+    fn reply(key_value: &str, line: &[u8]) -> CacheResult {
+        let line = String::from_utf8_lossy(line);
+        let items: Vec<String> = values(&line).into_iter().map(unescape_stored).collect();
+        if key_value.starts_with(&format!("{}\t", CHANGE_CMD[0])) {
+            CacheResult::Success(items.join(" "))
+        } else {
+            CacheResult::Array(items)
+        }
+    }
+    /// The records that rebuild the keys accepted by only, skipping keys past their expiry time.
+    fn records(&self, only: impl Fn(&str) -> bool) -> Vec<Vec<String>> {
+        let lines = self.item.iter().map(|(key, position)| (key, &self.buffer[position.start..position.end]))
+            .chain(self.recent.iter().map(|(key, value)| (key, &value[..])));
+        let mut records = Vec::new();
+        for (command_key, line) in lines {
+            let key = Memory::key_of(command_key);
+            if !only(key) || self.expired(key) || line.is_empty() {
+                continue;
+            }
+            let mut args = stored_args(&String::from_utf8_lossy(line));
+            if let Some(expires_at) = self.expires.get(key) {
+                args[0] = String::from(replication::SETEXAT);
+                args.insert(2, expires_at.to_string());
+            }
+            records.push(args);
+        }
+        records
+    }
+    /// True if the key is stored with any command.
+    pub fn exists(&self, key: &str) -> bool {
+        self.item.keys().chain(self.recent.keys()).any(|command_key| {
+            let mut split = command_key.split('\t');
+            split.next();
+            split.next() == Some(key)
+        })
+    }
+    /// True once the expiry time of the key has passed, even if the sweeper did not remove it yet.
+    pub fn expired(&self, key: &str) -> bool {
+        self.expires.get(key).is_some_and(|time| *time <= now_millis())
+    }
+    pub fn handle_del(&mut self, mut del: Delete, tx: Permit<'_, Pipe>) -> CacheResult {
+        match del.cmd {
+            Cache::Del => {
+                let mut delete_type = DeleteType::None;
+                for item in self.recent.keys() {
+                    let keys: Vec<&str> = item.split('\t').collect();
+                    if keys.len() != 2 {
+                        return CacheResult::error(ErrorCode::Err, format!("Malformed stored key {}", item));
+                    }
+                    if keys[1] == del.key {
+                        delete_type = DeleteType::Recent(item.clone());
+                    }
+                }
+                if delete_type == DeleteType::None {
+                    for item in self.item.keys() {
+                        let keys: Vec<&str> = item.split('\t').collect();
+                        if keys.len() != 2 {
+                            return CacheResult::error(ErrorCode::Err, format!("Malformed stored key {}", item));
+                        }
+                        if keys[1] == del.key {
+                            delete_type = DeleteType::Item(item.clone());
+                        }
+                    }
+                }
+                match delete_type {
+                    DeleteType::Item(value) => {
+                        let result = self.item.remove(&value);
+                        if result.is_none() {
+                            return CacheResult::Nil;
+                        }
+                        self.expires.remove(&del.key);
+                        del.update_key_value(value);
+                        tx.send(Pipe::Delete(del));
+                        CacheResult::Success("1".to_string())
+                    },
+                    DeleteType::Recent(value) => {
+                        let result = self.recent.remove(&value);
+                        if result.is_none() {
+                            return CacheResult::Nil;
+                        }
+                        self.expires.remove(&del.key);
+                        del.update_key_value(value);
+                        tx.send(Pipe::Delete(del));
+                        CacheResult::Success("1".to_string())
+                    }, 
+                    DeleteType::None => CacheResult::Nil
+                }
+            },
+            Cache::HDel | Cache::SRemove => {
+                if let Some(result ) = self.recent.get(&del.key_value) {
+                    let text = remove_items(&del.cmd, &del.key, &String::from_utf8_lossy(result));
+                    if text.len() == result.len() {
+                        return CacheResult::Success("0".to_string());
+                    }
+                    self.recent.insert(del.key_value.to_string(), Bytes::from(text));
+                    tx.send(Pipe::Delete(del));
+                    CacheResult::Success("1".to_string())
+                } else if let Some(result) = self.item.get(&del.key_value) {
+                    let items = &self.buffer[result.start..result.end];
+                    let text = remove_items(&del.cmd, &del.key, &String::from_utf8_lossy(items));
+                    if text.len() == items.len() {
+                        return CacheResult::Success("0".to_string());
+                    }
+                    // The new line is never longer, so it is written over the old one
+                    let start = result.start;
+                    self.buffer[start..start+text.len()].copy_from_slice(text.as_bytes());
+                    let position = Position {start, end: start+text.len()};
+                    self.item.insert(del.key_value.clone(), position);
+                    tx.send(Pipe::Delete(del));
+                    CacheResult::Success("1".to_string())
+                } else if self.exists(Memory::key_of(&del.key_value)) {
+                    CacheResult::error(ErrorCode::WrongType, WRONG_TYPE)
+                } else {
+                    CacheResult::Nil
+                }
+            }, 
+            _ => CacheResult::error(ErrorCode::Err, "Not a delete command")
+        }
+    }
+    pub fn set(&mut self, key: String, value: String, mut action: String, tx: Permit<'_, Pipe>) -> CacheResult {
+        action += "\t";
+        action = action+&key;
+        // First check if the key exist
+        if self.item.contains_key(&action) || self.recent.contains_key(&action) {
+            return CacheResult::error(ErrorCode::Err, "Key already exist. Try another kind")
+        } else if self.exists(&key) {
+            return CacheResult::error(ErrorCode::WrongType, WRONG_TYPE)
+        }
+        let value = Bytes::from(value);
+        self.recent.insert(action.clone(), value.clone());
+        tx.send(Pipe::Recent(action, value));
+        CacheResult::Success(String::from("1"))
+    }
+}
+
+/// The values of a stored line, the part after ' split on ".
+pub fn values(line: &str) -> Vec<&str> {
+    match line.split_once('\'') {
+        Some((_, values)) => {
+            let mut items: Vec<&str> = values.split('"').collect();
+            // Every value ends with ", so the last item is what follows the final one
+            items.pop();
+            items
+        },
+        None => Vec::new()
+    }
+}
+
+/// The arguments of the command that stores a line, e.g. `["hset", "person", "name", "makuo"]`.
+pub fn stored_args(line: &str) -> Vec<String> {
+    let command_key = line.split('\'').next().unwrap_or("");
+    let (action, key) = command_key.split_once('\t').unwrap_or((command_key, ""));
+    let mut args = vec![action.to_string(), unescape_stored(key)];
+    args.extend(values(line).into_iter().map(unescape_stored));
+    args
+}
+
+/// Matches text against a glob pattern where `*` is any run of characters, `?` any single one
+/// and `\\` takes the next character as is.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last * was and the text position it currently stands for
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            },
+            Some('?') => {
+                p += 1;
+                t += 1;
+                continue;
+            },
+            Some('\\') if pattern.get(p + 1) == Some(&text[t]) => {
+                p += 2;
+                t += 1;
+                continue;
+            },
+            Some(c) if *c != '\\' && *c == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            },
+            _ => {}
+        }
+        match star {
+            // Let the last * take one more character
+            Some((star_p, star_t)) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            },
+            None => return false
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Escapes a key or value for the backup file: backslash, tab, newline and the ' and " delimiters.
+pub fn escape_stored(field: &str) -> String {
+    let mut text = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => text.push_str("\\\\"),
+            '\t' => text.push_str("\\t"),
+            '\n' => text.push_str("\\n"),
+            '\r' => text.push_str("\\r"),
+            '\'' => text.push_str("\\s"),
+            '"' => text.push_str("\\d"),
+            _ => text.push(c)
+        }
+    }
+    text
+}
+
+pub fn unescape_stored(field: &str) -> String {
+    let mut text = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => text.push('\t'),
+            Some('n') => text.push('\n'),
+            Some('r') => text.push('\r'),
+            Some('s') => text.push('\''),
+            Some('d') => text.push('"'),
+            Some(other) => text.push(other),
+            None => text.push('\\')
+        }
+    }
+    text
+}
+
+/// Rebuilds a stored line without the hash field (and its value) or the set member named key.
+fn remove_items(cmd: &Cache, key: &str, line: &str) -> String {
+    let mut text = match line.split_once('\'') {
+        Some((command_key, _)) => format!("{}'", command_key),
+        None => return line.to_string()
+    };
+    let items = values(line);
+    let kept: Vec<&str> = match cmd {
+        Cache::HDel => items.chunks(2).filter(|pair| pair[0] != key).flatten().copied().collect(),
+        _ => items.into_iter().filter(|item| *item != key).collect()
+    };
+    for item in kept {
+        text.push_str(item);
+        text.push('"');
+    }
+    text
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        if let Err(e) = self.recent_to_file() {
+            tracing::error!(path = %self.path.display(), error = %e, "could not save pending writes");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_of_command_key() {
+        assert_eq!(Memory::key_of("hset\tperson"), "person");
+        assert_eq!(Memory::key_of("\tperson"), "person");
+        assert_eq!(Memory::key_of("person"), "person");
+    }
+
+    #[test]
+    fn glob_patterns_match_keys() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("cache:*", "cache:name"));
+        assert!(!glob_match("cache:*", "other:name"));
+        assert!(glob_match("user:?:*name", "user:1:first name"));
+        assert!(!glob_match("user:?", "user:10"));
+        assert!(glob_match("a*b*c", "aXXbYYbc"));
+        assert!(glob_match("literal\\*", "literal*"));
+        assert!(!glob_match("literal\\*", "literally"));
+    }
+}
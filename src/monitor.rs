@@ -0,0 +1,97 @@
+//! MONITOR: connections that receive every command the server runs, as a line with the time,
+//! the database and the client. Feeding them costs one atomic load while none is attached.
+
+use std::{fmt::Write, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::{SystemTime, UNIX_EPOCH}};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::slowlog;
+
+/// Lines waiting for a monitor before it is disconnected.
+pub const BACKLOG: usize = 4096;
+
+/// Every attached monitor, shared by the connections.
+#[derive(Debug)]
+pub struct Monitors {
+    attached: AtomicUsize,
+    lines: broadcast::Sender<Arc<str>>
+}
+
+impl Default for Monitors {
+    fn default() -> Self {
+        Monitors { attached: AtomicUsize::new(0), lines: broadcast::Sender::new(BACKLOG) }
+    }
+}
+
+impl Monitors {
+    /// Sends the command to the monitors, if any.
+    pub fn feed<S: AsRef<str>>(&self, args: &[S], client: &str) {
+        if self.attached.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let _ = self.lines.send(Arc::from(line(args, client)));
+    }
+    /// Starts receiving the commands, until the returned feed is dropped.
+    pub fn attach(self: &Arc<Self>) -> Feed {
+        self.attached.fetch_add(1, Ordering::Relaxed);
+        Feed { monitors: self.clone(), rx: self.lines.subscribe() }
+    }
+    pub fn attached(&self) -> usize {
+        self.attached.load(Ordering::Relaxed)
+    }
+}
+
+/// The commands sent to one monitor, see [`Monitors::attach`].
+pub struct Feed {
+    monitors: Arc<Monitors>,
+    rx: broadcast::Receiver<Arc<str>>
+}
+
+impl Feed {
+    /// The next line, or how many were lost once the monitor fell [`BACKLOG`] lines behind.
+    pub async fn recv(&mut self) -> Result<Arc<str>, u64> {
+        match self.rx.recv().await {
+            Ok(line) => Ok(line),
+            Err(RecvError::Lagged(lost)) => Err(lost),
+            // The sender lives as long as the monitors, which this holds
+            Err(RecvError::Closed) => std::future::pending().await
+        }
+    }
+}
+
+impl Drop for Feed {
+    fn drop(&mut self) {
+        self.monitors.attached.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// `1700000000.123456 [0 127.0.0.1:50312] "set" "key" "value"`, like in Redis. Arguments are
+/// quoted with their special characters escaped, passwords are left out like in the slow log.
+pub fn line<S: AsRef<str>>(args: &[S], client: &str) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut line = format!("{}.{:06} [0 {}]", now.as_secs(), now.subsec_micros(), client);
+    for arg in slowlog::redact(args) {
+        let _ = write!(line, " {:?}", arg.as_ref());
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn monitors_only_get_commands_while_attached() {
+        let monitors = Arc::new(Monitors::default());
+        monitors.feed(&["get", "before"], "127.0.0.1:1");
+        let mut feed = monitors.attach();
+        assert_eq!(monitors.attached(), 1);
+        monitors.feed(&["set", "key", "tab\there \"quoted\""], "127.0.0.1:1");
+        monitors.feed(&["auth", "admin", "secret"], "/tmp/cache.sock");
+        let line = feed.recv().await.unwrap();
+        assert!(line.ends_with(r#" [0 127.0.0.1:1] "set" "key" "tab\there \"quoted\"""#), "{}", line);
+        assert!(feed.recv().await.unwrap().ends_with(r#" [0 /tmp/cache.sock] "auth""#));
+        drop(feed);
+        assert_eq!(monitors.attached(), 0);
+    }
+}
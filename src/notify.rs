@@ -0,0 +1,150 @@
+//! Keyspace notifications: changes to keys published over pub/sub, configured like redis'
+//! `notify-keyspace-events`.
+//!
+//! A keyspace event goes to `__keyspace@0__:<key>` with the event as message, a keyevent event
+//! to `__keyevent@0__:<event>` with the key as message. Nothing is published unless `K` or `E`
+//! is set along with at least one class of events.
+
+use std::{fmt::{self, Display}, sync::Arc};
+
+use crate::{models::{self, MainError}, pubsub::PubSub};
+
+/// Kinds of events, each enabled by one character of the flags.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    /// `g`: del and expire.
+    Generic,
+    /// `$`: set.
+    String,
+    /// `h`: hset and hdel.
+    Hash,
+    /// `s`: sadd and srem.
+    Set,
+    /// `x`: a key reached its expiry time.
+    Expired,
+    /// `e`: a key was evicted. The server has no memory limit yet, so these are never sent.
+    Evicted
+}
+
+impl Class {
+    const ALL: [Class; 6] = [Class::Generic, Class::String, Class::Hash, Class::Set, Class::Expired, Class::Evicted];
+
+    fn flag(&self) -> char {
+        match self {
+            Self::Generic => 'g',
+            Self::String => '$',
+            Self::Hash => 'h',
+            Self::Set => 's',
+            Self::Expired => 'x',
+            Self::Evicted => 'e'
+        }
+    }
+    fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+const KEYSPACE: u8 = 1 << 6;
+const KEYEVENT: u8 = 1 << 7;
+const CLASSES: u8 = KEYSPACE - 1;
+
+/// The enabled notifications, parsed from flags such as `KEA` or `Kg$x`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Events(u8);
+
+impl Events {
+    /// `K` keyspace, `E` keyevent, `g$hsxe` the classes and `A` all of them. Empty turns them off.
+    pub fn new(flags: &str) -> Result<Events, MainError> {
+        let mut bits = 0;
+        for flag in flags.chars() {
+            bits |= match flag {
+                'K' => KEYSPACE,
+                'E' => KEYEVENT,
+                'A' => CLASSES,
+                _ => match Class::ALL.iter().find(|class| class.flag() == flag) {
+                    Some(class) => class.bit(),
+                    None => return Err(MainError::BadCommandFormat(format!("Unknown keyspace event flag {} in {}. Use K, E, g, $, h, s, x, e or A", flag, flags)))
+                }
+            };
+        }
+        Ok(Events(bits))
+    }
+    /// True if some event can be published: a channel kind and a class are both set.
+    pub fn enabled(&self) -> bool {
+        self.0 & (KEYSPACE | KEYEVENT) != 0 && self.0 & CLASSES != 0
+    }
+    pub fn contains(&self, class: Class) -> bool {
+        self.0 & class.bit() != 0
+    }
+}
+
+impl Display for Events {
+    /// The flags in their usual order, `A` standing for every class.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut flags = String::new();
+        if self.0 & KEYSPACE != 0 {
+            flags.push('K');
+        }
+        if self.0 & KEYEVENT != 0 {
+            flags.push('E');
+        }
+        if self.0 & CLASSES == CLASSES {
+            flags.push('A');
+        } else {
+            flags.extend(Class::ALL.iter().filter(|class| self.contains(**class)).map(Class::flag));
+        }
+        f.write_str(&flags)
+    }
+}
+
+/// Publishes the changes of keys on the pub/sub channels of the server.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    pubsub: Arc<PubSub>,
+    events: Events
+}
+
+impl Notifier {
+    pub fn new(pubsub: Arc<PubSub>, events: Events) -> Notifier {
+        Notifier { pubsub, events }
+    }
+    /// The channels notifications are published on, also used for PUBLISH and SUBSCRIBE.
+    pub fn pubsub(&self) -> &Arc<PubSub> {
+        &self.pubsub
+    }
+    pub fn events(&self) -> Events {
+        self.events
+    }
+    /// Publishes event for key, given in its stored form, if its class is enabled.
+    pub fn notify(&self, class: Class, event: &str, key: &str) {
+        if !self.events.enabled() || !self.events.contains(class) {
+            return;
+        }
+        let key = models::unescape_stored(key);
+        if self.events.0 & KEYSPACE != 0 {
+            self.pubsub.publish(&format!("__keyspace@0__:{}", key), event);
+        }
+        if self.events.0 & KEYEVENT != 0 {
+            self.pubsub.publish(&format!("__keyevent@0__:{}", event), &key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyspace_event_flags() {
+        let events = Events::new("KEA").unwrap();
+        assert!(events.enabled() && events.contains(Class::Evicted));
+        assert_eq!(events.to_string(), "KEA");
+        let events = Events::new("Kx$").unwrap();
+        assert!(events.contains(Class::Expired) && !events.contains(Class::Generic));
+        assert_eq!(events.to_string(), "K$x");
+        // Classes alone publish nothing
+        assert!(!Events::new("A").unwrap().enabled());
+        assert!(!Events::new("").unwrap().enabled());
+        assert!(Events::new("Kz").is_err());
+    }
+}
@@ -0,0 +1,265 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, OnceLock}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use tokio::{sync::{mpsc::{Receiver, Sender, WeakSender}, watch}, time};
+
+use super::{config::Config, models::{MainError, Memory, Pipe}};
+
+/// The longest the writer waits between two attempts at the same write.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// What the server does once a backup write still fails after every retry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteErrorPolicy {
+    /// Keep serving reads but refuse writes until the disk accepts data again
+    /// (like redis stop-writes-on-bgsave-error).
+    StopWrites,
+    /// Stop the server with a failure status.
+    FailFast
+}
+
+impl WriteErrorPolicy {
+    pub fn new(value: &str) -> Result<WriteErrorPolicy, MainError> {
+        match value.to_lowercase().as_str() {
+            "stop-writes" => Ok(Self::StopWrites),
+            "fail-fast" => Ok(Self::FailFast),
+            _ => Err(MainError::BadCommandFormat(format!("Unknown write error policy {}. Use stop-writes or fail-fast", value)))
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::StopWrites => "stop-writes",
+            Self::FailFast => "fail-fast"
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Status {
+    last_save: Option<SystemTime>,
+    last_error: Option<String>,
+    last_error_time: Option<SystemTime>,
+    failed_writes: u64
+}
+
+/// Health of the backup file, shared by the writer task and the connections.
+#[derive(Debug)]
+pub struct Persistence {
+    pub policy: WriteErrorPolicy,
+    pub retries: u32,
+    pub backoff: Duration,
+    refuse_writes: AtomicBool,
+    discard: AtomicBool,
+    failure: watch::Sender<Option<String>>,
+    status: Mutex<Status>,
+    /// The channel of the writer, to report how many writes wait in it
+    queue: OnceLock<WeakSender<Pipe>>
+}
+
+impl Persistence {
+    pub fn new(config: &Config) -> Persistence {
+        Persistence {
+            policy: config.write_error_policy,
+            retries: config.write_retries.max(1),
+            backoff: config.write_backoff,
+            refuse_writes: AtomicBool::new(false),
+            discard: AtomicBool::new(false),
+            failure: watch::Sender::new(None),
+            status: Mutex::new(Status::default()),
+            queue: OnceLock::new()
+        }
+    }
+    /// Reports the writes waiting in the channel of tx in info.
+    pub fn watch_queue(&self, tx: &Sender<Pipe>) {
+        let _ = self.queue.set(tx.downgrade());
+    }
+    /// Writes sent to the writer and not taken by it yet.
+    pub fn queued(&self) -> usize {
+        match self.queue.get().and_then(WeakSender::upgrade) {
+            Some(tx) => tx.max_capacity() - tx.capacity(),
+            None => 0
+        }
+    }
+    /// Delay before the given retry, doubled on every attempt.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+    /// True while the last backup write failed and the policy asks to refuse writes.
+    pub fn refuses_writes(&self) -> bool {
+        self.refuse_writes.load(Ordering::SeqCst)
+    }
+    pub fn refuse_writes(&self) {
+        if !self.refuse_writes.swap(true, Ordering::SeqCst) {
+            tracing::error!("backup writes keep failing, refusing writes until the disk recovers");
+        }
+    }
+    /// Makes the writer drop whatever is still queued (shutdown nosave).
+    pub fn discard_pending(&self) {
+        self.discard.store(true, Ordering::SeqCst);
+    }
+    /// Waits until the writer gave up under fail-fast and returns the error.
+    pub async fn failed(&self) -> String {
+        let mut failure = self.failure.subscribe();
+        let error = match failure.wait_for(|f| f.is_some()).await {
+            Ok(error) => error.clone().unwrap_or_default(),
+            Err(_) => return std::future::pending().await
+        };
+        error
+    }
+    pub fn record_success(&self) {
+        if self.refuse_writes.swap(false, Ordering::SeqCst) {
+            tracing::info!("backup writes recovered, accepting writes again");
+        }
+        let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        status.last_save = Some(SystemTime::now());
+    }
+    pub fn record_failure(&self, error: &std::io::Error, attempt: u32) {
+        tracing::warn!(attempt, retries = self.retries, %error, "backup write failed");
+        let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        status.failed_writes += 1;
+        status.last_error = Some(error.to_string());
+        status.last_error_time = Some(SystemTime::now());
+    }
+    /// Backup writes that failed, retries included.
+    pub fn failed_writes(&self) -> u64 {
+        let status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        status.failed_writes
+    }
+    pub fn last_error(&self) -> Option<String> {
+        let status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        status.last_error.clone()
+    }
+    /// The persistence section of info.
+    pub fn info(&self) -> String {
+        let status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        let mut text = String::from("# Persistence\n");
+        text.push_str(&format!("write_error_policy:{}\n", self.policy.name()));
+        text.push_str(&format!("writes_refused:{}\n", self.refuses_writes() as u8));
+        text.push_str(&format!("last_save_time:{}\n", unix_time(status.last_save)));
+        text.push_str(&format!("pending_writes:{}\n", self.queued()));
+        text.push_str(&format!("failed_writes:{}\n", status.failed_writes));
+        text.push_str(&format!("last_write_error:{}\n", status.last_error.as_deref().unwrap_or("none")));
+        text.push_str(&format!("last_write_error_time:{}\n", unix_time(status.last_error_time)));
+        text
+    }
+}
+
+/// The writer task: applies every Pipe to the backup file in order, retrying failed writes.
+pub async fn update_data_to_file(memory: Arc<Memory>, mut rx: Receiver<Pipe>, persistence: Arc<Persistence>) -> Result<(), MainError> {
+    while let Some(data) = rx.recv().await {
+        let mut attempt = 0;
+        // shutdown nosave, whatever is still queued is dropped
+        while !persistence.discard.load(Ordering::SeqCst) {
+            let result = write_to_file(&memory, &data).await;
+            let error = match result {
+                Ok(_) => {
+                    persistence.record_success();
+                    break;
+                },
+                Err(e) => e
+            };
+            attempt += 1;
+            persistence.record_failure(&error, attempt);
+            if attempt >= persistence.retries {
+                // Nobody is left to send writes, so there is no point waiting for the disk
+                if persistence.policy == WriteErrorPolicy::FailFast || rx.is_closed() {
+                    persistence.failure.send_replace(Some(error.to_string()));
+                    return Err(MainError::FileWriteError(error.to_string()));
+                }
+                persistence.refuse_writes();
+            }
+            time::sleep(persistence.backoff(attempt)).await;
+        }
+    }
+    Ok(())
+}
+
+async fn write_to_file(memory: &Memory, data: &Pipe) -> Result<(), std::io::Error> {
+    match data {
+        Pipe::Delete(value) => {
+            memory.modify_file(value).await
+        }, 
+        Pipe::Recent(key, value) => {
+            memory.recent_to_file_schedular(key.clone(), value).await
+        },
+        Pipe::Expire(key, expires_at) => {
+            memory.expire_to_file(key, *expires_at).await
+        },
+        Pipe::Batch(_) => {
+            memory.batch_to_file(data).await
+        },
+        Pipe::Clear => {
+            memory.clear_file().await
+        }
+    }
+}
+
+fn unix_time(time: Option<SystemTime>) -> u64 {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tokio::sync::mpsc;
+    use crate::{Cache, Command};
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mini-cache-{}.bin", name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn failing_config(policy: WriteErrorPolicy) -> Config {
+        Config { write_error_policy: policy, write_retries: 2, write_backoff: Duration::from_millis(1), ..Config::default() }
+    }
+
+    #[tokio::test]
+    async fn writer_fails_fast() {
+        let path = test_path("writer_fails_fast");
+        let memory = Arc::new(Memory::new(path.clone()).unwrap());
+        // The writer never creates the backup file, so every append fails
+        std::fs::remove_file(&path).unwrap();
+        let (tx, rx) = mpsc::channel(100);
+        let cmd = Command::from_args(&["set", "name", "makuo"]).unwrap();
+        let _ = Cache::new(&cmd).unwrap().handle_cmd(cmd, memory.clone(), tx.clone()).await;
+        let persistence = Arc::new(Persistence::new(&failing_config(WriteErrorPolicy::FailFast)));
+        let result = update_data_to_file(memory.clone(), rx, persistence.clone()).await;
+        assert!(matches!(result, Err(MainError::FileWriteError(_))));
+        assert!(persistence.last_error().is_some());
+        assert!(persistence.info().contains("failed_writes:2"));
+        memory.clear_pending().await;
+    }
+
+    #[tokio::test]
+    async fn writer_refuses_writes_until_disk_recovers() {
+        let path = test_path("writer_refuses_writes_until_disk_recovers");
+        let memory = Arc::new(Memory::new(path.clone()).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let (tx, rx) = mpsc::channel(100);
+        let cmd = Command::from_args(&["set", "name", "makuo"]).unwrap();
+        let _ = Cache::new(&cmd).unwrap().handle_cmd(cmd, memory.clone(), tx.clone()).await;
+        let persistence = Arc::new(Persistence::new(&failing_config(WriteErrorPolicy::StopWrites)));
+        let writer = tokio::spawn(update_data_to_file(memory.clone(), rx, persistence.clone()));
+        while !persistence.refuses_writes() {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(persistence.info().contains("writes_refused:1"));
+        std::fs::File::create(&path).unwrap();
+        while persistence.refuses_writes() {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        drop(tx);
+        assert!(writer.await.unwrap().is_ok());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "set\tname\'makuo\"\n");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_limit() {
+        let persistence = Persistence::new(&Config::default());
+        assert_eq!(persistence.backoff(1), Duration::from_millis(100));
+        assert_eq!(persistence.backoff(3), Duration::from_millis(400));
+        assert_eq!(persistence.backoff(40), Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,224 @@
+// Wire format, one frame per line:
+// request -> name\tcommand\targ\targ\t\n
+// reply   -> +value\n on success, -CODE message\n on failure, *count\titem\titem\n for arrays, _\n for nil
+// Tabs, newlines and backslashes inside a field are escaped so a frame never spans two lines.
+
+use std::io;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+use super::{models::{CacheError, ErrorCode}, CacheResult};
+
+pub const SUCCESS: u8 = b'+';
+pub const FAILURE: u8 = b'-';
+pub const ARRAY: u8 = b'*';
+pub const NIL: u8 = b'_';
+/// Longest request a server reads, its newline included.
+pub const MAX_REQUEST: usize = 16 * 1024 * 1024;
+
+pub fn escape(field: &str) -> String {
+    let mut text = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => text.push_str("\\\\"),
+            '\t' => text.push_str("\\t"),
+            '\n' => text.push_str("\\n"),
+            '\r' => text.push_str("\\r"),
+            _ => text.push(c)
+        }
+    }
+    text
+}
+
+pub fn unescape(field: &str) -> String {
+    let mut text = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => text.push('\t'),
+            Some('n') => text.push('\n'),
+            Some('r') => text.push('\r'),
+            Some(other) => text.push(other),
+            None => text.push('\\')
+        }
+    }
+    text
+}
+
+/// Splits a typed line into arguments like a shell does. Whitespace separates arguments,
+/// "double quotes" keep spaces and read \" \\ \n \t \r escapes, 'single quotes' take
+/// everything as is until the closing quote, and a backslash outside quotes keeps the next character.
+pub fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(args);
+        }
+        let mut arg = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => break,
+                '"' => loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => arg.push('\n'),
+                            Some('t') => arg.push('\t'),
+                            Some('r') => arg.push('\r'),
+                            Some(other) => arg.push(other),
+                            None => return Err(String::from("Unbalanced quotes"))
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(String::from("Unbalanced quotes"))
+                    }
+                },
+                '\'' => loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(String::from("Unbalanced quotes"))
+                    }
+                },
+                '\\' => arg.push(chars.next().unwrap_or('\\')),
+                c => arg.push(c)
+            }
+        }
+        args.push(arg);
+    }
+}
+
+/// Builds a request frame, name is what the server sees as the client.
+/// Reads up to the end of a request into line like `read_until`, and is as safe to cancel: what was
+/// read stays in line. Fails once line holds [`MAX_REQUEST`] bytes without the newline.
+pub async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut Vec<u8>) -> io::Result<usize> {
+    let limit = MAX_REQUEST.saturating_sub(line.len()) as u64;
+    let read = reader.take(limit).read_until(b'\n', line).await?;
+    if line.len() >= MAX_REQUEST && line.last() != Some(&b'\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Requests are limited to {} bytes", MAX_REQUEST)));
+    }
+    Ok(read)
+}
+
+pub fn encode_request<S: AsRef<str>>(name: &str, args: &[S]) -> Vec<u8> {
+    let mut frame = escape(name);
+    frame.push('\t');
+    for arg in args {
+        frame.push_str(&escape(arg.as_ref()));
+        frame.push('\t');
+    }
+    frame.push('\n');
+    frame.into_bytes()
+}
+
+pub fn encode_reply(result: &CacheResult) -> Vec<u8> {
+    let error;
+    let (kind, text) = match result {
+        CacheResult::Success(s) => (SUCCESS, s),
+        CacheResult::Failure(f) => {
+            error = f.to_string();
+            (FAILURE, &error)
+        },
+        CacheResult::Nil => return vec![NIL, b'\n'],
+        CacheResult::Array(items) => {
+            // The count keeps an empty array apart from an array holding one empty item
+            let mut frame = format!("*{}", items.len());
+            for item in items {
+                frame.push('\t');
+                frame.push_str(&escape(item));
+            }
+            frame.push('\n');
+            return frame.into_bytes()
+        }
+    };
+    let mut frame = vec![kind];
+    frame.extend_from_slice(escape(text).as_bytes());
+    frame.push(b'\n');
+    frame
+}
+
+/// A reply as an item of an array, e.g. one of the replies of EXEC. decode_reply reads it back.
+pub fn encode_nested(result: &CacheResult) -> String {
+    let mut frame = encode_reply(result);
+    frame.pop();
+    String::from_utf8_lossy(&frame).into_owned()
+}
+
+/// Reads a reply line back into a result. Lines without a marker come from older servers.
+pub fn decode_reply(line: &[u8]) -> CacheResult {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim_end_matches(['\n', '\r']);
+    match line.as_bytes().first() {
+        Some(&SUCCESS) => CacheResult::Success(unescape(&line[1..])),
+        Some(&FAILURE) => CacheResult::Failure(decode_error(&unescape(&line[1..]))),
+        Some(&NIL) => CacheResult::Nil,
+        Some(&ARRAY) => {
+            let mut fields = line[1..].split('\t');
+            let count = fields.next().and_then(|c| c.parse::<usize>().ok());
+            let items: Vec<String> = fields.map(unescape).collect();
+            match count {
+                Some(count) if count == items.len() => CacheResult::Array(items),
+                _ => CacheResult::error(ErrorCode::Err, format!("Malformed array reply {}", line))
+            }
+        },
+        _ => CacheResult::Success(unescape(line))
+    }
+}
+
+/// Splits CODE message. Servers from before error codes only sent the message, it is read as ERR.
+fn decode_error(text: &str) -> CacheError {
+    match text.split_once(' ') {
+        Some((code, message)) => match ErrorCode::new(code) {
+            Some(code) => CacheError::new(code, message),
+            None => CacheError::new(ErrorCode::Err, text)
+        },
+        None => match ErrorCode::new(text) {
+            Some(code) => CacheError::new(code, ""),
+            None => CacheError::new(ErrorCode::Err, text)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cache, Command};
+
+    #[test]
+    fn protocol_round_trip() {
+        let frame = encode_request("client", &["set", "greeting", "hello\tworld\n"]);
+        assert_eq!(frame, b"client\tset\tgreeting\thello\\tworld\\n\t\n".to_vec());
+        let cmd = Command::new(frame.len() - 1, frame[..frame.len() - 1].to_vec()).unwrap();
+        assert!(matches!(Cache::new(&cmd), Ok(Cache::Set)));
+        let reply = encode_reply(&CacheResult::error(ErrorCode::Syntax, "Use set\nset key value"));
+        assert_eq!(reply, b"-SYNTAX Use set\\nset key value\n".to_vec());
+        assert_eq!(decode_reply(&reply), CacheResult::error(ErrorCode::Syntax, "Use set\nset key value"));
+        assert_eq!(decode_reply(b"-NOAUTH\n"), CacheResult::error(ErrorCode::NoAuth, ""));
+        // Replies of servers from before error codes
+        assert_eq!(decode_reply(b"-Data not found\n"), CacheResult::error(ErrorCode::Err, "Data not found"));
+        assert_eq!(encode_reply(&CacheResult::Nil), b"_\n".to_vec());
+        assert_eq!(decode_reply(b"_\n"), CacheResult::Nil);
+        assert!(matches!(decode_reply(b"+1\n"), CacheResult::Success(s) if s == "1"));
+        let array = CacheResult::Array(vec![String::from("a\tb"), String::new()]);
+        assert_eq!(encode_reply(&array), b"*2\ta\\tb\t\n".to_vec());
+        assert_eq!(decode_reply(&encode_reply(&array)), array);
+        assert_eq!(decode_reply(b"*0\n"), CacheResult::Array(Vec::new()));
+        assert!(matches!(decode_reply(b"*3\ta\n"), CacheResult::Failure(_)));
+    }
+
+    #[test]
+    fn split_args_like_a_shell() {
+        let args = split_args(r#"  set greeting "hello world"  "#).unwrap();
+        assert_eq!(args, ["set", "greeting", "hello world"]);
+        let args = split_args(r#"set quote "say \"hi\"\n" 'it''s' a\ b "" x"y"z"#).unwrap();
+        assert_eq!(args, ["set", "quote", "say \"hi\"\n", "its", "a b", "", "xyz"]);
+        assert!(split_args(r#"set greeting "hello"#).is_err());
+        assert!(split_args("set greeting 'hello").is_err());
+        assert!(split_args("   ").unwrap().is_empty());
+    }
+}
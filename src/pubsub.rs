@@ -0,0 +1,230 @@
+//! Channels connections subscribe to, by name or by glob pattern, and the messages published on them.
+//!
+//! Each subscribed connection has an outbox the publishers push into. A subscriber that lets its
+//! outbox fill up is cut off rather than slowing publishers down or silently missing messages.
+
+use std::{collections::{BTreeSet, HashMap}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}};
+
+use tokio::sync::mpsc;
+
+use crate::{models, CacheResult};
+
+/// Messages waiting for a subscriber before it is disconnected.
+pub const OUTBOX_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// The pattern the channel matched, for subscriptions made with PSUBSCRIBE.
+    pub pattern: Option<String>,
+    pub channel: String,
+    pub payload: String
+}
+
+impl Message {
+    /// `message <channel> <payload>` or `pmessage <pattern> <channel> <payload>`, as pushed to subscribers.
+    pub fn reply(&self) -> CacheResult {
+        match &self.pattern {
+            Some(pattern) => CacheResult::Array(vec![String::from("pmessage"), pattern.clone(), self.channel.clone(), self.payload.clone()]),
+            None => CacheResult::Array(vec![String::from("message"), self.channel.clone(), self.payload.clone()])
+        }
+    }
+    /// The message a pushed reply holds, None for any other reply.
+    pub fn from_reply(result: &CacheResult) -> Option<Message> {
+        let CacheResult::Array(items) = result else {
+            return None;
+        };
+        match items.as_slice() {
+            [kind, channel, payload] if kind == "message" => Some(Message { pattern: None, channel: channel.clone(), payload: payload.clone() }),
+            [kind, pattern, channel, payload] if kind == "pmessage" => {
+                Some(Message { pattern: Some(pattern.clone()), channel: channel.clone(), payload: payload.clone() })
+            },
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Outbox {
+    tx: mpsc::Sender<Message>,
+    overflowed: Arc<AtomicBool>
+}
+
+impl Outbox {
+    /// False if the subscriber was cut off for being too slow.
+    fn push(&self, message: Message) -> bool {
+        if self.overflowed.load(Ordering::Relaxed) {
+            return false;
+        }
+        match self.tx.try_send(message) {
+            Ok(_) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.overflowed.store(true, Ordering::Relaxed);
+                false
+            },
+            Err(mpsc::error::TrySendError::Closed(_)) => false
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// Channel name -> subscription id -> outbox
+    channels: HashMap<String, HashMap<u64, Outbox>>,
+    /// Glob pattern -> subscription id -> outbox
+    patterns: HashMap<String, HashMap<u64, Outbox>>
+}
+
+/// Every subscription of the server, shared by all connections.
+#[derive(Debug, Default)]
+pub struct PubSub {
+    registry: Mutex<Registry>,
+    next_id: AtomicU64
+}
+
+impl PubSub {
+    fn registry(&self) -> std::sync::MutexGuard<'_, Registry> {
+        self.registry.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// Sends the payload to the subscribers of the channel and of the patterns it matches.
+    /// Returns how many subscriptions received it.
+    pub fn publish(&self, channel: &str, payload: &str) -> usize {
+        let registry = self.registry();
+        let mut received = 0;
+        if let Some(outboxes) = registry.channels.get(channel) {
+            let message = Message { pattern: None, channel: channel.to_string(), payload: payload.to_string() };
+            received += outboxes.values().filter(|outbox| outbox.push(message.clone())).count();
+        }
+        for (pattern, outboxes) in registry.patterns.iter().filter(|(pattern, _)| models::glob_match(pattern, channel)) {
+            let message = Message { pattern: Some(pattern.clone()), channel: channel.to_string(), payload: payload.to_string() };
+            received += outboxes.values().filter(|outbox| outbox.push(message.clone())).count();
+        }
+        received
+    }
+    /// A new, empty subscription for a connection.
+    pub fn subscription(self: &Arc<Self>) -> Subscription {
+        let (tx, rx) = mpsc::channel(OUTBOX_SIZE);
+        let outbox = Outbox { tx, overflowed: Arc::new(AtomicBool::new(false)) };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        Subscription { pubsub: self.clone(), id, outbox, rx, channels: BTreeSet::new(), patterns: BTreeSet::new() }
+    }
+    /// Channels with at least one subscriber, only those matching pattern if given.
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        let mut channels: Vec<String> = self.registry().channels.keys()
+            .filter(|channel| pattern.is_none_or(|pattern| models::glob_match(pattern, channel)))
+            .cloned()
+            .collect();
+        channels.sort();
+        channels
+    }
+    /// Subscribers of the channel by name, pattern subscriptions not counted.
+    pub fn numsub(&self, channel: &str) -> usize {
+        self.registry().channels.get(channel).map_or(0, HashMap::len)
+    }
+    /// Subscriptions made with a pattern, over all connections.
+    pub fn numpat(&self) -> usize {
+        self.registry().patterns.values().map(HashMap::len).sum()
+    }
+}
+
+/// What a connection is subscribed to. Dropping it unsubscribes from everything.
+pub struct Subscription {
+    pubsub: Arc<PubSub>,
+    id: u64,
+    outbox: Outbox,
+    rx: mpsc::Receiver<Message>,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>
+}
+
+impl Subscription {
+    /// Number of channels and patterns subscribed to.
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+    pub fn channels(&self) -> Vec<String> {
+        self.channels.iter().cloned().collect()
+    }
+    pub fn patterns(&self) -> Vec<String> {
+        self.patterns.iter().cloned().collect()
+    }
+    /// Returns the number of subscriptions afterwards.
+    pub fn subscribe(&mut self, channel: &str) -> usize {
+        if self.channels.insert(channel.to_string()) {
+            self.pubsub.registry().channels.entry(channel.to_string()).or_default().insert(self.id, self.outbox.clone());
+        }
+        self.count()
+    }
+    pub fn unsubscribe(&mut self, channel: &str) -> usize {
+        if self.channels.remove(channel) {
+            let mut registry = self.pubsub.registry();
+            Subscription::remove(&mut registry.channels, channel, self.id);
+        }
+        self.count()
+    }
+    pub fn psubscribe(&mut self, pattern: &str) -> usize {
+        if self.patterns.insert(pattern.to_string()) {
+            self.pubsub.registry().patterns.entry(pattern.to_string()).or_default().insert(self.id, self.outbox.clone());
+        }
+        self.count()
+    }
+    pub fn punsubscribe(&mut self, pattern: &str) -> usize {
+        if self.patterns.remove(pattern) {
+            let mut registry = self.pubsub.registry();
+            Subscription::remove(&mut registry.patterns, pattern, self.id);
+        }
+        self.count()
+    }
+    fn remove(map: &mut HashMap<String, HashMap<u64, Outbox>>, name: &str, id: u64) {
+        if let Some(outboxes) = map.get_mut(name) {
+            outboxes.remove(&id);
+            if outboxes.is_empty() {
+                map.remove(name);
+            }
+        }
+    }
+    /// The next message published to a subscribed channel, None once the outbox overflowed.
+    pub async fn recv(&mut self) -> Option<Message> {
+        if self.outbox.overflowed.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.rx.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut registry = self.pubsub.registry();
+        for channel in &self.channels {
+            Subscription::remove(&mut registry.channels, channel, self.id);
+        }
+        for pattern in &self.patterns {
+            Subscription::remove(&mut registry.patterns, pattern, self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn slow_subscribers_are_cut_off() {
+        let pubsub = Arc::new(PubSub::default());
+        let mut slow = pubsub.subscription();
+        let mut fast = pubsub.subscription();
+        slow.subscribe("news");
+        fast.psubscribe("n*");
+        for i in 0..OUTBOX_SIZE {
+            assert_eq!(pubsub.publish("news", &i.to_string()), 2);
+            let message = fast.recv().await.unwrap();
+            assert_eq!(Message::from_reply(&message.reply()), Some(message));
+        }
+        // One message too many and the slow subscriber no longer receives any
+        assert_eq!(pubsub.publish("news", "late"), 1);
+        assert!(slow.recv().await.is_none());
+        assert_eq!(pubsub.numsub("news"), 1);
+        drop(slow);
+        assert_eq!((pubsub.numsub("news"), pubsub.numpat()), (0, 1));
+        assert_eq!(pubsub.channels(None), Vec::<String>::new());
+    }
+}
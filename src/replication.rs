@@ -0,0 +1,616 @@
+//! Primary/replica replication: replicas follow every change made on their primary.
+//!
+//! A replica connects to its primary like a client and sends `replconf listening-port <port>`,
+//! then `psync <replid> <offset>` with the position it reached. When the primary still holds the
+//! stream from that position in its backlog it replies `+CONTINUE <replid>`, otherwise
+//! `+FULLRESYNC <replid> <offset> <count>` followed by count requests that rebuild its memory.
+//! The connection then carries the stream: every change to memory as a request, in the order it
+//! happened, with the changes of a transaction or script between `multi` and `exec`. The replica
+//! applies them without replying and sends `replconf ack <offset>` every second. Offsets count
+//! the bytes of the stream, and a replica keeps what it received in its own backlog so it can
+//! serve the others once promoted.
+
+use std::{collections::{HashMap, VecDeque}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex, MutexGuard, Weak}, time::{Duration, Instant, SystemTime}};
+
+use sha1::{Digest, Sha1};
+use tokio::{io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt}, sync::{broadcast, mpsc::{self, Sender, WeakSender}, watch}, task::JoinHandle, time};
+
+use crate::{client::Connection, config::Config, models::{self, Memory, Pipe}, protocol, Cache, CacheResult, Command, CHANGE_CMD};
+
+/// Record of a string along with its expiry time in unix milliseconds, only sent to replicas.
+pub const SETEXAT: &str = "setexat";
+/// Name of the requests in the stream.
+const STREAM_NAME: &str = "primary";
+/// How often a replica acknowledges its offset, and waits before connecting again.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most bytes of the stream written to a replica at once.
+const CHUNK: usize = 64 * 1024;
+
+/// The connection of a replica to its primary.
+#[derive(Debug)]
+struct Link {
+    /// host:port of the primary
+    addr: String,
+    up: bool,
+    /// True while the data of the primary is loaded
+    syncing: bool,
+    last_io: Option<Instant>
+}
+
+#[derive(Debug)]
+struct State {
+    /// None on a primary
+    link: Option<Link>,
+    replid: String,
+    /// Replid of the former primary and the offset it is valid up to, once a replica is promoted
+    replid2: Option<(String, u64)>,
+    offset: u64,
+    /// The end of the stream, None until a replica attached
+    backlog: Option<VecDeque<u8>>,
+    /// Changes of the running transaction or script, sent together once it ends
+    batch: Option<Vec<Vec<u8>>>
+}
+
+/// A replica connected to this server.
+#[derive(Debug)]
+struct Peer {
+    ip: String,
+    port: u16,
+    online: bool,
+    offset: u64,
+    last_ack: Instant
+}
+
+/// The replication state of a server, as a primary and as a replica.
+#[derive(Debug)]
+pub struct Replication {
+    state: Mutex<State>,
+    /// True on a primary with a backlog, when changes have to be recorded
+    feeding: AtomicBool,
+    /// End of the stream, for the connections of replicas waiting for more
+    offsets: watch::Sender<u64>,
+    replicas: Mutex<HashMap<u64, Peer>>,
+    next_id: AtomicU64,
+    /// Replicas served a full copy, and those that continued from the backlog
+    full_syncs: AtomicU64,
+    partial_syncs: AtomicU64,
+    backlog_size: usize,
+    /// Port announced to the primary
+    port: u16,
+    primary_user: Option<String>,
+    primary_auth: Option<String>,
+    link: Mutex<Option<JoinHandle<()>>>
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Replication::new(&Config::default())
+    }
+}
+
+impl Replication {
+    pub fn new(config: &Config) -> Replication {
+        let state = State { link: None, replid: new_replid(), replid2: None, offset: 0, backlog: None, batch: None };
+        Replication {
+            state: Mutex::new(state),
+            feeding: AtomicBool::new(false),
+            offsets: watch::Sender::new(0),
+            replicas: Mutex::default(),
+            next_id: AtomicU64::new(0),
+            full_syncs: AtomicU64::new(0),
+            partial_syncs: AtomicU64::new(0),
+            backlog_size: config.repl_backlog_size.max(1),
+            port: config.port,
+            primary_user: config.primary_user.clone(),
+            primary_auth: config.primary_auth.clone(),
+            link: Mutex::default()
+        }
+    }
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn replicas(&self) -> MutexGuard<'_, HashMap<u64, Peer>> {
+        self.replicas.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// True when changes to memory have to be given to [`Replication::feed`]. Writes check it
+    /// under the shared gate, which is held exclusively while it changes.
+    pub fn feeding(&self) -> bool {
+        self.feeding.load(Ordering::SeqCst)
+    }
+    /// True while following a primary, writes are then refused.
+    pub fn is_replica(&self) -> bool {
+        self.state().link.is_some()
+    }
+    /// Adds a change, given as the command that makes it, to the stream.
+    pub fn feed<S: AsRef<str>>(&self, args: &[S]) {
+        let mut state = self.state();
+        if state.link.is_some() || state.backlog.is_none() {
+            return;
+        }
+        let request = protocol::encode_request(STREAM_NAME, args);
+        match state.batch.as_mut() {
+            Some(batch) => batch.push(request),
+            None => self.append(&mut state, &request)
+        }
+    }
+    /// Holds the changes fed from now on until [`Replication::end_batch`], called with the gate
+    /// taken exclusively by transactions and scripts.
+    pub fn begin_batch(&self) {
+        let mut state = self.state();
+        if state.link.is_none() && state.backlog.is_some() {
+            state.batch = Some(Vec::new());
+        }
+    }
+    /// Adds the held changes to the stream, between multi and exec if there are several.
+    pub fn end_batch(&self) {
+        let mut state = self.state();
+        let Some(batch) = state.batch.take() else {
+            return;
+        };
+        if batch.len() > 1 {
+            self.append(&mut state, &protocol::encode_request(STREAM_NAME, &["multi"]));
+        }
+        for request in &batch {
+            self.append(&mut state, request);
+        }
+        if batch.len() > 1 {
+            self.append(&mut state, &protocol::encode_request(STREAM_NAME, &["exec"]));
+        }
+    }
+    fn append(&self, state: &mut State, bytes: &[u8]) {
+        let Some(backlog) = state.backlog.as_mut() else {
+            return;
+        };
+        backlog.extend(bytes);
+        let excess = backlog.len().saturating_sub(self.backlog_size);
+        backlog.drain(..excess);
+        state.offset += bytes.len() as u64;
+        self.offsets.send_replace(state.offset);
+    }
+    /// The replid and offset reached, what a replica asks to continue from.
+    pub fn position(&self) -> (String, u64) {
+        let state = self.state();
+        (state.replid.clone(), state.offset)
+    }
+    /// Where a replica asking for the stream after offset of replid continues from, None if it
+    /// has to load a full copy. Starts the backlog, so call it with the gate taken exclusively.
+    fn psync(&self, replid: &str, offset: Option<u64>) -> Option<u64> {
+        let mut state = self.state();
+        if state.backlog.is_none() {
+            state.backlog = Some(VecDeque::new());
+            self.feeding.store(state.link.is_none(), Ordering::SeqCst);
+        }
+        let Some(offset) = offset else {
+            self.full_syncs.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let backlog_start = state.offset - state.backlog.as_ref().map_or(0, |b| b.len() as u64);
+        let known = replid == state.replid || state.replid2.as_ref().is_some_and(|(id, end)| id == replid && offset <= *end);
+        if known && offset >= backlog_start && offset <= state.offset {
+            self.partial_syncs.fetch_add(1, Ordering::Relaxed);
+            return Some(offset);
+        }
+        self.full_syncs.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+    /// The stream from offset on, None once it left the backlog.
+    fn read_from(&self, offset: u64) -> Option<Vec<u8>> {
+        let state = self.state();
+        let backlog = state.backlog.as_ref()?;
+        let start = state.offset - backlog.len() as u64;
+        if offset < start || offset > state.offset {
+            return None;
+        }
+        let from = (offset - start) as usize;
+        let to = backlog.len().min(from + CHUNK);
+        Some(backlog.range(from..to).copied().collect())
+    }
+    /// Takes the replid and offset of the primary after a full sync, with an empty backlog.
+    fn synced(&self, replid: &str, offset: u64) {
+        let mut state = self.state();
+        state.replid = replid.to_string();
+        state.replid2 = None;
+        state.offset = offset;
+        state.backlog = Some(VecDeque::new());
+        self.offsets.send_replace(offset);
+    }
+    /// The primary continued under another replid, e.g. after a promotion.
+    fn continued(&self, replid: &str) {
+        let mut state = self.state();
+        if state.replid != replid {
+            let old = std::mem::replace(&mut state.replid, replid.to_string());
+            state.replid2 = Some((old, state.offset));
+        }
+    }
+    /// Adds what the primary sent to the backlog, once applied.
+    fn mirror(&self, bytes: &[u8]) {
+        let mut state = self.state();
+        if state.backlog.is_none() {
+            state.backlog = Some(VecDeque::new());
+        }
+        self.append(&mut state, bytes);
+    }
+    fn update_link(&self, update: impl FnOnce(&mut Link)) {
+        if let Some(link) = self.state().link.as_mut() {
+            update(link);
+        }
+    }
+    /// Follows the primary at addr, or stops following with None and becomes a primary, under
+    /// a new replid so replicas of the old primary do not mistake this one for it. Call it with
+    /// the gate taken exclusively, after [`Replication::stop_link`].
+    fn set_primary(&self, primary: Option<String>) {
+        let mut state = self.state();
+        match primary {
+            Some(addr) => {
+                state.link = Some(Link { addr, up: false, syncing: false, last_io: None });
+                state.batch = None;
+                self.feeding.store(false, Ordering::SeqCst);
+            },
+            None if state.link.is_some() => {
+                state.link = None;
+                let old = std::mem::replace(&mut state.replid, new_replid());
+                state.replid2 = Some((old, state.offset));
+                self.feeding.store(state.backlog.is_some(), Ordering::SeqCst);
+            },
+            None => {}
+        }
+    }
+    /// Stops following the primary, waiting until the link let go of memory.
+    pub async fn stop_link(&self) {
+        let link = self.link.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(link) = link {
+            link.abort();
+            let _ = link.await;
+        }
+    }
+    fn attach(self: &Arc<Self>, ip: String, port: u16) -> Attached {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.replicas().insert(id, Peer { ip, port, online: false, offset: 0, last_ack: Instant::now() });
+        Attached { replication: self.clone(), id }
+    }
+    /// The replication section of info.
+    pub fn info(&self) -> String {
+        let state = self.state();
+        let mut text = String::from("# Replication\n");
+        match &state.link {
+            None => text.push_str("role:primary\n"),
+            Some(link) => {
+                let (host, port) = link.addr.rsplit_once(':').unwrap_or((&link.addr, ""));
+                text.push_str("role:replica\n");
+                text.push_str(&format!("primary_host:{}\n", host));
+                text.push_str(&format!("primary_port:{}\n", port));
+                text.push_str(&format!("primary_link_status:{}\n", if link.up { "up" } else { "down" }));
+                let last_io = link.last_io.map_or(-1, |t| t.elapsed().as_secs() as i64);
+                text.push_str(&format!("primary_last_io_seconds_ago:{}\n", last_io));
+                text.push_str(&format!("primary_sync_in_progress:{}\n", link.syncing as u8));
+                text.push_str(&format!("replica_repl_offset:{}\n", state.offset));
+            }
+        }
+        let replicas = self.replicas();
+        let mut peers: Vec<(&u64, &Peer)> = replicas.iter().collect();
+        peers.sort_by_key(|(id, _)| **id);
+        text.push_str(&format!("connected_replicas:{}\n", peers.len()));
+        for (i, (_, peer)) in peers.iter().enumerate() {
+            text.push_str(&format!("replica{}:ip={},port={},state={},offset={},lag={}\n", i, peer.ip, peer.port,
+                if peer.online { "online" } else { "sync" }, peer.offset, peer.last_ack.elapsed().as_secs()));
+        }
+        text.push_str(&format!("replid:{}\n", state.replid));
+        let (replid2, second_offset) = match &state.replid2 {
+            Some((id, end)) => (id.clone(), *end as i64),
+            None => ("0".repeat(40), -1)
+        };
+        text.push_str(&format!("replid2:{}\n", replid2));
+        text.push_str(&format!("repl_offset:{}\n", state.offset));
+        text.push_str(&format!("second_repl_offset:{}\n", second_offset));
+        let histlen = state.backlog.as_ref().map_or(0, |b| b.len() as u64);
+        text.push_str(&format!("repl_backlog_active:{}\n", state.backlog.is_some() as u8));
+        text.push_str(&format!("repl_backlog_size:{}\n", self.backlog_size));
+        text.push_str(&format!("repl_backlog_first_byte_offset:{}\n", state.offset - histlen));
+        text.push_str(&format!("repl_backlog_histlen:{}\n", histlen));
+        text.push_str(&format!("sync_full:{}\n", self.full_syncs.load(Ordering::Relaxed)));
+        text.push_str(&format!("sync_partial_ok:{}\n", self.partial_syncs.load(Ordering::Relaxed)));
+        text
+    }
+}
+
+/// A replica listed in info while its connection lasts.
+struct Attached {
+    replication: Arc<Replication>,
+    id: u64
+}
+
+impl Attached {
+    fn update(&self, update: impl FnOnce(&mut Peer)) {
+        if let Some(peer) = self.replication.replicas().get_mut(&self.id) {
+            update(peer);
+        }
+    }
+}
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        self.replication.replicas().remove(&self.id);
+    }
+}
+
+/// 40 hex characters that name a history of changes, or a sentinel.
+pub(crate) fn new_replid() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let seed = format!("{:?} {} {}", SystemTime::now(), std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+    Sha1::digest(seed.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Follows the primary at addr, or becomes a primary with None, e.g. for `replicaof`.
+pub async fn replicaof(memory: &Arc<Memory>, tx: &Sender<Pipe>, primary: Option<String>) {
+    memory.replication.stop_link().await;
+    let _exclusive = memory.exclusive.write().await;
+    start(memory, tx, primary);
+}
+
+/// Same as [`replicaof`] for a server that just started, before any command ran.
+pub fn start(memory: &Arc<Memory>, tx: &Sender<Pipe>, primary: Option<String>) {
+    let replication = &memory.replication;
+    replication.set_primary(primary.clone());
+    if let Some(addr) = primary {
+        let link = tokio::spawn(follow(Arc::downgrade(memory), tx.downgrade(), addr));
+        *replication.link.lock().unwrap_or_else(|e| e.into_inner()) = Some(link);
+    }
+}
+
+/// Serves the replica that sent `psync <replid> <offset>` on this connection: a full copy of
+/// memory if needed, then the stream until the connection or the server closes.
+pub async fn serve<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    memory: Arc<Memory>,
+    args: &[String],
+    peer: (String, u16),
+    mut reader: R,
+    mut writer: W,
+    mut shutdown: broadcast::Receiver<()>
+) {
+    let replication = memory.replication.clone();
+    let replica = replication.attach(peer.0.clone(), peer.1);
+    let (reply, snapshot, mut position) = {
+        // Nothing changes between the copy and the offset it stands for
+        let _exclusive = memory.exclusive.write().await;
+        match replication.psync(&args[1], args[2].parse().ok()) {
+            Some(offset) => (format!("CONTINUE {}", replication.position().0), Vec::new(), offset),
+            None => {
+                let records = memory.snapshot().await;
+                let (replid, offset) = replication.position();
+                (format!("FULLRESYNC {} {} {}", replid, offset, records.len()), records, offset)
+            }
+        }
+    };
+    let mut data = protocol::encode_reply(&CacheResult::Success(reply));
+    for record in &snapshot {
+        data.extend(protocol::encode_request(STREAM_NAME, record));
+    }
+    if writer.write_all(&data).await.is_err() || writer.flush().await.is_err() {
+        return;
+    }
+    replica.update(|peer| {
+        peer.online = true;
+        peer.offset = position;
+    });
+    let mut offsets = replication.offsets.subscribe();
+    let mut line = Vec::new();
+    loop {
+        offsets.borrow_and_update();
+        match replication.read_from(position) {
+            Some(bytes) if !bytes.is_empty() => {
+                if writer.write_all(&bytes).await.is_err() || writer.flush().await.is_err() {
+                    return;
+                }
+                position += bytes.len() as u64;
+                continue;
+            },
+            Some(_) => {},
+            None => {
+                tracing::warn!(replica = %format_args!("{}:{}", peer.0, peer.1), "replica fell out of the backlog, it has to sync again");
+                return;
+            }
+        }
+        tokio::select! {
+            changed = offsets.changed() => if changed.is_err() {
+                return;
+            },
+            read = reader.read_until(b'\n', &mut line) => match read {
+                Ok(0) | Err(_) => return,
+                Ok(_) if line.last() != Some(&b'\n') => continue,
+                Ok(_) => {
+                    line.pop();
+                    let args = Command::new(line.len(), std::mem::take(&mut line)).map(|cmd| cmd.args()).unwrap_or_default();
+                    if let [name, option, offset] = &args[..] {
+                        if name.eq_ignore_ascii_case("replconf") && option.eq_ignore_ascii_case("ack") {
+                            let offset = offset.parse().unwrap_or(0);
+                            replica.update(|peer| {
+                                peer.offset = offset;
+                                peer.last_ack = Instant::now();
+                            });
+                        }
+                    }
+                }
+            },
+            _ = shutdown.recv() => return
+        }
+    }
+}
+
+/// The link of a replica: syncs with the primary, applies its stream, and connects again
+/// whenever the connection breaks. Only weak references are held between two changes.
+async fn follow(memory: Weak<Memory>, tx: WeakSender<Pipe>, addr: String) {
+    loop {
+        let Some(replication) = memory.upgrade().map(|m| m.replication.clone()) else {
+            return
+        };
+        match sync(&memory, &tx, &addr, &replication).await {
+            Ok(()) => return,
+            Err(e) => tracing::warn!(primary = %addr, error = %e, "replication stopped")
+        }
+        replication.update_link(|link| {
+            link.up = false;
+            link.syncing = false;
+        });
+        drop(replication);
+        time::sleep(ACK_INTERVAL).await;
+    }
+}
+
+/// One connection to the primary. Ok once memory is gone.
+async fn sync(memory: &Weak<Memory>, tx: &WeakSender<Pipe>, addr: &str, replication: &Replication) -> Result<(), String> {
+    let mut connection = match time::timeout(CONNECT_TIMEOUT, Connection::connect(addr)).await {
+        Ok(connection) => connection.map_err(|e| e.to_string())?,
+        Err(_) => return Err(format!("Connection to {} timed out", addr))
+    };
+    if let Some(password) = &replication.primary_auth {
+        connection.auth(replication.primary_user.as_deref(), password).await.map_err(|e| e.to_string())?;
+    }
+    let port = replication.port.to_string();
+    if let CacheResult::Failure(e) = connection.execute(&["replconf", "listening-port", &port]).await.map_err(|e| e.to_string())? {
+        return Err(e.to_string());
+    }
+    let (replid, offset) = replication.position();
+    let reply = connection.execute(&["psync", &replid, &offset.to_string()]).await.map_err(|e| e.to_string())?;
+    let (mut reader, mut writer) = connection.into_split();
+    let reply = match reply {
+        CacheResult::Success(reply) => reply,
+        CacheResult::Failure(e) => return Err(e.to_string()),
+        other => return Err(format!("Unexpected psync reply {:?}", other))
+    };
+    match reply.split(' ').collect::<Vec<&str>>()[..] {
+        ["FULLRESYNC", replid, offset, count] => {
+            let (Ok(offset), Ok(count)) = (offset.parse(), count.parse()) else {
+                return Err(format!("Malformed psync reply {}", reply));
+            };
+            replication.update_link(|link| link.syncing = true);
+            let (Some(memory), Some(tx)) = (memory.upgrade(), tx.upgrade()) else {
+                return Ok(())
+            };
+            load(&memory, &tx, &mut reader, count).await?;
+            replication.synced(replid, offset);
+            replication.update_link(|link| link.syncing = false);
+        },
+        ["CONTINUE", replid] => replication.continued(replid),
+        _ => return Err(format!("Malformed psync reply {}", reply))
+    }
+    replication.update_link(|link| {
+        link.up = true;
+        link.last_io = Some(Instant::now());
+    });
+    let mut ack = time::interval(ACK_INTERVAL);
+    let mut line = Vec::new();
+    // Raw bytes and records of a transaction until its exec
+    let mut pending = Vec::new();
+    let mut batch: Option<Vec<Vec<String>>> = None;
+    loop {
+        tokio::select! {
+            read = reader.read_until(b'\n', &mut line) => {
+                match read {
+                    Ok(0) => return Err(String::from("Primary closed the connection")),
+                    Err(e) => return Err(e.to_string()),
+                    Ok(_) if line.last() != Some(&b'\n') => continue,
+                    Ok(_) => {}
+                }
+                replication.update_link(|link| link.last_io = Some(Instant::now()));
+                pending.extend_from_slice(&line);
+                let args = read_record(&mut line)?;
+                let (Some(memory), Some(tx)) = (memory.upgrade(), tx.upgrade()) else {
+                    return Ok(())
+                };
+                match (args[0].as_str(), batch.as_mut()) {
+                    ("multi", None) => batch = Some(Vec::new()),
+                    ("exec", Some(_)) => apply(&memory, &tx, batch.take().unwrap_or_default()).await,
+                    (_, Some(records)) => records.push(args),
+                    (_, None) => apply(&memory, &tx, vec![args]).await
+                }
+                if batch.is_none() {
+                    replication.mirror(&pending);
+                    pending.clear();
+                }
+            },
+            _ = ack.tick() => {
+                let offset = replication.position().1.to_string();
+                let request = protocol::encode_request(STREAM_NAME, &["replconf", "ack", &offset]);
+                writer.write_all(&request).await.map_err(|e| e.to_string())?;
+                writer.flush().await.map_err(|e| e.to_string())?;
+            }
+        }
+    }
+}
+
+/// The arguments of a request line read from the primary, which is cleared.
+fn read_record(line: &mut Vec<u8>) -> Result<Vec<String>, String> {
+    let mut request = std::mem::take(line);
+    request.pop();
+    Command::new(request.len(), request).map(|cmd| cmd.args()).map_err(|e| format!("Malformed record from the primary: {}", e))
+}
+
+/// Replaces memory with the count records of a full sync, with the gate taken all along.
+async fn load<R: AsyncBufRead + Unpin>(memory: &Arc<Memory>, tx: &Sender<Pipe>, reader: &mut R, count: usize) -> Result<(), String> {
+    let _exclusive = memory.exclusive.write().await;
+    memory.clear(tx).await.map_err(|e| e.to_string())?;
+    let mut line = Vec::new();
+    for _ in 0..count {
+        let read = reader.read_until(b'\n', &mut line).await.map_err(|e| e.to_string())?;
+        if read == 0 || line.last() != Some(&b'\n') {
+            return Err(String::from("Primary closed the connection during the sync"));
+        }
+        let args = read_record(&mut line)?;
+        apply_record(memory, args, tx.clone()).await;
+    }
+    Ok(())
+}
+
+/// Applies the records of the stream, those of a transaction with nothing in between and
+/// written to the backup file together.
+async fn apply(memory: &Arc<Memory>, tx: &Sender<Pipe>, records: Vec<Vec<String>>) {
+    if records.len() <= 1 {
+        let _shared = memory.exclusive.read().await;
+        for record in records {
+            apply_record(memory, record, tx.clone()).await;
+        }
+        return;
+    }
+    let _exclusive = memory.exclusive.write().await;
+    let (batch_tx, mut rx) = mpsc::channel(records.len() * 3 + 1);
+    for record in records {
+        apply_record(memory, record, batch_tx.clone()).await;
+    }
+    drop(batch_tx);
+    let mut batch = Vec::new();
+    while let Ok(pipe) = rx.try_recv() {
+        batch.push(pipe);
+    }
+    if !batch.is_empty() {
+        let _ = tx.send(Pipe::Batch(batch)).await;
+    }
+}
+
+/// Runs one record on memory, past the refusal of writes on a replica.
+async fn apply_record(memory: &Arc<Memory>, args: Vec<String>, tx: Sender<Pipe>) -> CacheResult {
+    let result = match &args[..] {
+        [name, key, expires_at, value] if name == SETEXAT => match expires_at.parse() {
+            Ok(expires_at) => {
+                let key = models::escape_stored(key);
+                let data = format!("{}\t{}'{}\"", CHANGE_CMD[0], key, models::escape_stored(value));
+                memory.set_ex(key, data, expires_at, tx).await
+            },
+            Err(_) => CacheResult::error(models::ErrorCode::Syntax, format!("{} is not an expiry time", expires_at))
+        },
+        _ => {
+            let cmd = match Command::from_args(&args) {
+                Ok(cmd) => cmd,
+                Err(e) => return CacheResult::Failure(e.into())
+            };
+            match Cache::new(&cmd) {
+                Ok(cache) => cache.handle_cmd(cmd, memory.clone(), tx).await,
+                Err(e) => CacheResult::Failure(e.into())
+            }
+        }
+    };
+    if let CacheResult::Failure(e) = &result {
+        tracing::error!(command = %args[0], error = %e, "replicated command failed");
+    }
+    result
+}
@@ -0,0 +1,198 @@
+//! Lua scripts run by EVAL and EVALSHA, and the cache of scripts SCRIPT manages.
+//!
+//! A script sees its key names in `KEYS` and other arguments in `ARGV`, and runs commands with
+//! `redis.call` (a failure stops the script) or `redis.pcall` (a failure is returned as
+//! `{err = "CODE message"}`). Only the table, string and math libraries are loaded. A script
+//! running past the time limit, or stopped by SCRIPT KILL, fails at its next instructions.
+
+use std::{cell::RefCell, collections::HashMap, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Table, Value, Variadic};
+use sha1::{Digest, Sha1};
+
+use crate::{models::{CacheError, ErrorCode}, CacheResult};
+
+/// Instructions run between two checks of the time limit and of SCRIPT KILL.
+const CHECK_EVERY: u32 = 1000;
+
+/// Scripts loaded by EVAL or SCRIPT LOAD, and the one running.
+#[derive(Debug)]
+pub struct Scripts {
+    /// SHA-1 of the source, in hex -> source
+    sources: Mutex<HashMap<String, String>>,
+    time_limit: Duration,
+    /// Kill flag of the running script
+    running: Mutex<Option<Arc<AtomicBool>>>
+}
+
+impl Scripts {
+    pub fn new(time_limit: Duration) -> Scripts {
+        Scripts { sources: Mutex::default(), time_limit, running: Mutex::default() }
+    }
+    /// SHA-1 of a script in hex, the name EVALSHA runs it by.
+    pub fn sha1(source: &str) -> String {
+        Sha1::digest(source.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+    fn sources(&self) -> std::sync::MutexGuard<'_, HashMap<String, String>> {
+        self.sources.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// Keeps a script to run by its SHA-1, which is returned.
+    pub fn load(&self, source: &str) -> String {
+        let sha = Scripts::sha1(source);
+        self.sources().insert(sha.clone(), source.to_string());
+        sha
+    }
+    pub fn source(&self, sha: &str) -> Option<String> {
+        self.sources().get(&sha.to_lowercase()).cloned()
+    }
+    /// Runs `script load <source> | exists <sha>... | flush | kill`.
+    pub fn command(&self, args: &[String]) -> CacheResult {
+        match (args[1].to_lowercase().as_str(), &args[2..]) {
+            ("load", [source]) => CacheResult::Success(self.load(source)),
+            ("exists", shas) if !shas.is_empty() => {
+                let sources = self.sources();
+                CacheResult::Array(shas.iter().map(|sha| (sources.contains_key(&sha.to_lowercase()) as u8).to_string()).collect())
+            },
+            ("flush", []) => {
+                self.sources().clear();
+                CacheResult::Success(String::from("OK"))
+            },
+            ("kill", []) => match self.running.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+                Some(kill) => {
+                    kill.store(true, Ordering::SeqCst);
+                    CacheResult::Success(String::from("OK"))
+                },
+                None => CacheResult::error(ErrorCode::Err, "No script is running")
+            },
+            _ => CacheResult::error(ErrorCode::Syntax, "Use script load <source> | exists <sha>... | flush | kill")
+        }
+    }
+    /// Runs a script, call running each command it sends. Blocks until the script ends.
+    pub fn run(&self, source: &str, keys: &[String], argv: &[String], call: impl FnMut(Vec<String>) -> CacheResult) -> CacheResult {
+        let kill = Arc::new(AtomicBool::new(false));
+        *self.running.lock().unwrap_or_else(|e| e.into_inner()) = Some(kill.clone());
+        let result = run(source, keys, argv, self.time_limit, kill, call);
+        *self.running.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        match result {
+            Ok(result) => result,
+            Err(e) => CacheResult::Failure(failure(&e))
+        }
+    }
+}
+
+fn run(source: &str, keys: &[String], argv: &[String], time_limit: Duration, kill: Arc<AtomicBool>, call: impl FnMut(Vec<String>) -> CacheResult) -> mlua::Result<CacheResult> {
+    let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::new())?;
+    let start = Instant::now();
+    lua.set_hook(HookTriggers::new().every_nth_instruction(CHECK_EVERY), move |_, _| {
+        if kill.load(Ordering::SeqCst) {
+            return Err(mlua::Error::external(CacheError::new(ErrorCode::Err, "Script killed by SCRIPT KILL")));
+        }
+        if start.elapsed() > time_limit {
+            return Err(mlua::Error::external(CacheError::new(ErrorCode::Err, format!("Script ran longer than {} ms", time_limit.as_millis()))));
+        }
+        Ok(())
+    });
+    lua.globals().set("KEYS", lua.create_sequence_from(keys.iter().map(String::as_str))?)?;
+    lua.globals().set("ARGV", lua.create_sequence_from(argv.iter().map(String::as_str))?)?;
+    let call = RefCell::new(call);
+    lua.scope(|scope| {
+        let redis = lua.create_table()?;
+        redis.set("call", scope.create_function(|lua, args: Variadic<Value>| {
+            match (call.borrow_mut())(arguments(args)?) {
+                CacheResult::Failure(e) => Err(mlua::Error::external(e)),
+                result => to_lua(lua, result)
+            }
+        })?)?;
+        redis.set("pcall", scope.create_function(|lua, args: Variadic<Value>| {
+            let result = match arguments(args) {
+                Ok(args) => (call.borrow_mut())(args),
+                Err(e) => CacheResult::Failure(failure(&e))
+            };
+            to_lua(lua, result)
+        })?)?;
+        redis.set("error_reply", lua.create_function(|lua, message: String| {
+            lua.create_table_from([("err", message)])
+        })?)?;
+        redis.set("status_reply", lua.create_function(|lua, status: String| {
+            lua.create_table_from([("ok", status)])
+        })?)?;
+        lua.globals().set("redis", redis)?;
+        let value: Value = lua.load(source).set_name("script").eval()?;
+        from_lua(value)
+    })
+}
+
+/// The arguments given to redis.call, strings or numbers.
+fn arguments(args: Variadic<Value>) -> mlua::Result<Vec<String>> {
+    if args.is_empty() {
+        return Err(mlua::Error::external(CacheError::new(ErrorCode::Syntax, "redis.call needs a command")));
+    }
+    args.iter().map(|arg| match arg {
+        Value::String(s) => Ok(s.to_string_lossy().into_owned()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Number(n) => Ok(n.to_string()),
+        _ => Err(mlua::Error::external(CacheError::new(ErrorCode::Syntax, "Command arguments must be strings or numbers")))
+    }).collect()
+}
+
+/// A command reply as Lua sees it: a string, a table of strings, false for nil or {err = ...}.
+fn to_lua(lua: &Lua, result: CacheResult) -> mlua::Result<Value<'_>> {
+    match result {
+        CacheResult::Success(s) => Ok(Value::String(lua.create_string(&s)?)),
+        CacheResult::Array(items) => Ok(Value::Table(lua.create_sequence_from(items)?)),
+        CacheResult::Nil => Ok(Value::Boolean(false)),
+        CacheResult::Failure(e) => Ok(Value::Table(lua.create_table_from([("err", e.to_string())])?))
+    }
+}
+
+/// The reply of a script from the value it returned.
+fn from_lua(value: Value) -> mlua::Result<CacheResult> {
+    match value {
+        Value::Nil | Value::Boolean(false) => Ok(CacheResult::Nil),
+        Value::Boolean(true) => Ok(CacheResult::Success(String::from("1"))),
+        Value::Integer(i) => Ok(CacheResult::Success(i.to_string())),
+        // Like redis, numbers are replied as integers
+        Value::Number(n) => Ok(CacheResult::Success((n as i64).to_string())),
+        Value::String(s) => Ok(CacheResult::Success(s.to_string_lossy().into_owned())),
+        Value::Table(table) => from_table(table),
+        other => Err(mlua::Error::external(CacheError::new(ErrorCode::Err, format!("Scripts cannot return a {}", other.type_name()))))
+    }
+}
+
+fn from_table(table: Table) -> mlua::Result<CacheResult> {
+    if let Some(error) = table.get::<_, Option<String>>("err")? {
+        let (code, message) = error.split_once(' ').unwrap_or((&error, ""));
+        return Ok(CacheResult::Failure(match ErrorCode::new(code) {
+            Some(code) => CacheError::new(code, message),
+            None => CacheError::new(ErrorCode::Err, error.clone())
+        }));
+    }
+    if let Some(status) = table.get::<_, Option<String>>("ok")? {
+        return Ok(CacheResult::Success(status));
+    }
+    let mut items = Vec::new();
+    // Like redis, the array ends at the first nil
+    for value in table.sequence_values::<Value>() {
+        items.push(match from_lua(value?)? {
+            CacheResult::Success(item) => item,
+            CacheResult::Nil => break,
+            _ => return Err(mlua::Error::external(CacheError::new(ErrorCode::Err, "Arrays returned by scripts hold strings and numbers")))
+        });
+    }
+    Ok(CacheResult::Array(items))
+}
+
+/// The failure a script error is replied as, keeping the code of a failed redis.call.
+fn failure(error: &mlua::Error) -> CacheError {
+    match error {
+        mlua::Error::CallbackError { cause, .. } => failure(cause),
+        mlua::Error::ExternalError(e) => match e.downcast_ref::<CacheError>() {
+            Some(e) => e.clone(),
+            None => CacheError::new(ErrorCode::Err, e.to_string())
+        },
+        // Errors raised with error() in the script
+        mlua::Error::RuntimeError(message) => CacheError::new(ErrorCode::Err, format!("Script failed: {}", message)),
+        mlua::Error::SyntaxError { message, .. } => CacheError::new(ErrorCode::Syntax, format!("Script does not compile: {}", message)),
+        e => CacheError::new(ErrorCode::Err, format!("Script failed: {}", e))
+    }
+}
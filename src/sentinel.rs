@@ -0,0 +1,738 @@
+//! Sentinel: watches primaries and their replicas, and replaces a primary that fails.
+//!
+//! A sentinel asks every server it watches for `info replication` a few times a second, which
+//! also lists the replicas of a primary. Sentinels find each other through the hello messages
+//! they publish on [`HELLO_CHANNEL`] of those servers, carrying their address and the primary
+//! they know. A primary that does not answer for `--down-after-ms` is down for one sentinel
+//! (`s_down`), and down for good (`o_down`) once `quorum` sentinels agree, asked with
+//! `sentinel is-primary-down-by-addr`. A sentinel then asks the others to vote for it in a new
+//! epoch, each votes once per epoch. Elected by a majority, it promotes the replica with the
+//! highest offset with `replicaof no one` and points the other replicas at it. Its hello
+//! messages carry the new primary with the epoch of the failover, and the highest epoch wins.
+
+use std::{collections::{HashMap, HashSet}, net::{IpAddr, Ipv4Addr, SocketAddr}, sync::{Arc, Mutex, MutexGuard}, time::{Duration, Instant}};
+
+use tokio::time;
+
+use crate::{client::{ClientError, Connection}, logging::LogConfig, models::{ErrorCode, MainError}, protocol, pubsub::Message, replication, CacheResult};
+
+pub const USAGE: &str = r#"usage: sentinel --monitor <name> <host> <port> <quorum> [options]
+
+options:
+  --port <port>
+      port to listen on (default: 26379).
+  --bind <ip>
+      address to listen on, 0.0.0.0 for every interface (default: 127.0.0.1).
+  --announce-ip <ip>
+      address the other sentinels reach this one at, sent in hello messages (default: the --bind address).
+  --monitor <name> <host> <port> <quorum>
+      watch the primary at host and port under name, quorum sentinels have to agree it failed. can be repeated.
+  --down-after-ms <ms>
+      how long a server may not answer before it is considered down (default: 5000).
+  --failover-timeout-ms <ms>
+      how long a failover may take before another one is tried (default: 30000).
+  --auth-user <name> --auth-pass <password>
+      user and password to log in to the watched servers with.
+  --loglevel <filter>
+      what to log: error, warn, info, debug or trace, or per module like warn,mini_mcache::replication=debug (default: info).
+  --log-format <text|pretty|json>
+      one line per event, several indented lines, or one JSON object per line (default: text).
+  --logfile <path>
+      log to this file instead of stderr.
+  --log-rotation <never|hourly|daily>
+      start a new log file, named after the date, every hour or day (default: never).
+"#;
+
+/// Channel of the watched servers sentinels announce themselves on.
+pub const HELLO_CHANNEL: &str = "__sentinel__:hello";
+/// Longest wait for a server or another sentinel to answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// Longest random pause before asking for votes, so sentinels that saw a failure together do not all run.
+const MAX_DESYNC: Duration = Duration::from_secs(1);
+
+/// A primary to watch, from `--monitor`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Monitored {
+    pub name: String,
+    /// host:port
+    pub addr: String,
+    pub quorum: usize
+}
+
+/// Settings of the sentinel, read from the command line.
+#[derive(Debug, Clone)]
+pub struct SentinelConfig {
+    pub port: u16,
+    /// Address the listener binds to.
+    pub bind: IpAddr,
+    /// Address given to the other sentinels, the bind address when none.
+    pub announce_ip: Option<IpAddr>,
+    pub monitors: Vec<Monitored>,
+    pub down_after: Duration,
+    pub failover_timeout: Duration,
+    pub auth_user: Option<String>,
+    pub auth_pass: Option<String>,
+    pub log: LogConfig
+}
+
+impl Default for SentinelConfig {
+    fn default() -> Self {
+        SentinelConfig {
+            port: 26379,
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            announce_ip: None,
+            monitors: Vec::new(),
+            down_after: Duration::from_secs(5),
+            failover_timeout: Duration::from_secs(30),
+            auth_user: None,
+            auth_pass: None,
+            log: LogConfig::default()
+        }
+    }
+}
+
+impl SentinelConfig {
+    /// Builds the config from the arguments after the program name.
+    pub fn new(mut args: impl Iterator<Item = String>) -> Result<SentinelConfig, MainError> {
+        let mut config = SentinelConfig::default();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| MainError::BadCommandFormat(format!("{} needs a value", arg)));
+            match arg.as_str() {
+                "--port" => config.port = number(&value()?)?,
+                "--bind" => config.bind = ip(&value()?)?,
+                "--announce-ip" => config.announce_ip = Some(ip(&value()?)?),
+                "--monitor" => {
+                    let name = value()?;
+                    let host = value()?;
+                    let port: u16 = number(&value()?)?;
+                    let quorum = number(&value()?)?;
+                    if quorum == 0 {
+                        return Err(MainError::BadCommandFormat(String::from("The quorum of --monitor is at least 1")));
+                    }
+                    config.monitors.retain(|m| m.name != name);
+                    config.monitors.push(Monitored { name, addr: format!("{}:{}", host, port), quorum });
+                },
+                "--down-after-ms" => config.down_after = Duration::from_millis(number(&value()?)?),
+                "--failover-timeout-ms" => config.failover_timeout = Duration::from_millis(number(&value()?)?),
+                "--auth-user" => config.auth_user = Some(value()?),
+                "--auth-pass" => config.auth_pass = Some(value()?),
+                _ => if !config.log.option(&arg, value)? {
+                    return Err(MainError::BadCommandFormat(format!("Unknown option {}\n{}", arg, USAGE)))
+                }
+            }
+        }
+        if config.monitors.is_empty() {
+            return Err(MainError::BadCommandFormat(format!("Nothing to watch, give at least one --monitor\n{}", USAGE)));
+        }
+        if config.announce_ip.unwrap_or(config.bind).is_unspecified() {
+            return Err(MainError::BadCommandFormat(format!("--bind {} needs --announce-ip, the other sentinels cannot reach that address", config.bind)));
+        }
+        Ok(config)
+    }
+    /// ip:port the other sentinels reach this one at.
+    pub fn announced_addr(&self) -> String {
+        SocketAddr::new(self.announce_ip.unwrap_or(self.bind), self.port).to_string()
+    }
+}
+
+fn ip(value: &str) -> Result<IpAddr, MainError> {
+    value.parse().map_err(|_| MainError::BadCommandFormat(format!("{} is not an IP address", value)))
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> Result<T, MainError> {
+    value.parse().map_err(|_| MainError::BadCommandFormat(format!("{} is not a valid number", value)))
+}
+
+/// What a server said about itself in its last `info replication`.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Report {
+    /// host:port it follows, None for a primary
+    primary: Option<String>,
+    offset: u64,
+    /// host:port of the replicas of a primary
+    replicas: Vec<String>
+}
+
+impl Report {
+    fn parse(info: &str) -> Report {
+        let fields: HashMap<&str, &str> = info.lines().filter_map(|line| line.split_once(':')).collect();
+        let primary = match (fields.get("role"), fields.get("primary_host"), fields.get("primary_port")) {
+            (Some(&"replica"), Some(host), Some(port)) => Some(format!("{}:{}", host, port)),
+            _ => None
+        };
+        let offset = fields.get(if primary.is_some() { "replica_repl_offset" } else { "repl_offset" });
+        let mut replicas: Vec<String> = fields.iter()
+            .filter(|(key, _)| key.strip_prefix("replica").is_some_and(|n| n.parse::<usize>().is_ok()))
+            .filter_map(|(_, value)| {
+                let replica: HashMap<&str, &str> = value.split(',').filter_map(|field| field.split_once('=')).collect();
+                // Replicas that did not say which port they listen on cannot be reached
+                Some(format!("{}:{}", replica.get("ip")?, replica.get("port").filter(|port| **port != "0")?))
+            })
+            .collect();
+        replicas.sort();
+        Report { primary, offset: offset.and_then(|o| o.parse().ok()).unwrap_or(0), replicas }
+    }
+}
+
+/// A watched server.
+#[derive(Debug)]
+struct Instance {
+    /// Last time it answered, or when it was found
+    seen: Instant,
+    report: Option<Report>
+}
+
+impl Instance {
+    fn new() -> Instance {
+        Instance { seen: Instant::now(), report: None }
+    }
+}
+
+/// A primary, its replicas and the other sentinels watching it.
+#[derive(Debug)]
+struct Monitor {
+    quorum: usize,
+    /// host:port
+    primary: String,
+    /// Epoch of the failover that made primary the primary, 0 for the one given on the command line
+    config_epoch: u64,
+    /// The primary and its replicas by host:port, a former primary stays to be pointed at the new one
+    instances: HashMap<String, Instance>,
+    /// host:port of the other sentinels by run id
+    sentinels: HashMap<String, String>,
+    /// Down for the quorum
+    odown: bool,
+    /// Run id and epoch of the sentinel voted for to fail over
+    leader: Option<(String, u64)>,
+    /// Last failover tried or voted for, no other one starts before the failover timeout
+    failover_at: Option<Instant>
+}
+
+impl Monitor {
+    fn new(monitored: &Monitored) -> Monitor {
+        Monitor {
+            quorum: monitored.quorum,
+            primary: monitored.addr.clone(),
+            config_epoch: 0,
+            instances: HashMap::from([(monitored.addr.clone(), Instance::new())]),
+            sentinels: HashMap::new(),
+            odown: false,
+            leader: None,
+            failover_at: None
+        }
+    }
+    /// True when addr did not answer for longer than after.
+    fn down(&self, addr: &str, after: Duration) -> bool {
+        self.instances.get(addr).is_none_or(|instance| instance.seen.elapsed() > after)
+    }
+    /// Takes primary as the primary since epoch.
+    fn switch(&mut self, primary: String, epoch: u64) {
+        self.instances.entry(primary.clone()).or_insert_with(Instance::new);
+        self.primary = primary;
+        self.config_epoch = epoch;
+        self.odown = false;
+    }
+    fn flags(&self, addr: &str, after: Duration) -> String {
+        let mut flags = String::from(if addr == self.primary { "primary" } else { "replica" });
+        if self.down(addr, after) {
+            flags.push_str(",s_down");
+        }
+        if addr == self.primary && self.odown {
+            flags.push_str(",o_down");
+        }
+        flags
+    }
+}
+
+/// Splits host:port.
+fn host_port(addr: &str) -> (&str, &str) {
+    addr.rsplit_once(':').unwrap_or((addr, ""))
+}
+
+/// A random pause of at most max.
+fn jitter(max: Duration) -> Duration {
+    let random = u64::from_str_radix(&replication::new_replid()[..12], 16).unwrap_or(0);
+    Duration::from_millis(random % (max.as_millis() as u64).max(1))
+}
+
+/// The state of a sentinel, shared by its connections and the tasks watching servers.
+#[derive(Debug)]
+pub struct Sentinel {
+    runid: String,
+    /// host:port the other sentinels reach this one at
+    addr: String,
+    config: SentinelConfig,
+    /// Highest epoch seen, a failover runs in the next one
+    epoch: Mutex<u64>,
+    monitors: HashMap<String, Mutex<Monitor>>
+}
+
+impl Sentinel {
+    pub fn new(config: SentinelConfig) -> Sentinel {
+        let monitors = config.monitors.iter().map(|m| (m.name.clone(), Mutex::new(Monitor::new(m)))).collect();
+        Sentinel {
+            runid: replication::new_replid(),
+            addr: config.announced_addr(),
+            config,
+            epoch: Mutex::new(0),
+            monitors
+        }
+    }
+    pub fn runid(&self) -> &str {
+        &self.runid
+    }
+    fn epoch(&self) -> MutexGuard<'_, u64> {
+        self.epoch.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// The monitor named name, which has to exist.
+    fn monitor(&self, name: &str) -> MutexGuard<'_, Monitor> {
+        self.monitors[name].lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// How often servers are asked for their state.
+    fn period(&self) -> Duration {
+        (self.config.down_after / 4).clamp(Duration::from_millis(100), Duration::from_secs(1))
+    }
+    /// Starts watching every primary.
+    pub fn start(self: &Arc<Self>) {
+        for name in self.monitors.keys() {
+            tokio::spawn(self.clone().watch_primary(name.clone()));
+        }
+    }
+    /// Watches every server of a primary as it is found, and fails the primary over once it is down.
+    async fn watch_primary(self: Arc<Self>, name: String) {
+        let mut watched = HashSet::new();
+        loop {
+            let found: Vec<String> = self.monitor(&name).instances.keys().cloned().collect();
+            for addr in found {
+                if watched.insert(addr.clone()) {
+                    tokio::spawn(self.clone().watch_instance(name.clone(), addr.clone()));
+                    tokio::spawn(self.clone().listen(addr));
+                }
+            }
+            time::sleep(self.period()).await;
+            if self.check(&name).await {
+                self.failover(&name).await;
+            }
+        }
+    }
+    /// Asks a server for its state until the sentinel stops.
+    async fn watch_instance(self: Arc<Self>, name: String, addr: String) {
+        let mut connection = None;
+        loop {
+            if self.probe(&name, &addr, &mut connection).await.is_err() {
+                connection = None;
+            }
+            time::sleep(self.period()).await;
+        }
+    }
+    /// Reads the state of a server, points it at the primary if it follows another one and says hello.
+    async fn probe(&self, name: &str, addr: &str, connection: &mut Option<Connection>) -> Result<(), ClientError> {
+        let connection = match connection {
+            Some(connection) => connection,
+            None => connection.insert(self.connect(addr).await?)
+        };
+        let report = match request(connection, &["info", "replication"]).await? {
+            CacheResult::Success(info) => Report::parse(&info),
+            other => return Err(ClientError::ReplyError(format!("Unexpected info reply {:?}", other)))
+        };
+        if let Some(primary) = self.reported(name, addr, report) {
+            tracing::info!(server = %addr, %primary, monitor = %name, "pointing a server at its primary");
+            let (host, port) = host_port(&primary);
+            request(connection, &["replicaof", host, port]).await?;
+        }
+        let hello = self.hello_of(name);
+        request(connection, &["publish", HELLO_CHANNEL, &hello]).await?;
+        Ok(())
+    }
+    /// Records what a server said, returns the primary it has to follow when it follows another one.
+    fn reported(&self, name: &str, addr: &str, report: Report) -> Option<String> {
+        let mut monitor = self.monitor(name);
+        if addr == monitor.primary {
+            for replica in &report.replicas {
+                monitor.instances.entry(replica.clone()).or_insert_with(Instance::new);
+            }
+        }
+        let instance = monitor.instances.entry(addr.to_string()).or_insert_with(Instance::new);
+        instance.seen = Instant::now();
+        let follows = report.primary.clone();
+        instance.report = Some(report);
+        // While the primary is down a replica may be promoted by another sentinel, its hello tells
+        if addr == monitor.primary || monitor.down(&monitor.primary, self.config.down_after) {
+            return None;
+        }
+        match follows {
+            Some(primary) if primary == monitor.primary => None,
+            _ => Some(monitor.primary.clone())
+        }
+    }
+    /// The hello message for a primary: ip,port,runid,epoch of this sentinel, then name,ip,port,epoch of the primary.
+    fn hello_of(&self, name: &str) -> String {
+        let (ip, port) = host_port(&self.addr);
+        let epoch = *self.epoch();
+        let monitor = self.monitor(name);
+        let (primary_ip, primary_port) = host_port(&monitor.primary);
+        format!("{},{},{},{},{},{},{},{}", ip, port, self.runid, epoch, name, primary_ip, primary_port, monitor.config_epoch)
+    }
+    /// Reads the hello messages published on a server until the sentinel stops.
+    async fn listen(self: Arc<Self>, addr: String) {
+        loop {
+            if let Ok(mut connection) = self.connect(&addr).await {
+                if let Ok(CacheResult::Array(_)) = request(&mut connection, &["subscribe", HELLO_CHANNEL]).await {
+                    while let Ok(reply) = connection.read_reply().await {
+                        if let Some(message) = Message::from_reply(&reply) {
+                            self.hello(&message.payload);
+                        }
+                    }
+                }
+            }
+            time::sleep(self.period()).await;
+        }
+    }
+    /// Learns another sentinel and the primary it knows from its hello message.
+    fn hello(&self, payload: &str) {
+        let fields: Vec<&str> = payload.split(',').collect();
+        let [ip, port, runid, epoch, name, primary_ip, primary_port, config_epoch] = fields[..] else {
+            return;
+        };
+        let (Ok(epoch), Ok(config_epoch)) = (epoch.parse::<u64>(), config_epoch.parse::<u64>()) else {
+            return;
+        };
+        if runid == self.runid || !self.monitors.contains_key(name) {
+            return;
+        }
+        let mut monitor = self.monitor(name);
+        let addr = format!("{}:{}", ip, port);
+        // A sentinel that restarted comes back under a new run id
+        monitor.sentinels.retain(|id, known| id == runid || *known != addr);
+        if monitor.sentinels.insert(runid.to_string(), addr.clone()).is_none() {
+            tracing::info!(%runid, sentinel = %addr, monitor = %name, "found sentinel");
+        }
+        let mut current = self.epoch();
+        *current = (*current).max(epoch);
+        let primary = format!("{}:{}", primary_ip, primary_port);
+        if config_epoch > monitor.config_epoch && primary != monitor.primary {
+            tracing::warn!(monitor = %name, %primary, epoch = config_epoch, %runid, "switching primary, as another sentinel says");
+            monitor.switch(primary, config_epoch);
+        }
+    }
+    /// Checks whether the primary is down for the quorum, true when a failover has to start.
+    async fn check(&self, name: &str) -> bool {
+        let (primary, sentinels) = {
+            let mut monitor = self.monitor(name);
+            if !monitor.down(&monitor.primary, self.config.down_after) {
+                monitor.odown = false;
+                return false;
+            }
+            (monitor.primary.clone(), monitor.sentinels.values().cloned().collect::<Vec<String>>())
+        };
+        let epoch = *self.epoch();
+        let mut agreed = 1;
+        for sentinel in &sentinels {
+            if let Ok((true, _)) = self.ask(sentinel, &primary, epoch, "*").await {
+                agreed += 1;
+            }
+        }
+        let mut monitor = self.monitor(name);
+        if monitor.primary != primary {
+            return false;
+        }
+        let odown = agreed >= monitor.quorum;
+        if odown && !monitor.odown {
+            tracing::warn!(%primary, monitor = %name, agreed, quorum = monitor.quorum, "primary is down");
+        }
+        monitor.odown = odown;
+        odown && monitor.failover_at.is_none_or(|at| at.elapsed() > self.config.failover_timeout)
+    }
+    /// Asks another sentinel whether it sees primary down, and for its vote unless runid is `*`.
+    async fn ask(&self, sentinel: &str, primary: &str, epoch: u64, runid: &str) -> Result<(bool, Option<(String, u64)>), ClientError> {
+        let mut connection = match time::timeout(REQUEST_TIMEOUT, Connection::connect(sentinel)).await {
+            Ok(connection) => connection?,
+            Err(_) => return Err(ClientError::ConnectionError(format!("Connection to {} timed out", sentinel)))
+        };
+        let (ip, port) = host_port(primary);
+        let reply = request(&mut connection, &["sentinel", "is-primary-down-by-addr", ip, port, &epoch.to_string(), runid]).await?;
+        match reply {
+            CacheResult::Array(items) if items.len() == 3 => {
+                let vote = items[2].parse().ok().filter(|_| items[1] != "*").map(|epoch| (items[1].clone(), epoch));
+                Ok((items[0] == "1", vote))
+            },
+            other => Err(ClientError::ReplyError(format!("Unexpected is-primary-down-by-addr reply {:?}", other)))
+        }
+    }
+    /// Asks for votes in a new epoch and promotes a replica once elected.
+    async fn failover(&self, name: &str) {
+        time::sleep(jitter(MAX_DESYNC)).await;
+        let (primary, sentinels, needed, epoch) = {
+            let mut monitor = self.monitor(name);
+            // Another sentinel asked for our vote meanwhile, or the primary came back
+            if !monitor.odown || monitor.failover_at.is_some_and(|at| at.elapsed() <= self.config.failover_timeout) {
+                return;
+            }
+            let mut current = self.epoch();
+            *current += 1;
+            monitor.leader = Some((self.runid.clone(), *current));
+            monitor.failover_at = Some(Instant::now());
+            // A majority of every sentinel known, this one included, and at least the quorum
+            let voters = monitor.sentinels.len() + 1;
+            let needed = monitor.quorum.max(voters / 2 + 1);
+            (monitor.primary.clone(), monitor.sentinels.values().cloned().collect::<Vec<String>>(), needed, *current)
+        };
+        let mut votes = 1;
+        for sentinel in &sentinels {
+            if let Ok((_, Some((leader, leader_epoch)))) = self.ask(sentinel, &primary, epoch, &self.runid).await {
+                if leader == self.runid && leader_epoch == epoch {
+                    votes += 1;
+                }
+            }
+        }
+        if votes < needed {
+            tracing::info!(monitor = %name, epoch, votes, needed, "not elected to fail over");
+            return;
+        }
+        tracing::warn!(monitor = %name, epoch, votes, "elected to fail over");
+        self.promote(name, &primary, epoch).await;
+    }
+    /// Promotes the most up to date replica of primary and points the others at it.
+    async fn promote(&self, name: &str, primary: &str, epoch: u64) {
+        let mut replicas: Vec<(String, u64)> = {
+            let monitor = self.monitor(name);
+            if monitor.primary != primary {
+                return;
+            }
+            monitor.instances.iter()
+                .filter(|(addr, instance)| *addr != primary && instance.seen.elapsed() <= self.config.down_after)
+                .filter_map(|(addr, instance)| instance.report.as_ref().filter(|r| r.primary.is_some()).map(|r| (addr.clone(), r.offset)))
+                .collect()
+        };
+        // Highest offset first, the address breaks ties so every sentinel would pick the same
+        replicas.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let Some((chosen, _)) = replicas.first().cloned() else {
+            tracing::error!(monitor = %name, "no replica can be promoted");
+            return;
+        };
+        let promoted = match self.connect(&chosen).await {
+            Ok(mut connection) => request(&mut connection, &["replicaof", "no", "one"]).await,
+            Err(e) => Err(e)
+        };
+        match promoted {
+            Ok(CacheResult::Success(_)) => {},
+            Ok(other) => return tracing::error!(replica = %chosen, reply = ?other, "promoting failed"),
+            Err(e) => return tracing::error!(replica = %chosen, error = %e, "promoting failed")
+        }
+        tracing::warn!(replica = %chosen, monitor = %name, epoch, "promoted replica to primary");
+        self.monitor(name).switch(chosen.clone(), epoch);
+        let (host, port) = host_port(&chosen);
+        // Replicas missed here are pointed at the new primary once they answer again
+        for (replica, _) in &replicas[1..] {
+            if let Ok(mut connection) = self.connect(replica).await {
+                let _ = request(&mut connection, &["replicaof", host, port]).await;
+            }
+        }
+    }
+    /// A connection to a watched server, logged in when a password is set.
+    async fn connect(&self, addr: &str) -> Result<Connection, ClientError> {
+        let mut connection = match time::timeout(REQUEST_TIMEOUT, Connection::connect(addr)).await {
+            Ok(connection) => connection?,
+            Err(_) => return Err(ClientError::ConnectionError(format!("Connection to {} timed out", addr)))
+        };
+        if let Some(password) = &self.config.auth_pass {
+            connection.auth(self.config.auth_user.as_deref(), password).await?;
+        }
+        Ok(connection)
+    }
+    /// Runs a command sent to the sentinel.
+    pub fn execute(&self, args: &[String]) -> CacheResult {
+        match (args[0].as_str(), &args[1..]) {
+            ("ping", []) => CacheResult::Success(String::from("PONG")),
+            ("info", []) => CacheResult::Success(self.info()),
+            ("sentinel", [action, rest @ ..]) => self.sentinel(&action.to_lowercase(), rest),
+            _ => CacheResult::error(ErrorCode::Err, format!("Unknown command {}, a sentinel runs ping, info and sentinel", args[0]))
+        }
+    }
+    fn sentinel(&self, action: &str, args: &[String]) -> CacheResult {
+        let after = self.config.down_after;
+        if let ("is-primary-down-by-addr", [ip, port, epoch, runid]) = (action, args) {
+            return match epoch.parse() {
+                Ok(epoch) => self.primary_down_by_addr(&format!("{}:{}", ip, port), epoch, runid),
+                Err(_) => CacheResult::error(ErrorCode::Syntax, format!("{} is not an epoch", epoch))
+            };
+        }
+        let monitor = match args {
+            [name] if self.monitors.contains_key(name) => Some(self.monitor(name)),
+            _ => None
+        };
+        match (action, args, monitor) {
+            ("myid", [], _) => CacheResult::Success(self.runid.clone()),
+            ("primaries", [], _) => {
+                let mut names: Vec<&String> = self.monitors.keys().collect();
+                names.sort();
+                CacheResult::Array(names.into_iter().map(|name| {
+                    protocol::encode_nested(&CacheResult::Array(describe(name, &self.monitor(name), after)))
+                }).collect())
+            },
+            ("get-primary-addr-by-name", [_], None) => CacheResult::Nil,
+            ("get-primary-addr-by-name", [_], Some(monitor)) => {
+                let (host, port) = host_port(&monitor.primary);
+                CacheResult::Array(vec![host.to_string(), port.to_string()])
+            },
+            ("primary", [name], Some(monitor)) => CacheResult::Array(describe(name, &monitor, after)),
+            ("replicas", [_], Some(monitor)) => {
+                let mut replicas: Vec<(&String, &Instance)> = monitor.instances.iter().filter(|(addr, _)| **addr != monitor.primary).collect();
+                replicas.sort_by_key(|(addr, _)| *addr);
+                CacheResult::Array(replicas.into_iter().map(|(addr, instance)| {
+                    let (ip, port) = host_port(addr);
+                    let report = instance.report.clone().unwrap_or_default();
+                    let fields = [
+                        "ip", ip, "port", port, "flags", &monitor.flags(addr, after),
+                        "primary", report.primary.as_deref().unwrap_or(""), "offset", &report.offset.to_string()
+                    ];
+                    protocol::encode_nested(&CacheResult::Array(fields.map(String::from).to_vec()))
+                }).collect())
+            },
+            ("sentinels", [_], Some(monitor)) => {
+                let mut sentinels: Vec<(&String, &String)> = monitor.sentinels.iter().collect();
+                sentinels.sort_by_key(|(_, addr)| *addr);
+                CacheResult::Array(sentinels.into_iter().map(|(runid, addr)| {
+                    let (ip, port) = host_port(addr);
+                    let fields = ["ip", ip, "port", port, "runid", runid];
+                    protocol::encode_nested(&CacheResult::Array(fields.map(String::from).to_vec()))
+                }).collect())
+            },
+            ("primary" | "replicas" | "sentinels", [name], None) => CacheResult::error(ErrorCode::Err, format!("No primary named {}", name)),
+            _ => CacheResult::error(ErrorCode::Syntax, "Use sentinel myid | primaries | primary <name> | replicas <name> | sentinels <name> \
+                | get-primary-addr-by-name <name> | is-primary-down-by-addr <ip> <port> <epoch> <runid>")
+        }
+    }
+    /// Whether the primary at addr is down for this sentinel, and the vote given in epoch to runid
+    /// unless it is `*`. Only one sentinel gets the vote of an epoch.
+    fn primary_down_by_addr(&self, addr: &str, epoch: u64, runid: &str) -> CacheResult {
+        let Some((name, monitor)) = self.monitors.iter().find(|(name, _)| self.monitor(name).primary == addr) else {
+            return CacheResult::Array(vec![String::from("0"), String::from("*"), String::from("0")]);
+        };
+        let mut monitor = monitor.lock().unwrap_or_else(|e| e.into_inner());
+        let down = monitor.down(addr, self.config.down_after);
+        if runid == "*" {
+            return CacheResult::Array(vec![(down as u8).to_string(), String::from("*"), String::from("0")]);
+        }
+        let mut current = self.epoch();
+        *current = (*current).max(epoch);
+        if *current == epoch && monitor.leader.as_ref().is_none_or(|(_, voted)| *voted < epoch) {
+            tracing::info!(%runid, monitor = %name, epoch, "voted for a sentinel to fail over");
+            monitor.leader = Some((runid.to_string(), epoch));
+            // The elected sentinel gets the time to fail over before this one tries
+            if runid != self.runid {
+                monitor.failover_at = Some(Instant::now());
+            }
+        }
+        let (leader, voted) = monitor.leader.clone().unwrap_or_default();
+        CacheResult::Array(vec![(down as u8).to_string(), leader, voted.to_string()])
+    }
+    /// The sentinel section of info.
+    fn info(&self) -> String {
+        let mut names: Vec<&String> = self.monitors.keys().collect();
+        names.sort();
+        let mut text = String::from("# Sentinel\n");
+        text.push_str(&format!("sentinel_runid:{}\n", self.runid));
+        text.push_str(&format!("sentinel_primaries:{}\n", names.len()));
+        text.push_str(&format!("current_epoch:{}\n", *self.epoch()));
+        for (i, name) in names.into_iter().enumerate() {
+            let monitor = self.monitor(name);
+            let status = match (monitor.odown, monitor.down(&monitor.primary, self.config.down_after)) {
+                (true, _) => "odown",
+                (false, true) => "sdown",
+                _ => "ok"
+            };
+            text.push_str(&format!("primary{}:name={},status={},address={},replicas={},sentinels={}\n", i, name, status,
+                monitor.primary, monitor.instances.len() - 1, monitor.sentinels.len() + 1));
+        }
+        text
+    }
+}
+
+/// Field and value pairs describing a primary for `sentinel primary`.
+fn describe(name: &str, monitor: &Monitor, after: Duration) -> Vec<String> {
+    let (ip, port) = host_port(&monitor.primary);
+    [
+        ("name", name.to_string()),
+        ("ip", ip.to_string()),
+        ("port", port.to_string()),
+        ("flags", monitor.flags(&monitor.primary, after)),
+        ("num-replicas", (monitor.instances.len() - 1).to_string()),
+        ("num-other-sentinels", monitor.sentinels.len().to_string()),
+        ("quorum", monitor.quorum.to_string()),
+        ("config-epoch", monitor.config_epoch.to_string())
+    ].into_iter().flat_map(|(field, value)| [field.to_string(), value]).collect()
+}
+
+/// Sends a command and waits for its reply, for at most [`REQUEST_TIMEOUT`].
+async fn request(connection: &mut Connection, args: &[&str]) -> Result<CacheResult, ClientError> {
+    match time::timeout(REQUEST_TIMEOUT, connection.execute(args)).await {
+        Ok(reply) => reply,
+        Err(_) => Err(ClientError::ConnectionError(format!("No reply to {} in time", args[0])))
+    }
+}
+
+/// Asks the sentinels in turn for the host:port of the primary named name, the first that knows it answers.
+pub async fn primary_addr<S: AsRef<str>>(sentinels: &[S], name: &str) -> Result<String, ClientError> {
+    let mut error = ClientError::ConnectionError(String::from("No sentinel to ask"));
+    for sentinel in sentinels {
+        let sentinel = sentinel.as_ref();
+        let reply = match time::timeout(REQUEST_TIMEOUT, Connection::connect(sentinel)).await {
+            Ok(Ok(mut connection)) => request(&mut connection, &["sentinel", "get-primary-addr-by-name", name]).await,
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(ClientError::ConnectionError(format!("Connection to {} timed out", sentinel)))
+        };
+        error = match reply {
+            Ok(CacheResult::Array(items)) if items.len() == 2 => return Ok(format!("{}:{}", items[0], items[1])),
+            Ok(CacheResult::Nil) => ClientError::ReplyError(format!("Sentinel {} watches no primary named {}", sentinel, name)),
+            Ok(CacheResult::Failure(e)) => ClientError::ServerError(e),
+            Ok(other) => ClientError::ReplyError(format!("Unexpected get-primary-addr-by-name reply {:?}", other)),
+            Err(e) => e
+        };
+    }
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentinels_vote_once_per_epoch() {
+        let args = ["--monitor", "cache", "127.0.0.1", "6400", "2", "--down-after-ms", "500"].map(String::from);
+        let config = SentinelConfig::new(args.into_iter()).unwrap();
+        assert_eq!(config.monitors[0].addr, "127.0.0.1:6400");
+        assert_eq!(config.down_after, Duration::from_millis(500));
+        assert!(SentinelConfig::new(["--port", "26380"].map(String::from).into_iter()).is_err());
+        assert!(SentinelConfig::new(["--monitor", "cache", "127.0.0.1", "6400", "0"].map(String::from).into_iter()).is_err());
+        let sentinel = Sentinel::new(config);
+        let ask = |epoch: &str, runid: &str| {
+            let args = ["sentinel", "is-primary-down-by-addr", "127.0.0.1", "6400", epoch, runid].map(String::from);
+            sentinel.execute(&args)
+        };
+        let reply = |down: &str, leader: &str, epoch: &str| CacheResult::Array([down, leader, epoch].map(String::from).to_vec());
+        assert_eq!(ask("1", "*"), reply("0", "*", "0"));
+        assert_eq!(ask("1", "a"), reply("0", "a", "1"));
+        assert_eq!(ask("1", "b"), reply("0", "a", "1"));
+        assert_eq!(ask("2", "b"), reply("0", "b", "2"));
+        // An older epoch gets the vote already given
+        assert_eq!(ask("1", "c"), reply("0", "b", "2"));
+        let other = ["sentinel", "is-primary-down-by-addr", "127.0.0.1", "6401", "3", "c"].map(String::from);
+        assert_eq!(sentinel.execute(&other), reply("0", "*", "0"));
+        let addr = ["sentinel", "get-primary-addr-by-name", "cache"].map(String::from);
+        assert_eq!(sentinel.execute(&addr), CacheResult::Array(["127.0.0.1", "6400"].map(String::from).to_vec()));
+    }
+
+    #[test]
+    fn bind_and_announce_ip() {
+        let config = |args: &[&str]| SentinelConfig::new(["--monitor", "cache", "127.0.0.1", "6400", "2"].iter().chain(args).map(|arg| arg.to_string()));
+        assert_eq!(config(&[]).unwrap().announced_addr(), "127.0.0.1:26379");
+        let bound = config(&["--bind", "0.0.0.0", "--port", "26380", "--announce-ip", "10.0.0.5"]).unwrap();
+        assert_eq!(bound.bind, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(bound.announced_addr(), "10.0.0.5:26380");
+        assert_eq!(Sentinel::new(bound).addr, "10.0.0.5:26380");
+        // The other sentinels cannot reach every interface
+        assert!(config(&["--bind", "0.0.0.0"]).is_err());
+        assert!(config(&["--announce-ip", "sentinel-1"]).is_err());
+    }
+}
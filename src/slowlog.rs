@@ -0,0 +1,176 @@
+//! The slow log: the last commands that ran longer than `--slowlog-log-slower-than`, read with
+//! `slowlog get`. Only the time spent running the command counts, not reading or replying.
+
+use std::{collections::VecDeque, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::{config::Config, models::ErrorCode, protocol, CacheResult};
+
+/// Arguments kept per entry, the last one then tells how many were left out.
+pub const MAX_ARGS: usize = 32;
+/// Characters kept per argument.
+pub const MAX_ARG_LEN: usize = 128;
+/// Entries `slowlog get` replies without a count.
+const DEFAULT_GET: usize = 10;
+
+/// A command that ran longer than the threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Keeps counting after a reset
+    pub id: u64,
+    /// Unix seconds when the command was logged
+    pub timestamp: u64,
+    pub duration: Duration,
+    /// The command and its arguments, truncated
+    pub args: Vec<String>,
+    /// Address of the client, ip:port or the path of the unix socket
+    pub client: String
+}
+
+impl Entry {
+    /// id, timestamp, microseconds, arguments and client, like in Redis.
+    fn reply(&self) -> String {
+        protocol::encode_nested(&CacheResult::Array(vec![
+            self.id.to_string(),
+            self.timestamp.to_string(),
+            self.duration.as_micros().to_string(),
+            protocol::encode_nested(&CacheResult::Array(self.args.clone())),
+            self.client.clone()
+        ]))
+    }
+}
+
+/// The newest entries, at most max_len of them.
+#[derive(Debug)]
+pub struct SlowLog {
+    /// None when the log is off
+    threshold: Option<Duration>,
+    max_len: usize,
+    /// Newest first
+    entries: Mutex<VecDeque<Entry>>,
+    next_id: AtomicU64
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        SlowLog::new(&Config::default())
+    }
+}
+
+impl SlowLog {
+    pub fn new(config: &Config) -> SlowLog {
+        SlowLog {
+            // Negative turns the log off, 0 logs every command
+            threshold: u64::try_from(config.slowlog_log_slower_than).ok().map(Duration::from_micros),
+            max_len: config.slowlog_max_len,
+            entries: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0)
+        }
+    }
+    /// Logs the command when it took at least the threshold.
+    pub fn record<S: AsRef<str>>(&self, args: &[S], client: &str, duration: Duration) {
+        if self.max_len == 0 || self.threshold.is_none_or(|threshold| duration < threshold) {
+            return;
+        }
+        let entry = Entry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            duration,
+            args: truncate(args),
+            client: client.to_string()
+        };
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.push_front(entry);
+        entries.truncate(self.max_len);
+    }
+    /// The count newest entries, newest first.
+    pub fn get(&self, count: usize) -> Vec<Entry> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.iter().take(count).cloned().collect()
+    }
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn reset(&self) {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+    /// Runs `slowlog get [count] | len | reset`.
+    pub fn command(&self, args: &[String]) -> CacheResult {
+        match (args[1].to_lowercase().as_str(), &args[2..]) {
+            ("get", []) => CacheResult::Array(self.get(DEFAULT_GET).iter().map(Entry::reply).collect()),
+            // -1 is every entry, like in Redis
+            ("get", [count]) => match count.parse::<i64>() {
+                Ok(-1) => CacheResult::Array(self.get(usize::MAX).iter().map(Entry::reply).collect()),
+                Ok(count) if count >= 0 => CacheResult::Array(self.get(count as usize).iter().map(Entry::reply).collect()),
+                _ => CacheResult::error(ErrorCode::Err, format!("{} is not a count, use a number or -1 for every entry", count))
+            },
+            ("len", []) => CacheResult::Success(self.len().to_string()),
+            ("reset", []) => {
+                self.reset();
+                CacheResult::Success(String::from("OK"))
+            },
+            _ => CacheResult::error(ErrorCode::Syntax, "Use slowlog get [count] | len | reset")
+        }
+    }
+}
+
+/// The arguments without passwords: auth keeps only its name and acl its subcommand.
+pub fn redact<S: AsRef<str>>(args: &[S]) -> &[S] {
+    match args.first().map(|name| name.as_ref().to_lowercase()).as_deref() {
+        Some("auth") => &args[..1],
+        Some("acl") => &args[..args.len().min(2)],
+        _ => args
+    }
+}
+
+/// At most [`MAX_ARGS`] arguments of at most [`MAX_ARG_LEN`] characters, saying how much was cut.
+fn truncate<S: AsRef<str>>(args: &[S]) -> Vec<String> {
+    let args = redact(args);
+    let kept = if args.len() > MAX_ARGS { MAX_ARGS - 1 } else { args.len() };
+    let mut truncated: Vec<String> = args[..kept].iter().map(|arg| {
+        let arg = arg.as_ref();
+        match arg.char_indices().nth(MAX_ARG_LEN) {
+            Some((end, _)) => format!("{}... ({} more bytes)", &arg[..end], arg.len() - end),
+            None => arg.to_string()
+        }
+    }).collect();
+    if kept < args.len() {
+        truncated.push(format!("... ({} more arguments)", args.len() - kept));
+    }
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slowlog_keeps_the_newest_slow_commands_truncated() {
+        let config = Config { slowlog_log_slower_than: 1000, slowlog_max_len: 2, ..Config::default() };
+        let slowlog = SlowLog::new(&config);
+        slowlog.record(&["get", "fast"], "127.0.0.1:1", Duration::from_micros(999));
+        assert!(slowlog.is_empty());
+        let long = "x".repeat(200);
+        let many: Vec<String> = std::iter::once(String::from("sadd")).chain((0..40).map(|n| n.to_string())).collect();
+        slowlog.record(&["set", "key", long.as_str()], "127.0.0.1:1", Duration::from_millis(1));
+        slowlog.record(&many, "127.0.0.1:2", Duration::from_millis(5));
+        slowlog.record(&["auth", "admin", "secret"], "127.0.0.1:3", Duration::from_millis(2));
+        assert_eq!(slowlog.len(), 2);
+        let entries = slowlog.get(10);
+        assert_eq!((entries[0].id, entries[0].args.clone(), entries[0].client.as_str()), (2, vec![String::from("auth")], "127.0.0.1:3"));
+        assert_eq!(entries[1].duration, Duration::from_millis(5));
+        assert_eq!(entries[1].args.len(), MAX_ARGS);
+        assert_eq!(entries[1].args[MAX_ARGS - 1], "... (10 more arguments)");
+        slowlog.reset();
+        slowlog.record(&["set", "key", long.as_str()], "127.0.0.1:1", Duration::from_millis(1));
+        let entry = &slowlog.get(1)[0];
+        assert_eq!(entry.id, 3);
+        assert_eq!(entry.args[2], format!("{}... (72 more bytes)", "x".repeat(MAX_ARG_LEN)));
+        // Off when negative
+        let off = SlowLog::new(&Config { slowlog_log_slower_than: -1, ..Config::default() });
+        off.record(&["get", "key"], "127.0.0.1:1", Duration::from_secs(10));
+        assert!(off.is_empty());
+    }
+}
@@ -0,0 +1,330 @@
+//! Counters reported by `info` and the metrics: uptime, clients, commands and their latency,
+//! keyspace hits and misses, fsync latency, and the memory and keys held by the shards.
+
+use std::{mem, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use bytes::Bytes;
+
+use crate::{commands::{CommandSpec, COMMANDS}, config::Config, models::{Memory, Position}, CHANGE_CMD};
+
+/// Upper bounds of the latency buckets, in seconds.
+pub const BUCKETS: [f64; 12] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+/// Durations counted in the [`BUCKETS`] they fit, plus one for the slower ones.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    /// Microseconds of every observation
+    sum: AtomicU64
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+    /// The observations at or below each bound of [`BUCKETS`], the last one being all of them.
+    pub fn cumulative(&self) -> Vec<u64> {
+        self.buckets.iter().scan(0, |total, count| {
+            *total += count.load(Ordering::Relaxed);
+            Some(*total)
+        }).collect()
+    }
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
+    /// Seconds of every observation.
+    pub fn sum(&self) -> f64 {
+        self.sum.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    }
+}
+
+/// What happened since the server started, shared by the connections and the memory.
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    /// Unix seconds of the start
+    start_time: u64,
+    tcp_port: u16,
+    cluster_enabled: bool,
+    commands: AtomicU64,
+    connections_received: AtomicU64,
+    connected_clients: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+    /// Calls and latency by position in [`COMMANDS`]
+    latency: Vec<Histogram>,
+    /// Flushes of the backup file to the disk
+    pub fsync: Histogram,
+    /// The unix second being counted, the commands in it and in the one before
+    ops: Mutex<(u64, u64, u64)>
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new(&Config::default())
+    }
+}
+
+impl Stats {
+    pub fn new(config: &Config) -> Stats {
+        Stats {
+            started: Instant::now(),
+            start_time: unix_seconds(),
+            tcp_port: config.port,
+            cluster_enabled: config.cluster_enabled,
+            commands: AtomicU64::new(0),
+            connections_received: AtomicU64::new(0),
+            connected_clients: AtomicUsize::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            latency: COMMANDS.iter().map(|_| Histogram::default()).collect(),
+            fsync: Histogram::default(),
+            ops: Mutex::new((0, 0, 0))
+        }
+    }
+    /// Counts a command received, whether it runs or is refused.
+    pub fn command(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        let mut ops = self.ops.lock().unwrap_or_else(|e| e.into_inner());
+        Stats::roll(&mut ops);
+        ops.1 += 1;
+    }
+    /// Times a known command until the returned guard is dropped.
+    pub fn time(&self, spec: &CommandSpec) -> CommandTimer<'_> {
+        let index = COMMANDS.iter().position(|known| std::ptr::eq(known, spec)).unwrap_or(0);
+        CommandTimer { histogram: &self.latency[index], started: Instant::now() }
+    }
+    /// The commands called at least once, with their latency.
+    pub fn command_latency(&self) -> impl Iterator<Item = (&'static str, &Histogram)> {
+        COMMANDS.iter().zip(&self.latency)
+            .filter(|(_, histogram)| histogram.count() > 0)
+            .map(|(spec, histogram)| (spec.name, histogram))
+    }
+    /// Moves the count to the current second.
+    fn roll(ops: &mut (u64, u64, u64)) {
+        let now = unix_seconds();
+        if ops.0 != now {
+            // Nothing was counted in the second before a gap
+            ops.2 = if ops.0 + 1 == now { ops.1 } else { 0 };
+            ops.1 = 0;
+            ops.0 = now;
+        }
+    }
+    /// Commands run during the last full second.
+    pub fn ops_per_sec(&self) -> u64 {
+        let mut ops = self.ops.lock().unwrap_or_else(|e| e.into_inner());
+        Stats::roll(&mut ops);
+        ops.2
+    }
+    /// A read that found its key.
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+    /// A read of a key that does not exist.
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+    /// A key deleted once past its expiry time.
+    pub fn expired(&self) {
+        self.expired.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Counts a connection, until the returned guard is dropped.
+    pub fn connected(self: &Arc<Self>) -> ClientGuard {
+        self.connections_received.fetch_add(1, Ordering::Relaxed);
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        ClientGuard { stats: self.clone() }
+    }
+    pub fn keyspace_hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+    pub fn keyspace_misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+    pub fn expired_keys(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+    pub fn connected_clients(&self) -> usize {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+    pub fn connections_received(&self) -> u64 {
+        self.connections_received.load(Ordering::Relaxed)
+    }
+    pub fn commands_processed(&self) -> u64 {
+        self.commands.load(Ordering::Relaxed)
+    }
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+    /// The server section of info.
+    pub fn server_info(&self) -> String {
+        let uptime = self.started.elapsed().as_secs();
+        let mut text = String::from("# Server\n");
+        text.push_str(&format!("version:{}\n", env!("CARGO_PKG_VERSION")));
+        text.push_str(&format!("mode:{}\n", if self.cluster_enabled { "cluster" } else { "standalone" }));
+        text.push_str(&format!("process_id:{}\n", std::process::id()));
+        text.push_str(&format!("tcp_port:{}\n", self.tcp_port));
+        text.push_str(&format!("start_time:{}\n", self.start_time));
+        text.push_str(&format!("uptime_in_seconds:{}\n", uptime));
+        text.push_str(&format!("uptime_in_days:{}\n", uptime / 86400));
+        text
+    }
+    /// The clients section of info.
+    pub fn clients_info(&self) -> String {
+        format!("# Clients\nconnected_clients:{}\n", self.connected_clients())
+    }
+    /// The stats section of info.
+    pub fn stats_info(&self) -> String {
+        let mut text = String::from("# Stats\n");
+        text.push_str(&format!("total_connections_received:{}\n", self.connections_received()));
+        text.push_str(&format!("total_commands_processed:{}\n", self.commands_processed()));
+        text.push_str(&format!("instantaneous_ops_per_sec:{}\n", self.ops_per_sec()));
+        text.push_str(&format!("keyspace_hits:{}\n", self.keyspace_hits()));
+        text.push_str(&format!("keyspace_misses:{}\n", self.keyspace_misses()));
+        text.push_str(&format!("expired_keys:{}\n", self.expired_keys()));
+        text
+    }
+}
+
+/// Held by a connection while it is open, see [`Stats::connected`].
+pub struct ClientGuard {
+    stats: Arc<Stats>
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.stats.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Held while a command runs, see [`Stats::time`].
+pub struct CommandTimer<'a> {
+    histogram: &'a Histogram,
+    started: Instant
+}
+
+impl Drop for CommandTimer<'_> {
+    fn drop(&mut self) {
+        self.histogram.observe(self.started.elapsed());
+    }
+}
+
+/// Bytes held by the shards, see [`usage`].
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Usage {
+    /// Lines loaded from or written to the backup file
+    pub buffer: usize,
+    /// Space reserved for the buffers
+    pub buffer_allocated: usize,
+    /// Values not yet in the backup file
+    pub recent: usize,
+    /// Keys and entries of the maps
+    pub maps: usize
+}
+
+impl Usage {
+    pub fn total(&self) -> usize {
+        self.buffer_allocated + self.recent + self.maps
+    }
+}
+
+/// Adds up the memory of every shard. Map entries are counted for the space the maps reserve
+/// plus the keys themselves, so it is an estimate.
+pub async fn usage(memory: &Memory) -> Usage {
+    let mut usage = Usage::default();
+    for shard in &memory.shards {
+        let shard = shard.read().await;
+        usage.buffer += shard.buffer.len();
+        usage.buffer_allocated += shard.buffer.capacity();
+        usage.recent += shard.recent.values().map(Bytes::len).sum::<usize>();
+        usage.maps += shard.item.capacity() * mem::size_of::<(String, Position)>() + shard.item.keys().map(String::len).sum::<usize>();
+        usage.maps += shard.recent.capacity() * mem::size_of::<(String, Bytes)>() + shard.recent.keys().map(String::len).sum::<usize>();
+        usage.maps += shard.expires.capacity() * mem::size_of::<(String, u64)>() + shard.expires.keys().map(String::len).sum::<usize>();
+    }
+    usage
+}
+
+/// The memory section of info.
+pub async fn memory_info(memory: &Memory) -> String {
+    let usage = usage(memory).await;
+    let mut text = String::from("# Memory\n");
+    text.push_str(&format!("used_memory:{}\n", usage.total()));
+    text.push_str(&format!("used_memory_human:{}\n", human(usage.total())));
+    text.push_str(&format!("used_memory_buffer:{}\n", usage.buffer));
+    text.push_str(&format!("used_memory_buffer_allocated:{}\n", usage.buffer_allocated));
+    text.push_str(&format!("used_memory_recent:{}\n", usage.recent));
+    text.push_str(&format!("used_memory_maps:{}\n", usage.maps));
+    text
+}
+
+/// Keys not past their expiry time by type: strings, hashes and sets, and how many of them expire.
+pub async fn key_counts(memory: &Memory) -> [usize; 4] {
+    let mut counts = [0; 4];
+    for shard in &memory.shards {
+        let shard = shard.read().await;
+        for command_key in shard.item.keys().chain(shard.recent.keys()) {
+            let Some((action, key)) = command_key.split_once('\t') else {
+                continue;
+            };
+            if shard.expired(key) {
+                continue;
+            }
+            if let Some(kind) = CHANGE_CMD.iter().position(|cmd| *cmd == action) {
+                counts[kind] += 1;
+            }
+        }
+        counts[3] += shard.expires.keys().filter(|key| !shard.expired(key)).count();
+    }
+    counts
+}
+
+/// The keyspace section of info.
+pub async fn keyspace_info(memory: &Memory) -> String {
+    let [strings, hashes, sets, expires] = key_counts(memory).await;
+    let mut text = String::from("# Keyspace\n");
+    text.push_str(&format!("db0:keys={},expires={}\n", strings + hashes + sets, expires));
+    text.push_str(&format!("keys_string:{}\n", strings));
+    text.push_str(&format!("keys_hash:{}\n", hashes));
+    text.push_str(&format!("keys_set:{}\n", sets));
+    text
+}
+
+/// Bytes with a K, M or G suffix, e.g. `1.50M`.
+fn human(bytes: usize) -> String {
+    let units = [("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+    match units.iter().find(|(_, size)| bytes >= *size) {
+        Some((unit, size)) => format!("{:.2}{}", bytes as f64 / *size as f64, unit),
+        None => format!("{}B", bytes)
+    }
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_count_durations_in_cumulative_buckets() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(50));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(2));
+        let counts = histogram.cumulative();
+        assert_eq!(counts.len(), BUCKETS.len() + 1);
+        assert_eq!(counts[0], 1);
+        // 3ms is above the 2.5ms bound and within 5ms
+        assert_eq!(counts[4], 1);
+        assert_eq!(counts[5], 2);
+        assert_eq!(counts[BUCKETS.len() - 1], 2);
+        assert_eq!(counts[BUCKETS.len()], 3);
+        assert_eq!(histogram.count(), 3);
+        assert!((histogram.sum() - 2.00305).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,409 @@
+use std::{path::PathBuf, sync::{Arc, Weak}, time::Duration};
+
+use tokio::{runtime::Handle, sync::mpsc::{self, Sender, WeakSender}, task::{self, JoinHandle}, time};
+
+use crate::{acl::Caller, cluster::{self, Cluster}, commands, config::Config, models::{CacheError, ErrorCode, MainError, Memory, Pipe}, monitor::Monitors, notify::Notifier, persistence::{self, Persistence}, protocol, pubsub::PubSub, replication::{self, Replication}, scripting::Scripts, slowlog::SlowLog, stats::{self, Stats}, transaction::Watch, Cache, CacheResult, Command, ShutdownMode};
+
+/// How often keys past their expiry time are deleted.
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An in-process cache: the same memory, backup file and commands as the server, without TCP.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), mini_mcache::models::MainError> {
+/// let store = mini_mcache::Store::open("/tmp/_data.bin")?;
+/// store.set("name", "makuo").await;
+/// store.hset("person", &[("name", "makuo"), ("age", "25")]).await;
+/// let raw = store.execute(&["smembers", "humans"]).await;
+/// store.close(mini_mcache::ShutdownMode::Save).await?;
+/// # Ok(()) }
+/// ```
+///
+/// Writes are appended to the backup file by a background task, so a store has to be
+/// created inside a tokio runtime. Call [`Store::close`] to wait for them to reach the disk.
+pub struct Store {
+    memory: Arc<Memory>,
+    tx: Sender<Pipe>,
+    persistence: Arc<Persistence>,
+    scripts: Arc<Scripts>,
+    /// Set in cluster mode.
+    cluster: Option<Arc<Cluster>>,
+    /// Connections that asked for every command with monitor.
+    monitors: Arc<Monitors>,
+    writer: JoinHandle<Result<(), MainError>>,
+    expirer: JoinHandle<()>
+}
+
+impl Store {
+    /// Opens the store backed by the file at path, loading what it already holds.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Store, MainError> {
+        Store::with_config(path, &Config::default())
+    }
+    pub fn with_config(path: impl Into<PathBuf>, config: &Config) -> Result<Store, MainError> {
+        let path = path.into();
+        let cluster = match config.cluster_enabled {
+            true => {
+                let nodes = config.cluster_config_file.clone().unwrap_or_else(|| path.with_extension("nodes"));
+                Some(Arc::new(Cluster::open(nodes, config)?))
+            },
+            false => None
+        };
+        let mut memory = Memory::new(path)?;
+        memory.notifier = Notifier::new(Arc::new(PubSub::default()), config.notify_keyspace_events);
+        memory.replication = Arc::new(Replication::new(config));
+        memory.stats = Arc::new(Stats::new(config));
+        memory.slowlog = Arc::new(SlowLog::new(config));
+        let memory = Arc::new(memory);
+        let persistence = Arc::new(Persistence::new(config));
+        let (tx, rx) = mpsc::channel(100);
+        persistence.watch_queue(&tx);
+        let writer = tokio::spawn(persistence::update_data_to_file(memory.clone(), rx, persistence.clone()));
+        let expirer = tokio::spawn(remove_expired(Arc::downgrade(&memory), tx.downgrade()));
+        let scripts = Arc::new(Scripts::new(config.lua_time_limit));
+        if let Some(primary) = &config.replicaof {
+            replication::start(&memory, &tx, Some(primary.clone()));
+        }
+        if let Some(cluster) = &cluster {
+            cluster.start();
+        }
+        Ok(Store { memory, tx, persistence, scripts, cluster, monitors: Arc::default(), writer, expirer })
+    }
+    pub fn memory(&self) -> &Arc<Memory> {
+        &self.memory
+    }
+    pub fn persistence(&self) -> &Arc<Persistence> {
+        &self.persistence
+    }
+    /// The channels keyspace notifications are published on, e.g. to subscribe to them in-process.
+    pub fn pubsub(&self) -> &Arc<PubSub> {
+        self.memory.notifier.pubsub()
+    }
+    /// The replication role, offsets and connected replicas.
+    pub fn replication(&self) -> &Arc<Replication> {
+        &self.memory.replication
+    }
+    /// Counters reported by info: commands, clients, hits and misses.
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.memory.stats
+    }
+    /// Commands that ran longer than the threshold, see `--slowlog-log-slower-than`.
+    pub fn slowlog(&self) -> &Arc<SlowLog> {
+        &self.memory.slowlog
+    }
+    /// Connections receiving every command, see [`Monitors::feed`].
+    pub fn monitors(&self) -> &Arc<Monitors> {
+        &self.monitors
+    }
+    /// The nodes and slots of the cluster, None outside cluster mode.
+    pub fn cluster(&self) -> Option<&Arc<Cluster>> {
+        self.cluster.as_ref()
+    }
+    /// Follows the primary at host:port as a read-only replica, or stops following one with None.
+    pub async fn replicaof(&self, primary: Option<String>) {
+        replication::replicaof(&self.memory, &self.tx, primary).await
+    }
+    /// Runs a command given as its arguments, e.g. `["hset", "person", "name", "makuo"]`.
+    /// Admin commands that act on a server process, like shutdown, are refused.
+    pub async fn execute<S: AsRef<str>>(&self, args: &[S]) -> CacheResult {
+        self.memory.stats.command();
+        let cmd = match Command::from_args(args) {
+            Ok(c) => c,
+            Err(e) => return CacheResult::Failure(e.into())
+        };
+        let _timer = commands::lookup(&cmd.args()[0]).map(|spec| self.memory.stats.time(spec));
+        let cache = match Cache::new(&cmd) {
+            Ok(c) => c,
+            Err(e) => return CacheResult::Failure(e.into())
+        };
+        self.run(cache, cmd).await
+    }
+    /// Runs an already parsed command.
+    pub async fn run(&self, cache: Cache, cmd: Command) -> CacheResult {
+        match cache {
+            // Outside the gate: scripts take it on their own, and script kill has to reach the one holding it
+            Cache::Script => return self.scripts.command(&cmd.args()),
+            Cache::Eval | Cache::EvalSha => return self.eval(cache, cmd.args(), None).await,
+            // Takes the gate exclusively to change the role
+            Cache::ReplicaOf => {
+                let args = cmd.args();
+                let primary = match (args[1].to_lowercase().as_str(), args[2].to_lowercase().as_str()) {
+                    ("no", "one") => None,
+                    (_, port) if port.parse::<u16>().is_ok() => Some(format!("{}:{}", args[1], args[2])),
+                    _ => return CacheResult::error(ErrorCode::Syntax, "Use replicaof <host> <port> | no one")
+                };
+                self.replicaof(primary).await;
+                return CacheResult::Success(String::from("OK"));
+            },
+            Cache::Cluster => return match &self.cluster {
+                Some(cluster) => cluster.execute(&cmd.args(), &self.memory).await,
+                None => CacheResult::error(ErrorCode::Err, "This server is not in cluster mode, start it with --cluster-enabled yes")
+            },
+            // Takes the gate exclusively per key, the keys are gone once it returns. It deletes keys, so
+            // it is refused like any write
+            Cache::Migrate => return match refuse_writes(&self.memory, &self.persistence) {
+                Some(refused) => refused,
+                None => cluster::migrate(&self.memory, &self.tx, &cmd.args(), self.cluster.as_deref()).await
+            },
+            _ => {}
+        }
+        let _shared = self.memory.exclusive.read().await;
+        apply(&self.memory, &self.persistence, cache, cmd, self.tx.clone()).await
+    }
+    /// A watch to give EXEC, keys are added with [`Watch::add`] in their stored form.
+    pub fn watch(&self) -> Watch {
+        self.memory.watches.watch()
+    }
+    /// Runs queued commands with nothing else in between, the reply of each one in an array.
+    /// Their writes reach the backup file together. Nil, without running anything, if a key
+    /// watched by watch changed.
+    pub async fn exec(&self, queued: Vec<(Cache, Command)>, watch: Option<&Watch>) -> CacheResult {
+        let _exclusive = self.memory.exclusive.write().await;
+        if watch.is_some_and(Watch::is_dirty) {
+            return CacheResult::Nil;
+        }
+        if queued.iter().any(|(cache, _)| cache.is_write()) {
+            if let Some(refused) = refuse_writes(&self.memory, &self.persistence) {
+                return refused;
+            }
+        }
+        // A command queues at most three writes: an expired key, its value and its expiry
+        let (tx, mut rx) = mpsc::channel(queued.len() * 3 + 1);
+        let mut replies = Vec::with_capacity(queued.len());
+        self.memory.replication.begin_batch();
+        for (cache, cmd) in queued {
+            let result = apply(&self.memory, &self.persistence, cache, cmd, tx.clone()).await;
+            replies.push(protocol::encode_nested(&result));
+        }
+        self.memory.replication.end_batch();
+        drop(tx);
+        let mut batch = Vec::new();
+        while let Ok(pipe) = rx.try_recv() {
+            batch.push(pipe);
+        }
+        if !batch.is_empty() && self.tx.send(Pipe::Batch(batch)).await.is_err() {
+            return CacheResult::error(ErrorCode::Err, "Backup writer is not running");
+        }
+        CacheResult::Array(replies)
+    }
+    /// Runs `eval <script> <numkeys> <key>... <arg>...` or `evalsha <sha1> ...` with nothing else
+    /// in between, like a transaction. The script runs on a blocking thread, its writes reach the
+    /// backup file together once it ends. With a caller, the commands of the script are checked
+    /// against its ACL rules, and in cluster mode they may only touch keys in the slot of KEYS.
+    pub async fn eval(&self, cache: Cache, args: Vec<String>, caller: Option<Caller>) -> CacheResult {
+        let source = match cache {
+            Cache::Eval => {
+                self.scripts.load(&args[1]);
+                args[1].clone()
+            },
+            _ => match self.scripts.source(&args[1]) {
+                Some(source) => source,
+                None => return CacheResult::error(ErrorCode::NoScript, "No script with this SHA-1, run it with eval or script load first")
+            }
+        };
+        let numkeys = match args[2].parse::<usize>() {
+            Ok(n) if n <= args.len() - 3 => n,
+            _ => return CacheResult::error(ErrorCode::Syntax, "numkeys has to be a number no greater than the keys and arguments given")
+        };
+        let keys = args[3..3 + numkeys].to_vec();
+        let argv = args[3 + numkeys..].to_vec();
+        // The command was routed by its KEYS, the script cannot reach further
+        let slot = self.cluster.is_some().then(|| keys.first().map(|key| cluster::key_slot(key)));
+        let _exclusive = self.memory.exclusive.write().await;
+        // A command queues at most three writes, they are moved to the batch after each one
+        let (tx, mut rx) = mpsc::channel(4);
+        let (memory, persistence, scripts, handle) = (self.memory.clone(), self.persistence.clone(), self.scripts.clone(), Handle::current());
+        let script = task::spawn_blocking(move || {
+            let mut batch = Vec::new();
+            let result = scripts.run(&source, &keys, &argv, |args| {
+                if let Err(e) = check_call(&args, caller.as_ref(), slot) {
+                    return CacheResult::Failure(e);
+                }
+                let result = handle.block_on(call(&memory, &persistence, args, tx.clone()));
+                while let Ok(pipe) = rx.try_recv() {
+                    batch.push(pipe);
+                }
+                result
+            });
+            (result, batch)
+        });
+        self.memory.replication.begin_batch();
+        let script = script.await;
+        self.memory.replication.end_batch();
+        let (result, batch) = match script {
+            Ok(done) => done,
+            Err(e) => return CacheResult::error(ErrorCode::Err, format!("Script failed: {}", e))
+        };
+        if !batch.is_empty() && self.tx.send(Pipe::Batch(batch)).await.is_err() {
+            return CacheResult::error(ErrorCode::Err, "Backup writer is not running");
+        }
+        result
+    }
+    /// Stores a string, fails if the key already exists.
+    pub async fn set(&self, key: &str, value: &str) -> CacheResult {
+        self.execute(&["set", key, value]).await
+    }
+    /// Stores a hash made of field and value pairs.
+    pub async fn hset(&self, key: &str, fields: &[(&str, &str)]) -> CacheResult {
+        let mut args = vec!["hset", key];
+        for (field, value) in fields {
+            args.push(field);
+            args.push(value);
+        }
+        self.execute(&args).await
+    }
+    /// Stores a set of unique members.
+    pub async fn sadd(&self, key: &str, members: &[&str]) -> CacheResult {
+        let mut args = vec!["sadd", key];
+        args.extend_from_slice(members);
+        self.execute(&args).await
+    }
+    /// Stores a string that is deleted once the given seconds passed.
+    pub async fn set_ex(&self, key: &str, value: &str, seconds: u64) -> CacheResult {
+        self.execute(&["setex", key, &seconds.to_string(), value]).await
+    }
+    /// Seconds left before the key expires, -1 if it never does and -2 if it does not exist.
+    pub async fn ttl(&self, key: &str) -> CacheResult {
+        self.execute(&["ttl", key]).await
+    }
+    pub async fn get(&self, key: &str) -> CacheResult {
+        self.execute(&["get", key]).await
+    }
+    /// All fields and values of a hash.
+    pub async fn hget(&self, key: &str) -> CacheResult {
+        self.execute(&["hget", key]).await
+    }
+    pub async fn smembers(&self, key: &str) -> CacheResult {
+        self.execute(&["smembers", key]).await
+    }
+    /// Deletes a key of any type.
+    pub async fn del(&self, key: &str) -> CacheResult {
+        self.execute(&["del", key]).await
+    }
+    pub async fn hdel(&self, key: &str, field: &str) -> CacheResult {
+        self.execute(&["hdel", key, field]).await
+    }
+    pub async fn sremove(&self, key: &str, member: &str) -> CacheResult {
+        self.execute(&["sremove", key, member]).await
+    }
+    /// Resolves once the backup writer gave up under the fail-fast policy.
+    pub async fn failed(&self) -> String {
+        self.persistence.failed().await
+    }
+    /// Stops the writer and, with Save, waits for every pending write to be in the file and synced.
+    /// Fails if another reference to the memory (e.g. from [`Store::memory`]) is still alive.
+    pub async fn close(self, mode: ShutdownMode) -> Result<(), MainError> {
+        self.memory.replication.stop_link().await;
+        if mode == ShutdownMode::NoSave {
+            self.persistence.discard_pending();
+        }
+        self.expirer.abort();
+        let _ = self.expirer.await;
+        // Once the last sender is gone the writer drains what is queued and returns
+        drop(self.tx);
+        match self.writer.await {
+            Ok(result) => result?,
+            Err(e) => return Err(MainError::FileWriteError(e.to_string()))
+        }
+        let mut memory = match Arc::try_unwrap(self.memory) {
+            Ok(m) => m,
+            Err(_) => return Err(MainError::FileWriteError(String::from("Memory is still in use, pending writes could not be saved")))
+        };
+        if mode == ShutdownMode::NoSave {
+            memory.clear_pending().await;
+            return Ok(());
+        }
+        memory.recent_to_file().map_err(|e| MainError::FileWriteError(e.to_string()))?;
+        memory.sync().await.map_err(|e| MainError::FileWriteError(e.to_string()))
+    }
+}
+
+/// Runs a command on memory, with the gate already taken by the caller.
+async fn apply(memory: &Arc<Memory>, persistence: &Persistence, cache: Cache, cmd: Command, tx: Sender<Pipe>) -> CacheResult {
+    match cache {
+        Cache::Info => info(memory, persistence, &cmd).await,
+        Cache::SlowLog => memory.slowlog.command(&cmd.args()),
+        _ if cache.needs_server() => CacheResult::error(ErrorCode::Err, format!("{} is only available on a server", cmd.action)),
+        _ if cache.runs_alone() => {
+            CacheResult::error(ErrorCode::Err, format!("{} cannot run inside a transaction or a script", cmd.action))
+        },
+        _ => match cache.is_write().then(|| refuse_writes(memory, persistence)).flatten() {
+            Some(refused) => refused,
+            None => cache.handle_cmd(cmd, memory.clone(), tx).await
+        }
+    }
+}
+
+/// The READONLY failure when writes are refused: on a replica, or while the backup file cannot be written.
+fn refuse_writes(memory: &Memory, persistence: &Persistence) -> Option<CacheResult> {
+    if memory.replication.is_replica() {
+        return Some(CacheResult::error(ErrorCode::ReadOnly, "Writes are refused by a replica, send them to its primary"));
+    }
+    if persistence.refuses_writes() {
+        let error = persistence.last_error().unwrap_or_default();
+        return Some(CacheResult::error(ErrorCode::ReadOnly, format!("Writes are refused because the backup file cannot be written: {}", error)));
+    }
+    None
+}
+
+/// Runs `info [section]`, every section without one.
+async fn info(memory: &Memory, persistence: &Persistence, cmd: &Command) -> CacheResult {
+    const SECTIONS: [&str; 7] = ["server", "clients", "memory", "persistence", "stats", "replication", "keyspace"];
+    let args = cmd.args();
+    let section = args.get(1).map(|s| s.to_lowercase()).filter(|s| s != "all" && s != "everything" && s != "default");
+    let mut text = Vec::new();
+    for name in SECTIONS.into_iter().filter(|name| section.as_deref().is_none_or(|s| s == *name)) {
+        text.push(match name {
+            "server" => memory.stats.server_info(),
+            "clients" => memory.stats.clients_info(),
+            "memory" => stats::memory_info(memory).await,
+            "persistence" => persistence.info(),
+            "stats" => memory.stats.stats_info(),
+            "replication" => memory.replication.info(),
+            _ => stats::keyspace_info(memory).await
+        });
+    }
+    CacheResult::Success(text.join("\n"))
+}
+
+/// Runs a command sent by a script with redis.call.
+async fn call(memory: &Arc<Memory>, persistence: &Persistence, args: Vec<String>, tx: Sender<Pipe>) -> CacheResult {
+    let cmd = match Command::from_args(&args) {
+        Ok(c) => c,
+        Err(e) => return CacheResult::Failure(e.into())
+    };
+    match Cache::new(&cmd) {
+        Ok(cache) => apply(memory, persistence, cache, cmd, tx).await,
+        Err(e) => CacheResult::Failure(e.into())
+    }
+}
+
+/// Fails if the user of the script may not run a command it sends, or the command touches a key
+/// outside slot. slot is None outside cluster mode, and Some(None) for a script without KEYS.
+fn check_call(args: &[String], caller: Option<&Caller>, slot: Option<Option<u16>>) -> Result<(), CacheError> {
+    if let Some(caller) = caller {
+        caller.check(args)?;
+    }
+    let (Some(slot), Some(spec)) = (slot, args.first().and_then(|name| commands::lookup(name))) else {
+        return Ok(());
+    };
+    match cluster::command_keys(spec, args).into_iter().find(|key| Some(cluster::key_slot(key)) != slot) {
+        Some(key) => Err(CacheError::new(ErrorCode::CrossSlot, format!("Script accessed {} outside the slot of its KEYS, pass every key it uses in KEYS", key))),
+        None => Ok(())
+    }
+}
+
+/// Deletes expired keys in the background. Only weak references are held so a dropped store is not kept alive.
+async fn remove_expired(memory: Weak<Memory>, tx: WeakSender<Pipe>) {
+    let mut interval = time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (Some(memory), Some(tx)) = (memory.upgrade(), tx.upgrade()) else {
+            return
+        };
+        for key in memory.expired_keys().await {
+            // Keys do not expire in the middle of a transaction
+            let _shared = memory.exclusive.read().await;
+            memory.expire(&key, &tx).await;
+        }
+    }
+}
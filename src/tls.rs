@@ -0,0 +1,85 @@
+//! TLS settings for the listener and for clients, built from PEM files with rustls.
+//!
+//! The server encrypts every connection once given a certificate and key, and with a CA
+//! certificate for clients it also asks each client for a certificate signed by that CA.
+
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use rustls::{crypto::{ring, CryptoProvider}, pki_types::{CertificateDer, PrivateKeyDer, ServerName}, server::WebPkiClientVerifier, ClientConfig, RootCertStore, ServerConfig};
+
+use crate::models::MainError;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, MainError> {
+    let file = File::open(path).map_err(|e| MainError::FileReadError(format!("Certificate {} could not be read: {}", path.display(), e)))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| MainError::FileReadError(format!("Certificate {} is not valid PEM: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(MainError::FileReadError(format!("No certificate found in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, MainError> {
+    let file = File::open(path).map_err(|e| MainError::FileReadError(format!("Key {} could not be read: {}", path.display(), e)))?;
+    match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(MainError::FileReadError(format!("No private key found in {}", path.display()))),
+        Err(e) => Err(MainError::FileReadError(format!("Key {} is not valid PEM: {}", path.display(), e)))
+    }
+}
+
+fn roots(path: &Path) -> Result<RootCertStore, MainError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| MainError::FileReadError(format!("CA certificate {} was refused: {}", path.display(), e)))?;
+    }
+    Ok(roots)
+}
+
+/// Settings of a TLS listener. With client_ca, clients without a certificate signed by it are refused.
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Arc<ServerConfig>, MainError> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| MainError::BadCommandFormat(e.to_string()))?;
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots(ca)?), provider()).build()
+                .map_err(|e| MainError::BadCommandFormat(format!("Client CA {} cannot verify clients: {}", ca.display(), e)))?;
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth()
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| MainError::BadCommandFormat(format!("Certificate {} does not match key {}: {}", cert.display(), key.display(), e)))?;
+    Ok(Arc::new(config))
+}
+
+/// Settings of a TLS client. The server certificate is checked against ca, or the usual public
+/// roots without one. identity is the certificate and key shown to servers that ask for one.
+pub fn client_config(ca: Option<&Path>, identity: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>, MainError> {
+    let roots = match ca {
+        Some(ca) => roots(ca)?,
+        None => RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() }
+    };
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| MainError::BadCommandFormat(e.to_string()))?
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| MainError::BadCommandFormat(format!("Certificate {} does not match key {}: {}", cert.display(), key.display(), e)))?,
+        None => builder.with_no_client_auth()
+    };
+    Ok(Arc::new(config))
+}
+
+/// The name a server certificate has to hold for addr (host:port), a DNS name or an IP address.
+pub fn server_name(addr: &str) -> Result<ServerName<'static>, MainError> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string()).map_err(|e| MainError::BadCommandFormat(format!("{} is not a valid server name: {}", host, e)))
+}
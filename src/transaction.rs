@@ -0,0 +1,83 @@
+//! Optimistic locking for MULTI/EXEC: the keys each connection watches, and which of them
+//! changed since.
+//!
+//! Every change to a key marks the connections watching it, and EXEC refuses to run the
+//! transaction of a marked connection.
+
+use std::{collections::{BTreeSet, HashMap}, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, Mutex}};
+
+/// Connection id -> flag set once a watched key changed
+type Watchers = HashMap<u64, Arc<AtomicBool>>;
+
+/// Every watched key of the server.
+#[derive(Debug, Default)]
+pub struct Watches {
+    keys: Mutex<HashMap<String, Watchers>>,
+    /// Number of watched keys, so changes skip the lock while nobody watches
+    watched: AtomicUsize,
+    next_id: AtomicU64
+}
+
+impl Watches {
+    fn keys(&self) -> std::sync::MutexGuard<'_, HashMap<String, Watchers>> {
+        self.keys.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// Marks the connections watching key, given in its stored form.
+    pub fn touch(&self, key: &str) {
+        if self.watched.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if let Some(watchers) = self.keys().get(key) {
+            for dirty in watchers.values() {
+                dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+    /// A new watch for a connection, with no key yet.
+    pub fn watch(self: &Arc<Self>) -> Watch {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        Watch { watches: self.clone(), id, dirty: Arc::new(AtomicBool::new(false)), keys: BTreeSet::new() }
+    }
+}
+
+/// The keys a connection watches. Dropping it unwatches them.
+pub struct Watch {
+    watches: Arc<Watches>,
+    id: u64,
+    dirty: Arc<AtomicBool>,
+    keys: BTreeSet<String>
+}
+
+impl Watch {
+    /// Watches key, given in its stored form.
+    pub fn add(&mut self, key: &str) {
+        if !self.keys.insert(key.to_string()) {
+            return;
+        }
+        let mut keys = self.watches.keys();
+        let watchers = keys.entry(key.to_string()).or_default();
+        if watchers.is_empty() {
+            self.watches.watched.fetch_add(1, Ordering::Relaxed);
+        }
+        watchers.insert(self.id, self.dirty.clone());
+    }
+    /// True once a watched key changed.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let mut keys = self.watches.keys();
+        for key in &self.keys {
+            if let Some(watchers) = keys.get_mut(key) {
+                watchers.remove(&self.id);
+                if watchers.is_empty() {
+                    keys.remove(key);
+                    self.watches.watched.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use std::process::Command;
+
+use common::Server;
+use mini_mcache::{client::{Client, ClientConfig, ClientError, Connection}, models::ErrorCode, CacheResult};
+
+fn code(result: CacheResult) -> Option<ErrorCode> {
+    match result {
+        CacheResult::Failure(f) => Some(f.code),
+        _ => None
+    }
+}
+
+fn ok() -> CacheResult {
+    CacheResult::Success(String::from("OK"))
+}
+
+#[tokio::test]
+async fn requirepass_needs_auth_first() {
+    let server = Server::start_with("requirepass_needs_auth_first", &["--requirepass", "s3cret"]);
+    let mut connection = Connection::connect(&server.addr).await.unwrap();
+    assert_eq!(code(connection.execute(&["get", "name"]).await.unwrap()), Some(ErrorCode::NoAuth));
+    assert_eq!(code(connection.execute(&["ping"]).await.unwrap()), Some(ErrorCode::NoAuth));
+    assert_eq!(code(connection.execute(&["auth", "wrong"]).await.unwrap()), Some(ErrorCode::WrongPass));
+    assert_eq!(connection.execute(&["auth", "s3cret"]).await.unwrap(), ok());
+    assert_eq!(connection.execute(&["set", "name", "makuo"]).await.unwrap(), CacheResult::Success(String::from("1")));
+    assert_eq!(connection.execute(&["acl", "whoami"]).await.unwrap(), CacheResult::Success(String::from("default")));
+
+    let client = Client::connect(&server.addr).await.unwrap();
+    assert!(matches!(client.get("name").await, Err(ClientError::ServerError(e)) if e.code == ErrorCode::NoAuth));
+    let config = ClientConfig { password: Some(String::from("s3cret")), ..ClientConfig::default() };
+    let client = Client::with_config(&server.addr, config).await.unwrap();
+    assert_eq!(client.get("name").await.unwrap(), Some(String::from("makuo")));
+    let config = ClientConfig { password: Some(String::from("wrong")), ..ClientConfig::default() };
+    assert!(matches!(Client::with_config(&server.addr, config).await, Err(ClientError::ServerError(e)) if e.code == ErrorCode::WrongPass));
+}
+
+#[tokio::test]
+async fn users_are_limited_to_categories_and_keys() {
+    let server = Server::start("users_are_limited_to_categories_and_keys");
+    let mut admin = Connection::connect(&server.addr).await.unwrap();
+    assert_eq!(admin.execute(&["acl", "setuser", "reader", "on", ">pw", "+@read", "~cache:*"]).await.unwrap(), ok());
+    assert_eq!(code(admin.execute(&["acl", "setuser", "reader", "+@nothing"]).await.unwrap()), Some(ErrorCode::Syntax));
+    admin.execute(&["set", "cache:name", "makuo"]).await.unwrap();
+    admin.execute(&["set", "secret", "hidden"]).await.unwrap();
+
+    let mut reader = Connection::connect(&server.addr).await.unwrap();
+    assert_eq!(code(reader.execute(&["auth", "reader", "wrong"]).await.unwrap()), Some(ErrorCode::WrongPass));
+    assert_eq!(reader.execute(&["auth", "reader", "pw"]).await.unwrap(), ok());
+    assert_eq!(reader.execute(&["acl", "whoami"]).await.unwrap(), CacheResult::Success(String::from("reader")));
+    assert_eq!(reader.execute(&["get", "cache:name"]).await.unwrap(), CacheResult::Success(String::from("makuo")));
+    assert_eq!(code(reader.execute(&["get", "secret"]).await.unwrap()), Some(ErrorCode::NoPerm));
+    assert_eq!(code(reader.execute(&["set", "cache:other", "1"]).await.unwrap()), Some(ErrorCode::NoPerm));
+    assert_eq!(code(reader.execute(&["acl", "list"]).await.unwrap()), Some(ErrorCode::NoPerm));
+    reader.ping().await.unwrap();
+
+    match admin.execute(&["acl", "list"]).await.unwrap() {
+        CacheResult::Array(users) => {
+            assert_eq!(users.len(), 2);
+            assert_eq!(users[0], "user default on nopass ~* +@all");
+            assert!(users[1].starts_with("user reader on #"));
+            assert!(users[1].ends_with(" ~cache:* +@read"));
+            assert!(!users[1].contains("pw "));
+        },
+        other => panic!("Unexpected reply {:?}", other)
+    }
+    assert_eq!(code(admin.execute(&["acl", "deluser", "default"]).await.unwrap()), Some(ErrorCode::Err));
+    assert_eq!(admin.execute(&["acl", "deluser", "reader", "nobody"]).await.unwrap(), CacheResult::Success(String::from("1")));
+    // A deleted user is logged out
+    assert_eq!(code(reader.execute(&["get", "cache:name"]).await.unwrap()), Some(ErrorCode::NoAuth));
+}
+
+#[tokio::test]
+async fn acl_file_keeps_users_across_restarts() {
+    let acl_path = std::env::temp_dir().join("mini-cache-it-acl_file_keeps_users_across_restarts.acl");
+    let _ = std::fs::remove_file(&acl_path);
+    let options = ["--aclfile", acl_path.to_str().unwrap(), "--requirepass", "s3cret"];
+    let server = Server::start_with("acl_file_keeps_users_across_restarts", &options);
+    let config = ClientConfig { password: Some(String::from("s3cret")), ..ClientConfig::default() };
+    let client = Client::with_config(&server.addr, config).await.unwrap();
+    assert_eq!(client.execute(&["acl", "setuser", "writer", "on", ">pw", "+@read", "+@write", "allkeys"]).await.unwrap(), ok());
+    let saved = std::fs::read_to_string(&acl_path).unwrap();
+    assert!(saved.contains("user writer on #"));
+    assert!(!saved.contains(">pw"));
+    let (path, port) = (server.path.clone(), server.port());
+    drop(server);
+
+    let server = Server::start_at_with(path, port, &options);
+    let config = ClientConfig { user: Some(String::from("writer")), password: Some(String::from("pw")), ..ClientConfig::default() };
+    let client = Client::with_config(&server.addr, config).await.unwrap();
+    client.set("name", "makuo").await.unwrap();
+    assert!(matches!(client.execute(&["acl", "list"]).await.unwrap(), CacheResult::Failure(f) if f.code == ErrorCode::NoPerm));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--port", &server.port().to_string(), "--user", "writer", "-a", "pw", "get", "name"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "makuo\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--port", &server.port().to_string(), "get", "name"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use std::{io::Write, process::{Command, Output, Stdio}};
+
+use common::Server;
+
+fn client(server: &Server, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--port", &server.port().to_string()])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn one_shot_commands_and_exit_codes() {
+    let server = Server::start("one_shot_commands_and_exit_codes");
+    let output = client(&server, &["set", "greeting", "hello world"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "1\n");
+    let output = client(&server, &["set", "greeting", "again"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("(error)"));
+    let output = client(&server, &["--raw", "get", "greeting"]);
+    assert_eq!(stdout(&output), "hello world\n");
+    // A value starting with - is not read as an option once the command started
+    assert!(client(&server, &["set", "negative", "-5"]).status.success());
+    assert_eq!(stdout(&client(&server, &["get", "negative"])), "-5\n");
+}
+
+#[test]
+fn json_output() {
+    let server = Server::start("json_output");
+    client(&server, &["hset", "person", "name", "makuo \"m\"", "age", "25"]);
+    assert_eq!(stdout(&client(&server, &["--json", "hget", "person"])), "[\"name\",\"makuo \\\"m\\\"\",\"age\",\"25\"]\n");
+    assert_eq!(stdout(&client(&server, &["--json", "ping"])), "\"PONG\"\n");
+    let output = client(&server, &["--json", "get", "nobody"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "null\n");
+    let output = client(&server, &["--json", "get", "person"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).starts_with("{\"error\":"));
+    assert!(stdout(&output).contains("\"code\":\"WRONGTYPE\""));
+}
+
+#[test]
+fn pipe_runs_stdin_commands() {
+    let server = Server::start("pipe_runs_stdin_commands");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--port", &server.port().to_string(), "--pipe", "--raw"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut commands = String::new();
+    for i in 0..500 {
+        commands.push_str(&format!("set key{} \"value {}\"\n", i, i));
+    }
+    commands.push_str("\nsadd humans anita 'james bond'\nsmembers humans\nget key499\n");
+    child.stdin.take().unwrap().write_all(commands.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let out = stdout(&output);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 504);
+    assert_eq!(&lines[500..], ["1", "anita", "james bond", "value 499"]);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--port", &server.port().to_string(), "--pipe"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"set key0 again\nget \"key1\nget key1\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "value 1\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("line 2: Unbalanced quotes"));
+}
+
+#[test]
+fn unreachable_server_exits_with_2() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let output = Command::new(env!("CARGO_BIN_EXE_client")).args(["-p", &port.to_string(), "ping"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let output = Command::new(env!("CARGO_BIN_EXE_client")).args(["--bogus"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
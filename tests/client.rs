@@ -0,0 +1,141 @@
+mod common;
+
+use std::{collections::HashMap, time::Duration};
+
+use common::Server;
+use mini_mcache::{client::{Client, ClientConfig, ClientError}, CacheResult};
+
+#[tokio::test]
+async fn typed_commands() {
+    let server = Server::start("typed_commands");
+    let client = Client::connect(&server.addr).await.unwrap();
+    client.ping().await.unwrap();
+    client.set("greeting", "hello world").await.unwrap();
+    assert_eq!(client.get("greeting").await.unwrap(), Some(String::from("hello world")));
+    assert_eq!(client.get("nobody").await.unwrap(), None);
+    assert!(matches!(client.set("greeting", "again").await, Err(ClientError::ServerError(_))));
+
+    client.hset("person", &[("name", "makuo"), ("age", "25")]).await.unwrap();
+    let person = HashMap::from([(String::from("name"), String::from("makuo")), (String::from("age"), String::from("25"))]);
+    assert_eq!(client.hgetall("person").await.unwrap(), person);
+    assert!(client.hdel("person", "age").await.unwrap());
+    assert_eq!(client.hgetall("person").await.unwrap().len(), 1);
+    assert!(client.hgetall("nobody").await.unwrap().is_empty());
+
+    client.sadd("humans", &["anita", "james"]).await.unwrap();
+    assert_eq!(client.smembers("humans").await.unwrap(), vec![String::from("anita"), String::from("james")]);
+    assert!(client.srem("humans", "anita").await.unwrap());
+    assert_eq!(client.smembers("humans").await.unwrap(), vec![String::from("james")]);
+
+    assert!(client.del("greeting").await.unwrap());
+    assert!(!client.del("greeting").await.unwrap());
+    assert!(client.info().await.unwrap().contains("# Persistence"));
+    assert!(matches!(client.execute(&["met", "name"]).await.unwrap(), CacheResult::Failure(_)));
+}
+
+/// The fields of info, by name.
+fn fields(info: &str) -> HashMap<String, String> {
+    info.lines().filter_map(|line| line.split_once(':')).map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[tokio::test]
+async fn info_reports_the_server_state() {
+    let server = Server::start("info_reports_the_server_state");
+    let client = Client::connect(&server.addr).await.unwrap();
+    let _other = Client::connect(&server.addr).await.unwrap();
+    client.set("name", "makuo").await.unwrap();
+    client.set_ex("session", "makuo", Duration::from_secs(60)).await.unwrap();
+    client.hset("person", &[("name", "makuo")]).await.unwrap();
+    client.sadd("humans", &["anita", "james"]).await.unwrap();
+    assert!(client.get("name").await.unwrap().is_some());
+    assert!(client.get("nobody").await.unwrap().is_none());
+
+    let info = client.info().await.unwrap();
+    for section in ["# Server", "# Clients", "# Memory", "# Persistence", "# Stats", "# Replication", "# Keyspace"] {
+        assert!(info.contains(section), "{} is missing from\n{}", section, info);
+    }
+    let info = fields(&info);
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(info["tcp_port"], server.port().to_string());
+    assert_eq!(info["connected_clients"], "2");
+    // Also counts the connection that checked the server was up
+    assert!(info["total_connections_received"].parse::<u64>().unwrap() >= 2);
+    // The six commands above and this info
+    assert_eq!(info["total_commands_processed"], "7");
+    assert_eq!(info["keyspace_hits"], "1");
+    assert_eq!(info["keyspace_misses"], "1");
+    assert_eq!(info["db0"], "keys=4,expires=1");
+    assert_eq!((info["keys_string"].as_str(), info["keys_hash"].as_str(), info["keys_set"].as_str()), ("2", "1", "1"));
+    assert!(info["used_memory"].parse::<usize>().unwrap() > 0);
+    assert!(info.contains_key("pending_writes") && info.contains_key("last_write_error"));
+
+    // A section alone
+    let CacheResult::Success(stats) = client.execute(&["info", "stats"]).await.unwrap() else {
+        panic!("info stats failed");
+    };
+    assert!(stats.starts_with("# Stats\n") && !stats.contains("# Server"), "{}", stats);
+    assert!(fields(&stats).contains_key("instantaneous_ops_per_sec"));
+}
+
+#[tokio::test]
+async fn set_ex_expires() {
+    let server = Server::start("set_ex_expires");
+    let client = Client::connect(&server.addr).await.unwrap();
+    client.set_ex("session", "makuo", Duration::from_secs(1)).await.unwrap();
+    assert_eq!(client.ttl("session").await.unwrap(), Some(Duration::from_secs(1)));
+    assert_eq!(client.get("session").await.unwrap(), Some(String::from("makuo")));
+    assert!(matches!(client.set_ex("short", "makuo", Duration::from_millis(10)).await, Err(ClientError::ServerError(_))));
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    assert_eq!(client.get("session").await.unwrap(), None);
+    assert_eq!(client.ttl("session").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn pool_serves_concurrent_commands() {
+    let server = Server::start("pool_serves_concurrent_commands");
+    let config = ClientConfig { pool_size: 3, ..ClientConfig::default() };
+    let client = Client::with_config(&server.addr, config).await.unwrap();
+    let mut tasks = Vec::new();
+    for i in 0..50 {
+        let client = client.clone();
+        tasks.push(tokio::spawn(async move {
+            let key = format!("key{}", i);
+            client.set(&key, &i.to_string()).await.unwrap();
+            client.get(&key).await.unwrap()
+        }));
+    }
+    for (i, task) in tasks.into_iter().enumerate() {
+        assert_eq!(task.await.unwrap(), Some(i.to_string()));
+    }
+}
+
+#[tokio::test]
+async fn reconnects_after_server_restart() {
+    let mut server = Server::start("reconnects_after_server_restart");
+    let client = Client::connect(&server.addr).await.unwrap();
+    client.set("name", "makuo").await.unwrap();
+    assert!(matches!(client.execute(&["shutdown"]).await.unwrap(), CacheResult::Success(_)));
+    assert!(server.wait());
+    let server = Server::start_at(server.path.clone(), server.port());
+    // The pooled connection died with the old process, the client replaces it on its own
+    assert_eq!(client.get("name").await.unwrap(), Some(String::from("makuo")));
+    drop(server);
+}
+
+#[tokio::test]
+async fn health_check_replaces_dead_connections() {
+    let mut server = Server::start("health_check_replaces_dead_connections");
+    let config = ClientConfig { health_check_after: Duration::ZERO, ..ClientConfig::default() };
+    let client = Client::with_config(&server.addr, config).await.unwrap();
+    client.execute(&["shutdown", "nosave"]).await.unwrap();
+    assert!(server.wait());
+    let _server = Server::start_at(server.path.clone(), server.port());
+    client.ping().await.unwrap();
+}
+
+#[tokio::test]
+async fn unreachable_server_is_an_error() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let result = Client::connect(&format!("127.0.0.1:{}", port)).await;
+    assert!(matches!(result, Err(ClientError::ConnectionError(_))));
+}
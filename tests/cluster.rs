@@ -0,0 +1,282 @@
+mod common;
+
+use std::{collections::HashMap, future::Future, time::{Duration, Instant}};
+
+use common::Server;
+use mini_mcache::{client::{Client, Connection}, cluster, models::ErrorCode, CacheResult};
+use tokio::time;
+
+/// Options of a cluster node with its bus on a free port: the default, the port + 10000, may be
+/// taken or past the last port.
+fn cluster_options(bus_port: &str) -> [&str; 4] {
+    ["--cluster-enabled", "yes", "--cluster-port", bus_port]
+}
+
+/// Starts a cluster node, with its bus port.
+fn start_node(name: &str, options: &[&str]) -> (Server, String) {
+    let bus_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+    let mut all = cluster_options(&bus_port).to_vec();
+    all.extend(options);
+    (Server::start_with(name, &all), bus_port)
+}
+
+/// Polls check until it holds, for at most ten seconds: nodes learn of each other by gossip.
+async fn eventually<F: Future<Output = bool>>(what: &str, mut check: impl FnMut() -> F) {
+    let start = Instant::now();
+    while !check().await {
+        assert!(start.elapsed() < Duration::from_secs(10), "timed out waiting for {}", what);
+        time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+async fn ask(addr: &str, args: &[&str]) -> CacheResult {
+    Connection::connect(addr).await.unwrap().execute(args).await.unwrap()
+}
+
+async fn text(addr: &str, args: &[&str]) -> String {
+    match ask(addr, args).await {
+        CacheResult::Success(text) => text,
+        other => panic!("{:?} replied {:?}", args, other)
+    }
+}
+
+/// The fields of cluster info.
+async fn cluster_info(addr: &str) -> HashMap<String, String> {
+    text(addr, &["cluster", "info"]).await.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn redirect(code: ErrorCode, slot: u16, addr: &str) -> CacheResult {
+    CacheResult::error(code, format!("{} {}", slot, addr))
+}
+
+#[tokio::test]
+async fn keys_are_served_by_the_node_of_their_slot() {
+    let (nodes, bus_ports): (Vec<Server>, Vec<String>) = (0..3)
+        .map(|n| start_node(&format!("keys_are_served_by_the_node_{}", n), &[]))
+        .unzip();
+    let ranges = [(0, 5460), (5461, 10922), (10923, 16383)];
+    for (node, (start, end)) in nodes.iter().zip(ranges) {
+        let (start, end) = (start.to_string(), end.to_string());
+        assert_eq!(text(&node.addr, &["cluster", "addslotsrange", &start, &end]).await, "OK");
+    }
+    for (node, bus_port) in nodes[1..].iter().zip(&bus_ports[1..]) {
+        assert_eq!(text(&nodes[0].addr, &["cluster", "meet", "127.0.0.1", &node.port().to_string(), bus_port]).await, "OK");
+    }
+    for node in &nodes {
+        eventually("every node to know the others and their slots", || async {
+            let info = cluster_info(&node.addr).await;
+            info["cluster_state"] == "ok" && info["cluster_known_nodes"] == "3"
+        }).await;
+    }
+    let owner = |key: &str| {
+        let slot = cluster::key_slot(key);
+        ranges.iter().position(|(start, end)| (*start..=*end).contains(&slot)).unwrap()
+    };
+
+    // A node answers for its own keys and redirects the others
+    assert_eq!(text(&nodes[0].addr, &["cluster", "keyslot", "foo"]).await, "12182");
+    assert_eq!(ask(&nodes[0].addr, &["set", "foo", "bar"]).await, redirect(ErrorCode::Moved, 12182, &nodes[2].addr));
+    assert_eq!(text(&nodes[2].addr, &["set", "foo", "bar"]).await, "1");
+    assert_eq!(ask(&nodes[1].addr, &["get", "foo"]).await, redirect(ErrorCode::Moved, 12182, &nodes[2].addr));
+    // Keys of a command have to share a slot, which hash tags make sure of
+    let tagged = &nodes[owner("{user42}")].addr;
+    assert_eq!(text(tagged, &["watch", "{user42}.name", "{user42}.age"]).await, "OK");
+    let CacheResult::Failure(e) = ask(tagged, &["watch", "{user42}.name", "{user43}.name"]).await else {
+        panic!("keys of different slots were accepted");
+    };
+    assert_eq!(e.code, ErrorCode::CrossSlot);
+
+    // The client follows the redirects, whichever node it was given
+    let client = Client::connect(&nodes[0].addr).await.unwrap();
+    for n in 0..30 {
+        client.set(&format!("key:{}", n), &n.to_string()).await.unwrap();
+    }
+    client.hset("{user42}.profile", &[("name", "makuo")]).await.unwrap();
+    for n in 0..30 {
+        let key = format!("key:{}", n);
+        assert_eq!(client.get(&key).await.unwrap(), Some(n.to_string()));
+        assert_eq!(text(&nodes[owner(&key)].addr, &["get", &key]).await, n.to_string());
+    }
+    assert_eq!(client.hgetall("{user42}.profile").await.unwrap()["name"], "makuo");
+    let counts: Vec<usize> = (0..3).map(|i| (0..30).filter(|n| owner(&format!("key:{}", n)) == i).count()).collect();
+    assert!(counts.iter().all(|count| *count > 0), "keys are spread over every node: {:?}", counts);
+
+    // Every node lists the same slots
+    let CacheResult::Array(slots) = ask(&nodes[1].addr, &["cluster", "slots"]).await else {
+        panic!("cluster slots did not reply an array");
+    };
+    assert_eq!(slots.len(), 3);
+    let id = text(&nodes[2].addr, &["cluster", "myid"]).await;
+    assert_eq!(slots[2], format!("*5\t10923\t16383\t127.0.0.1\t{}\t{}", nodes[2].port(), id));
+    let listed = text(&nodes[2].addr, &["cluster", "nodes"]).await;
+    assert_eq!(listed.lines().count(), 3);
+    assert!(listed.starts_with(&format!("{} {}@{} myself,primary", id, nodes[2].addr, bus_ports[2])), "{}", listed);
+}
+
+#[tokio::test]
+async fn a_slot_migrates_between_live_nodes() {
+    let (source, source_bus) = start_node("a_slot_migrates_source", &[]);
+    let (target, _) = start_node("a_slot_migrates_target", &[]);
+    assert_eq!(text(&source.addr, &["cluster", "addslotsrange", "0", "16383"]).await, "OK");
+    assert_eq!(text(&target.addr, &["cluster", "meet", "127.0.0.1", &source.port().to_string(), &source_bus]).await, "OK");
+    eventually("the target to learn the slots of the source", || async { cluster_info(&target.addr).await["cluster_state"] == "ok" }).await;
+    let (source_id, target_id) = (text(&source.addr, &["cluster", "myid"]).await, text(&target.addr, &["cluster", "myid"]).await);
+
+    let client = Client::connect(&source.addr).await.unwrap();
+    for n in 1..=4 {
+        client.set(&format!("{{m}}{}", n), &n.to_string()).await.unwrap();
+    }
+    client.hset("{m}h", &[("name", "makuo")]).await.unwrap();
+    client.set_ex("{m}ttl", "soon", Duration::from_secs(100)).await.unwrap();
+    let slot = cluster::key_slot("{m}");
+    let slot_arg = slot.to_string();
+    assert_eq!(text(&target.addr, &["cluster", "setslot", &slot_arg, "importing", &source_id]).await, "OK");
+    assert_eq!(text(&source.addr, &["cluster", "setslot", &slot_arg, "migrating", &target_id]).await, "OK");
+    assert_eq!(text(&source.addr, &["cluster", "countkeysinslot", &slot_arg]).await, "6");
+    assert_eq!(ask(&source.addr, &["cluster", "getkeysinslot", &slot_arg, "2"]).await, CacheResult::Array(vec![String::from("{m}1"), String::from("{m}2")]));
+
+    // Part of the slot is moved, the source still serves the rest
+    let port = target.port().to_string();
+    assert_eq!(text(&source.addr, &["migrate", "127.0.0.1", &port, "{m}1", "{m}h", "{m}ttl"]).await, "OK");
+    assert_eq!(text(&source.addr, &["migrate", "127.0.0.1", &port, "{m}1"]).await, "NOKEY");
+    assert_eq!(ask(&source.addr, &["get", "{m}1"]).await, redirect(ErrorCode::Ask, slot, &target.addr));
+    assert_eq!(text(&source.addr, &["get", "{m}2"]).await, "2");
+    // The target only serves the slot after asking, for a single command
+    assert_eq!(ask(&target.addr, &["get", "{m}1"]).await, redirect(ErrorCode::Moved, slot, &source.addr));
+    let mut connection = Connection::connect(&target.addr).await.unwrap();
+    connection.execute(&["asking"]).await.unwrap();
+    assert_eq!(connection.execute(&["get", "{m}1"]).await.unwrap(), CacheResult::Success(String::from("1")));
+    assert_eq!(connection.execute(&["get", "{m}1"]).await.unwrap(), redirect(ErrorCode::Moved, slot, &source.addr));
+    connection.execute(&["asking"]).await.unwrap();
+    let CacheResult::Success(ttl) = connection.execute(&["ttl", "{m}ttl"]).await.unwrap() else {
+        panic!("ttl failed");
+    };
+    assert!((1..=100).contains(&ttl.parse::<i64>().unwrap()), "ttl {}", ttl);
+
+    // The client follows ASK for keys already moved and new ones
+    assert_eq!(client.get("{m}1").await.unwrap(), Some(String::from("1")));
+    assert_eq!(client.hgetall("{m}h").await.unwrap()["name"], "makuo");
+    assert_eq!(client.get("{m}3").await.unwrap(), Some(String::from("3")));
+    client.set("{m}new", "new").await.unwrap();
+    assert_eq!(client.get("{m}new").await.unwrap(), Some(String::from("new")));
+
+    // The slot changes hands once the source holds none of its keys
+    let CacheResult::Failure(e) = ask(&source.addr, &["cluster", "setslot", &slot_arg, "node", &target_id]).await else {
+        panic!("a slot with keys left was given away");
+    };
+    assert_eq!(e.code, ErrorCode::Err);
+    let CacheResult::Array(left) = ask(&source.addr, &["cluster", "getkeysinslot", &slot_arg, "100"]).await else {
+        panic!("getkeysinslot did not reply an array");
+    };
+    let mut args = vec!["migrate", "127.0.0.1", &port];
+    args.extend(left.iter().map(String::as_str));
+    assert_eq!(text(&source.addr, &args).await, "OK");
+    assert_eq!(text(&target.addr, &["cluster", "setslot", &slot_arg, "node", &target_id]).await, "OK");
+    assert_eq!(text(&source.addr, &["cluster", "setslot", &slot_arg, "node", &target_id]).await, "OK");
+    assert_eq!(ask(&source.addr, &["get", "{m}2"]).await, redirect(ErrorCode::Moved, slot, &target.addr));
+    assert_eq!(text(&source.addr, &["cluster", "countkeysinslot", &slot_arg]).await, "0");
+    assert_eq!(text(&target.addr, &["cluster", "countkeysinslot", &slot_arg]).await, "7");
+    assert_eq!(client.get("{m}2").await.unwrap(), Some(String::from("2")));
+    assert_eq!(client.get("{m}new").await.unwrap(), Some(String::from("new")));
+
+    // A restarted node keeps its id and what it knew of the cluster
+    let (path, source_port) = (source.path.clone(), source.port());
+    drop(source);
+    let source = Server::start_at_with(path, source_port, &cluster_options(&source_bus));
+    assert_eq!(text(&source.addr, &["cluster", "myid"]).await, source_id);
+    assert_eq!(ask(&source.addr, &["get", "{m}2"]).await, redirect(ErrorCode::Moved, slot, &target.addr));
+    assert_eq!(ask(&source.addr, &["get", "other"]).await, CacheResult::Nil);
+}
+
+#[tokio::test]
+async fn scripts_only_touch_the_slot_of_their_keys() {
+    let (node, _) = start_node("scripts_only_touch_the_slot_of_their_keys", &[]);
+    assert_eq!(text(&node.addr, &["cluster", "addslotsrange", "0", "16383"]).await, "OK");
+    let set = "redis.call('set', KEYS[1], ARGV[1]) return redis.call('set', ARGV[2], ARGV[1])";
+    assert_eq!(text(&node.addr, &["eval", set, "1", "{user42}.name", "makuo", "{user42}.alias"]).await, "1");
+    let CacheResult::Failure(e) = ask(&node.addr, &["eval", set, "1", "{user42}.age", "25", "{user43}.age"]).await else {
+        panic!("a script wrote a key outside the slot of its KEYS");
+    };
+    assert_eq!(e.code, ErrorCode::CrossSlot);
+    let CacheResult::Failure(e) = ask(&node.addr, &["eval", "return redis.call('get', '{user42}.name')", "0"]).await else {
+        panic!("a script without KEYS read a key");
+    };
+    assert_eq!(e.code, ErrorCode::CrossSlot);
+    assert_eq!(ask(&node.addr, &["get", "{user43}.age"]).await, CacheResult::Nil);
+}
+
+#[tokio::test]
+async fn nodes_are_announced_at_the_given_address() {
+    let (node, _) = start_node("nodes_are_announced_at_the_given_address", &["--cluster-announce-ip", "10.0.0.5"]);
+    assert_eq!(text(&node.addr, &["cluster", "addslotsrange", "0", "8191"]).await, "OK");
+    let announced = format!("10.0.0.5:{}", node.port());
+    assert!(text(&node.addr, &["cluster", "nodes"]).await.contains(&announced));
+    let CacheResult::Array(slots) = ask(&node.addr, &["cluster", "slots"]).await else {
+        panic!("cluster slots did not reply an array");
+    };
+    assert!(slots[0].contains("\t10.0.0.5\t"), "{:?}", slots);
+}
+
+#[tokio::test]
+async fn a_normal_user_cannot_reassign_slots() {
+    let (node, bus_port) = start_node("a_normal_user_cannot_reassign_slots", &["--requirepass", "secret", "--primaryauth", "secret"]);
+    let mut admin = Connection::connect(&node.addr).await.unwrap();
+    admin.execute(&["auth", "secret"]).await.unwrap();
+    assert_eq!(admin.execute(&["cluster", "addslotsrange", "0", "16383"]).await.unwrap(), CacheResult::Success(String::from("OK")));
+    let rules = ["acl", "setuser", "writer", "on", ">pw", "allkeys", "+@read", "+@write"];
+    assert_eq!(admin.execute(&rules).await.unwrap(), CacheResult::Success(String::from("OK")));
+    let CacheResult::Success(listed) = admin.execute(&["cluster", "nodes"]).await.unwrap() else {
+        panic!("cluster nodes failed");
+    };
+    // A node with a newer epoch claiming every slot
+    let forged = "e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0 10.0.0.9:6379@16379 myself,primary - 0 0 99 connected 0-16383";
+    let code = |reply: CacheResult| match reply {
+        CacheResult::Failure(e) => e.code,
+        other => panic!("{:?} was not refused", other)
+    };
+
+    // Gossip is not a client command, and cluster is an admin command
+    assert_eq!(code(admin.execute(&["cluster", "gossip", forged]).await.unwrap()), ErrorCode::Syntax);
+    let mut writer = Connection::connect(&node.addr).await.unwrap();
+    writer.execute(&["auth", "writer", "pw"]).await.unwrap();
+    assert_eq!(code(writer.execute(&["cluster", "setslot", "0", "node", "e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0e0"]).await.unwrap()), ErrorCode::NoPerm);
+    assert_eq!(code(writer.execute(&["cluster", "gossip", forged]).await.unwrap()), ErrorCode::NoPerm);
+
+    // The bus needs a user allowed to run cluster
+    let bus_addr = format!("127.0.0.1:{}", bus_port);
+    let mut bus = Connection::connect(&bus_addr).await.unwrap();
+    assert_eq!(code(bus.execute(&["gossip", forged]).await.unwrap()), ErrorCode::NoAuth);
+    assert_eq!(code(bus.execute(&["get", "key"]).await.unwrap()), ErrorCode::Err);
+    bus.execute(&["auth", "writer", "pw"]).await.unwrap();
+    assert_eq!(code(bus.execute(&["gossip", forged]).await.unwrap()), ErrorCode::NoPerm);
+    assert_eq!(admin.execute(&["cluster", "nodes"]).await.unwrap(), CacheResult::Success(listed.clone()));
+
+    // The nodes of the cluster log in with --primaryauth
+    bus.execute(&["auth", "secret"]).await.unwrap();
+    let CacheResult::Success(reply) = bus.execute(&["gossip", forged]).await.unwrap() else {
+        panic!("gossip of an admin was refused");
+    };
+    assert!(reply.starts_with(&listed[..40]), "{}", reply);
+}
+
+#[tokio::test]
+async fn migrate_only_overwrites_keys_with_replace() {
+    let source = Server::start("migrate_only_overwrites_keys_source");
+    let target = Server::start("migrate_only_overwrites_keys_target");
+    let port = target.port().to_string();
+    assert_eq!(text(&source.addr, &["set", "name", "new"]).await, "1");
+    assert_eq!(text(&target.addr, &["set", "name", "left over"]).await, "1");
+
+    // The target holds a copy, the key stays here
+    let CacheResult::Failure(e) = ask(&source.addr, &["migrate", "127.0.0.1", &port, "name"]).await else {
+        panic!("a key the target holds was migrated");
+    };
+    assert!(e.message.contains("refused name"), "{}", e.message);
+    assert_eq!(text(&source.addr, &["get", "name"]).await, "new");
+    assert_eq!(text(&source.addr, &["migrate", "127.0.0.1", &port, "replace", "name"]).await, "OK");
+    assert_eq!(text(&target.addr, &["get", "name"]).await, "new");
+    assert_eq!(ask(&source.addr, &["get", "name"]).await, CacheResult::Nil);
+}
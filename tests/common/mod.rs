@@ -0,0 +1,113 @@
+#![allow(dead_code)]
+
+// Runs the real server binary on a free port with its own backup file.
+
+use std::{net::TcpListener, path::PathBuf, process::{Child, Command, Stdio}, thread, time::{Duration, Instant}};
+
+pub struct Server {
+    pub addr: String,
+    pub path: PathBuf,
+    child: Child
+}
+
+impl Server {
+    /// Starts a server with an empty backup file named after the test.
+    pub fn start(name: &str) -> Server {
+        Server::start_with(name, &[])
+    }
+    /// Same as start, with more server options.
+    pub fn start_with(name: &str, options: &[&str]) -> Server {
+        let path = std::env::temp_dir().join(format!("mini-cache-it-{}.bin", name));
+        let _ = std::fs::remove_file(&path);
+        // What a cluster node knew, kept next to the backup file
+        let _ = std::fs::remove_file(path.with_extension("nodes"));
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        Server::start_at_with(path, port, options)
+    }
+    /// Starts a server on the given port, keeping what the backup file already holds.
+    pub fn start_at(path: PathBuf, port: u16) -> Server {
+        Server::start_at_with(path, port, &[])
+    }
+    pub fn start_at_with(path: PathBuf, port: u16, options: &[&str]) -> Server {
+        let child = Command::new(env!("CARGO_BIN_EXE_server"))
+            .args(["--port", &port.to_string(), "--data", path.to_str().unwrap()])
+            .args(options)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let addr = format!("127.0.0.1:{}", port);
+        let start = Instant::now();
+        while std::net::TcpStream::connect(&addr).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10), "server did not start on {}", addr);
+            thread::sleep(Duration::from_millis(20));
+        }
+        Server { addr, path, child }
+    }
+    /// Starts a server listening only on a unix socket, addr is then `unix:<path>`.
+    pub fn start_unix(name: &str, options: &[&str]) -> Server {
+        let path = std::env::temp_dir().join(format!("mini-cache-it-{}.bin", name));
+        let _ = std::fs::remove_file(&path);
+        let socket = Server::socket_path(name);
+        let child = Command::new(env!("CARGO_BIN_EXE_server"))
+            .args(["--port", "0", "--unixsocket", socket.to_str().unwrap(), "--data", path.to_str().unwrap()])
+            .args(options)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let start = Instant::now();
+        while std::os::unix::net::UnixStream::connect(&socket).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10), "server did not start on {}", socket.display());
+            thread::sleep(Duration::from_millis(20));
+        }
+        Server { addr: format!("unix:{}", socket.display()), path, child }
+    }
+    pub fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mini-cache-it-{}.sock", name))
+    }
+    pub fn port(&self) -> u16 {
+        self.addr.rsplit(':').next().unwrap().parse().unwrap()
+    }
+    /// Waits for the server to exit after a shutdown command.
+    pub fn wait(&mut self) -> bool {
+        self.child.wait().unwrap().success()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Runs the sentinel binary on a free port.
+pub struct Sentinel {
+    pub addr: String,
+    child: Child
+}
+
+impl Sentinel {
+    pub fn start(options: &[&str]) -> Sentinel {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let child = Command::new(env!("CARGO_BIN_EXE_sentinel"))
+            .args(["--port", &port.to_string()])
+            .args(options)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let addr = format!("127.0.0.1:{}", port);
+        let start = Instant::now();
+        while std::net::TcpStream::connect(&addr).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10), "sentinel did not start on {}", addr);
+            thread::sleep(Duration::from_millis(20));
+        }
+        Sentinel { addr, child }
+    }
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,30 @@
+mod common;
+
+use common::Server;
+use mini_mcache::{client::Connection, CacheResult};
+
+#[tokio::test]
+async fn commands_are_logged_as_json_with_their_spans() {
+    let log = std::env::temp_dir().join("mini-cache-it-commands_are_logged.log");
+    let _ = std::fs::remove_file(&log);
+    let mut server = Server::start_with("commands_are_logged_as_json", &[
+        "--loglevel", "debug", "--log-format", "json", "--logfile", log.to_str().unwrap()
+    ]);
+    let mut connection = Connection::connect(&server.addr).await.unwrap();
+    assert_eq!(connection.execute(&["set", "secret", "hunter2"]).await.unwrap(), CacheResult::Success(String::from("1")));
+    assert!(matches!(connection.execute(&["nope"]).await.unwrap(), CacheResult::Failure(_)));
+    connection.execute(&["shutdown"]).await.unwrap();
+    assert!(server.wait());
+
+    let text = std::fs::read_to_string(&log).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines.iter().all(|line| line.starts_with('{') && line.ends_with('}')), "{}", text);
+    assert!(lines.iter().any(|line| line.contains("\"message\":\"listening\"")), "{}", text);
+    let set = lines.iter().find(|line| line.contains("\"command\":\"set\"") && line.contains("command done")).expect(&text);
+    assert!(set.contains("\"client\":\"127.0.0.1:"), "{}", set);
+    assert!(set.contains("\"duration_us\":"), "{}", set);
+    assert!(lines.iter().any(|line| line.contains("\"command\":\"nope\"") && line.contains("command failed")), "{}", text);
+    // Values are never written to the log
+    assert!(!text.contains("hunter2"), "{}", text);
+    assert!(lines.iter().any(|line| line.contains("\"message\":\"data saved\"")), "{}", text);
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use std::{net::TcpListener, time::{Duration, Instant}};
+
+use common::Server;
+use mini_mcache::client::Client;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, time};
+
+/// The status line and body of an HTTP GET of path.
+async fn http_get(port: u16, path: &str) -> (String, String) {
+    let start = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)).await {
+            Ok(stream) => break stream,
+            Err(e) => assert!(start.elapsed() < Duration::from_secs(10), "metrics listener did not start: {}", e)
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    };
+    stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await.unwrap();
+    let (head, body) = reply.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+/// The value of the sample with the given name and labels.
+fn sample(body: &str, series: &str) -> f64 {
+    body.lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("{} missing from\n{}", series, body))
+        .parse().unwrap()
+}
+
+#[tokio::test]
+async fn metrics_are_served_over_http() {
+    let metrics_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = Server::start_with("metrics_are_served_over_http", &["--metrics-port", &metrics_port.to_string()]);
+    let client = Client::connect(&server.addr).await.unwrap();
+    client.set("name", "makuo").await.unwrap();
+    client.set_ex("session", "abc", Duration::from_secs(100)).await.unwrap();
+    client.hset("profile", &[("age", "30")]).await.unwrap();
+    assert_eq!(client.get("name").await.unwrap(), Some(String::from("makuo")));
+    assert_eq!(client.get("missing").await.unwrap(), None);
+
+    let (status, body) = http_get(metrics_port, "/metrics").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(sample(&body, "mini_cache_commands_total{command=\"set\"}"), 1.0);
+    assert_eq!(sample(&body, "mini_cache_commands_total{command=\"get\"}"), 2.0);
+    assert!(!body.contains("command=\"del\""), "commands never called are left out");
+    assert_eq!(sample(&body, "mini_cache_command_duration_seconds_count{command=\"get\"}"), 2.0);
+    assert_eq!(sample(&body, "mini_cache_command_duration_seconds_bucket{command=\"get\",le=\"+Inf\"}"), 2.0);
+    assert!(body.contains("# TYPE mini_cache_command_duration_seconds histogram"));
+    assert_eq!(sample(&body, "mini_cache_keys{type=\"string\"}"), 2.0);
+    assert_eq!(sample(&body, "mini_cache_keys{type=\"hash\"}"), 1.0);
+    assert_eq!(sample(&body, "mini_cache_keys_expiring"), 1.0);
+    assert_eq!(sample(&body, "mini_cache_keyspace_hits_total"), 1.0);
+    assert_eq!(sample(&body, "mini_cache_keyspace_misses_total"), 1.0);
+    assert_eq!(sample(&body, "mini_cache_keyspace_hit_ratio"), 0.5);
+    assert_eq!(sample(&body, "mini_cache_evicted_keys_total"), 0.0);
+    assert_eq!(sample(&body, "mini_cache_expired_keys_total"), 0.0);
+    assert!(sample(&body, "mini_cache_connected_clients") >= 1.0);
+    assert!(sample(&body, "mini_cache_memory_used_bytes_total") > 0.0);
+    assert_eq!(sample(&body, "mini_cache_persistence_queue_depth"), 0.0);
+    assert!(body.contains("mini_cache_fsync_duration_seconds_count "));
+
+    let (status, _) = http_get(metrics_port, "/").await;
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use std::time::Duration;
+
+use common::Server;
+use mini_mcache::{client::Connection, models::ErrorCode, CacheResult};
+use tokio::time;
+
+/// The next line pushed to a monitor, without its timestamp.
+async fn next_line(monitor: &mut Connection) -> String {
+    let reply = time::timeout(Duration::from_secs(5), monitor.read_reply()).await.expect("no command was pushed").unwrap();
+    let CacheResult::Success(line) = reply else {
+        panic!("{:?} is not a monitored command", reply);
+    };
+    let (timestamp, rest) = line.split_once(' ').unwrap();
+    let (seconds, micros) = timestamp.split_once('.').unwrap();
+    assert!(seconds.parse::<u64>().unwrap() > 1_700_000_000 && micros.len() == 6, "timestamp {}", timestamp);
+    rest.to_string()
+}
+
+#[tokio::test]
+async fn monitor_streams_every_command() {
+    let server = Server::start("monitor_streams_every_command");
+    let mut monitor = Connection::connect(&server.addr).await.unwrap();
+    assert_eq!(monitor.execute(&["monitor"]).await.unwrap(), CacheResult::Success(String::from("OK")));
+
+    let mut client = Connection::connect(&server.addr).await.unwrap();
+    client.execute(&["set", "greeting", "hello \"world\""]).await.unwrap();
+    client.execute(&["get", "greeting"]).await.unwrap();
+    client.execute(&["auth", "default", "secret"]).await.unwrap();
+    client.execute(&["unknown", "command"]).await.unwrap();
+
+    let first = next_line(&mut monitor).await;
+    let (client_addr, command) = first.strip_prefix("[0 ").unwrap().split_once("] ").unwrap();
+    assert!(client_addr.starts_with("127.0.0.1:"), "{}", first);
+    assert_eq!(command, r#""set" "greeting" "hello \"world\"""#);
+    assert_eq!(next_line(&mut monitor).await, format!(r#"[0 {}] "get" "greeting""#, client_addr));
+    // Passwords are not shown
+    assert_eq!(next_line(&mut monitor).await, format!(r#"[0 {}] "auth""#, client_addr));
+
+    // The unknown command was refused, the next line is the second monitor
+    let mut second = Connection::connect(&server.addr).await.unwrap();
+    second.execute(&["monitor"]).await.unwrap();
+    let pushed = next_line(&mut monitor).await;
+    assert!(pushed.ends_with(r#""monitor""#), "{}", pushed);
+    client.execute(&["del", "greeting"]).await.unwrap();
+    assert!(next_line(&mut monitor).await.ends_with(r#""del" "greeting""#));
+    assert!(next_line(&mut second).await.ends_with(r#""del" "greeting""#));
+
+    // A monitoring connection only answers ping, replies come before the commands pushed meanwhile
+    assert_eq!(second.execute(&["ping"]).await.unwrap(), CacheResult::Success(String::from("PONG")));
+    assert!(next_line(&mut second).await.ends_with(r#""ping""#));
+    let CacheResult::Failure(e) = second.execute(&["get", "greeting"]).await.unwrap() else {
+        panic!("a monitoring connection ran a command");
+    };
+    assert!(e.message.contains("monitoring"), "{}", e.message);
+}
+
+#[tokio::test]
+async fn refused_and_queued_commands_are_fed_when_they_run() {
+    let server = Server::start_with("refused_and_queued_commands_are_fed_when_they_run", &["--requirepass", "secret"]);
+    let ok = CacheResult::Success(String::from("OK"));
+    let mut admin = Connection::connect(&server.addr).await.unwrap();
+    admin.execute(&["auth", "secret"]).await.unwrap();
+    assert_eq!(admin.execute(&["acl", "setuser", "reader", "on", ">pw", "allkeys", "+@read"]).await.unwrap(), ok);
+    let mut monitor = Connection::connect(&server.addr).await.unwrap();
+    monitor.execute(&["auth", "secret"]).await.unwrap();
+    assert_eq!(monitor.execute(&["monitor"]).await.unwrap(), ok);
+    let code = |reply: CacheResult| match reply {
+        CacheResult::Failure(e) => e.code,
+        other => panic!("{:?} was not refused", other)
+    };
+
+    // Neither an unauthenticated command nor one the ACL refuses is shown
+    let mut stranger = Connection::connect(&server.addr).await.unwrap();
+    assert_eq!(code(stranger.execute(&["set", "leaked", "value"]).await.unwrap()), ErrorCode::NoAuth);
+    let mut reader = Connection::connect(&server.addr).await.unwrap();
+    reader.execute(&["auth", "reader", "pw"]).await.unwrap();
+    assert_eq!(code(reader.execute(&["set", "leaked", "value"]).await.unwrap()), ErrorCode::NoPerm);
+    reader.execute(&["get", "leaked"]).await.unwrap();
+    assert!(next_line(&mut monitor).await.ends_with(r#""auth""#));
+    assert!(next_line(&mut monitor).await.ends_with(r#""get" "leaked""#));
+
+    // The commands of a transaction are shown when exec runs them
+    admin.execute(&["multi"]).await.unwrap();
+    admin.execute(&["set", "queued", "value"]).await.unwrap();
+    assert!(next_line(&mut monitor).await.ends_with(r#""multi""#));
+    admin.execute(&["exec"]).await.unwrap();
+    assert!(next_line(&mut monitor).await.ends_with(r#""exec""#));
+    assert!(next_line(&mut monitor).await.ends_with(r#""set" "queued" "value""#));
+}
@@ -0,0 +1,139 @@
+mod common;
+
+use std::time::Duration;
+
+use common::Server;
+use mini_mcache::{client::{Client, Connection}, models::ErrorCode, protocol, pubsub::Message, CacheResult};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::TcpStream, time};
+
+fn array(items: &[&str]) -> CacheResult {
+    CacheResult::Array(items.iter().map(|i| i.to_string()).collect())
+}
+
+fn code(result: CacheResult) -> Option<ErrorCode> {
+    match result {
+        CacheResult::Failure(f) => Some(f.code),
+        _ => None
+    }
+}
+
+#[tokio::test]
+async fn subscribers_receive_published_messages() {
+    let server = Server::start("subscribers_receive_published_messages");
+    let client = Client::connect(&server.addr).await.unwrap();
+    assert_eq!(client.publish("news", "nobody listens").await.unwrap(), 0);
+
+    let mut subscriber = client.subscriber().await.unwrap();
+    assert_eq!(subscriber.subscribe(&["news", "sport"]).await.unwrap(), 2);
+    assert_eq!(subscriber.psubscribe(&["news.*"]).await.unwrap(), 3);
+    assert_eq!(client.publish("news", "hello world").await.unwrap(), 1);
+    assert_eq!(client.publish("news.local", "rain\ttonight").await.unwrap(), 1);
+
+    let message = time::timeout(Duration::from_secs(5), subscriber.next_message()).await.unwrap().unwrap();
+    assert_eq!(message, Message { pattern: None, channel: String::from("news"), payload: String::from("hello world") });
+    let message = subscriber.next_message().await.unwrap();
+    assert_eq!(message.pattern.as_deref(), Some("news.*"));
+    assert_eq!((message.channel.as_str(), message.payload.as_str()), ("news.local", "rain\ttonight"));
+
+    assert_eq!(subscriber.unsubscribe(&["sport"]).await.unwrap(), 2);
+    assert_eq!(subscriber.unsubscribe(&[]).await.unwrap(), 1);
+    assert_eq!(client.publish("news", "gone").await.unwrap(), 0);
+    assert_eq!(subscriber.punsubscribe(&[]).await.unwrap(), 0);
+    // Back to a normal connection
+    assert_eq!(client.publish("news.local", "gone").await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn pubsub_introspection_and_subscribed_mode() {
+    let server = Server::start("pubsub_introspection_and_subscribed_mode");
+    let mut first = Connection::connect(&server.addr).await.unwrap();
+    let mut second = Connection::connect(&server.addr).await.unwrap();
+    let mut other = Connection::connect(&server.addr).await.unwrap();
+    assert_eq!(first.execute(&["subscribe", "a", "b"]).await.unwrap(), array(&["subscribe", "a", "1", "subscribe", "b", "2"]));
+    assert_eq!(second.execute(&["subscribe", "a"]).await.unwrap(), array(&["subscribe", "a", "1"]));
+    assert_eq!(second.execute(&["psubscribe", "*"]).await.unwrap(), array(&["psubscribe", "*", "2"]));
+
+    assert_eq!(other.execute(&["pubsub", "channels"]).await.unwrap(), array(&["a", "b"]));
+    assert_eq!(other.execute(&["pubsub", "channels", "b*"]).await.unwrap(), array(&["b"]));
+    assert_eq!(other.execute(&["pubsub", "numsub", "a", "b", "c"]).await.unwrap(), array(&["a", "2", "b", "1", "c", "0"]));
+    assert_eq!(other.execute(&["pubsub", "numpat"]).await.unwrap(), CacheResult::Success(String::from("1")));
+    assert_eq!(code(other.execute(&["pubsub", "nothing"]).await.unwrap()), Some(ErrorCode::Syntax));
+
+    // Only the subscribe family and ping are allowed while subscribed
+    assert_eq!(code(first.execute(&["get", "a"]).await.unwrap()), Some(ErrorCode::Err));
+    assert_eq!(first.execute(&["ping"]).await.unwrap(), CacheResult::Success(String::from("PONG")));
+    assert_eq!(first.execute(&["unsubscribe"]).await.unwrap(), array(&["unsubscribe", "a", "1", "unsubscribe", "b", "0"]));
+    assert_eq!(first.execute(&["get", "a"]).await.unwrap(), CacheResult::Nil);
+    assert_eq!(first.execute(&["unsubscribe"]).await.unwrap(), array(&["unsubscribe", "", "0"]));
+
+    // A closed connection no longer counts
+    drop(second);
+    let start = std::time::Instant::now();
+    while other.execute(&["pubsub", "numpat"]).await.unwrap() != CacheResult::Success(String::from("0")) {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(other.execute(&["pubsub", "channels"]).await.unwrap(), array(&[]));
+}
+
+#[tokio::test]
+async fn pubsub_category_of_acl_users() {
+    let server = Server::start("pubsub_category_of_acl_users");
+    let mut admin = Connection::connect(&server.addr).await.unwrap();
+    let rules = ["acl", "setuser", "reader", "on", ">pw", "allkeys", "+@read"];
+    assert_eq!(admin.execute(&rules).await.unwrap(), CacheResult::Success(String::from("OK")));
+    let mut reader = Connection::connect(&server.addr).await.unwrap();
+    reader.auth(Some("reader"), "pw").await.unwrap();
+    assert_eq!(code(reader.execute(&["subscribe", "news"]).await.unwrap()), Some(ErrorCode::NoPerm));
+    assert_eq!(code(reader.execute(&["publish", "news", "hi"]).await.unwrap()), Some(ErrorCode::NoPerm));
+    assert_eq!(admin.execute(&["acl", "setuser", "reader", "+@pubsub"]).await.unwrap(), CacheResult::Success(String::from("OK")));
+    assert_eq!(reader.execute(&["subscribe", "news"]).await.unwrap(), array(&["subscribe", "news", "1"]));
+}
+
+#[tokio::test]
+async fn keyspace_notifications_are_opt_in() {
+    let server = Server::start_with("keyspace_notifications_are_opt_in", &["--notify-keyspace-events", "Egs"]);
+    let client = Client::connect(&server.addr).await.unwrap();
+    let mut subscriber = client.subscriber().await.unwrap();
+    subscriber.psubscribe(&["__key*"]).await.unwrap();
+    client.set("name", "makuo").await.unwrap();
+    client.sadd("humans", &["anita", "james"]).await.unwrap();
+    client.srem("humans", "james").await.unwrap();
+    client.del("name").await.unwrap();
+    for (event, key) in [("sadd", "humans"), ("srem", "humans"), ("del", "name")] {
+        let message = time::timeout(Duration::from_secs(5), subscriber.next_message()).await.unwrap().unwrap();
+        assert_eq!(message.channel, format!("__keyevent@0__:{}", event));
+        assert_eq!(message.payload, key);
+    }
+
+    let server = Server::start("keyspace_notifications_are_opt_in_off");
+    let client = Client::connect(&server.addr).await.unwrap();
+    let mut subscriber = client.subscriber().await.unwrap();
+    subscriber.psubscribe(&["__key*"]).await.unwrap();
+    client.set("name", "makuo").await.unwrap();
+    assert!(time::timeout(Duration::from_millis(200), subscriber.next_message()).await.is_err());
+}
+
+#[tokio::test]
+async fn requests_split_around_a_message_stay_whole() {
+    let server = Server::start("requests_split_around_a_message_stay_whole");
+    let (reader, mut writer) = TcpStream::connect(&server.addr).await.unwrap().into_split();
+    let mut reader = BufReader::new(reader);
+    let mut reply = async || {
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line).await.unwrap();
+        protocol::decode_reply(&line)
+    };
+    writer.write_all(&protocol::encode_request("test", &["subscribe", "news"])).await.unwrap();
+    assert_eq!(reply().await, array(&["subscribe", "news", "1"]));
+
+    // Half of a ping arrives, then a message is pushed, then the rest of the ping
+    let ping = protocol::encode_request("test", &["ping"]);
+    writer.write_all(&ping[..7]).await.unwrap();
+    time::sleep(Duration::from_millis(100)).await;
+    let publisher = Client::connect(&server.addr).await.unwrap();
+    assert_eq!(publisher.execute(&["publish", "news", "hello"]).await.unwrap(), CacheResult::Success(String::from("1")));
+    assert_eq!(reply().await, array(&["message", "news", "hello"]));
+    writer.write_all(&ping[7..]).await.unwrap();
+    assert_eq!(reply().await, CacheResult::Success(String::from("PONG")));
+}
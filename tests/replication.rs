@@ -0,0 +1,172 @@
+mod common;
+
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use common::Server;
+use mini_mcache::{client::Client, models::ErrorCode, CacheResult};
+use tokio::{net::{TcpListener, TcpStream}, task::JoinHandle, time};
+
+/// The fields of an info section.
+async fn info(client: &Client, section: &str) -> HashMap<String, String> {
+    let text = match client.execute(&["info", section]).await.unwrap() {
+        CacheResult::Success(text) => text,
+        other => panic!("info replied {:?}", other)
+    };
+    text.lines().filter_map(|line| line.split_once(':')).map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+/// Polls check until it holds, for at most ten seconds.
+async fn eventually<F: Future<Output = bool>>(what: &str, mut check: impl FnMut() -> F) {
+    let start = Instant::now();
+    while !check().await {
+        assert!(start.elapsed() < Duration::from_secs(10), "timed out waiting for {}", what);
+        time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+async fn in_sync(primary: &Client, replica: &Client) -> bool {
+    let (primary, replica) = (info(primary, "replication").await, info(replica, "replication").await);
+    replica["primary_link_status"] == "up" && primary["repl_offset"] == replica["replica_repl_offset"]
+}
+
+fn replica_of(primary: &Server, options: &[&str]) -> Vec<String> {
+    let mut args = vec![String::from("--replicaof"), String::from("127.0.0.1"), primary.port().to_string()];
+    args.extend(options.iter().map(|o| o.to_string()));
+    args
+}
+
+#[tokio::test]
+async fn replicas_copy_the_primary_and_follow_its_changes() {
+    let primary = Server::start("replicas_copy_the_primary");
+    let writer = Client::connect(&primary.addr).await.unwrap();
+    writer.set("name", "makuo\tokafor").await.unwrap();
+    writer.hset("person", &[("name", "makuo"), ("age", "25")]).await.unwrap();
+    writer.sadd("humans", &["anita", "james"]).await.unwrap();
+    writer.set_ex("session", "abc", Duration::from_secs(60)).await.unwrap();
+
+    let options = replica_of(&primary, &[]);
+    let replica = Server::start_with("replicas_copy_the_primary_replica", &options.iter().map(String::as_str).collect::<Vec<_>>());
+    let reader = Client::connect(&replica.addr).await.unwrap();
+    eventually("the full sync", || in_sync(&writer, &reader)).await;
+    assert_eq!(reader.get("name").await.unwrap(), Some(String::from("makuo\tokafor")));
+    assert_eq!(reader.hgetall("person").await.unwrap().get("age").map(String::as_str), Some("25"));
+    assert_eq!(reader.smembers("humans").await.unwrap(), ["anita", "james"]);
+    assert!(reader.ttl("session").await.unwrap().is_some());
+
+    // Changes made after the sync, a transaction among them
+    writer.srem("humans", "anita").await.unwrap();
+    writer.del("name").await.unwrap();
+    let commands: [&[&str]; 2] = [&["set", "a", "1"], &["set", "b", "2"]];
+    writer.transaction(&commands).await.unwrap();
+    writer.execute(&["eval", "redis.call('set', KEYS[1], ARGV[1]) return 1", "1", "scripted", "yes"]).await.unwrap();
+    eventually("the stream", || in_sync(&writer, &reader)).await;
+    assert_eq!(reader.smembers("humans").await.unwrap(), ["james"]);
+    assert_eq!(reader.get("name").await.unwrap(), None);
+    assert_eq!(reader.get("b").await.unwrap(), Some(String::from("2")));
+    assert_eq!(reader.get("scripted").await.unwrap(), Some(String::from("yes")));
+
+    // Replicas only serve reads
+    let refused = reader.execute(&["set", "other", "1"]).await.unwrap();
+    assert!(matches!(refused, CacheResult::Failure(e) if e.code == ErrorCode::ReadOnly));
+    // Migrate deletes the keys it moves, so it is refused too
+    let refused = reader.execute(&["migrate", "127.0.0.1", &primary.port().to_string(), "b"]).await.unwrap();
+    assert!(matches!(refused, CacheResult::Failure(e) if e.code == ErrorCode::ReadOnly));
+    assert_eq!(reader.get("b").await.unwrap(), Some(String::from("2")));
+    let replication = info(&writer, "replication").await;
+    assert_eq!(replication["role"], "primary");
+    assert_eq!(replication["connected_replicas"], "1");
+    assert!(replication["replica0"].starts_with(&format!("ip=127.0.0.1,port={},state=online", replica.port())));
+    let replication = info(&reader, "replication").await;
+    assert_eq!(replication["role"], "replica");
+    assert_eq!(replication["primary_port"], primary.port().to_string());
+    assert!(!replication.contains_key("last_write_error"), "only the replication section is shown");
+}
+
+/// Forwards the connections of a listener to target, until stopped.
+struct Proxy {
+    accept: JoinHandle<()>,
+    connections: Arc<Mutex<Vec<JoinHandle<()>>>>
+}
+
+impl Proxy {
+    fn start(listener: TcpListener, target: String) -> Proxy {
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let forwarded = connections.clone();
+        let accept = tokio::spawn(async move {
+            while let Ok((mut inbound, _)) = listener.accept().await {
+                let target = target.clone();
+                forwarded.lock().unwrap().push(tokio::spawn(async move {
+                    if let Ok(mut outbound) = TcpStream::connect(&target).await {
+                        let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                    }
+                }));
+            }
+        });
+        Proxy { accept, connections }
+    }
+    /// Closes the listener and every forwarded connection.
+    async fn stop(self) {
+        self.accept.abort();
+        let _ = self.accept.await;
+        for connection in self.connections.lock().unwrap().drain(..) {
+            connection.abort();
+        }
+    }
+}
+
+#[tokio::test]
+async fn replicas_continue_from_the_backlog_after_a_short_disconnect() {
+    let primary = Server::start("replicas_continue_from_the_backlog");
+    let writer = Client::connect(&primary.addr).await.unwrap();
+    writer.set("before", "1").await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_port = listener.local_addr().unwrap().port();
+    let forwarding = Proxy::start(listener, primary.addr.clone());
+
+    let port = proxy_port.to_string();
+    let replica = Server::start_with("replicas_continue_from_the_backlog_replica", &["--replicaof", "127.0.0.1", &port]);
+    let reader = Client::connect(&replica.addr).await.unwrap();
+    eventually("the full sync", || in_sync(&writer, &reader)).await;
+
+    forwarding.stop().await;
+    eventually("the link to break", || async { info(&reader, "replication").await["primary_link_status"] == "down" }).await;
+    writer.set("during", "2").await.unwrap();
+    writer.del("before").await.unwrap();
+    let forwarding = Proxy::start(TcpListener::bind(("127.0.0.1", proxy_port)).await.unwrap(), primary.addr.clone());
+
+    eventually("the partial sync", || in_sync(&writer, &reader)).await;
+    assert_eq!(reader.get("during").await.unwrap(), Some(String::from("2")));
+    assert_eq!(reader.get("before").await.unwrap(), None);
+    let replication = info(&writer, "replication").await;
+    assert_eq!((replication["sync_full"].as_str(), replication["sync_partial_ok"].as_str()), ("1", "1"));
+    forwarding.stop().await;
+}
+
+#[tokio::test]
+async fn replicaof_switches_roles_at_runtime() {
+    let primary = Server::start("replicaof_switches_roles");
+    let writer = Client::connect(&primary.addr).await.unwrap();
+    writer.set("name", "makuo").await.unwrap();
+    let other = Server::start("replicaof_switches_roles_other");
+    let client = Client::connect(&other.addr).await.unwrap();
+    client.set("stale", "1").await.unwrap();
+
+    let port = primary.port().to_string();
+    assert_eq!(client.execute(&["replicaof", "127.0.0.1", &port]).await.unwrap(), CacheResult::Success(String::from("OK")));
+    eventually("the full sync", || in_sync(&writer, &client)).await;
+    // The copy replaces what the replica held
+    assert_eq!(client.get("stale").await.unwrap(), None);
+    assert_eq!(client.get("name").await.unwrap(), Some(String::from("makuo")));
+
+    // Promoted, it accepts writes again under a new replid
+    let replid = info(&writer, "replication").await["replid"].clone();
+    assert_eq!(client.execute(&["replicaof", "no", "one"]).await.unwrap(), CacheResult::Success(String::from("OK")));
+    let replication = info(&client, "replication").await;
+    assert_eq!(replication["role"], "primary");
+    assert_eq!(replication["replid2"], replid);
+    client.set("promoted", "1").await.unwrap();
+    writer.set("lost", "1").await.unwrap();
+    assert_eq!(client.get("lost").await.unwrap(), None);
+    let syntax = client.execute(&["replicaof", "somewhere", "else"]).await.unwrap();
+    assert!(matches!(syntax, CacheResult::Failure(e) if e.code == ErrorCode::Syntax));
+}
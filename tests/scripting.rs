@@ -0,0 +1,142 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::Server;
+use mini_mcache::{client::{Client, Connection}, models::ErrorCode, CacheResult};
+
+fn code(result: CacheResult) -> Option<ErrorCode> {
+    match result {
+        CacheResult::Failure(f) => Some(f.code),
+        _ => None
+    }
+}
+
+fn success(text: &str) -> CacheResult {
+    CacheResult::Success(text.to_string())
+}
+
+const RATE_LIMIT: &str = r#"
+local used = tonumber(redis.call('get', KEYS[1]) or '0')
+if used >= tonumber(ARGV[1]) then
+    return redis.error_reply('ERR rate limited')
+end
+redis.call('del', KEYS[1])
+redis.call('set', KEYS[1], used + 1)
+return used + 1
+"#;
+
+#[tokio::test]
+async fn scripts_run_commands_atomically() {
+    let server = Server::start("scripts_run_commands_atomically");
+    let client = Client::connect(&server.addr).await.unwrap();
+    for expected in ["1", "2"] {
+        let result = client.execute(&["eval", RATE_LIMIT, "1", "calls", "2"]).await.unwrap();
+        assert_eq!(result, success(expected));
+    }
+    let result = client.execute(&["eval", RATE_LIMIT, "1", "calls", "2"]).await.unwrap();
+    assert!(matches!(result, CacheResult::Failure(f) if f.code == ErrorCode::Err && f.message == "rate limited"));
+    assert_eq!(client.get("calls").await.unwrap(), Some(String::from("2")));
+
+    // EVAL loaded the script, EVALSHA runs it by its SHA-1
+    let sha = match client.execute(&["script", "load", RATE_LIMIT]).await.unwrap() {
+        CacheResult::Success(sha) => sha,
+        other => panic!("script load replied {:?}", other)
+    };
+    assert_eq!(sha.len(), 40);
+    assert_eq!(client.execute(&["evalsha", &sha, "1", "other", "5"]).await.unwrap(), success("1"));
+    let exists = client.execute(&["script", "exists", &sha, "0000000000000000000000000000000000000000"]).await.unwrap();
+    assert_eq!(exists, CacheResult::Array(vec![String::from("1"), String::from("0")]));
+    assert_eq!(client.execute(&["script", "flush"]).await.unwrap(), success("OK"));
+    assert_eq!(code(client.execute(&["evalsha", &sha, "0"]).await.unwrap()), Some(ErrorCode::NoScript));
+}
+
+#[tokio::test]
+async fn script_errors_and_replies() {
+    let server = Server::start("script_errors_and_replies");
+    let client = Client::connect(&server.addr).await.unwrap();
+    client.set("name", "makuo").await.unwrap();
+    let eval = |script: &'static str| {
+        let client = client.clone();
+        async move { client.execute(&["eval", script, "0"]).await.unwrap() }
+    };
+    assert_eq!(eval("return {redis.call('get', 'name'), 2, 'three', nil, 'four'}").await,
+        CacheResult::Array(vec![String::from("makuo"), String::from("2"), String::from("three")]));
+    assert_eq!(eval("return redis.call('get', 'nobody')").await, CacheResult::Nil);
+    assert_eq!(eval("return 3.9").await, success("3"));
+    // A failed call stops the script with its error, pcall hands it to the script
+    assert_eq!(code(eval("redis.call('hget', 'name') return 1").await), Some(ErrorCode::WrongType));
+    assert_eq!(eval("return redis.pcall('hget', 'name').err:sub(1, 9)").await, success("WRONGTYPE"));
+    assert_eq!(code(eval("return redis.call('shutdown')").await), Some(ErrorCode::Err));
+    assert_eq!(code(eval("return redis.call('eval', 'return 1', '0')").await), Some(ErrorCode::Err));
+    assert_eq!(code(eval("return (").await), Some(ErrorCode::Syntax));
+    assert!(matches!(eval("error('boom')").await, CacheResult::Failure(f) if f.message.contains("boom")));
+    // Only safe libraries are loaded
+    assert_eq!(code(eval("return os.exit()").await), Some(ErrorCode::Err));
+    assert_eq!(eval("return redis.status_reply('FINE')").await, success("FINE"));
+    assert_eq!(code(client.execute(&["eval", "return 1", "2", "key"]).await.unwrap()), Some(ErrorCode::Syntax));
+}
+
+#[tokio::test]
+async fn long_scripts_are_stopped() {
+    let server = Server::start_with("long_scripts_are_stopped", &["--lua-time-limit", "200"]);
+    let client = Client::connect(&server.addr).await.unwrap();
+    let start = Instant::now();
+    let result = client.execute(&["eval", "while true do end", "0"]).await.unwrap();
+    assert!(matches!(result, CacheResult::Failure(f) if f.message.contains("200 ms")));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(code(client.execute(&["script", "kill"]).await.unwrap()), Some(ErrorCode::Err));
+
+    let server = Server::start_with("long_scripts_are_killed", &["--lua-time-limit", "60000"]);
+    let mut looping = Connection::connect(&server.addr).await.unwrap();
+    let client = Client::connect(&server.addr).await.unwrap();
+    let script = tokio::spawn(async move { looping.execute(&["eval", "redis.call('set', 'started', '1') while true do end", "0"]).await.unwrap() });
+    while client.execute(&["script", "kill"]).await.unwrap() != success("OK") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let result = script.await.unwrap();
+    assert!(matches!(result, CacheResult::Failure(f) if f.message.contains("SCRIPT KILL")));
+    // Writes made before the kill are kept
+    assert_eq!(client.get("started").await.unwrap(), Some(String::from("1")));
+}
+
+#[tokio::test]
+async fn scripts_outside_transactions_and_scripting_category() {
+    let server = Server::start("scripts_outside_transactions_and_scripting_category");
+    let mut admin = Connection::connect(&server.addr).await.unwrap();
+    assert_eq!(admin.execute(&["multi"]).await.unwrap(), success("OK"));
+    assert_eq!(code(admin.execute(&["eval", "return 1", "0"]).await.unwrap()), Some(ErrorCode::Err));
+    assert_eq!(code(admin.execute(&["exec"]).await.unwrap()), Some(ErrorCode::ExecAbort));
+
+    let rules = ["acl", "setuser", "writer", "on", ">pw", "allkeys", "+@read", "+@write"];
+    assert_eq!(admin.execute(&rules).await.unwrap(), success("OK"));
+    let mut writer = Connection::connect(&server.addr).await.unwrap();
+    writer.auth(Some("writer"), "pw").await.unwrap();
+    assert_eq!(code(writer.execute(&["eval", "return 1", "0"]).await.unwrap()), Some(ErrorCode::NoPerm));
+    assert_eq!(admin.execute(&["acl", "setuser", "writer", "+@scripting"]).await.unwrap(), success("OK"));
+    assert_eq!(writer.execute(&["eval", "return redis.call('set', KEYS[1], ARGV[1])", "1", "name", "makuo"]).await.unwrap(), success("1"));
+}
+
+#[tokio::test]
+async fn script_commands_are_checked_against_the_acl() {
+    let server = Server::start("script_commands_are_checked_against_the_acl");
+    let mut admin = Connection::connect(&server.addr).await.unwrap();
+    assert_eq!(admin.execute(&["set", "other", "secret"]).await.unwrap(), success("1"));
+    let rules = ["acl", "setuser", "scripter", "on", ">pw", "~allowed:*", "+@scripting", "+@read", "+@write"];
+    assert_eq!(admin.execute(&rules).await.unwrap(), success("OK"));
+    let mut scripter = Connection::connect(&server.addr).await.unwrap();
+    scripter.auth(Some("scripter"), "pw").await.unwrap();
+
+    let set = "return redis.call('set', KEYS[1], ARGV[1])";
+    assert_eq!(scripter.execute(&["eval", set, "1", "allowed:name", "makuo"]).await.unwrap(), success("1"));
+    assert_eq!(code(scripter.execute(&["eval", set, "1", "other", "makuo"]).await.unwrap()), Some(ErrorCode::NoPerm));
+    // Keys the script did not declare are checked as well
+    assert_eq!(code(scripter.execute(&["eval", "return redis.call('del', 'other')", "0"]).await.unwrap()), Some(ErrorCode::NoPerm));
+    assert_eq!(code(scripter.execute(&["eval", "return redis.pcall('get', 'other')", "0"]).await.unwrap()), Some(ErrorCode::NoPerm));
+    assert_eq!(admin.execute(&["get", "other"]).await.unwrap(), success("secret"));
+
+    // So are the categories of the commands
+    assert_eq!(admin.execute(&["acl", "setuser", "scripter", "-@write"]).await.unwrap(), success("OK"));
+    assert_eq!(code(scripter.execute(&["eval", set, "1", "allowed:age", "25"]).await.unwrap()), Some(ErrorCode::NoPerm));
+    assert_eq!(scripter.execute(&["eval", "return redis.call('get', KEYS[1])", "1", "allowed:name"]).await.unwrap(), success("makuo"));
+}
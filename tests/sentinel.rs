@@ -0,0 +1,145 @@
+mod common;
+
+use std::{collections::HashMap, future::Future, time::{Duration, Instant}};
+
+use common::{Sentinel, Server};
+use mini_mcache::{client::{Client, Connection}, protocol, sentinel, CacheResult};
+use tokio::time;
+
+/// The fields of the replication section of info.
+async fn replication(client: &Client) -> HashMap<String, String> {
+    let text = match client.execute(&["info", "replication"]).await.unwrap() {
+        CacheResult::Success(text) => text,
+        other => panic!("info replied {:?}", other)
+    };
+    text.lines().filter_map(|line| line.split_once(':')).map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+/// Polls check until it holds, for at most thirty seconds: a failover takes a few rounds.
+async fn eventually<F: Future<Output = bool>>(what: &str, mut check: impl FnMut() -> F) {
+    let start = Instant::now();
+    while !check().await {
+        assert!(start.elapsed() < Duration::from_secs(30), "timed out waiting for {}", what);
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn ask(sentinel: &Sentinel, args: &[&str]) -> CacheResult {
+    Connection::connect(&sentinel.addr).await.unwrap().execute(args).await.unwrap()
+}
+
+/// What a sentinel knows of the primary named cache.
+async fn primary(sentinel: &Sentinel) -> HashMap<String, String> {
+    let CacheResult::Array(items) = ask(sentinel, &["sentinel", "primary", "cache"]).await else {
+        panic!("sentinel primary did not reply an array");
+    };
+    items.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect()
+}
+
+#[tokio::test]
+async fn sentinels_promote_a_replica_once_the_quorum_agrees() {
+    let primary_server = Server::start("sentinels_promote_a_replica");
+    let port = primary_server.port().to_string();
+    let old_addr = primary_server.addr.clone();
+    let replica_of = ["--replicaof", "127.0.0.1", port.as_str()];
+    let replicas = [
+        Server::start_with("sentinels_promote_a_replica_1", &replica_of),
+        Server::start_with("sentinels_promote_a_replica_2", &replica_of)
+    ];
+    let options = ["--monitor", "cache", "127.0.0.1", &port, "2", "--down-after-ms", "1000", "--failover-timeout-ms", "3000"];
+    let sentinels = [Sentinel::start(&options), Sentinel::start(&options), Sentinel::start(&options)];
+    let writer = Client::connect(&primary_server.addr).await.unwrap();
+    writer.set("name", "makuo").await.unwrap();
+    // Replicas are found through the primary, sentinels through their hello messages
+    for sentinel in &sentinels {
+        eventually("the replicas and sentinels to be found", || async {
+            let known = primary(sentinel).await;
+            known["num-replicas"] == "2" && known["num-other-sentinels"] == "2" && known["flags"] == "primary"
+        }).await;
+    }
+    for replica in &replicas {
+        let reader = Client::connect(&replica.addr).await.unwrap();
+        eventually("the replicas to sync", || async { reader.get("name").await.unwrap().is_some() }).await;
+    }
+
+    let (path, old_port) = (primary_server.path.clone(), primary_server.port());
+    drop(primary_server);
+    let addrs: Vec<&str> = sentinels.iter().map(|s| s.addr.as_str()).collect();
+    eventually("the failover", || async { sentinel::primary_addr(&addrs, "cache").await.is_ok_and(|addr| addr != old_addr) }).await;
+    let promoted = sentinel::primary_addr(&addrs, "cache").await.unwrap();
+    assert!(replicas.iter().any(|r| r.addr == promoted), "{} is not one of the replicas", promoted);
+    for sentinel in &sentinels {
+        eventually("every sentinel to know the new primary", || async {
+            sentinel::primary_addr(&[&sentinel.addr], "cache").await.is_ok_and(|addr| addr == promoted)
+        }).await;
+        assert_ne!(primary(sentinel).await["config-epoch"], "0");
+    }
+
+    // The promoted replica takes writes, the other one follows it
+    let writer = Client::connect(&promoted).await.unwrap();
+    assert_eq!(replication(&writer).await["role"], "primary");
+    assert_eq!(writer.get("name").await.unwrap(), Some(String::from("makuo")));
+    writer.set("after", "1").await.unwrap();
+    let new_port = promoted.rsplit(':').next().unwrap().to_string();
+    let other = replicas.iter().find(|r| r.addr != promoted).unwrap();
+    let other = Client::connect(&other.addr).await.unwrap();
+    eventually("the other replica to follow the new primary", || async {
+        let info = replication(&other).await;
+        info["primary_port"] == new_port && other.get("after").await.unwrap().is_some()
+    }).await;
+
+    // The former primary comes back as a replica of the new one
+    let former = Server::start_at(path, old_port);
+    let former = Client::connect(&former.addr).await.unwrap();
+    eventually("the former primary to follow the new one", || async {
+        let info = replication(&former).await;
+        info["role"] == "replica" && info["primary_port"] == new_port && former.get("after").await.unwrap().is_some()
+    }).await;
+}
+
+#[tokio::test]
+async fn a_sentinel_below_the_quorum_does_not_fail_over() {
+    let primary_server = Server::start("a_sentinel_below_the_quorum");
+    let port = primary_server.port().to_string();
+    let replica = Server::start_with("a_sentinel_below_the_quorum_replica", &["--replicaof", "127.0.0.1", &port]);
+    let lone = Sentinel::start(&["--monitor", "cache", "127.0.0.1", &port, "2", "--down-after-ms", "500", "--failover-timeout-ms", "1000"]);
+    eventually("the replica to be found", || async { primary(&lone).await["num-replicas"] == "1" }).await;
+    let CacheResult::Array(replicas) = ask(&lone, &["sentinel", "replicas", "cache"]).await else {
+        panic!("sentinel replicas did not reply an array");
+    };
+    let fields = protocol::decode_reply(replicas[0].as_bytes());
+    let CacheResult::Array(fields) = fields else {
+        panic!("a replica is described by an array");
+    };
+    assert_eq!(fields[..4], ["ip", "127.0.0.1", "port", &replica.port().to_string()]);
+    assert_eq!(ask(&lone, &["sentinel", "sentinels", "cache"]).await, CacheResult::Array(Vec::new()));
+
+    drop(primary_server);
+    eventually("the primary to be down for the sentinel", || async { primary(&lone).await["flags"] == "primary,s_down" }).await;
+    time::sleep(Duration::from_secs(2)).await;
+    // One sentinel is not enough to agree, nothing changes
+    assert_eq!(primary(&lone).await["flags"], "primary,s_down");
+    let reader = Client::connect(&replica.addr).await.unwrap();
+    assert_eq!(replication(&reader).await["role"], "replica");
+    // Sentinels that do not answer are skipped, an unknown name is an error
+    let addr = sentinel::primary_addr(&["127.0.0.1:1", &lone.addr], "cache").await.unwrap();
+    assert_eq!(addr, format!("127.0.0.1:{}", port));
+    assert!(sentinel::primary_addr(&[&lone.addr], "other").await.is_err());
+}
+
+#[tokio::test]
+async fn sentinels_are_found_at_their_announced_address() {
+    let server = Server::start("sentinels_are_found_at_their_announced_address");
+    let port = server.port().to_string();
+    let options = ["--monitor", "cache", "127.0.0.1", &port, "1"];
+    let watcher = Sentinel::start(&options);
+    let announced = Sentinel::start(&[&options[..], &["--announce-ip", "10.0.0.5"]].concat());
+    let announced_port = announced.addr.rsplit_once(':').unwrap().1.to_string();
+    let expected = format!("\tip\t10.0.0.5\tport\t{}\t", announced_port);
+    eventually("the hello message of the announced sentinel", || async {
+        let CacheResult::Array(sentinels) = ask(&watcher, &["sentinel", "sentinels", "cache"]).await else {
+            return false;
+        };
+        sentinels.iter().any(|fields| fields.contains(&expected))
+    }).await;
+}
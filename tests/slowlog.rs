@@ -0,0 +1,59 @@
+mod common;
+
+use common::Server;
+use mini_mcache::{client::Connection, protocol, CacheResult};
+
+/// The fields of the entries of slowlog get, and the arguments of each.
+async fn entries(connection: &mut Connection, args: &[&str]) -> Vec<(Vec<String>, Vec<String>)> {
+    let CacheResult::Array(entries) = connection.execute(args).await.unwrap() else {
+        panic!("slowlog get did not reply an array");
+    };
+    entries.iter().map(|entry| {
+        let CacheResult::Array(fields) = protocol::decode_reply(entry.as_bytes()) else {
+            panic!("{} is not an entry", entry);
+        };
+        let CacheResult::Array(command) = protocol::decode_reply(fields[3].as_bytes()) else {
+            panic!("{} holds no arguments", entry);
+        };
+        (fields, command)
+    }).collect()
+}
+
+#[tokio::test]
+async fn slow_commands_are_logged_with_their_client() {
+    let server = Server::start_with("slow_commands_are_logged", &["--slowlog-log-slower-than", "0", "--slowlog-max-len", "3"]);
+    let mut connection = Connection::connect(&server.addr).await.unwrap();
+    let long = "v".repeat(300);
+    connection.execute(&["set", "first", "1"]).await.unwrap();
+    connection.execute(&["set", "long", &long]).await.unwrap();
+    connection.execute(&["get", "long"]).await.unwrap();
+
+    let logged = entries(&mut connection, &["slowlog", "get"]).await;
+    // The get of slowlog itself is logged once it ran
+    assert_eq!(logged.len(), 3);
+    let (fields, command) = &logged[0];
+    assert_eq!(fields.len(), 5);
+    assert_eq!(command, &["get", "long"]);
+    assert!(fields[4].starts_with("127.0.0.1:"), "client {}", fields[4]);
+    assert!(fields[1].parse::<u64>().unwrap() > 1_700_000_000, "timestamp {}", fields[1]);
+    fields[2].parse::<u64>().unwrap();
+    let (fields, command) = &logged[1];
+    assert_eq!(command[2], format!("{}... (172 more bytes)", "v".repeat(128)));
+    assert_eq!(fields[0].parse::<u64>().unwrap() + 1, logged[0].0[0].parse::<u64>().unwrap());
+
+    assert_eq!(entries(&mut connection, &["slowlog", "get", "1"]).await[0].1, ["slowlog", "get"]);
+    assert_eq!(connection.execute(&["slowlog", "len"]).await.unwrap(), CacheResult::Success(String::from("3")));
+    assert_eq!(connection.execute(&["slowlog", "reset"]).await.unwrap(), CacheResult::Success(String::from("OK")));
+    assert_eq!(connection.execute(&["slowlog", "len"]).await.unwrap(), CacheResult::Success(String::from("1")));
+    assert!(matches!(connection.execute(&["slowlog", "get", "many"]).await.unwrap(), CacheResult::Failure(_)));
+}
+
+#[tokio::test]
+async fn fast_commands_stay_out_of_the_slowlog() {
+    let server = Server::start("fast_commands_stay_out_of_the_slowlog");
+    let mut connection = Connection::connect(&server.addr).await.unwrap();
+    connection.execute(&["set", "key", "value"]).await.unwrap();
+    connection.execute(&["get", "key"]).await.unwrap();
+    assert_eq!(connection.execute(&["slowlog", "len"]).await.unwrap(), CacheResult::Success(String::from("0")));
+    assert_eq!(connection.execute(&["slowlog", "get"]).await.unwrap(), CacheResult::Array(Vec::new()));
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use std::{io::Write, path::PathBuf, process::{Command, Stdio}};
+
+use common::Server;
+use mini_mcache::{client::{Client, ClientConfig, Connection}, tls, CacheResult};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+/// A CA and the server and client certificates it signed, written as PEM files.
+struct Certs {
+    ca: PathBuf,
+    server_cert: PathBuf,
+    server_key: PathBuf,
+    client_cert: PathBuf,
+    client_key: PathBuf,
+    /// A CA that signed nothing here
+    other_ca: PathBuf
+}
+
+impl Certs {
+    fn new(name: &str) -> Certs {
+        let dir = std::env::temp_dir().join(format!("mini-cache-it-{}-certs", name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |file: &str, pem: String| {
+            let path = dir.join(file);
+            std::fs::write(&path, pem).unwrap();
+            path
+        };
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        let server_key = KeyPair::generate().unwrap();
+        let server = CertificateParams::new(vec![String::from("localhost"), String::from("127.0.0.1")]).unwrap()
+            .signed_by(&server_key, &ca, &ca_key).unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let client = CertificateParams::new(vec![String::from("client")]).unwrap()
+            .signed_by(&client_key, &ca, &ca_key).unwrap();
+        let other_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let other = params.self_signed(&other_key).unwrap();
+        Certs {
+            ca: write("ca.pem", ca.pem()),
+            server_cert: write("server.pem", server.pem()),
+            server_key: write("server.key", server_key.serialize_pem()),
+            client_cert: write("client.pem", client.pem()),
+            client_key: write("client.key", client_key.serialize_pem()),
+            other_ca: write("other.pem", other.pem())
+        }
+    }
+    fn server_options(&self) -> Vec<&str> {
+        vec!["--tls-cert", self.server_cert.to_str().unwrap(), "--tls-key", self.server_key.to_str().unwrap()]
+    }
+}
+
+#[tokio::test]
+async fn tls_listener_serves_tls_clients_only() {
+    let certs = Certs::new("tls_listener_serves_tls_clients_only");
+    let server = Server::start_with("tls_listener_serves_tls_clients_only", &certs.server_options());
+    let config = ClientConfig { tls: Some(tls::client_config(Some(&certs.ca), None).unwrap()), ..ClientConfig::default() };
+    let client = Client::with_config(&server.addr, config).await.unwrap();
+    client.set("greeting", "hello world").await.unwrap();
+    assert_eq!(client.get("greeting").await.unwrap(), Some(String::from("hello world")));
+    // The certificate holds both the DNS name and the IP address
+    let localhost = format!("localhost:{}", server.port());
+    let mut connection = Connection::connect_with(&localhost, Some(&tls::client_config(Some(&certs.ca), None).unwrap())).await.unwrap();
+    connection.ping().await.unwrap();
+
+    let mut plain = Connection::connect(&server.addr).await.unwrap();
+    assert!(plain.execute(&["get", "greeting"]).await.is_err());
+    let untrusted = tls::client_config(Some(&certs.other_ca), None).unwrap();
+    assert!(Connection::connect_with(&server.addr, Some(&untrusted)).await.is_err());
+
+    let port = server.port().to_string();
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--port", &port, "--tls", "--cacert", certs.ca.to_str().unwrap(), "get", "greeting"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hello world\n");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--port", &port, "--tls", "--cacert", certs.ca.to_str().unwrap(), "--pipe", "--raw"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"sadd humans anita james\nsmembers humans\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\nanita\njames\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--port", &port, "--cacert", certs.ca.to_str().unwrap(), "get", "greeting"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[tokio::test]
+async fn mutual_tls_requires_a_client_certificate() {
+    let certs = Certs::new("mutual_tls_requires_a_client_certificate");
+    let mut options = certs.server_options();
+    options.extend(["--tls-ca-cert", certs.ca.to_str().unwrap()]);
+    let server = Server::start_with("mutual_tls_requires_a_client_certificate", &options);
+
+    let anonymous = tls::client_config(Some(&certs.ca), None).unwrap();
+    // With TLS 1.3 the server refuses the client after the client considers the handshake done
+    let refused = match Connection::connect_with(&server.addr, Some(&anonymous)).await {
+        Ok(mut connection) => connection.execute(&["ping"]).await.is_err(),
+        Err(_) => true
+    };
+    assert!(refused);
+
+    let identity = tls::client_config(Some(&certs.ca), Some((&certs.client_cert, &certs.client_key))).unwrap();
+    let mut connection = Connection::connect_with(&server.addr, Some(&identity)).await.unwrap();
+    assert_eq!(connection.execute(&["ping"]).await.unwrap(), CacheResult::Success(String::from("PONG")));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--port", &server.port().to_string(), "--tls", "--cacert", certs.ca.to_str().unwrap()])
+        .args(["--cert", certs.client_cert.to_str().unwrap(), "--key", certs.client_key.to_str().unwrap(), "ping"])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "PONG\n");
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use common::Server;
+use mini_mcache::{client::{Client, ClientError, Connection}, models::ErrorCode, CacheResult};
+
+fn ok() -> CacheResult {
+    CacheResult::Success(String::from("OK"))
+}
+
+fn queued() -> CacheResult {
+    CacheResult::Success(String::from("QUEUED"))
+}
+
+fn code(result: CacheResult) -> Option<ErrorCode> {
+    match result {
+        CacheResult::Failure(f) => Some(f.code),
+        _ => None
+    }
+}
+
+#[tokio::test]
+async fn exec_runs_the_queued_commands() {
+    let server = Server::start("exec_runs_the_queued_commands");
+    let mut connection = Connection::connect(&server.addr).await.unwrap();
+    assert_eq!(code(connection.execute(&["exec"]).await.unwrap()), Some(ErrorCode::Err));
+    assert_eq!(code(connection.execute(&["discard"]).await.unwrap()), Some(ErrorCode::Err));
+    assert_eq!(connection.execute(&["multi"]).await.unwrap(), ok());
+    assert_eq!(code(connection.execute(&["multi"]).await.unwrap()), Some(ErrorCode::Err));
+    assert_eq!(connection.execute(&["set", "name", "makuo"]).await.unwrap(), queued());
+    assert_eq!(connection.execute(&["sadd", "humans", "anita", "james"]).await.unwrap(), queued());
+    assert_eq!(connection.execute(&["get", "name"]).await.unwrap(), queued());
+    assert_eq!(connection.execute(&["set", "name", "again"]).await.unwrap(), queued());
+    // Nothing ran yet
+    let other = Client::connect(&server.addr).await.unwrap();
+    assert_eq!(other.get("name").await.unwrap(), None);
+    let replies = match connection.execute(&["exec"]).await.unwrap() {
+        CacheResult::Array(items) => items,
+        other => panic!("exec replied {:?}", other)
+    };
+    assert_eq!(replies[..3], ["+1", "+1", "+makuo"]);
+    // A command failing while running does not stop the others
+    assert!(replies[3].starts_with("-ERR "));
+    assert_eq!(other.smembers("humans").await.unwrap(), ["anita", "james"]);
+
+    assert_eq!(connection.execute(&["multi"]).await.unwrap(), ok());
+    assert_eq!(connection.execute(&["del", "name"]).await.unwrap(), queued());
+    assert_eq!(connection.execute(&["discard"]).await.unwrap(), ok());
+    assert_eq!(other.get("name").await.unwrap(), Some(String::from("makuo")));
+}
+
+#[tokio::test]
+async fn refused_commands_abort_the_transaction() {
+    let server = Server::start("refused_commands_abort_the_transaction");
+    let mut connection = Connection::connect(&server.addr).await.unwrap();
+    assert_eq!(connection.execute(&["multi"]).await.unwrap(), ok());
+    assert_eq!(connection.execute(&["set", "name", "makuo"]).await.unwrap(), queued());
+    assert_eq!(code(connection.execute(&["set", "name"]).await.unwrap()), Some(ErrorCode::Syntax));
+    assert_eq!(code(connection.execute(&["subscribe", "news"]).await.unwrap()), Some(ErrorCode::Err));
+    assert_eq!(code(connection.execute(&["exec"]).await.unwrap()), Some(ErrorCode::ExecAbort));
+    assert_eq!(connection.execute(&["get", "name"]).await.unwrap(), CacheResult::Nil);
+
+    let client = Client::connect(&server.addr).await.unwrap();
+    let result = client.transaction(&[&["set", "name", "makuo"][..], &["nothing"][..]]).await;
+    assert!(matches!(result, Err(ClientError::ServerError(e)) if e.code == ErrorCode::ExecAbort));
+    let replies = client.transaction(&[&["set", "name", "makuo"][..], &["get", "name"][..], &["get", "nobody"][..]]).await.unwrap();
+    assert_eq!(replies, [CacheResult::Success(String::from("1")), CacheResult::Success(String::from("makuo")), CacheResult::Nil]);
+}
+
+#[tokio::test]
+async fn watched_keys_make_exec_fail() {
+    let server = Server::start("watched_keys_make_exec_fail");
+    let mut connection = Connection::connect(&server.addr).await.unwrap();
+    let client = Client::connect(&server.addr).await.unwrap();
+    client.sadd("pending", &["anita"]).await.unwrap();
+
+    assert_eq!(connection.execute(&["watch", "pending", "counter"]).await.unwrap(), ok());
+    assert_eq!(connection.execute(&["multi"]).await.unwrap(), ok());
+    assert_eq!(code(connection.execute(&["watch", "other"]).await.unwrap()), Some(ErrorCode::Err));
+    assert_eq!(connection.execute(&["sremove", "pending", "anita"]).await.unwrap(), queued());
+    // Changed by another connection before exec
+    client.set("counter", "1").await.unwrap();
+    assert_eq!(connection.execute(&["exec"]).await.unwrap(), CacheResult::Nil);
+    assert_eq!(client.smembers("pending").await.unwrap(), ["anita"]);
+
+    // Exec unwatched everything, and an unchanged watched key lets it run
+    assert_eq!(connection.execute(&["watch", "pending"]).await.unwrap(), ok());
+    client.set("unrelated", "1").await.unwrap();
+    assert_eq!(connection.execute(&["multi"]).await.unwrap(), ok());
+    assert_eq!(connection.execute(&["sremove", "pending", "anita"]).await.unwrap(), queued());
+    assert_eq!(connection.execute(&["exec"]).await.unwrap(), CacheResult::Array(vec![String::from("+1")]));
+
+    assert_eq!(connection.execute(&["watch", "counter"]).await.unwrap(), ok());
+    assert_eq!(connection.execute(&["unwatch"]).await.unwrap(), ok());
+    client.del("counter").await.unwrap();
+    assert_eq!(connection.execute(&["multi"]).await.unwrap(), ok());
+    assert_eq!(connection.execute(&["exec"]).await.unwrap(), CacheResult::Array(Vec::new()));
+}
+
+#[tokio::test]
+async fn transaction_writes_survive_a_restart() {
+    let mut server = Server::start("transaction_writes_survive_a_restart");
+    let client = Client::connect(&server.addr).await.unwrap();
+    client.sadd("pending", &["anita", "james"]).await.unwrap();
+    client.set_ex("session", "makuo", std::time::Duration::from_secs(60)).await.unwrap();
+    let commands: [&[&str]; 4] = [&["sremove", "pending", "anita"], &["sadd", "done", "anita"], &["del", "session"], &["setex", "token", "60", "abc"]];
+    client.transaction(&commands).await.unwrap();
+    client.execute(&["shutdown"]).await.unwrap();
+    assert!(server.wait());
+
+    let _server = Server::start_at(server.path.clone(), server.port());
+    assert_eq!(client.smembers("pending").await.unwrap(), ["james"]);
+    assert_eq!(client.smembers("done").await.unwrap(), ["anita"]);
+    assert_eq!(client.get("session").await.unwrap(), None);
+    assert_eq!(client.get("token").await.unwrap(), Some(String::from("abc")));
+    assert!(client.ttl("token").await.unwrap().is_some());
+}
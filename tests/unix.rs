@@ -0,0 +1,47 @@
+mod common;
+
+use std::{os::unix::fs::PermissionsExt, process::Command};
+
+use common::Server;
+use mini_mcache::client::Client;
+
+#[tokio::test]
+async fn unix_socket_serves_clients() {
+    let mut server = Server::start_unix("unix_socket_serves_clients", &[]);
+    let socket = Server::socket_path("unix_socket_serves_clients");
+    assert_eq!(std::fs::metadata(&socket).unwrap().permissions().mode() & 0o777, 0o700);
+    let client = Client::connect(&server.addr).await.unwrap();
+    client.set("greeting", "hello world").await.unwrap();
+    assert_eq!(client.get("greeting").await.unwrap(), Some(String::from("hello world")));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["-s", socket.to_str().unwrap(), "--raw", "get", "greeting"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hello world\n");
+
+    client.execute(&["shutdown"]).await.unwrap();
+    assert!(server.wait());
+    assert!(!socket.exists());
+}
+
+#[tokio::test]
+async fn socket_permissions_and_leftover_file() {
+    let name = "socket_permissions_and_leftover_file";
+    let socket = Server::socket_path(name);
+    // A socket file left by a server that was killed is replaced
+    let _ = std::fs::remove_file(&socket);
+    let leftover = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+    drop(leftover);
+    let server = Server::start_unix(name, &["--unixsocketperm", "660"]);
+    assert_eq!(std::fs::metadata(&socket).unwrap().permissions().mode() & 0o777, 0o660);
+    let client = Client::connect(&server.addr).await.unwrap();
+    client.ping().await.unwrap();
+    // Nothing listens on TCP with --port 0
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--port", "0", "ping"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}